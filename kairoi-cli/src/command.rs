@@ -0,0 +1,197 @@
+//! Parsing of commands, shared by the interactive and one-shot modes.
+//!
+//! Commands mirror instructions of the Kairoi Client Protocol, in lowercase:
+//! * `set identifier execution`,
+//! * `get identifier`,
+//! * `unset identifier`,
+//! * `rule set identifier pattern runner [runner_arguments...]`,
+//! * `rule list`,
+//! * `compact`,
+//! * `compact status`,
+//! * `backup name`,
+//! * `export name`,
+//! * `import name [skip | overwrite | fail]`,
+//! * `promote`,
+//! * and `auth name password`.
+//!
+//! Executions are either absolute datetimes like `2020-06-17 21:47:16` (in the UTC timezone), or
+//! durations relative to now, like `+10m` or `+1h30m`.
+
+use chrono::{DateTime, Duration};
+use chrono::offset::{TimeZone, Utc};
+use kairoi_client::{ImportPolicy, Request, Runner};
+use kairoi_protocol::DATETIME_FORMAT;
+
+/// Parse the given arguments as a request, computing relative executions from the given current
+/// datetime. Return a message explaining the expected usage when arguments are invalid.
+pub fn parse(arguments: &[String], now: DateTime<Utc>) -> Result<Request, String> {
+    let lowercase: Vec<String> = arguments.iter().take(2).map(|argument| argument.to_lowercase()).collect();
+    let lowercase: Vec<&str> = lowercase.iter().map(|argument| argument.as_str()).collect();
+
+    match (lowercase.as_slice(), arguments) {
+        (["set", ..], [_, identifier, execution]) => match parse_execution(execution, now) {
+            Some(execution) => Ok(Request::SetJob {
+                identifier: identifier.clone(),
+                execution,
+            }),
+            None => Err(format!("invalid execution '{}' (expected like '2020-06-17 21:47:16' or '+10m')", execution)),
+        },
+        (["set", ..], _) => Err(String::from("usage: set <identifier> <execution>")),
+        (["get", ..], [_, identifier]) => Ok(Request::GetJob { identifier: identifier.clone() }),
+        (["get", ..], _) => Err(String::from("usage: get <identifier>")),
+        (["unset", ..], [_, identifier]) => Ok(Request::UnsetJob { identifier: identifier.clone() }),
+        (["unset", ..], _) => Err(String::from("usage: unset <identifier>")),
+        (["rule", "set"], [_, _, identifier, pattern, runner, runner_arguments @ ..]) => {
+            let runner = match (runner.to_lowercase().as_str(), runner_arguments) {
+                ("shell", [command]) => Runner::Shell {
+                    command: command.clone(),
+                },
+                ("amqp", [dsn, exchange, routing_key]) => Runner::Amqp {
+                    dsn: dsn.clone(),
+                    exchange: exchange.clone(),
+                    routing_key: routing_key.clone(),
+                },
+                _ => return Err(String::from("usage: rule set <identifier> <pattern> (shell <command> | amqp <dsn> <exchange> <routing_key>)")),
+            };
+
+            Ok(Request::SetRule {
+                identifier: identifier.clone(),
+                pattern: pattern.clone(),
+                runner,
+            })
+        },
+        (["rule", "set"], _) => Err(String::from("usage: rule set <identifier> <pattern> (shell <command> | amqp <dsn> <exchange> <routing_key>)")),
+        (["rule", "list"], [_, _]) => Ok(Request::ListRules),
+        (["rule", ..], _) => Err(String::from("usage: rule (set | list) [arguments...]")),
+        (["compact"], [_]) => Ok(Request::Compact),
+        (["compact", "status"], [_, _]) => Ok(Request::GetCompaction),
+        (["compact", ..], _) => Err(String::from("usage: compact [status]")),
+        (["backup", ..], [_, path]) => Ok(Request::Backup { path: path.clone() }),
+        (["backup", ..], _) => Err(String::from("usage: backup <name>")),
+        (["export", ..], [_, path]) => Ok(Request::Export { path: path.clone() }),
+        (["export", ..], _) => Err(String::from("usage: export <name>")),
+        (["promote"], [_]) => Ok(Request::Promote),
+        (["promote", ..], _) => Err(String::from("usage: promote")),
+        (["import", ..], [_, path, policy @ ..]) if policy.len() <= 1 => {
+            let policy = match policy.first().map(|policy| policy.to_lowercase()).as_deref() {
+                None | Some("fail") => ImportPolicy::Fail,
+                Some("skip") => ImportPolicy::Skip,
+                Some("overwrite") => ImportPolicy::Overwrite,
+                Some(_) => return Err(String::from("usage: import <name> [skip | overwrite | fail]")),
+            };
+
+            Ok(Request::Import { path: path.clone(), policy })
+        },
+        (["import", ..], _) => Err(String::from("usage: import <name> [skip | overwrite | fail]")),
+        (["auth", ..], [_, name, password]) => Ok(Request::Authenticate { name: name.clone(), password: password.clone() }),
+        (["auth", ..], _) => Err(String::from("usage: auth <name> <password>")),
+        ([], _) => Err(String::from("missing command")),
+        ([command, ..], _) => Err(format!("unknown command '{}'", command)),
+    }
+}
+
+/// Parse the given execution, either absolute or relative to the given current datetime.
+fn parse_execution(execution: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match execution.strip_prefix('+') {
+        Some(relative) => {
+            let mut duration = Duration::zero();
+            let mut number = String::new();
+            for character in relative.chars() {
+                if character.is_ascii_digit() {
+                    number.push(character);
+
+                    continue;
+                };
+                // Bound values, so durations can't overflow.
+                let value: i64 = number.parse().ok().filter(|value| *value <= 1_000_000)?;
+                number.clear();
+                duration = duration.checked_add(&match character {
+                    's' => Duration::seconds(value),
+                    'm' => Duration::minutes(value),
+                    'h' => Duration::hours(value),
+                    'd' => Duration::days(value),
+                    'w' => Duration::weeks(value),
+                    _ => return None,
+                })?;
+            };
+            // Every number must be followed by its unit.
+            if !number.is_empty() || relative.is_empty() {
+                return None;
+            };
+
+            now.checked_add_signed(duration)
+        },
+        None => Utc.datetime_from_str(execution, DATETIME_FORMAT).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> Vec<String> {
+        arguments.iter().map(|argument| argument.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let now = Utc.ymd(2020, 6, 17).and_hms(21, 47, 16);
+
+        assert_eq!(
+            parse(&arguments(&["set", "app.job.1", "+10m"]), now),
+            Ok(Request::SetJob { identifier: String::from("app.job.1"), execution: Utc.ymd(2020, 6, 17).and_hms(21, 57, 16) }),
+        );
+        assert_eq!(
+            parse(&arguments(&["SET", "app.job.1", "2021-01-01 00:00:00"]), now),
+            Ok(Request::SetJob { identifier: String::from("app.job.1"), execution: Utc.ymd(2021, 1, 1).and_hms(0, 0, 0) }),
+        );
+        assert_eq!(parse(&arguments(&["unset", "app.job.1"]), now), Ok(Request::UnsetJob { identifier: String::from("app.job.1") }));
+        assert_eq!(
+            parse(&arguments(&["rule", "set", "app.rule", "app.", "shell", "script.sh"]), now),
+            Ok(Request::SetRule { identifier: String::from("app.rule"), pattern: String::from("app."), runner: Runner::Shell { command: String::from("script.sh") } }),
+        );
+        assert_eq!(parse(&arguments(&["Rule", "List"]), now), Ok(Request::ListRules));
+        assert_eq!(parse(&arguments(&["compact"]), now), Ok(Request::Compact));
+        assert_eq!(parse(&arguments(&["compact", "STATUS"]), now), Ok(Request::GetCompaction));
+        assert_eq!(parse(&arguments(&["backup", "kairoi.backup"]), now), Ok(Request::Backup { path: String::from("kairoi.backup") }));
+        assert_eq!(parse(&arguments(&["export", "kairoi.ndjson"]), now), Ok(Request::Export { path: String::from("kairoi.ndjson") }));
+        assert_eq!(parse(&arguments(&["Promote"]), now), Ok(Request::Promote));
+        assert_eq!(
+            parse(&arguments(&["import", "kairoi.ndjson", "Skip"]), now),
+            Ok(Request::Import { path: String::from("kairoi.ndjson"), policy: ImportPolicy::Skip }),
+        );
+        assert_eq!(
+            parse(&arguments(&["import", "kairoi.ndjson"]), now),
+            Ok(Request::Import { path: String::from("kairoi.ndjson"), policy: ImportPolicy::Fail }),
+        );
+        assert_eq!(
+            parse(&arguments(&["AUTH", "producer", "my password"]), now),
+            Ok(Request::Authenticate { name: String::from("producer"), password: String::from("my password") }),
+        );
+        // Test invalid commands.
+        assert!(parse(&arguments(&["set", "app.job.1", "tomorrow"]), now).is_err());
+        assert!(parse(&arguments(&["get"]), now).is_err());
+        assert!(parse(&arguments(&["rule", "set", "app.rule", "app.", "amqp", "dsn"]), now).is_err());
+        assert!(parse(&arguments(&["rule"]), now).is_err());
+        assert!(parse(&arguments(&["compact", "now"]), now).is_err());
+        assert!(parse(&arguments(&["backup"]), now).is_err());
+        assert!(parse(&arguments(&["import", "kairoi.ndjson", "merge"]), now).is_err());
+        assert!(parse(&arguments(&["auth", "producer"]), now).is_err());
+        assert!(parse(&arguments(&["version"]), now).is_err());
+        assert!(parse(&arguments(&[]), now).is_err());
+    }
+
+    #[test]
+    fn test_parse_execution() {
+        let now = Utc.ymd(2020, 6, 17).and_hms(21, 47, 16);
+
+        assert_eq!(parse_execution("+45s", now), Some(Utc.ymd(2020, 6, 17).and_hms(21, 48, 1)));
+        assert_eq!(parse_execution("+1h30m", now), Some(Utc.ymd(2020, 6, 17).and_hms(23, 17, 16)));
+        assert_eq!(parse_execution("+1w2d", now), Some(Utc.ymd(2020, 6, 26).and_hms(21, 47, 16)));
+        assert_eq!(parse_execution("+10", now), None);
+        assert_eq!(parse_execution("+m", now), None);
+        assert_eq!(parse_execution("+", now), None);
+        assert_eq!(parse_execution("+10y", now), None);
+        assert_eq!(parse_execution("+99999999999999999999w", now), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Kairoi's command-line client.
+//!
+//! Without any command, it starts an interactive mode (REPL), with history, auto-generated request
+//! identifiers and pretty-printed responses. With a command (like `kairoi-cli set app.job.1
+//! +10m`), it sends the single corresponding request, prints the response, and exits with a code
+//! describing the result (read more in the [`output`] module).
+
+mod command;
+mod output;
+mod repl;
+
+use chrono::offset::Utc;
+use clap::App;
+use clap::crate_name;
+use clap::crate_version;
+use clap::Arg;
+use kairoi_client::Connection;
+use output::{EXIT_INVALID_COMMAND, exit_code, format_error, format_reply};
+use std::env;
+use std::fs;
+use std::process;
+use std::time::Duration;
+
+/// The environment variable containing the password, when no password file is given.
+const PASSWORD_VARIABLE: &str = "KAIROI_PASSWORD";
+
+fn main() {
+    let matches = App::new(crate_name!())
+        .version(crate_version!())
+        .about("Command-line client for Kairoi. Starts an interactive mode when no command is given.")
+        .arg(
+            Arg::new("host")
+                .short('H')
+                .long("host")
+                .takes_value(true)
+                .value_name("ADDRESS")
+                .default_value("127.0.0.1:5678")
+                .help("Sets the address of the Kairoi server")
+        )
+        .arg(
+            Arg::new("unix")
+                .long("unix")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("Connects through the Unix socket at the given path, instead of the host")
+        )
+        .arg(
+            Arg::new("user")
+                .short('u')
+                .long("user")
+                .takes_value(true)
+                .value_name("NAME")
+                .help("Authenticates as the given user, with the password read from the password file or the KAIROI_PASSWORD environment variable")
+        )
+        .arg(
+            Arg::new("password-file")
+                .long("password-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .requires("user")
+                .help("Sets the file containing the password of the user")
+        )
+        .arg(
+            Arg::new("timeout")
+                .short('t')
+                .long("timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Sets the maximum duration to wait for responses")
+        )
+        .arg(
+            Arg::new("command")
+                .takes_value(true)
+                .multiple_values(true)
+                .value_name("COMMAND")
+                .help("Sets the command to send (for example: set app.job.1 +10m)")
+        )
+        .help_template("USAGE: {usage}\n\n{about}\n\n{all-args}")
+        .get_matches()
+    ;
+
+    let host = matches.value_of("host").unwrap_or("127.0.0.1:5678");
+    let timeout = match matches.value_of("timeout").map(|timeout| timeout.parse::<f64>()) {
+        Some(Ok(timeout)) if timeout > 0.0 && timeout.is_finite() => Some(Duration::from_secs_f64(timeout)),
+        Some(_) => {
+            eprintln!("error: the timeout must be a positive number of seconds");
+            process::exit(EXIT_INVALID_COMMAND);
+        },
+        None => None,
+    };
+    let credentials = match matches.value_of("user") {
+        Some(user) => match password(matches.value_of("password-file")) {
+            Ok(password) => Some((user, password)),
+            Err(message) => {
+                eprintln!("error: {}", message);
+                process::exit(EXIT_INVALID_COMMAND);
+            },
+        },
+        None => None,
+    };
+    let arguments: Option<Vec<String>> = matches.values_of("command").map(|values| values.map(|value| value.to_string()).collect());
+
+    // Validate one-shot commands before connecting, so invalid commands never reach the server.
+    let request = match &arguments {
+        Some(arguments) => match command::parse(arguments, Utc::now()) {
+            Ok(request) => Some(request),
+            Err(message) => {
+                eprintln!("error: {}", message);
+                process::exit(EXIT_INVALID_COMMAND);
+            },
+        },
+        None => None,
+    };
+
+    let (connection, server) = match matches.value_of("unix") {
+        Some(path) => (connect_unix(path), path),
+        None => (Connection::connect(host), host),
+    };
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(error) => {
+            eprintln!("error: unable to connect to {} ({})", server, error);
+            process::exit(exit_code(&error));
+        },
+    };
+    if let Err(error) = connection.set_timeout(timeout) {
+        eprintln!("{}", format_error(&error));
+        process::exit(exit_code(&error));
+    };
+    if let Some((user, password)) = credentials {
+        if let Err(error) = connection.authenticate(user, &password) {
+            eprintln!("error: unable to authenticate as {} ({})", user, error);
+            process::exit(exit_code(&error));
+        };
+    };
+
+    let code = match request {
+        Some(request) => match connection.pipeline(&[request]).and_then(|mut results| results.pop().unwrap_or(Err(kairoi_client::Error::Protocol))) {
+            Ok(reply) => {
+                println!("{}", format_reply(&reply));
+
+                0
+            },
+            Err(error) => {
+                eprintln!("{}", format_error(&error));
+
+                exit_code(&error)
+            },
+        },
+        None => repl::run(&mut connection, server),
+    };
+
+    process::exit(code);
+}
+
+/// Read the password of the user from the given file, or from the environment variable when no
+/// file is given. Line feeds ending the file are ignored.
+fn password(file: Option<&str>) -> Result<String, String> {
+    match file {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(text) => Ok(text.trim_end_matches(|character| character == '\n' || character == '\r').to_string()),
+            Err(error) => Err(format!("unable to read the password file {} ({})", path, error)),
+        },
+        None => match env::var(PASSWORD_VARIABLE) {
+            Ok(password) => Ok(password),
+            Err(_) => Err(format!("the password must be given with --password-file or the {} environment variable", PASSWORD_VARIABLE)),
+        },
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> Result<Connection, kairoi_client::Error> {
+    Connection::connect_unix(path)
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_: &str) -> Result<Connection, kairoi_client::Error> {
+    Err(kairoi_client::Error::Io(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform")))
+}
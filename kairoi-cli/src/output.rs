@@ -0,0 +1,132 @@
+//! Pretty-printing of replies and errors.
+
+use kairoi_client::{Error, JobStatus, Reply, Runner};
+use kairoi_protocol::DATETIME_FORMAT;
+
+/// The exit code when the server can't be reached, or sends invalid messages.
+pub const EXIT_CONNECTION_FAILURE: i32 = 1;
+/// The exit code when the command is invalid (also used for invalid command-line arguments).
+pub const EXIT_INVALID_COMMAND: i32 = 2;
+/// The exit code when the server rejects the request.
+pub const EXIT_REJECTED: i32 = 3;
+/// The exit code when the target of the request doesn't exist.
+pub const EXIT_NOT_FOUND: i32 = 4;
+/// The exit code when the request has been rejected by the rate limiter of the server.
+pub const EXIT_RATE_LIMITED: i32 = 5;
+/// The exit code when the request writes to a read-only replica.
+pub const EXIT_READ_ONLY: i32 = 6;
+/// The exit code when the request writes to a node of a cluster which isn't its leader.
+pub const EXIT_NOT_LEADER: i32 = 7;
+/// The exit code when the client isn't authenticated, or its credentials are invalid.
+pub const EXIT_UNAUTHORIZED: i32 = 8;
+
+/// Format the given reply for humans.
+pub fn format_reply(reply: &Reply) -> String {
+    match reply {
+        Reply::Done => String::from("OK"),
+        Reply::Job(job) => format!(
+            "identifier: {}\nexecution:  {} UTC\nstatus:     {}",
+            job.identifier,
+            job.execution.format(DATETIME_FORMAT),
+            match job.status {
+                JobStatus::Planned => "planned",
+                JobStatus::Triggered => "triggered",
+                JobStatus::Executed => "executed",
+                JobStatus::Failed => "failed",
+            },
+        ),
+        Reply::Rules(rules) if rules.is_empty() => String::from("(no rules)"),
+        Reply::Rules(rules) => {
+            let width = rules.iter().map(|rule| rule.identifier.chars().count()).max().unwrap_or(0);
+            let lines: Vec<String> = rules.iter().map(|rule| {
+                let runner = match &rule.runner {
+                    Runner::Shell { command } => format!("shell {:?}", command),
+                    Runner::Amqp { dsn, exchange, routing_key } => format!("amqp {:?} {:?} {:?}", dsn, exchange, routing_key),
+                };
+
+                format!("{:width$}  {:?} -> {}", rule.identifier, rule.pattern, runner, width = width)
+            }).collect();
+
+            lines.join("\n")
+        },
+        Reply::Compaction(compaction) => format!(
+            "running: {}\nlast:    {}",
+            match &compaction.running {
+                Some(progress) => format!("{}/{} bytes, started at {} UTC", progress.processed_bytes, progress.total_bytes, progress.started.format(DATETIME_FORMAT)),
+                None => String::from("no"),
+            },
+            match &compaction.last {
+                Some(result) if result.success => format!("success at {} UTC, {} entries kept", result.finished.format(DATETIME_FORMAT), result.entries),
+                Some(result) => format!("failure at {} UTC", result.finished.format(DATETIME_FORMAT)),
+                None => String::from("none"),
+            },
+        ),
+        Reply::Transfer(transfer) => format!("{} jobs, {} rules, {} skipped", transfer.jobs, transfer.rules, transfer.skipped),
+    }
+}
+
+/// Format the given error for humans.
+pub fn format_error(error: &Error) -> String {
+    format!("error: {}", error)
+}
+
+/// Get the exit code corresponding to the given error.
+pub fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::Io(_) | Error::Protocol => EXIT_CONNECTION_FAILURE,
+        Error::Rejected => EXIT_REJECTED,
+        Error::NotFound => EXIT_NOT_FOUND,
+        Error::RateLimited => EXIT_RATE_LIMITED,
+        Error::ReadOnly => EXIT_READ_ONLY,
+        Error::NotLeader(_) => EXIT_NOT_LEADER,
+        Error::Unauthenticated | Error::Unauthorized => EXIT_UNAUTHORIZED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::offset::{TimeZone, Utc};
+    use kairoi_client::{Compaction, CompactionProgress, CompactionResult, Job, Rule, Transfer};
+
+    #[test]
+    fn test_format_reply() {
+        assert_eq!(format_reply(&Reply::Done), "OK");
+        let job = Job {
+            identifier: String::from("app.job.1"),
+            execution: Utc.ymd(2020, 6, 17).and_hms(21, 47, 16),
+            status: JobStatus::Planned,
+        };
+        assert_eq!(
+            format_reply(&Reply::Job(job)),
+            "identifier: app.job.1\nexecution:  2020-06-17 21:47:16 UTC\nstatus:     planned",
+        );
+        let rules = vec![
+            Rule {
+                identifier: String::from("app.rule.default"),
+                pattern: String::from("app."),
+                runner: Runner::Shell { command: String::from("script.sh") },
+            },
+            Rule {
+                identifier: String::from("amqp"),
+                pattern: String::from(""),
+                runner: Runner::Amqp { dsn: String::from("amqp://localhost"), exchange: String::from("e"), routing_key: String::from("k") },
+            },
+        ];
+        assert_eq!(
+            format_reply(&Reply::Rules(rules)),
+            "app.rule.default  \"app.\" -> shell \"script.sh\"\namqp              \"\" -> amqp \"amqp://localhost\" \"e\" \"k\"",
+        );
+        assert_eq!(format_reply(&Reply::Rules(vec![])), "(no rules)");
+        let compaction = Compaction {
+            running: Some(CompactionProgress { started: Utc.ymd(2020, 6, 17).and_hms(21, 47, 16), processed_bytes: 120, total_bytes: 5000 }),
+            last: Some(CompactionResult { success: true, finished: Utc.ymd(2020, 6, 17).and_hms(21, 40, 0), entries: 4200 }),
+        };
+        assert_eq!(
+            format_reply(&Reply::Compaction(compaction)),
+            "running: 120/5000 bytes, started at 2020-06-17 21:47:16 UTC\nlast:    success at 2020-06-17 21:40:00 UTC, 4200 entries kept",
+        );
+        assert_eq!(format_reply(&Reply::Compaction(Compaction { running: None, last: None })), "running: no\nlast:    none");
+        assert_eq!(format_reply(&Reply::Transfer(Transfer { jobs: 120, rules: 2, skipped: 0 })), "120 jobs, 2 rules, 0 skipped");
+    }
+}
@@ -0,0 +1,136 @@
+//! The interactive mode, reading commands line by line.
+
+use chrono::offset::Utc;
+use kairoi_client::Connection;
+use kairoi_protocol::{Error as ParseError, parse};
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+use std::path::PathBuf;
+use super::command;
+use super::output::{format_error, format_reply};
+
+const HELP: &str = "\
+Commands:
+  set <identifier> <execution>     Set a job (execution like \"2020-06-17 21:47:16\" or +10m)
+  get <identifier>                 Get a job
+  unset <identifier>               Unset a job
+  rule set <identifier> <pattern> shell <command>
+  rule set <identifier> <pattern> amqp <dsn> <exchange> <routing_key>
+                                   Set a rule
+  rule list                        List all rules
+  compact                          Start compacting the storage
+  compact status                   Get the state of the storage compaction
+  backup <path>                    Start writing a backup of the storage, on the server
+  export <path>                    Export all jobs and rules as NDJSON, on the server
+  import <path> [skip | overwrite | fail]
+                                   Import jobs and rules from NDJSON, on the server
+  promote                          Promote a replica to a primary
+  auth <name> <password>           Authenticate as a user
+  help                             Display this help
+  quit                             Quit
+Arguments containing spaces must be quoted, like \"my job\" (backslashes and double quotes being
+escaped in quoted arguments).";
+
+/// Run the interactive mode on the given connection, until the user quits or the connection
+/// fails. Return the exit code of the program.
+pub fn run(connection: &mut Connection, prompt: &str) -> i32 {
+    let mut editor = Editor::<()>::new();
+    let history = history_path();
+    if let Some(history) = &history {
+        // A missing history file is expected on first use.
+        let _ = editor.load_history(history);
+    };
+
+    println!("Connected to {}. Type 'help' for the list of commands.", prompt);
+    let exit_code = loop {
+        let line = match editor.readline(&format!("{}> ", prompt)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break 0,
+            Err(error) => {
+                eprintln!("error: {}", error);
+
+                break 1;
+            },
+        };
+        if line.trim().is_empty() {
+            continue;
+        };
+        editor.add_history_entry(line.as_str());
+
+        let arguments = match split(&line) {
+            Ok(arguments) => arguments,
+            Err(message) => {
+                eprintln!("error: {}", message);
+
+                continue;
+            },
+        };
+        match arguments.first().map(|argument| argument.to_lowercase()).as_deref() {
+            Some("help") => {
+                println!("{}", HELP);
+
+                continue;
+            },
+            Some("quit") | Some("exit") => break 0,
+            _ => {},
+        };
+
+        let request = match command::parse(&arguments, Utc::now()) {
+            Ok(request) => request,
+            Err(message) => {
+                eprintln!("error: {}", message);
+
+                continue;
+            },
+        };
+        match connection.pipeline(&[request]) {
+            Ok(mut results) => match results.pop() {
+                Some(Ok(reply)) => println!("{}", format_reply(&reply)),
+                Some(Err(error)) => eprintln!("{}", format_error(&error)),
+                None => {},
+            },
+            Err(error) => {
+                eprintln!("{}", format_error(&error));
+
+                break 1;
+            },
+        };
+    };
+
+    if let Some(history) = &history {
+        if let Err(error) = editor.save_history(history) {
+            eprintln!("warning: unable to save the history ({}).", error);
+        };
+    };
+
+    exit_code
+}
+
+/// Split the given line into arguments, following the quoting rules of the Kairoi Client
+/// Protocol.
+fn split(line: &str) -> Result<Vec<String>, String> {
+    // The protocol grammar expects messages starting with a request identifier, so a placeholder
+    // one is prepended.
+    match parse(&format!("_ {}\n", line)) {
+        Ok((_, (_, arguments))) => Ok(arguments),
+        Err((_, ParseError::Incomplete)) => Err(String::from("unterminated quoted argument")),
+        Err(_) => Err(String::from("invalid quoting")),
+    }
+}
+
+/// Get the path of the history file, in the home directory of the user.
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kairoi_history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("set \"my job\" +10m"), Ok(vec![String::from("set"), String::from("my job"), String::from("+10m")]));
+        assert_eq!(split("  get   app.job.1 "), Ok(vec![String::from("get"), String::from("app.job.1")]));
+        assert!(split("get \"my job").is_err());
+    }
+}
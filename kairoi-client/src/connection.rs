@@ -0,0 +1,350 @@
+use chrono::DateTime;
+use chrono::offset::Utc;
+use kairoi_protocol::{Error as ParseError, format, parse};
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+use std::time::Duration;
+use super::{Compaction, Error, ImportPolicy, Job, Reply, Request, Rule, Runner, Transfer};
+
+/// The stream of a connection, either through TCP or through a Unix socket.
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buffer),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buffer),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A blocking connection to a Kairoi server.
+pub struct Connection {
+    stream: Stream,
+    input: String,
+    bytes_to_parse: Vec<u8>,
+    sequence: u64,
+}
+
+impl Connection {
+    /// Connect to the Kairoi server listening on the given address.
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<Connection, Error> {
+        let stream = TcpStream::connect(address)?;
+
+        Ok(Connection::from_stream(stream))
+    }
+
+    /// Connect to the Kairoi server listening on the Unix socket at the given path.
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Connection, Error> {
+        let stream = UnixStream::connect(path)?;
+
+        Ok(Connection::with_stream(Stream::Unix(stream)))
+    }
+
+    /// Create a new connection on the given stream, already connected to a Kairoi server.
+    pub fn from_stream(stream: TcpStream) -> Connection {
+        Connection::with_stream(Stream::Tcp(stream))
+    }
+
+    fn with_stream(stream: Stream) -> Connection {
+        Connection {
+            stream,
+            input: String::new(),
+            bytes_to_parse: Vec::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Set the maximum duration to wait for responses, or wait indefinitely with nothing.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.stream.set_read_timeout(timeout)?;
+
+        Ok(())
+    }
+
+    /// Authenticate as the user with the given name, when the server requires clients to
+    /// authenticate.
+    pub fn authenticate(&mut self, name: &str, password: &str) -> Result<(), Error> {
+        self.execute(Request::Authenticate {
+            name: name.to_string(),
+            password: password.to_string(),
+        }).map(|_| ())
+    }
+
+    /// Register a job to be executed at the given execution time.
+    pub fn set_job(&mut self, identifier: &str, execution: DateTime<Utc>) -> Result<(), Error> {
+        self.execute(Request::SetJob {
+            identifier: identifier.to_string(),
+            execution,
+        }).map(|_| ())
+    }
+
+    /// Retrieve the job with the given identifier.
+    pub fn get_job(&mut self, identifier: &str) -> Result<Job, Error> {
+        match self.execute(Request::GetJob { identifier: identifier.to_string() })? {
+            Reply::Job(job) => Ok(job),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    /// Remove the job with the given identifier.
+    pub fn unset_job(&mut self, identifier: &str) -> Result<(), Error> {
+        self.execute(Request::UnsetJob { identifier: identifier.to_string() }).map(|_| ())
+    }
+
+    /// Register a rule, executing jobs matching the given pattern with the given runner.
+    pub fn set_rule(&mut self, identifier: &str, pattern: &str, runner: Runner) -> Result<(), Error> {
+        self.execute(Request::SetRule {
+            identifier: identifier.to_string(),
+            pattern: pattern.to_string(),
+            runner,
+        }).map(|_| ())
+    }
+
+    /// Retrieve all rules.
+    pub fn list_rules(&mut self) -> Result<Vec<Rule>, Error> {
+        match self.execute(Request::ListRules)? {
+            Reply::Rules(rules) => Ok(rules),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    /// Start compacting the storage of the server in background.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        self.execute(Request::Compact).map(|_| ())
+    }
+
+    /// Retrieve the progress of the running compaction and the result of the last one.
+    pub fn get_compaction(&mut self) -> Result<Compaction, Error> {
+        match self.execute(Request::GetCompaction)? {
+            Reply::Compaction(compaction) => Ok(compaction),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    /// Start writing a backup of the storage to the file with the given name, in the backup
+    /// directory of the server. The backup file appears once complete.
+    pub fn backup(&mut self, path: &str) -> Result<(), Error> {
+        self.execute(Request::Backup { path: path.to_string() }).map(|_| ())
+    }
+
+    /// Export all jobs and rules as NDJSON to the file with the given name, in the backup
+    /// directory of the server.
+    pub fn export(&mut self, path: &str) -> Result<Transfer, Error> {
+        match self.execute(Request::Export { path: path.to_string() })? {
+            Reply::Transfer(transfer) => Ok(transfer),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    /// Import all jobs and rules from the NDJSON file with the given name, in the backup directory
+    /// of the server, handling conflicts with existing items following the given policy.
+    pub fn import(&mut self, path: &str, policy: ImportPolicy) -> Result<Transfer, Error> {
+        match self.execute(Request::Import { path: path.to_string(), policy })? {
+            Reply::Transfer(transfer) => Ok(transfer),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    /// Promote the server to a primary, if it's a replica. It fails if the replica isn't
+    /// synchronized with its primary yet.
+    pub fn promote(&mut self) -> Result<(), Error> {
+        self.execute(Request::Promote).map(|_| ())
+    }
+
+    /// Send all given requests at once, then wait for all their responses. Return the result of
+    /// each request, in the same order than requests. Fail only when the connection itself fails,
+    /// in which case the state of each request is unknown.
+    pub fn pipeline(&mut self, requests: &[Request]) -> Result<Vec<Result<Reply, Error>>, Error> {
+        let mut message = String::new();
+        let mut pending = HashMap::new();
+        for (index, request) in requests.iter().enumerate() {
+            self.sequence += 1;
+            let identifier = self.sequence.to_string();
+            message.push_str(&format(&identifier, &request.to_arguments()));
+            pending.insert(identifier, index);
+        };
+        self.stream.write_all(message.as_bytes())?;
+
+        // Responses may be received in any order (for example, invalid requests are directly
+        // rejected), so they are matched with their requests by identifier.
+        let mut results: Vec<Option<Result<Reply, Error>>> = requests.iter().map(|_| None).collect();
+        while !pending.is_empty() {
+            let (identifier, arguments) = self.receive()?;
+            match pending.remove(&identifier) {
+                Some(index) => results[index] = Some(requests[index].decode(&arguments)),
+                None => return Err(Error::Protocol),
+            };
+        };
+
+        Ok(results.into_iter().map(|result| result.unwrap_or(Err(Error::Protocol))).collect())
+    }
+
+    /// Send the given request, then wait for its response.
+    fn execute(&mut self, request: Request) -> Result<Reply, Error> {
+        match self.pipeline(&[request])?.pop() {
+            Some(result) => result,
+            None => Err(Error::Protocol),
+        }
+    }
+
+    /// Wait for the next message from the server, returning its request identifier and its
+    /// arguments.
+    fn receive(&mut self) -> Result<(String, Vec<String>), Error> {
+        loop {
+            match parse(&self.input) {
+                Ok((input_left, message)) => {
+                    let length = self.input.len() - input_left.len();
+                    self.input.drain(..length);
+
+                    return Ok(message);
+                },
+                Err((_, ParseError::Incomplete)) => {},
+                Err(_) => return Err(Error::Protocol),
+            };
+
+            let mut buffer = [0; 4096];
+            let length = self.stream.read(&mut buffer)?;
+            if length == 0 {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the server")));
+            };
+            self.bytes_to_parse.extend_from_slice(&buffer[..length]);
+
+            // Keep incomplete UTF-8 sequences for the next read.
+            let valid = match std::str::from_utf8(&self.bytes_to_parse) {
+                Ok(valid) => valid.len(),
+                Err(error) if error.error_len().is_none() => error.valid_up_to(),
+                Err(_) => return Err(Error::Protocol),
+            };
+            let bytes: Vec<u8> = self.bytes_to_parse.drain(..valid).collect();
+            self.input.push_str(std::str::from_utf8(&bytes).map_err(|_| Error::Protocol)?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::offset::TimeZone;
+    use crate::JobStatus;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_pipeline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut lines = Vec::new();
+            for _ in 0..3 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                lines.push(line);
+            };
+            // Respond out of order, splitting a multi-byte character between writes.
+            let mut stream = stream;
+            stream.write_all(b"3 ERROR\n1 OK\n2 OK \"my job \xF0\x9F").unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(10));
+            stream.write_all(b"\x98\xB1\" \"2020-06-17 21:47:16\" planned\n").unwrap();
+
+            lines
+        });
+
+        let mut connection = Connection::connect(address).unwrap();
+        let results = connection.pipeline(&[
+            Request::SetJob { identifier: String::from("my job"), execution: Utc.ymd(2020, 6, 17).and_hms(21, 47, 16) },
+            Request::GetJob { identifier: String::from("my job") },
+            Request::UnsetJob { identifier: String::from("my job") },
+        ]).unwrap();
+
+        assert_eq!(server.join().unwrap(), vec![
+            String::from("1 SET \"my job\" \"2020-06-17 21:47:16\"\n"),
+            String::from("2 GET \"my job\"\n"),
+            String::from("3 UNSET \"my job\"\n"),
+        ]);
+        assert_eq!(results[0].as_ref().unwrap(), &Reply::Done);
+        assert_eq!(results[1].as_ref().unwrap(), &Reply::Job(Job {
+            identifier: String::from("my job \u{1F631}"),
+            execution: Utc.ymd(2020, 6, 17).and_hms(21, 47, 16),
+            status: JobStatus::Planned,
+        }));
+        assert!(matches!(results[2], Err(Error::Rejected)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_authenticate() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("kairoi-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut stream = stream;
+            let mut lines = Vec::new();
+            for response in &[&b"1 ERROR UNAUTHORIZED\n"[..], &b"2 OK\n"[..]] {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                lines.push(line);
+                stream.write_all(response).unwrap();
+            };
+
+            lines
+        });
+
+        let mut connection = Connection::connect_unix(&path).unwrap();
+        assert!(matches!(connection.authenticate("producer", "wrong"), Err(Error::Unauthorized)));
+        assert!(connection.authenticate("producer", "my password").is_ok());
+        assert_eq!(server.join().unwrap(), vec![
+            String::from("1 AUTH producer wrong\n"),
+            String::from("2 AUTH producer \"my password\"\n"),
+        ]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
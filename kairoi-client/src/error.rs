@@ -0,0 +1,59 @@
+use std::fmt;
+use std::io;
+
+/// The reason why a request failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The connection with the server failed, or has been closed.
+    Io(io::Error),
+    /// The server sent a message not respecting the Kairoi Client Protocol.
+    Protocol,
+    /// The server rejected the request, either because it's invalid, because it conflicts with the
+    /// current state of its target, or because the server failed to handle it.
+    Rejected,
+    /// The target of the request doesn't exist.
+    NotFound,
+    /// The request has been rejected by the rate limiter of the server.
+    RateLimited,
+    /// The request writes to a replica, which is read-only.
+    ReadOnly,
+    /// The request writes to a node of a cluster which isn't its leader. The address of the
+    /// leader is given, if known.
+    NotLeader(Option<String>),
+    /// The server requires the client to authenticate before sending requests.
+    Unauthenticated,
+    /// The credentials sent to authenticate are invalid.
+    Unauthorized,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(formatter, "connection failure: {}", error),
+            Error::Protocol => write!(formatter, "invalid message received from the server"),
+            Error::Rejected => write!(formatter, "request rejected by the server"),
+            Error::NotFound => write!(formatter, "not found"),
+            Error::RateLimited => write!(formatter, "rate limited"),
+            Error::ReadOnly => write!(formatter, "read-only replica"),
+            Error::NotLeader(Some(leader)) => write!(formatter, "not the leader (the leader is {})", leader),
+            Error::NotLeader(None) => write!(formatter, "not the leader (no leader is elected)"),
+            Error::Unauthenticated => write!(formatter, "authentication required"),
+            Error::Unauthorized => write!(formatter, "invalid credentials"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
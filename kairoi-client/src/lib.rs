@@ -0,0 +1,28 @@
+//! A blocking client library for Kairoi, using the Kairoi Client Protocol.
+//!
+//! The [`Connection`] handles the formatting of requests (quoting and escaping arguments), the
+//! allocation of request identifiers, and the matching of responses with their requests. Requests
+//! can either be sent one at a time with typed methods, or pipelined. Connections go through TCP
+//! or Unix sockets, and authenticate with [`Connection::authenticate`] when the server requires it.
+//!
+//! ```no_run
+//! use chrono::{TimeZone, Utc};
+//! use kairoi_client::{Connection, Request, Runner};
+//!
+//! let mut connection = Connection::connect("127.0.0.1:5678").unwrap();
+//! connection.set_rule("app.rule.default", "app.", Runner::Shell { command: String::from("script.sh") }).unwrap();
+//! connection.set_job("app.domain.job.1", Utc.ymd(2020, 6, 17).and_hms(21, 47, 16)).unwrap();
+//!
+//! let replies = connection.pipeline(&[
+//!     Request::GetJob { identifier: String::from("app.domain.job.1") },
+//!     Request::ListRules,
+//! ]).unwrap();
+//! ```
+
+mod connection;
+mod error;
+mod request;
+
+pub use connection::Connection;
+pub use error::Error;
+pub use request::{Compaction, CompactionProgress, CompactionResult, ImportPolicy, Job, JobStatus, Reply, Request, Rule, Runner, Transfer};
@@ -0,0 +1,386 @@
+use chrono::DateTime;
+use chrono::offset::{TimeZone, Utc};
+use kairoi_protocol::DATETIME_FORMAT;
+use super::Error;
+
+/// A runner, executing jobs matched by a rule.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Runner {
+    Shell {
+        command: String,
+    },
+    Amqp {
+        dsn: String,
+        exchange: String,
+        routing_key: String,
+    },
+}
+
+/// The status of a job.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobStatus {
+    Planned,
+    Triggered,
+    Executed,
+    Failed,
+}
+
+/// A job, as returned by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub identifier: String,
+    pub execution: DateTime<Utc>,
+    pub status: JobStatus,
+}
+
+/// A rule, as returned by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub identifier: String,
+    pub pattern: String,
+    pub runner: Runner,
+}
+
+/// The progress of a running compaction, in bytes of logfiles.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionProgress {
+    pub started: DateTime<Utc>,
+    pub processed_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// The result of the last terminated compaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionResult {
+    pub success: bool,
+    pub finished: DateTime<Utc>,
+    /// The number of entries kept by the compaction.
+    pub entries: u64,
+}
+
+/// The state of the storage compaction, as returned by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compaction {
+    pub running: Option<CompactionProgress>,
+    pub last: Option<CompactionResult>,
+}
+
+/// The numbers of items exported or imported by the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transfer {
+    pub jobs: u64,
+    pub rules: u64,
+    /// The number of imported items skipped because of a conflict.
+    pub skipped: u64,
+}
+
+/// The handling of imported items conflicting with existing ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportPolicy {
+    /// Keep existing items.
+    Skip,
+    /// Replace existing items (except jobs being executed).
+    Overwrite,
+    /// Fail the whole import, before importing anything.
+    Fail,
+}
+
+/// A request, equivalent to an instruction of the Kairoi Client Protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    /// Register a job to be executed at the given execution time (`SET`).
+    SetJob {
+        identifier: String,
+        execution: DateTime<Utc>,
+    },
+    /// Retrieve a job (`GET`).
+    GetJob {
+        identifier: String,
+    },
+    /// Remove a job (`UNSET`).
+    UnsetJob {
+        identifier: String,
+    },
+    /// Register a rule (`RULE SET`).
+    SetRule {
+        identifier: String,
+        pattern: String,
+        runner: Runner,
+    },
+    /// Retrieve all rules (`RULE LIST`).
+    ListRules,
+    /// Start compacting the storage (`COMPACT`).
+    Compact,
+    /// Retrieve the state of the storage compaction (`COMPACT STATUS`).
+    GetCompaction,
+    /// Start writing a backup of the storage to the file with the given name, in the backup
+    /// directory of the server (`BACKUP`).
+    Backup {
+        path: String,
+    },
+    /// Export all jobs and rules as NDJSON to the file with the given name, in the backup
+    /// directory of the server (`EXPORT`).
+    Export {
+        path: String,
+    },
+    /// Import all jobs and rules from the NDJSON file with the given name, in the backup directory
+    /// of the server (`IMPORT`).
+    Import {
+        path: String,
+        policy: ImportPolicy,
+    },
+    /// Promote a replica to a primary (`PROMOTE`).
+    Promote,
+    /// Authenticate as the user with the given name (`AUTH`).
+    Authenticate {
+        name: String,
+        password: String,
+    },
+}
+
+/// The reply to a successful request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reply {
+    /// The request has been handled, without any output.
+    Done,
+    Job(Job),
+    Rules(Vec<Rule>),
+    Compaction(Compaction),
+    Transfer(Transfer),
+}
+
+impl Request {
+    /// Get the arguments of this request, following its request identifier.
+    pub(crate) fn to_arguments(&self) -> Vec<String> {
+        match self {
+            Request::SetJob { identifier, execution } => vec![String::from("SET"), identifier.clone(), execution.format(DATETIME_FORMAT).to_string()],
+            Request::GetJob { identifier } => vec![String::from("GET"), identifier.clone()],
+            Request::UnsetJob { identifier } => vec![String::from("UNSET"), identifier.clone()],
+            Request::SetRule { identifier, pattern, runner } => {
+                let mut arguments = vec![String::from("RULE"), String::from("SET"), identifier.clone(), pattern.clone()];
+                match runner {
+                    Runner::Shell { command } => {
+                        arguments.push(String::from("shell"));
+                        arguments.push(command.clone());
+                    },
+                    Runner::Amqp { dsn, exchange, routing_key } => {
+                        arguments.push(String::from("amqp"));
+                        arguments.push(dsn.clone());
+                        arguments.push(exchange.clone());
+                        arguments.push(routing_key.clone());
+                    },
+                };
+
+                arguments
+            },
+            Request::ListRules => vec![String::from("RULE"), String::from("LIST")],
+            Request::Compact => vec![String::from("COMPACT")],
+            Request::GetCompaction => vec![String::from("COMPACT"), String::from("STATUS")],
+            Request::Backup { path } => vec![String::from("BACKUP"), path.clone()],
+            Request::Export { path } => vec![String::from("EXPORT"), path.clone()],
+            Request::Import { path, policy } => vec![String::from("IMPORT"), path.clone(), String::from(match policy {
+                ImportPolicy::Skip => "SKIP",
+                ImportPolicy::Overwrite => "OVERWRITE",
+                ImportPolicy::Fail => "FAIL",
+            })],
+            Request::Promote => vec![String::from("PROMOTE")],
+            Request::Authenticate { name, password } => vec![String::from("AUTH"), name.clone(), password.clone()],
+        }
+    }
+
+    /// Decode the given arguments of a response to this request, following its request identifier.
+    pub(crate) fn decode(&self, arguments: &[String]) -> Result<Reply, Error> {
+        let (status, outputs) = match arguments.split_first() {
+            Some((status, outputs)) => (status.as_str(), outputs),
+            None => return Err(Error::Protocol),
+        };
+
+        match (status, self, outputs) {
+            ("OK", Request::SetJob { .. }, []) | ("OK", Request::UnsetJob { .. }, []) | ("OK", Request::SetRule { .. }, []) | ("OK", Request::Compact, []) | ("OK", Request::Backup { .. }, []) | ("OK", Request::Promote, []) | ("OK", Request::Authenticate { .. }, []) => Ok(Reply::Done),
+            ("OK", Request::GetJob { .. }, [identifier, execution, status]) => {
+                let execution = Utc.datetime_from_str(execution, DATETIME_FORMAT).map_err(|_| Error::Protocol)?;
+                let status = match status.as_str() {
+                    "planned" => JobStatus::Planned,
+                    "triggered" => JobStatus::Triggered,
+                    "executed" => JobStatus::Executed,
+                    "failed" => JobStatus::Failed,
+                    _ => return Err(Error::Protocol),
+                };
+
+                Ok(Reply::Job(Job {
+                    identifier: identifier.clone(),
+                    execution,
+                    status,
+                }))
+            },
+            ("OK", Request::ListRules, mut outputs) => {
+                let mut rules = Vec::new();
+                while !outputs.is_empty() {
+                    let (runner, size) = match outputs {
+                        [_, _, runner, command, ..] if runner == "shell" => (Runner::Shell {
+                            command: command.clone(),
+                        }, 4),
+                        [_, _, runner, dsn, exchange, routing_key, ..] if runner == "amqp" => (Runner::Amqp {
+                            dsn: dsn.clone(),
+                            exchange: exchange.clone(),
+                            routing_key: routing_key.clone(),
+                        }, 6),
+                        _ => return Err(Error::Protocol),
+                    };
+                    rules.push(Rule {
+                        identifier: outputs[0].clone(),
+                        pattern: outputs[1].clone(),
+                        runner,
+                    });
+                    outputs = &outputs[size..];
+                };
+
+                Ok(Reply::Rules(rules))
+            },
+            ("OK", Request::GetCompaction, outputs) => {
+                let number = |number: &String| number.parse::<u64>().map_err(|_| Error::Protocol);
+                let datetime = |datetime: &String| Utc.datetime_from_str(datetime, DATETIME_FORMAT).map_err(|_| Error::Protocol);
+                let (running, outputs) = match outputs {
+                    [state, started, processed, total, outputs @ ..] if state == "running" => (Some(CompactionProgress {
+                        started: datetime(started)?,
+                        processed_bytes: number(processed)?,
+                        total_bytes: number(total)?,
+                    }), outputs),
+                    [state, outputs @ ..] if state == "idle" => (None, outputs),
+                    _ => return Err(Error::Protocol),
+                };
+                let last = match outputs {
+                    [result, finished, entries] if result == "success" || result == "failure" => Some(CompactionResult {
+                        success: result == "success",
+                        finished: datetime(finished)?,
+                        entries: number(entries)?,
+                    }),
+                    [result] if result == "none" => None,
+                    _ => return Err(Error::Protocol),
+                };
+
+                Ok(Reply::Compaction(Compaction { running, last }))
+            },
+            ("OK", Request::Export { .. }, [jobs, rules, skipped]) | ("OK", Request::Import { .. }, [jobs, rules, skipped]) => {
+                let number = |number: &String| number.parse::<u64>().map_err(|_| Error::Protocol);
+
+                Ok(Reply::Transfer(Transfer {
+                    jobs: number(jobs)?,
+                    rules: number(rules)?,
+                    skipped: number(skipped)?,
+                }))
+            },
+            ("OK", _, _) => Err(Error::Protocol),
+            ("ERROR", _, []) => Err(Error::Rejected),
+            ("ERROR", _, [reason, details @ ..]) => match reason.as_str() {
+                "NOT_FOUND" => Err(Error::NotFound),
+                "RATE_LIMITED" => Err(Error::RateLimited),
+                "READ_ONLY" => Err(Error::ReadOnly),
+                "NOT_LEADER" => Err(Error::NotLeader(details.first().cloned())),
+                "UNAUTHENTICATED" => Err(Error::Unauthenticated),
+                "UNAUTHORIZED" => Err(Error::Unauthorized),
+                _ => Err(Error::Rejected),
+            },
+            _ => Err(Error::Protocol),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> Vec<String> {
+        arguments.iter().map(|argument| argument.to_string()).collect()
+    }
+
+    #[test]
+    fn test_to_arguments() {
+        let request = Request::SetJob {
+            identifier: String::from("app.job.1"),
+            execution: Utc.ymd(2020, 6, 17).and_hms(21, 47, 16),
+        };
+        assert_eq!(request.to_arguments(), arguments(&["SET", "app.job.1", "2020-06-17 21:47:16"]));
+        let request = Request::SetRule {
+            identifier: String::from("app.rule"),
+            pattern: String::from("app."),
+            runner: Runner::Amqp {
+                dsn: String::from("amqp://localhost"),
+                exchange: String::from("e"),
+                routing_key: String::from("k"),
+            },
+        };
+        assert_eq!(request.to_arguments(), arguments(&["RULE", "SET", "app.rule", "app.", "amqp", "amqp://localhost", "e", "k"]));
+        let request = Request::Authenticate {
+            name: String::from("producer"),
+            password: String::from("my password"),
+        };
+        assert_eq!(request.to_arguments(), arguments(&["AUTH", "producer", "my password"]));
+        assert_eq!(request.decode(&arguments(&["OK"])).unwrap(), Reply::Done);
+        assert!(matches!(request.decode(&arguments(&["ERROR", "UNAUTHORIZED"])), Err(Error::Unauthorized)));
+    }
+
+    #[test]
+    fn test_decode() {
+        let get = Request::GetJob { identifier: String::from("app.job.1") };
+        assert_eq!(
+            get.decode(&arguments(&["OK", "app.job.1", "2020-06-17 21:47:16", "executed"])).unwrap(),
+            Reply::Job(Job {
+                identifier: String::from("app.job.1"),
+                execution: Utc.ymd(2020, 6, 17).and_hms(21, 47, 16),
+                status: JobStatus::Executed,
+            }),
+        );
+        assert!(matches!(get.decode(&arguments(&["ERROR", "NOT_FOUND"])), Err(Error::NotFound)));
+        assert!(matches!(get.decode(&arguments(&["ERROR", "RATE_LIMITED"])), Err(Error::RateLimited)));
+        assert!(matches!(get.decode(&arguments(&["ERROR", "READ_ONLY"])), Err(Error::ReadOnly)));
+        assert!(matches!(get.decode(&arguments(&["ERROR", "NOT_LEADER", "10.0.0.2:5678"])), Err(Error::NotLeader(Some(leader))) if leader == "10.0.0.2:5678"));
+        assert!(matches!(get.decode(&arguments(&["ERROR", "NOT_LEADER"])), Err(Error::NotLeader(None))));
+        assert!(matches!(get.decode(&arguments(&["ERROR"])), Err(Error::Rejected)));
+        assert!(matches!(get.decode(&arguments(&["ERROR", "UNAUTHENTICATED"])), Err(Error::Unauthenticated)));
+        assert!(matches!(get.decode(&arguments(&["OK"])), Err(Error::Protocol)));
+        assert!(matches!(get.decode(&arguments(&["OK", "app.job.1", "tomorrow", "planned"])), Err(Error::Protocol)));
+        // Test rule lists.
+        assert_eq!(
+            Request::ListRules.decode(&arguments(&["OK", "r1", "app.", "shell", "script.sh", "r2", "", "amqp", "dsn", "e", "k"])).unwrap(),
+            Reply::Rules(vec![
+                Rule {
+                    identifier: String::from("r1"),
+                    pattern: String::from("app."),
+                    runner: Runner::Shell { command: String::from("script.sh") },
+                },
+                Rule {
+                    identifier: String::from("r2"),
+                    pattern: String::from(""),
+                    runner: Runner::Amqp { dsn: String::from("dsn"), exchange: String::from("e"), routing_key: String::from("k") },
+                },
+            ]),
+        );
+        assert_eq!(Request::ListRules.decode(&arguments(&["OK"])).unwrap(), Reply::Rules(vec![]));
+        assert!(matches!(Request::ListRules.decode(&arguments(&["OK", "r1", "app.", "cron", "daily"])), Err(Error::Protocol)));
+        // Test compaction states.
+        assert_eq!(
+            Request::GetCompaction.decode(&arguments(&["OK", "running", "2020-06-17 21:47:16", "120", "5000", "success", "2020-06-17 21:40:00", "4200"])).unwrap(),
+            Reply::Compaction(Compaction {
+                running: Some(CompactionProgress { started: Utc.ymd(2020, 6, 17).and_hms(21, 47, 16), processed_bytes: 120, total_bytes: 5000 }),
+                last: Some(CompactionResult { success: true, finished: Utc.ymd(2020, 6, 17).and_hms(21, 40, 0), entries: 4200 }),
+            }),
+        );
+        assert_eq!(
+            Request::GetCompaction.decode(&arguments(&["OK", "idle", "none"])).unwrap(),
+            Reply::Compaction(Compaction { running: None, last: None }),
+        );
+        assert!(matches!(Request::GetCompaction.decode(&arguments(&["OK", "idle"])), Err(Error::Protocol)));
+        assert_eq!(Request::Compact.decode(&arguments(&["OK"])).unwrap(), Reply::Done);
+        assert_eq!(Request::Backup { path: String::from("kairoi.backup") }.decode(&arguments(&["OK"])).unwrap(), Reply::Done);
+        assert_eq!(Request::Promote.decode(&arguments(&["OK"])).unwrap(), Reply::Done);
+        // Test transfers.
+        let import = Request::Import { path: String::from("kairoi.ndjson"), policy: ImportPolicy::Skip };
+        assert_eq!(import.to_arguments(), arguments(&["IMPORT", "kairoi.ndjson", "SKIP"]));
+        assert_eq!(
+            import.decode(&arguments(&["OK", "120", "2", "3"])).unwrap(),
+            Reply::Transfer(Transfer { jobs: 120, rules: 2, skipped: 3 }),
+        );
+        assert!(matches!(import.decode(&arguments(&["OK", "120"])), Err(Error::Protocol)));
+    }
+}
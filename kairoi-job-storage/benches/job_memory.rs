@@ -0,0 +1,61 @@
+//! Measurement of the memory used by the job storage for each planned job, counting all bytes
+//! allocated by the storage through a global allocator.
+//!
+//! Run it with `cargo bench -p kairoi-job-storage --bench job_memory`.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::alloc::{GlobalAlloc, Layout, System};
+use kairoi_job_storage::{Job, Status, Storage};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The numbers of planned jobs in the measured storages.
+const SIZES: [usize; 2] = [1_000_000, 10_000_000];
+
+/// An allocator counting the number of allocated bytes.
+struct Counter;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counter {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+
+        System.dealloc(pointer, layout)
+    }
+}
+
+#[global_allocator]
+static COUNTER: Counter = Counter;
+
+fn origin() -> DateTime<Utc> {
+    Utc.ymd(2020, 7, 24).and_hms(10, 30, 0)
+}
+
+/// Measure the number of bytes used for each job by a storage with the given number of jobs,
+/// planned in a random order, with identifiers made by the given function.
+fn measure(name: &str, size: usize, identifier: impl Fn(usize) -> String) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let mut storage = Storage::new();
+    for index in 0..size {
+        // Spread executions over a day, in a pseudo-random order.
+        let offset = (index as i64).wrapping_mul(7919) % 86_400;
+        storage.set(Job::new(identifier(index), origin() + Duration::seconds(offset), Status::Planned));
+    };
+    let used = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!("{:<12} {:>10} jobs: {:>6.1} bytes per job", name, size, used as f64 / size as f64);
+
+    drop(storage);
+}
+
+fn main() {
+    for size in SIZES {
+        measure("sequential", size, |index| format!("app.domain.job.{}", index));
+        measure("uuid", size, |index| format!("app.reminders.{:08x}-2b9c-4e1f-9a6d-{:012x}", index, index * 7919));
+    };
+}
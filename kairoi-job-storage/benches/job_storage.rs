@@ -0,0 +1,88 @@
+//! Benchmarks of the job storage, showing that setting, rescheduling and retrieving jobs to
+//! execute scale logarithmically with the number of planned jobs.
+//!
+//! Run them with `cargo bench -p kairoi-job-storage --bench job_storage`.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use kairoi_job_storage::{Job, Status, Storage};
+
+/// The numbers of planned jobs in the benchmarked storages.
+const SIZES: [usize; 3] = [10_000, 100_000, 1_000_000];
+
+fn origin() -> DateTime<Utc> {
+    Utc.ymd(2020, 7, 24).and_hms(10, 30, 0)
+}
+
+/// Create a storage with the given number of jobs, planned one second apart.
+fn planned(size: usize) -> Storage {
+    let mut storage = Storage::new();
+    for index in 0..size {
+        storage.set(Job::new(format!("job.{}", index), origin() + Duration::seconds(index as i64), Status::Planned));
+    };
+
+    storage
+}
+
+fn bench_set(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("set");
+    for size in SIZES {
+        let mut storage = planned(size);
+        let middle = origin() + Duration::seconds(size as i64 / 2);
+
+        group.bench_with_input(BenchmarkId::new("insert", size), &size, |bencher, _| {
+            let mut index = 0;
+            bencher.iter(|| {
+                index += 1;
+                storage.set(Job::new(format!("new.{}", index), middle, Status::Planned));
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("reschedule", size), &size, |bencher, size| {
+            let mut index = 0;
+            bencher.iter(|| {
+                index = (index + 7919) % size;
+                storage.set(Job::new(format!("job.{}", index), middle + Duration::seconds(index as i64 % 60), Status::Planned));
+            });
+        });
+        group.bench_with_input(BenchmarkId::new("remove_and_set", size), &size, |bencher, size| {
+            let mut index = 0;
+            bencher.iter(|| {
+                index = (index + 7919) % size;
+                let identifier = format!("job.{}", index);
+                storage.remove(&identifier);
+                storage.set(Job::new(identifier, origin() + Duration::seconds(index as i64), Status::Planned));
+            });
+        });
+    };
+    group.finish();
+}
+
+fn bench_get_to_execute(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("get_to_execute");
+    for size in SIZES {
+        let mut storage = planned(size);
+        // A typical frame: only the first 10 jobs are due.
+        let now = origin() + Duration::seconds(9);
+
+        group.bench_with_input(BenchmarkId::new("due", size), &size, |bencher, _| {
+            bencher.iter(|| storage.get_to_execute(&now));
+        });
+        // Due jobs are popped and triggered, then planned again for the next iteration.
+        group.bench_with_input(BenchmarkId::new("pop_due", size), &size, |bencher, _| {
+            bencher.iter(|| {
+                let mut due = Vec::new();
+                while let Some(job) = storage.pop_to_execute(&now) {
+                    storage.set_status(job.get_identifier(), Status::Triggered);
+                    due.push(job);
+                };
+                for job in due {
+                    storage.set(job);
+                };
+            });
+        });
+    };
+    group.finish();
+}
+
+criterion_group!(benches, bench_set, bench_get_to_execute);
+criterion_main!(benches);
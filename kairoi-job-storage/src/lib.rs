@@ -0,0 +1,674 @@
+//! The in-memory storage of jobs of the Kairoi server, kept in its own crate so the server and the
+//! benchmarks (see the `benches` directory) share it.
+
+use chrono::DateTime;
+use chrono::offset::Utc;
+use hashbrown::raw::RawTable;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+/// The status of a job, either Planned, Triggered, Executed or Failed.
+///
+/// A job can have the following statuses:
+/// - Planned: the job is planned for execution.
+/// - Triggered: the job execution is triggered and an execution request is being sent to the processor.
+/// - Executed: the processor has confirmed that the job has been properly executed.
+/// - Failed: the processor has confirmed that the job has failed to be executed.
+/// A job status can only change from Planned to Triggered or Failed, from Triggered to Executed or
+/// Failed, and from Executed or Failed to Planned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Planned,
+    Triggered,
+    Executed,
+    Failed,
+}
+
+/// A job, executed at some point in the time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    identifier: String,
+    execution: DateTime<Utc>,
+    status: Status,
+}
+
+impl Job {
+    /// Create a new job.
+    pub fn new(identifier: String, execution: DateTime<Utc>, status: Status) -> Job {
+        Job {
+            identifier: identifier,
+            execution: execution,
+            status: status,
+        }
+    }
+
+    /// Get the identifier of this job.
+    pub fn get_identifier(&self) -> &String {
+        &self.identifier
+    }
+
+    /// Get the execution datetime of this job.
+    pub fn get_execution(&self) -> &DateTime<Utc> {
+        &self.execution
+    }
+
+    /// Get the current status of this job.
+    pub fn get_status(&self) -> &Status {
+        &self.status
+    }
+
+    /// Take the identifier of this job.
+    pub fn into_identifier(self) -> String {
+        self.identifier
+    }
+}
+
+/// The handle of a stored job, being the index of its slot.
+type Handle = u32;
+
+/// The number of slots allocated at once. Slots are allocated by chunks, so the storage never
+/// reallocates (nor wastes) the memory of all jobs when growing.
+const CHUNK: usize = 4096;
+
+/// The maximum length of suffixes stored inline, without allocating.
+const INLINE_SUFFIX: usize = 22;
+
+/// The characters ending the prefix of an identifier (like `app.domain.job.` in
+/// `app.domain.job.42`).
+const PREFIX_SEPARATORS: [u8; 3] = [b'.', b':', b'/'];
+
+/// The last part of an identifier, following its prefix. Short suffixes (like numbers) are stored
+/// inline, and longer ones (like UUIDs) in their own allocation.
+enum Suffix {
+    Inline(u8, [u8; INLINE_SUFFIX]),
+    Heap(Box<str>),
+}
+
+impl Suffix {
+    fn new(suffix: &str) -> Self {
+        if suffix.len() > INLINE_SUFFIX {
+            return Suffix::Heap(Box::from(suffix));
+        };
+        let mut bytes = [0; INLINE_SUFFIX];
+        bytes[..suffix.len()].copy_from_slice(suffix.as_bytes());
+
+        Suffix::Inline(suffix.len() as u8, bytes)
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            // Inline bytes are copied from a string, split at an ASCII character.
+            Suffix::Inline(length, bytes) => std::str::from_utf8(&bytes[..*length as usize]).unwrap_or_default(),
+            Suffix::Heap(suffix) => suffix,
+        }
+    }
+}
+
+/// A stored job. Its identifier is split between a prefix, shared with other jobs, and its own
+/// suffix.
+struct Slot {
+    prefix: u32,
+    status: Status,
+    execution: DateTime<Utc>,
+    /// The sequence of the last time the job has been planned, ordering jobs planned for the same
+    /// execution datetime.
+    sequence: u64,
+    suffix: Suffix,
+}
+
+/// A prefix shared by the identifiers of jobs, with the number of these jobs.
+struct Prefix {
+    text: Box<str>,
+    jobs: usize,
+}
+
+/// An optimized storage implementation for jobs, aiming for fast reads on specific domain needs.
+///
+/// This storage provides access to all jobs "that must be executed" at a given date. Jobs can also
+/// be retrieved directly using their identifiers. Finally, jobs can be set (creation or
+/// modification) or removed using their identifiers.
+///
+/// Planned jobs are indexed by their execution datetime, in an ordered map: setting, removing or
+/// popping a job to execute is done in O(log n), and retrieving jobs to execute only visits these
+/// jobs. Jobs planned for the same execution datetime are kept in the order they have been
+/// planned, each planned job being given a new sequence.
+///
+/// The storage is designed to hold tens of millions of jobs. Each job is stored once, in a slot of
+/// a few bytes, and is referenced elsewhere by its handle (the index of its slot): in the ordered
+/// map, and in the index of jobs by identifier. Identifiers are split at their last separator (a
+/// `.`, `:` or `/`): prefixes are interned, being shared by all jobs with the same prefix, and
+/// short suffixes are stored in the slot itself. Jobs are only materialized as [`Job`] when
+/// retrieved.
+pub struct Storage {
+    slots: Vec<Vec<Option<Slot>>>,
+    free_slots: Vec<Handle>,
+    /// The handles of all jobs, indexed by the hash of their identifier.
+    index: RawTable<Handle>,
+    hasher: RandomState,
+    prefixes: Vec<Option<Prefix>>,
+    free_prefixes: Vec<u32>,
+    prefix_index: HashMap<Box<str>, u32>,
+    to_execute: BTreeMap<(DateTime<Utc>, u64), Handle>,
+    /// The sequence given to the next planned job.
+    sequence: u64,
+}
+
+impl Storage
+{
+    /// Create a new empty storage.
+    pub fn new() -> Storage {
+        Storage {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            index: RawTable::new(),
+            hasher: RandomState::new(),
+            prefixes: Vec::new(),
+            free_prefixes: Vec::new(),
+            prefix_index: HashMap::new(),
+            to_execute: BTreeMap::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Retrieve all jobs to be executed at the given current_datetime (included). Only retrieve
+    /// jobs in the Planned status, ordered by execution datetime, then in the order they have been
+    /// planned. The database pops these jobs instead (see [`pop_to_execute`]).
+    pub fn get_to_execute(&self, current_datetime: &DateTime<Utc>) -> Vec<Job> {
+        self.to_execute
+            .range(..=(*current_datetime, u64::MAX))
+            .map(|(_, handle)| self.materialize(*handle))
+            .collect()
+    }
+
+    /// Remove the first job to be executed at the given current_datetime (included) from the
+    /// planned jobs, and retrieve it, in O(log n). The job keeps its Planned status: it must then
+    /// be set with another status (like Triggered), or be set again to be planned again.
+    pub fn pop_to_execute(&mut self, current_datetime: &DateTime<Utc>) -> Option<Job> {
+        let (key, handle) = match self.to_execute.iter().next() {
+            Some((key, handle)) if key.0 <= *current_datetime => (*key, *handle),
+            _ => return None,
+        };
+        self.to_execute.remove(&key);
+
+        Some(self.materialize(handle))
+    }
+
+    /// Put back the job with the given identifier among the jobs to execute, at the place it had
+    /// before being popped, if it's still planned (see [`pop_to_execute`]). It's used when the job
+    /// can't be set with another status.
+    pub fn requeue(&mut self, identifier: &str) {
+        if let Some(handle) = self.find(identifier) {
+            let slot = self.slot(handle);
+            if slot.status == Status::Planned {
+                let key = (slot.execution, slot.sequence);
+                self.to_execute.insert(key, handle);
+            };
+        };
+    }
+
+    /// Retrieve the execution datetime of the next planned job, if there is one.
+    pub fn get_next_execution(&self) -> Option<DateTime<Utc>> {
+        self.to_execute.keys().next().map(|(execution, _)| *execution)
+    }
+
+    /// Retrieve the job with the given identifier, if there is one.
+    pub fn get(&self, identifier: &str) -> Option<Job> {
+        self.find(identifier).map(|handle| self.materialize(handle))
+    }
+
+    /// Retrieve all jobs, in no particular order.
+    pub fn get_all(&self) -> Vec<Job> {
+        let handles = self.slots.iter().flatten().enumerate().filter(|(_, slot)| slot.is_some());
+
+        handles.map(|(handle, _)| self.materialize(handle as Handle)).collect()
+    }
+
+    /// Set the given job, creating it if it doesn't exist, or modifying the entry with the same
+    /// identifier to set the new properties.
+    pub fn set(&mut self, job: Job) {
+        match self.find(job.get_identifier()) {
+            Some(handle) => self.update(handle, *job.get_execution(), *job.get_status()),
+            None => self.create(&job),
+        };
+    }
+
+    /// Set the status of the job with the given identifier, if there is one, keeping its execution
+    /// datetime.
+    pub fn set_status(&mut self, identifier: &str, status: Status) {
+        if let Some(handle) = self.find(identifier) {
+            let execution = self.slot(handle).execution;
+            self.update(handle, execution, status);
+        };
+    }
+
+    /// Remove the job with the given identifier, if there is one.
+    pub fn remove(&mut self, identifier: &str) {
+        let handle = match self.find(identifier) {
+            Some(handle) => handle,
+            None => return,
+        };
+        let hash = self.hash_slot(handle);
+        self.index.remove_entry(hash, |candidate| *candidate == handle);
+        let slot = match self.slots[handle as usize / CHUNK][handle as usize % CHUNK].take() {
+            Some(slot) => slot,
+            None => return,
+        };
+        if slot.status == Status::Planned {
+            self.to_execute.remove(&(slot.execution, slot.sequence));
+        };
+        self.free_slots.push(handle);
+        self.release_prefix(slot.prefix);
+    }
+
+    /// Update the execution datetime and the status of the job with the given handle, keeping the
+    /// ordered map of planned jobs up to date. A job planned again is moved after all other jobs
+    /// planned for the same execution datetime (even when it was already planned).
+    fn update(&mut self, handle: Handle, execution: DateTime<Utc>, status: Status) {
+        let slot = self.slot_mut(handle);
+        let previous = (slot.execution, slot.status, slot.sequence);
+        slot.execution = execution;
+        slot.status = status;
+
+        // A popped job isn't in the ordered map anymore, but is still planned.
+        if previous.1 == Status::Planned {
+            self.to_execute.remove(&(previous.0, previous.2));
+        };
+        if status == Status::Planned {
+            self.plan(handle, execution);
+        };
+    }
+
+    /// Add the job with the given handle to the ordered map of planned jobs, after all jobs
+    /// planned for the same execution datetime.
+    fn plan(&mut self, handle: Handle, execution: DateTime<Utc>) {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        self.slot_mut(handle).sequence = sequence;
+        self.to_execute.insert((execution, sequence), handle);
+    }
+
+    /// Store the given new job, indexing it by identifier, and by execution datetime if planned.
+    fn create(&mut self, job: &Job) {
+        let (prefix, suffix) = split(job.get_identifier());
+        let slot = Slot {
+            prefix: self.intern_prefix(prefix),
+            status: *job.get_status(),
+            execution: *job.get_execution(),
+            sequence: 0,
+            suffix: Suffix::new(suffix),
+        };
+        let handle = match self.free_slots.pop() {
+            Some(handle) => {
+                self.slots[handle as usize / CHUNK][handle as usize % CHUNK] = Some(slot);
+
+                handle
+            },
+            None => {
+                if self.slots.last().map_or(true, |chunk| chunk.len() == CHUNK) {
+                    self.slots.push(Vec::with_capacity(CHUNK));
+                };
+                let handle = ((self.slots.len() - 1) * CHUNK + self.slots[self.slots.len() - 1].len()) as Handle;
+                if let Some(chunk) = self.slots.last_mut() {
+                    chunk.push(Some(slot));
+                };
+
+                handle
+            },
+        };
+
+        let hash = self.hash_slot(handle);
+        let (slots, hasher) = (&self.slots, &self.hasher);
+        self.index.insert(hash, handle, |handle| {
+            let slot = get_slot(slots, *handle);
+            hash_parts(hasher, slot.prefix, slot.suffix.as_str())
+        });
+        if *job.get_status() == Status::Planned {
+            self.plan(handle, *job.get_execution());
+        };
+    }
+
+    /// Find the handle of the job with the given identifier, if there is one.
+    fn find(&self, identifier: &str) -> Option<Handle> {
+        let (prefix, suffix) = split(identifier);
+        let prefix = *self.prefix_index.get(prefix)?;
+        let hash = hash_parts(&self.hasher, prefix, suffix);
+
+        self.index.get(hash, |handle| {
+            let slot = self.slot(*handle);
+            slot.prefix == prefix && slot.suffix.as_str() == suffix
+        }).copied()
+    }
+
+    /// Get the interned prefix with the given text, interning it if needed, and count a new job
+    /// using it.
+    fn intern_prefix(&mut self, text: &str) -> u32 {
+        if let Some(prefix) = self.prefix_index.get(text) {
+            if let Some(prefix) = &mut self.prefixes[*prefix as usize] {
+                prefix.jobs += 1;
+            };
+
+            return *prefix;
+        };
+
+        let prefix = Prefix {
+            text: Box::from(text),
+            jobs: 1,
+        };
+        let identifier = match self.free_prefixes.pop() {
+            Some(identifier) => {
+                self.prefixes[identifier as usize] = Some(prefix);
+
+                identifier
+            },
+            None => {
+                self.prefixes.push(Some(prefix));
+
+                (self.prefixes.len() - 1) as u32
+            },
+        };
+        self.prefix_index.insert(Box::from(text), identifier);
+
+        identifier
+    }
+
+    /// Count a job not using the given prefix anymore, forgetting the prefix once unused.
+    fn release_prefix(&mut self, identifier: u32) {
+        let entry = &mut self.prefixes[identifier as usize];
+        if let Some(prefix) = entry {
+            prefix.jobs -= 1;
+            if prefix.jobs == 0 {
+                self.prefix_index.remove(&prefix.text);
+                *entry = None;
+                self.free_prefixes.push(identifier);
+            };
+        };
+    }
+
+    /// Get the slot of the job with the given handle.
+    fn slot(&self, handle: Handle) -> &Slot {
+        get_slot(&self.slots, handle)
+    }
+
+    /// Get the mutable slot of the job with the given handle.
+    fn slot_mut(&mut self, handle: Handle) -> &mut Slot {
+        match &mut self.slots[handle as usize / CHUNK][handle as usize % CHUNK] {
+            Some(slot) => slot,
+            None => panic!("The job storage has no job with the handle {}.", handle),
+        }
+    }
+
+    /// Compute the hash of the identifier of the job with the given handle.
+    fn hash_slot(&self, handle: Handle) -> u64 {
+        let slot = self.slot(handle);
+
+        hash_parts(&self.hasher, slot.prefix, slot.suffix.as_str())
+    }
+
+    /// Materialize the job with the given handle.
+    fn materialize(&self, handle: Handle) -> Job {
+        let slot = self.slot(handle);
+        let prefix = match &self.prefixes[slot.prefix as usize] {
+            Some(prefix) => &prefix.text,
+            None => "",
+        };
+        let suffix = slot.suffix.as_str();
+        let mut identifier = String::with_capacity(prefix.len() + suffix.len());
+        identifier.push_str(prefix);
+        identifier.push_str(suffix);
+
+        Job::new(identifier, slot.execution, slot.status)
+    }
+}
+
+/// Split the given identifier between its prefix (ending with its last separator) and its suffix.
+fn split(identifier: &str) -> (&str, &str) {
+    match identifier.bytes().rposition(|byte| PREFIX_SEPARATORS.contains(&byte)) {
+        Some(position) => identifier.split_at(position + 1),
+        None => ("", identifier),
+    }
+}
+
+/// Compute the hash of the identifier with the given interned prefix and suffix.
+fn hash_parts(hasher: &RandomState, prefix: u32, suffix: &str) -> u64 {
+    let mut hasher = hasher.build_hasher();
+    prefix.hash(&mut hasher);
+    suffix.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Get the slot of the job with the given handle, in the given chunks of slots.
+fn get_slot(slots: &[Vec<Option<Slot>>], handle: Handle) -> &Slot {
+    match &slots[handle as usize / CHUNK][handle as usize % CHUNK] {
+        Some(slot) => slot,
+        None => panic!("The job storage has no job with the handle {}.", handle),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn single_set_get() {
+        let mut storage = Storage::new();
+        let now = Utc::now();
+        let job = Job::new(String::from("job"), now, Status::Planned);
+
+        storage.set(job.clone());
+        assert_eq!(
+            storage.get(&"job"),
+            Some(job),
+        );
+    }
+
+    #[test]
+    fn get_to_execute() {
+        let mut storage = Storage::new();
+        let now = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let datetime1 = Utc.ymd(2020, 7, 24).and_hms(10, 30, 00);
+        let datetime2 = Utc.ymd(2020, 7, 24).and_hms(10, 31, 00);
+        let datetime3 = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let datetime4 = Utc.ymd(2020, 7, 24).and_hms(10, 33, 00);
+        let job1 = Job::new(String::from("job.1"), datetime1, Status::Planned);
+        let job2 = Job::new(String::from("job.2"), datetime2, Status::Executed);
+        let job3 = Job::new(String::from("job.3"), datetime3, Status::Planned);
+        let job4 = Job::new(String::from("job.4"), datetime4, Status::Planned);
+
+        storage.set(job1.clone());
+        storage.set(job2.clone());
+        storage.set(job3.clone());
+        storage.set(job4.clone());
+        assert_eq!(
+            storage.get_to_execute(&now),
+            vec![job1, job3],
+        );
+    }
+    #[test]
+    fn get_to_execute_with_sequential_modifications() {
+        let mut storage = Storage::new();
+        let now = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let datetime1 = Utc.ymd(2020, 7, 24).and_hms(10, 30, 00);
+        let datetime2 = Utc.ymd(2020, 7, 24).and_hms(10, 31, 00);
+        let datetime3 = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let datetime4 = Utc.ymd(2020, 7, 24).and_hms(10, 33, 00);
+        let job1 = Job::new(String::from("job.1"), datetime1, Status::Planned);
+        let job2 = Job::new(String::from("job.2"), datetime2, Status::Executed);
+        let job3 = Job::new(String::from("job.3"), datetime3, Status::Planned);
+        let job4 = Job::new(String::from("job.4"), datetime4, Status::Planned);
+
+        storage.set(job1.clone());
+        storage.set(job2.clone());
+        storage.set(job3.clone());
+        storage.set(job4.clone());
+        assert_eq!(
+            storage.get_to_execute(&now),
+            vec![job1, job3.clone()],
+        );
+
+        let job1 = Job::new(String::from("job.1"), datetime1, Status::Triggered);
+        storage.set(job1.clone());
+        assert_eq!(
+            storage.get_to_execute(&now),
+            vec![job3],
+        );
+    }
+
+    #[test]
+    fn get_to_execute_with_rescheduling() {
+        let mut storage = Storage::new();
+        let now = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let datetime1 = Utc.ymd(2020, 7, 24).and_hms(10, 30, 00);
+        let datetime2 = Utc.ymd(2020, 7, 24).and_hms(10, 31, 00);
+        let datetime3 = Utc.ymd(2020, 7, 24).and_hms(10, 33, 00);
+        let job1 = Job::new(String::from("job.1"), datetime2, Status::Planned);
+        let job2 = Job::new(String::from("job.2"), datetime2, Status::Planned);
+        let job3 = Job::new(String::from("job.3"), datetime1, Status::Planned);
+
+        assert_eq!(storage.get_next_execution(), None);
+        storage.set(job2.clone());
+        storage.set(job1.clone());
+        storage.set(job3.clone());
+        storage.set(job3.clone());
+        // Jobs planned for the same execution datetime are kept in the order they were planned.
+        assert_eq!(
+            storage.get_to_execute(&now),
+            vec![job3.clone(), job2.clone(), job1.clone()],
+        );
+        assert_eq!(storage.get_next_execution(), Some(datetime1));
+
+        let job3 = Job::new(String::from("job.3"), datetime3, Status::Planned);
+        storage.set(job3.clone());
+        assert_eq!(
+            storage.get_to_execute(&now),
+            vec![job2.clone(), job1.clone()],
+        );
+        assert_eq!(
+            storage.get_to_execute(&datetime3),
+            vec![job2.clone(), job1.clone(), job3],
+        );
+
+        // A job planned again moves after the other jobs planned for the same time.
+        storage.set(job2.clone());
+        assert_eq!(
+            storage.get_to_execute(&now),
+            vec![job1, job2],
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let mut storage = Storage::new();
+        let now = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let datetime1 = Utc.ymd(2020, 7, 24).and_hms(10, 30, 00);
+        let datetime2 = Utc.ymd(2020, 7, 24).and_hms(10, 31, 00);
+        let job1 = Job::new(String::from("job.1"), datetime1, Status::Planned);
+        let job2 = Job::new(String::from("job.2"), datetime2, Status::Planned);
+
+        storage.set(job1.clone());
+        storage.set(job2.clone());
+        storage.remove("job.1");
+        storage.remove("job.3");
+        assert_eq!(
+            storage.get("job.1"),
+            None,
+        );
+        assert_eq!(
+            storage.get_to_execute(&now),
+            vec![job2],
+        );
+    }
+
+    #[test]
+    fn compact_identifiers() {
+        let mut storage = Storage::new();
+        let now = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let identifiers = [
+            "app.domain.job.1",
+            "app.domain.job.2",
+            "app:user:5f0c2b9c-2b9c-4e1f-9a6d-00000000002a",
+            "app/jobs/",
+            "job",
+            "",
+            "app.domain.job.\u{e9}t\u{e9}",
+        ];
+        for identifier in &identifiers {
+            storage.set(Job::new(String::from(*identifier), now, Status::Planned));
+        };
+        for identifier in &identifiers {
+            assert_eq!(storage.get(identifier), Some(Job::new(String::from(*identifier), now, Status::Planned)));
+        };
+        assert_eq!(storage.get("app.domain.job.3"), None);
+        assert_eq!(storage.get("other.job.1"), None);
+        assert_eq!(storage.prefixes.iter().flatten().count(), 4);
+
+        // Prefixes are forgotten once unused, and slots are reused.
+        storage.remove("app.domain.job.1");
+        storage.remove("app.domain.job.2");
+        assert_eq!(storage.prefixes.iter().flatten().count(), 4);
+        storage.remove("app.domain.job.\u{e9}t\u{e9}");
+        assert_eq!(storage.prefixes.iter().flatten().count(), 3);
+        assert_eq!(storage.get("app.domain.job.1"), None);
+        storage.set(Job::new(String::from("other.job.1"), now, Status::Executed));
+        assert_eq!(storage.slots.iter().flatten().count(), 7);
+        assert_eq!(storage.get_all().len(), 5);
+        assert_eq!(storage.get_to_execute(&now).len(), 4);
+    }
+
+    #[test]
+    fn set_status() {
+        let mut storage = Storage::new();
+        let now = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let job1 = Job::new(String::from("job.1"), now, Status::Planned);
+        let job2 = Job::new(String::from("job.2"), now, Status::Planned);
+
+        storage.set(job1.clone());
+        storage.set(job2.clone());
+        storage.set_status("job.1", Status::Triggered);
+        storage.set_status("job.3", Status::Triggered);
+        assert_eq!(storage.get("job.1"), Some(Job::new(String::from("job.1"), now, Status::Triggered)));
+        assert_eq!(storage.get("job.3"), None);
+        assert_eq!(storage.get_to_execute(&now), vec![job2]);
+
+        storage.set_status("job.1", Status::Planned);
+        assert_eq!(storage.get_to_execute(&now), vec![Job::new(String::from("job.2"), now, Status::Planned), job1]);
+    }
+
+    #[test]
+    fn pop_to_execute() {
+        let mut storage = Storage::new();
+        let now = Utc.ymd(2020, 7, 24).and_hms(10, 32, 00);
+        let datetime1 = Utc.ymd(2020, 7, 24).and_hms(10, 30, 00);
+        let datetime2 = Utc.ymd(2020, 7, 24).and_hms(10, 33, 00);
+        let job1 = Job::new(String::from("job.1"), now, Status::Planned);
+        let job2 = Job::new(String::from("job.2"), datetime1, Status::Planned);
+        let job3 = Job::new(String::from("job.3"), now, Status::Planned);
+        let job4 = Job::new(String::from("job.4"), datetime2, Status::Planned);
+
+        storage.set(job1.clone());
+        storage.set(job2.clone());
+        storage.set(job3.clone());
+        storage.set(job4.clone());
+        assert_eq!(storage.pop_to_execute(&now), Some(job2));
+        assert_eq!(storage.pop_to_execute(&now), Some(job1.clone()));
+        assert_eq!(storage.get_to_execute(&now), vec![job3.clone()]);
+
+        // Popped jobs keep their status until set, and are planned again once set as planned.
+        assert_eq!(storage.get("job.1"), Some(job1.clone()));
+        storage.set_status("job.2", Status::Triggered);
+        assert_eq!(storage.get("job.2"), Some(Job::new(String::from("job.2"), datetime1, Status::Triggered)));
+        storage.set(job1.clone());
+        assert_eq!(storage.pop_to_execute(&now), Some(job3));
+        assert_eq!(storage.pop_to_execute(&now), Some(job1.clone()));
+        assert_eq!(storage.pop_to_execute(&now), None);
+        assert_eq!(storage.get_next_execution(), Some(datetime2));
+
+        // Popped jobs can be put back among the jobs to execute, as long as they're planned.
+        storage.requeue("job.2");
+        storage.requeue("job.1");
+        storage.requeue("job.1");
+        assert_eq!(storage.get_to_execute(&now), vec![job1]);
+    }
+}
@@ -1,3 +1,10 @@
+//! The grammar of the Kairoi Client Protocol, shared by the Kairoi server and its clients.
+//!
+//! Every message of the protocol, either a request or a response, is a line of space separated
+//! arguments, the first one being the identifier of the request. Arguments are formatted either
+//! as simple strings (without any space, newline or double quote character), or as universal
+//! strings, delimited by double quotes, where backslash and double quote characters are escaped.
+
 use nom::branch::alt;
 use nom::bytes::streaming::{escaped_transform, take_while, take_while1};
 use nom::character::streaming::{char};
@@ -14,7 +21,12 @@ pub enum Error {
     Error,
 }
 
-pub fn parse(input: &str) -> Result<(&str, (String, Vec<String>)), (&str, Error)> {
+/// A parsed message, made of its request identifier and its arguments.
+pub type Message = (String, Vec<String>);
+
+/// Parse a single message from the given input. Return the input left after the message, or the
+/// whole input with the reason of the failure.
+pub fn parse(input: &str) -> Result<(&str, Message), (&str, Error)> {
     match do_parse(input) {
         Ok(result) => Ok(result),
         Err(error) => match error {
@@ -27,7 +39,7 @@ pub fn parse(input: &str) -> Result<(&str, (String, Vec<String>)), (&str, Error)
 }
 
 
-fn do_parse<'a>(input: &'a str) -> IResult<&'a str, (String, Vec<String>)> {
+fn do_parse(input: &str) -> IResult<&str, Message> {
     let identifier = argument();
     let arguments = many1(argument());
     let query = tuple((identifier, arguments));
@@ -36,6 +48,33 @@ fn do_parse<'a>(input: &'a str) -> IResult<&'a str, (String, Vec<String>)> {
     terminated(query, endline)(input)
 }
 
+/// The format of datetimes in arguments, like `2020-06-17 21:47:16` (always in the UTC timezone).
+pub const DATETIME_FORMAT: &str = "%F %T";
+
+/// Format a message with the given request identifier and arguments, escaping every argument
+/// when needed.
+pub fn format(identifier: &str, arguments: &[String]) -> String {
+    let mut message = escape(identifier);
+    for argument in arguments {
+        message.push(' ');
+        message.push_str(&escape(argument));
+    };
+    message.push('\n');
+
+    message
+}
+
+/// Format the given string as an argument, using the simple form when possible, or the universal
+/// form otherwise.
+pub fn escape(argument: &str) -> String {
+    let simple = !argument.is_empty() && !argument.contains(|c| c == ' ' || c == '\n' || c == '"');
+
+    match simple {
+        true => argument.to_string(),
+        false => format!("\"{}\"", argument.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
+
 fn argument<'a>() -> impl FnMut(&'a str) -> IResult<&'a str, String> {
     let space_before = take_while(|c| c == ' ');
     let space_after = take_while(|c| c == ' ');
@@ -118,4 +157,20 @@ mod tests {
             Ok(("\n\nHEYHEY \"next", (String::from("XYZ"), vec![String::from("UNSET"), String::from("\n"), String::from(r#"I can" con$tain\every.thing""#)]))),
         );
     }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("app.domain$/!#*,toto"), "app.domain$/!#*,toto");
+        assert_eq!(escape("32t\\ata"), "32t\\ata");
+        assert_eq!(escape("2020-05-26 22:26:18"), "\"2020-05-26 22:26:18\"");
+        assert_eq!(escape(r#"I can" con$tain\every.thing"#), r#""I can\" con$tain\\every.thing""#);
+        assert_eq!(escape("\n"), "\"\n\"");
+        // Test that escaped arguments are parsed back to their original value.
+        let arguments = vec![String::from("SET"), String::from("my job"), String::from("\"\\\""), String::from("\n")];
+        let message = format("A", &arguments);
+        assert_eq!(
+            parse(&message),
+            Ok(("", (String::from("A"), vec![String::from("SET"), String::from("my job"), String::from("\"\\\""), String::from("\n")]))),
+        );
+    }
 }
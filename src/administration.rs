@@ -0,0 +1,325 @@
+//! Kairoi's offline administration commands, run instead of the server.
+//!
+//! Each command displays its results on the standard output (errors on the standard error), and
+//! returns the exit code of the program. Commands work on the configured data directory by
+//! default, and commands modifying logfiles lock their data directory, so they can't be run while
+//! a server is using it.
+
+use crate::cli::{Command, DumpFormat, ImportPolicy, LogfileCommand};
+use crate::database::Configuration as DatabaseConfiguration;
+use crate::database::Keyring;
+use crate::database::administration;
+use crate::database::administration::{DumpFormat as AdministrationDumpFormat, Error, LOGFILE_VERSION, LOGFILES, Repair, Statistics};
+use crate::database::offline;
+use crate::database::offline::{Error as OfflineError, ImportPolicy as OfflineImportPolicy, TransferError};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Run the given administration command on the database with the given configuration (on its
+/// data directory by default), returning the exit code of the program.
+pub fn run(command: Command, configuration: DatabaseConfiguration) -> i32 {
+    let data_directory = configuration.storage_persistence_data_directory.clone();
+    let keyring = configuration.storage_persistence_keyring.clone();
+    let directory = |directory: Option<String>| directory.map(PathBuf::from).unwrap_or_else(|| data_directory.clone());
+
+    match command {
+        Command::Logfile(LogfileCommand::Dump { files, format }) => dump(&select_files(&files, &data_directory), &keyring, format),
+        Command::Logfile(LogfileCommand::Stats { files }) => stats(&select_files(&files, &data_directory), &keyring),
+        Command::Logfile(LogfileCommand::Compact { directory: compacted }) => compact(&directory(compacted), &keyring),
+        Command::Logfile(LogfileCommand::Verify { files }) => verify(&select_files(&files, &data_directory), &keyring),
+        Command::Logfile(LogfileCommand::Repair { files }) => repair(&select_files(&files, &data_directory), &keyring),
+        Command::Migrate { directory: migrated } => migrate(&directory(migrated), &keyring),
+        Command::Restore { file, directory: restored } => restore(Path::new(&file), &directory(restored), &keyring),
+        Command::Export { file, directory: exported } => export(file, DatabaseConfiguration {
+            storage_persistence_data_directory: directory(exported),
+            ..configuration
+        }),
+        Command::Import { file, directory: imported, policy } => import(&file, policy, DatabaseConfiguration {
+            storage_persistence_data_directory: directory(imported),
+            ..configuration
+        }),
+    }
+}
+
+fn dump(files: &[PathBuf], keyring: &Keyring, format: DumpFormat) -> i32 {
+    let format = match format {
+        DumpFormat::Text => AdministrationDumpFormat::Text,
+        DumpFormat::Ndjson => AdministrationDumpFormat::Ndjson,
+    };
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+
+    for file in files {
+        if let Err(error) = administration::dump(file, keyring, format, &mut output) {
+            eprintln!("Unable to dump '{}': {}.", file.display(), describe(&error));
+
+            return 1;
+        };
+    };
+
+    0
+}
+
+fn stats(files: &[PathBuf], keyring: &Keyring) -> i32 {
+    let mut total = Statistics::default();
+
+    for file in files {
+        let statistics = match administration::statistics(file, keyring) {
+            Ok(statistics) => statistics,
+            Err(error) => {
+                eprintln!("Unable to read '{}': {}.", file.display(), describe(&error));
+
+                return 1;
+            },
+        };
+        println!("{}", file.display());
+        print_statistics(&statistics);
+        total.size += statistics.size;
+        total.entries += statistics.entries;
+        total.jobs += statistics.jobs;
+        total.job_removals += statistics.job_removals;
+        total.rules += statistics.rules;
+        total.invalid += statistics.invalid;
+    };
+    println!("total");
+    print_statistics(&total);
+
+    0
+}
+
+fn compact(directory: &Path, keyring: &Keyring) -> i32 {
+    let count = || -> usize {
+        select_files(&[], directory).iter()
+            .filter_map(|file| administration::statistics(file, keyring).ok())
+            .map(|statistics| statistics.entries)
+            .sum()
+    };
+    let before = count();
+    if let Err(error) = administration::compact(directory, keyring) {
+        eprintln!("Unable to compact logfiles of '{}': {}.", directory.display(), describe(&error));
+
+        return 1;
+    };
+    println!("Compacted {} entries into {} entries.", before, count());
+
+    0
+}
+
+fn migrate(directory: &Path, keyring: &Keyring) -> i32 {
+    let _lock = match administration::lock(directory) {
+        Ok(lock) => lock,
+        Err(error) => {
+            eprintln!("Unable to lock '{}': {}.", directory.display(), describe(&error));
+
+            return 1;
+        },
+    };
+
+    let mut code = 0;
+    for file in select_files(&[], directory) {
+        match administration::migrate(&file, keyring) {
+            Ok(Some(version)) => println!("{}: migrated from the format version {} to {}", file.display(), version, LOGFILE_VERSION),
+            Ok(None) => println!("{}: already using the format version {}", file.display(), LOGFILE_VERSION),
+            Err(error) => {
+                eprintln!("Unable to migrate '{}': {}.", file.display(), describe(&error));
+                code = 1;
+            },
+        };
+    };
+
+    code
+}
+
+fn restore(file: &Path, directory: &Path, keyring: &Keyring) -> i32 {
+    match administration::restore(file, directory, keyring) {
+        Ok(entries) => {
+            println!("Restored {} entries from '{}' into '{}'.", entries, file.display(), directory.display());
+
+            0
+        },
+        Err(error) => {
+            eprintln!("Unable to restore '{}' into '{}': {}.", file.display(), directory.display(), describe(&error));
+
+            1
+        },
+    }
+}
+
+fn export(file: Option<String>, configuration: DatabaseConfiguration) -> i32 {
+    let directory = configuration.storage_persistence_data_directory.clone();
+    let mut output: Box<dyn Write> = match &file {
+        Some(file) => match File::create(file) {
+            Ok(created) => Box::new(BufWriter::new(created)),
+            Err(_) => {
+                eprintln!("Unable to create '{}'.", file);
+
+                return 1;
+            },
+        },
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    match offline::export(configuration, &mut output) {
+        Ok(transfer) => {
+            // The summary is only displayed when the export isn't written to the standard output.
+            if let Some(file) = file {
+                println!("Exported {} jobs and {} rules from '{}' to '{}'.", transfer.jobs, transfer.rules, directory.display(), file);
+            };
+
+            0
+        },
+        Err(error) => {
+            eprintln!("Unable to export '{}': {}.", directory.display(), describe_offline(&error));
+
+            1
+        },
+    }
+}
+
+fn import(file: &str, policy: ImportPolicy, configuration: DatabaseConfiguration) -> i32 {
+    let directory = configuration.storage_persistence_data_directory.clone();
+    let stdin = io::stdin();
+    let mut input: Box<dyn BufRead> = match file {
+        "-" => Box::new(stdin.lock()),
+        _ => match File::open(file) {
+            Ok(opened) => Box::new(BufReader::new(opened)),
+            Err(_) => {
+                eprintln!("Unable to open '{}'.", file);
+
+                return 1;
+            },
+        },
+    };
+    let policy = match policy {
+        ImportPolicy::Skip => OfflineImportPolicy::Skip,
+        ImportPolicy::Overwrite => OfflineImportPolicy::Overwrite,
+        ImportPolicy::Fail => OfflineImportPolicy::Fail,
+    };
+
+    match offline::import(configuration, &mut input, policy) {
+        Ok(transfer) => {
+            println!("Imported {} jobs and {} rules into '{}', skipped {} existing items.", transfer.jobs, transfer.rules, directory.display(), transfer.skipped);
+
+            0
+        },
+        Err(error) => {
+            eprintln!("Unable to import '{}' into '{}': {}.", file, directory.display(), describe_offline(&error));
+
+            1
+        },
+    }
+}
+
+fn verify(files: &[PathBuf], keyring: &Keyring) -> i32 {
+    let mut code = 0;
+
+    for file in files {
+        let verification = match administration::verify(file, keyring) {
+            Ok(verification) => verification,
+            Err(error) => {
+                eprintln!("Unable to verify '{}': {}.", file.display(), describe(&error));
+                code = 1;
+
+                continue;
+            },
+        };
+        println!("{}: format version {}, {} entries", file.display(), verification.version, verification.entries);
+        for (offset, length) in &verification.corrupted {
+            println!("  corrupted part at offset {} ({} bytes), refused by the server", offset, length);
+        };
+        if let Some((offset, length)) = verification.torn {
+            println!("  torn entry at offset {} ({} bytes), removed by the server on startup", offset, length);
+        };
+        if verification.invalid > 0 {
+            println!("  {} entries can't be decoded, refused by the server", verification.invalid);
+        };
+        if verification.version < LOGFILE_VERSION {
+            println!("  older format version, migrated by the server on startup");
+        };
+        if !verification.is_healthy() {
+            code = 1;
+        };
+    };
+
+    code
+}
+
+fn repair(files: &[PathBuf], keyring: &Keyring) -> i32 {
+    let mut code = 0;
+
+    for file in files {
+        // Lock the directory containing the file, in case it's used by a server.
+        let directory = match file.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        let result = administration::lock(directory).and_then(|_lock| administration::repair(file, keyring));
+        match result {
+            Ok(Repair { removed_bytes: 0, removed_entries: 0, upgraded: false }) => println!("{}: nothing to repair", file.display()),
+            Ok(repair) => println!(
+                "{}: removed {} invalid bytes and {} undecodable entries{}",
+                file.display(),
+                repair.removed_bytes,
+                repair.removed_entries,
+                match repair.upgraded {
+                    true => format!(", migrated to the format version {}", LOGFILE_VERSION),
+                    false => String::new(),
+                },
+            ),
+            Err(error) => {
+                eprintln!("Unable to repair '{}': {}.", file.display(), describe(&error));
+                code = 1;
+            },
+        };
+    };
+
+    code
+}
+
+/// Get the given files, or all existing logfiles of the given data directory when none is given.
+fn select_files(files: &[String], directory: &Path) -> Vec<PathBuf> {
+    match files.is_empty() {
+        true => LOGFILES.iter().map(|file| directory.join(file)).filter(|file| file.exists()).collect(),
+        false => files.iter().map(PathBuf::from).collect(),
+    }
+}
+
+fn print_statistics(statistics: &Statistics) {
+    println!("  size:         {} bytes", statistics.size);
+    println!("  entries:      {}", statistics.entries);
+    println!("  jobs:         {}", statistics.jobs);
+    println!("  job removals: {}", statistics.job_removals);
+    println!("  rules:        {}", statistics.rules);
+    println!("  invalid:      {}", statistics.invalid);
+    // Items are only counted per file, since the same item may appear in several files.
+    if statistics.subjects > 0 || statistics.entries == 0 {
+        println!("  items:        {}", statistics.subjects);
+        println!("  duplication:  {:.2}%", statistics.get_duplication_ratio() * 100.0);
+    };
+}
+
+fn describe_offline(error: &OfflineError) -> String {
+    match error {
+        OfflineError::UninitializableStorage => String::from("the data directory can't be loaded (is it used by a running server?)"),
+        OfflineError::Clustered => String::from("the database is a node of a cluster (send the IMPORT instruction to its leader instead)"),
+        OfflineError::Transfer(TransferError::Io) => String::from("the file can't be read or written"),
+        OfflineError::Transfer(TransferError::InvalidRecord(line)) => format!("the line {} isn't a valid record", line),
+        OfflineError::Transfer(TransferError::Conflict(identifier)) => format!("{:?} already exists (see the --policy option)", identifier),
+        OfflineError::Transfer(TransferError::PersistenceFailure) => String::from("the imported items can't be written"),
+    }
+}
+
+fn describe(error: &Error) -> String {
+    match error {
+        Error::UnreadableFile => String::from("the file can't be read or written"),
+        Error::CorruptedFile(offset) => format!("the file is corrupted at offset {} (see `kairoi logfile verify`)", offset),
+        Error::UnsupportedVersion(version) => format!("the file uses the unsupported format version {}", version),
+        Error::UndecryptableFile(offset) => format!("the entry at offset {} can't be decrypted with the configured keys", offset),
+        Error::UndecodableEntry(index) => format!("the entry #{} can't be decoded", index),
+        Error::LockedDirectory => String::from("the data directory is used by another process (a running server?)"),
+        Error::CompressionFailure => String::from("the compression process failed"),
+        Error::ExistingData => String::from("the data directory already contains logfiles"),
+    }
+}
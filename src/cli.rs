@@ -8,14 +8,80 @@
 //! Currently, there is a single argument handles by this module:
 //! * `-c`, `--config`: it takes a value as parameter, being the path to the configuration file
 //! used for the current execution.
+//!
+//! It also handles subcommands, running offline administration tasks instead of the server:
+//! * `logfile dump [--format <FORMAT>] [FILE]...`: it displays decoded entries of logfiles,
+//! * `logfile stats [FILE]...`: it displays statistics about entries of logfiles,
+//! * `logfile compact [--directory <DIRECTORY>]`: it compresses logfiles of the given directory,
+//! * `logfile verify [FILE]...`: it verifies the integrity of logfiles,
+//! * `logfile repair [FILE]...`: it removes invalid parts of logfiles,
+//! * `migrate [--directory <DIRECTORY>]`: it migrates logfiles of the given directory to the
+//!   current format version,
+//! * `restore [--directory <DIRECTORY>] <FILE>`: it restores a backup written by the `BACKUP`
+//!   instruction into the given (empty) directory,
+//! * `export [--directory <DIRECTORY>] [FILE]`: it exports all jobs and rules of the given
+//!   directory as NDJSON,
+//! * `import [--directory <DIRECTORY>] [--policy <POLICY>] <FILE>`: it imports jobs and rules
+//!   from NDJSON into the given directory.
 
 use clap::App;
+use clap::AppSettings;
 use clap::crate_name;
 use clap::crate_version;
 use clap::Arg;
 
+#[derive(Clone, Copy)]
+pub enum DumpFormat {
+    Text,
+    Ndjson,
+}
+#[derive(Clone, Copy)]
+pub enum ImportPolicy {
+    Skip,
+    Overwrite,
+    Fail,
+}
+pub enum LogfileCommand {
+    Dump {
+        files: Vec<String>,
+        format: DumpFormat,
+    },
+    Stats {
+        files: Vec<String>,
+    },
+    Compact {
+        directory: Option<String>,
+    },
+    Verify {
+        files: Vec<String>,
+    },
+    Repair {
+        files: Vec<String>,
+    },
+}
+pub enum Command {
+    Logfile(LogfileCommand),
+    Migrate {
+        directory: Option<String>,
+    },
+    Restore {
+        file: String,
+        directory: Option<String>,
+    },
+    Export {
+        file: Option<String>,
+        directory: Option<String>,
+    },
+    Import {
+        file: String,
+        directory: Option<String>,
+        policy: ImportPolicy,
+    },
+}
 pub struct Arguments {
     pub configuration_path: Option<String>,
+    /// The administration command to run instead of the server, if any.
+    pub command: Option<Command>,
 }
 
 pub struct Application {}
@@ -34,15 +100,174 @@ impl Application {
                     .value_name("FILE")
                     .help("Sets the path of the configuration file")
             )
+            .subcommand(
+                App::new("logfile")
+                    .about("Administrates logfiles offline, while the server is stopped")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        App::new("dump")
+                            .about("Displays decoded entries of logfiles (all logfiles of the data directory by default)")
+                            .arg(
+                                Arg::new("format")
+                                    .short('f')
+                                    .long("format")
+                                    .takes_value(true)
+                                    .possible_values(["text", "ndjson"])
+                                    .default_value("text")
+                                    .help("Sets the output format")
+                            )
+                            .arg(Arg::new("files").multiple_values(true).value_name("FILE"))
+                    )
+                    .subcommand(
+                        App::new("stats")
+                            .about("Displays statistics about entries of logfiles (all logfiles of the data directory by default)")
+                            .arg(Arg::new("files").multiple_values(true).value_name("FILE"))
+                    )
+                    .subcommand(
+                        App::new("compact")
+                            .about("Compresses all logfiles into 'logfile.compressed'")
+                            .arg(
+                                Arg::new("directory")
+                                    .short('d')
+                                    .long("directory")
+                                    .takes_value(true)
+                                    .value_name("DIRECTORY")
+                                    .help("Sets the directory containing logfiles (the configured data directory by default)")
+                            )
+                    )
+                    .subcommand(
+                        App::new("verify")
+                            .about("Verifies the integrity of logfiles (all logfiles of the data directory by default)")
+                            .arg(Arg::new("files").multiple_values(true).value_name("FILE"))
+                    )
+                    .subcommand(
+                        App::new("repair")
+                            .about("Removes invalid parts of logfiles and migrates their format (all logfiles of the data directory by default)")
+                            .arg(Arg::new("files").multiple_values(true).value_name("FILE"))
+                    )
+            )
+            .subcommand(
+                App::new("migrate")
+                    .about("Migrates all logfiles to the current format version, while the server is stopped")
+                    .arg(
+                        Arg::new("directory")
+                            .short('d')
+                            .long("directory")
+                            .takes_value(true)
+                            .value_name("DIRECTORY")
+                            .help("Sets the directory containing logfiles (the configured data directory by default)")
+                    )
+            )
+            .subcommand(
+                App::new("restore")
+                    .about("Restores a backup into an empty data directory, before starting the server")
+                    .arg(
+                        Arg::new("directory")
+                            .short('d')
+                            .long("directory")
+                            .takes_value(true)
+                            .value_name("DIRECTORY")
+                            .help("Sets the directory to restore the backup into (the configured data directory by default)")
+                    )
+                    .arg(Arg::new("file").required(true).value_name("FILE"))
+            )
+            .subcommand(
+                App::new("export")
+                    .about("Exports all jobs and rules as NDJSON (to the standard output by default), while the server is stopped")
+                    .arg(
+                        Arg::new("directory")
+                            .short('d')
+                            .long("directory")
+                            .takes_value(true)
+                            .value_name("DIRECTORY")
+                            .help("Sets the directory containing logfiles (the configured data directory by default)")
+                    )
+                    .arg(Arg::new("file").value_name("FILE"))
+            )
+            .subcommand(
+                App::new("import")
+                    .about("Imports jobs and rules from NDJSON (\"-\" for the standard input), while the server is stopped")
+                    .arg(
+                        Arg::new("directory")
+                            .short('d')
+                            .long("directory")
+                            .takes_value(true)
+                            .value_name("DIRECTORY")
+                            .help("Sets the directory containing logfiles (the configured data directory by default)")
+                    )
+                    .arg(
+                        Arg::new("policy")
+                            .short('p')
+                            .long("policy")
+                            .takes_value(true)
+                            .possible_values(["skip", "overwrite", "fail"])
+                            .default_value("fail")
+                            .help("Sets the handling of imported items conflicting with existing ones")
+                    )
+                    .arg(Arg::new("file").required(true).value_name("FILE"))
+            )
             .help_template("USAGE: {usage}\n\n{all-args}")
             .get_matches()
         ;
 
+        let files = |matches: &clap::ArgMatches| -> Vec<String> {
+            match matches.values_of("files") {
+                Some(files) => files.map(|file| file.to_string()).collect(),
+                None => Vec::new(),
+            }
+        };
+        let command = match matches.subcommand() {
+            Some(("logfile", matches)) => match matches.subcommand() {
+                Some(("dump", matches)) => Some(Command::Logfile(LogfileCommand::Dump {
+                    files: files(matches),
+                    format: match matches.value_of("format") {
+                        Some("ndjson") => DumpFormat::Ndjson,
+                        _ => DumpFormat::Text,
+                    },
+                })),
+                Some(("stats", matches)) => Some(Command::Logfile(LogfileCommand::Stats {
+                    files: files(matches),
+                })),
+                Some(("compact", matches)) => Some(Command::Logfile(LogfileCommand::Compact {
+                    directory: matches.value_of("directory").map(|directory| directory.to_string()),
+                })),
+                Some(("verify", matches)) => Some(Command::Logfile(LogfileCommand::Verify {
+                    files: files(matches),
+                })),
+                Some(("repair", matches)) => Some(Command::Logfile(LogfileCommand::Repair {
+                    files: files(matches),
+                })),
+                _ => None,
+            },
+            Some(("migrate", matches)) => Some(Command::Migrate {
+                directory: matches.value_of("directory").map(|directory| directory.to_string()),
+            }),
+            Some(("restore", matches)) => Some(Command::Restore {
+                file: matches.value_of("file").unwrap_or_default().to_string(),
+                directory: matches.value_of("directory").map(|directory| directory.to_string()),
+            }),
+            Some(("export", matches)) => Some(Command::Export {
+                file: matches.value_of("file").map(|file| file.to_string()),
+                directory: matches.value_of("directory").map(|directory| directory.to_string()),
+            }),
+            Some(("import", matches)) => Some(Command::Import {
+                file: matches.value_of("file").unwrap_or_default().to_string(),
+                directory: matches.value_of("directory").map(|directory| directory.to_string()),
+                policy: match matches.value_of("policy") {
+                    Some("skip") => ImportPolicy::Skip,
+                    Some("overwrite") => ImportPolicy::Overwrite,
+                    _ => ImportPolicy::Fail,
+                },
+            }),
+            _ => None,
+        };
+
         Arguments {
             configuration_path: match matches.value_of("configuration_path") {
                 Some(path) => Some(path.to_string()),
                 None => None,
-            }
+            },
+            command,
         }
     }
 }
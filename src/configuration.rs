@@ -14,6 +14,7 @@ use config::ConfigError;
 use config::File;
 use config::FileFormat;
 use serde::Deserialize;
+use std::net::ToSocketAddrs;
 use validator::Validate;
 
 #[derive(Clone, Copy, Debug, Deserialize)]
@@ -52,39 +53,292 @@ impl Default for ControllerListen {
     }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitPolicy {
+    Delay,
+    Reject,
+}
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        RateLimitPolicy::Delay
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitScope {
+    Connection,
+    Address,
+    Identity,
+}
+impl Default for RateLimitScope {
+    fn default() -> Self {
+        RateLimitScope::Connection
+    }
+}
+
 #[derive(Debug, Default, Deserialize)]
 #[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub rate: u32,
+    #[serde(default)]
+    pub burst: u32,
+    #[serde(default)]
+    pub policy: RateLimitPolicy,
+    #[serde(default)]
+    pub scope: RateLimitScope,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    pub listen: Option<String>,
+    #[validate(range(min = 1, max = 1024))]
+    pub workers: i64,
+}
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            workers: 4,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Resp {
+    pub listen: Option<String>,
+}
+
+/// Passwords are never part of the configuration: only the files or environment variables
+/// containing them are.
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    #[validate(length(min = 1))]
+    pub name: String,
+    #[serde(default)]
+    pub password_file: Option<String>,
+    #[serde(default)]
+    pub password_variable: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
 pub struct Controller {
     #[serde(default)]
     pub listen: ControllerListen,
+    #[serde(default)]
+    pub unix_socket: Option<String>,
+    #[serde(default)]
+    pub rate_limit: RateLimit,
+    #[serde(default)]
+    #[validate]
+    pub http: Http,
+    #[serde(default)]
+    pub resp: Resp,
+    #[serde(default)]
+    #[validate]
+    pub users: Vec<User>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Persistence {
+    Disk,
+    None,
+}
+impl Default for Persistence {
+    fn default() -> Self {
+        Persistence::Disk
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Logfile,
+    Sled,
+}
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Logfile
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncMode {
+    Frame,
+    Everysec,
+}
+
+/// Either a boolean (`true` synchronizing each write, `false` never synchronizing), or a
+/// synchronization mode.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum FsyncOnPersist {
+    Enabled(bool),
+    Mode(FsyncMode),
+}
+impl Default for FsyncOnPersist {
+    fn default() -> Self {
+        FsyncOnPersist::Enabled(true)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactionTrigger {
+    Entries,
+    Size,
+    Ratio,
+}
+impl Default for CompactionTrigger {
+    fn default() -> Self {
+        CompactionTrigger::Entries
+    }
 }
 
 #[derive(Debug, Deserialize, Validate)]
-#[serde(deny_unknown_fields)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compaction {
+    pub trigger: CompactionTrigger,
+    #[validate(range(min = 1))]
+    pub entries: i64,
+    #[validate(range(min = 1))]
+    pub size: i64,
+    #[validate(range(min = 0.01))]
+    pub ratio: f64,
+    #[validate(range(min = 0))]
+    pub minimum_size: i64,
+}
+impl Default for Compaction {
+    fn default() -> Self {
+        Self {
+            trigger: CompactionTrigger::Entries,
+            entries: 5000,
+            size: 64 * 1024 * 1024,
+            ratio: 1.0,
+            minimum_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Encryption keys are never part of the configuration: only the files or environment variables
+/// containing them are.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Encryption {
+    pub key_file: Option<String>,
+    pub key_variable: Option<String>,
+    pub previous_key_files: Vec<String>,
+    pub previous_key_variables: Vec<String>,
+}
+
+/// Every option has a default value, so a `database` table only needs to contain the overridden
+/// options, and stays valid when options are added.
+#[derive(Debug, Deserialize, Validate)]
+#[serde(default, deny_unknown_fields)]
 pub struct Database {
-    pub fsync_on_persist: bool,
+    pub persistence: Persistence,
+    pub backend: Backend,
+    #[validate(length(min = 1))]
+    pub data_directory: String,
+    pub fsync_on_persist: FsyncOnPersist,
     #[validate(range(min = 1, max = 65535))]
     pub framerate: i64,
+    #[validate(range(min = 1))]
+    pub client_frame_quota: i64,
+    #[validate(range(min = 1, max = 1024))]
+    pub shards: i64,
+    #[validate]
+    pub compaction: Compaction,
+    pub encryption: Encryption,
 }
 impl Default for Database {
     fn default() -> Self {
         Self {
-            fsync_on_persist: true,
+            persistence: Persistence::Disk,
+            backend: Backend::Logfile,
+            data_directory: String::from("."),
+            fsync_on_persist: FsyncOnPersist::Enabled(true),
             framerate: 512,
+            client_frame_quota: 1024,
+            shards: 1,
+            compaction: Compaction::default(),
+            encryption: Encryption::default(),
         }
     }
 }
 
+/// Clients only give the name of the files written by BACKUP instructions: they are always written
+/// in this directory, so no other file of the server can be written.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Backup {
+    pub directory: Option<String>,
+}
+
+/// The secret shared by the primary and its replicas is read either from a file, or from an
+/// environment variable, like passwords of users.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Replication {
+    pub listen: Option<String>,
+    pub primary: Option<String>,
+    pub secret_file: Option<String>,
+    pub secret_variable: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterPeer {
+    #[validate(range(min = 1))]
+    pub node: i64,
+    pub address: String,
+}
+
+/// The secret shared by all nodes of the cluster is read either from a file, or from an
+/// environment variable, like the replication secret.
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct Cluster {
+    #[validate(range(min = 1))]
+    pub node: i64,
+    pub listen: String,
+    #[serde(default)]
+    pub advertise: Option<String>,
+    #[serde(default)]
+    #[validate]
+    pub peers: Vec<ClusterPeer>,
+    #[serde(default)]
+    pub secret_file: Option<String>,
+    #[serde(default)]
+    pub secret_variable: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Validate)]
 #[serde(deny_unknown_fields)]
 pub struct Configuration {
     #[serde(default)]
     pub log: Log,
     #[serde(default)]
+    #[validate]
     pub controller: Controller,
     #[serde(default)]
     #[validate]
     pub database: Database,
+    #[serde(default)]
+    pub backup: Backup,
+    #[serde(default)]
+    pub replication: Replication,
+    #[serde(default)]
+    #[validate]
+    pub cluster: Option<Cluster>,
 }
 
 impl Configuration {
@@ -102,6 +356,13 @@ impl Configuration {
                     Ok(_) => {},
                     Err(error) => return Err(error.to_string()),
                 };
+                configuration.check_users()?;
+                configuration.check_cluster()?;
+                configuration.check_replication()?;
+                configuration.check_encryption()?;
+                configuration.check_backend()?;
+                configuration.check_persistence()?;
+                configuration.check_shards()?;
 
                 Ok(configuration)
             },
@@ -109,6 +370,133 @@ impl Configuration {
         }
     }
 
+    /// Check that users have distinct names, and that the password of each user is given either by
+    /// a file or by an environment variable.
+    fn check_users(&self) -> Result<(), String> {
+        let users = &self.controller.users;
+        for user in users {
+            if user.password_file.is_some() == user.password_variable.is_some() {
+                return Err(format!("the password of the user '{}' must be read either from a file or from an environment variable", user.name));
+            };
+        };
+        let mut names: Vec<&String> = users.iter().map(|user| &user.name).collect();
+        names.sort_unstable();
+        names.dedup();
+        if names.len() != users.len() {
+            return Err(String::from("users must have distinct names"));
+        };
+
+        Ok(())
+    }
+
+    /// Check that the nodes of the cluster (if any) have distinct identifiers, that the cluster
+    /// isn't combined with the replication of a primary, and that its secret is given either by a
+    /// file or by an environment variable, not both. Like primaries, nodes only listen to their
+    /// peers without a secret on a loopback address.
+    fn check_cluster(&self) -> Result<(), String> {
+        let cluster = match &self.cluster {
+            Some(cluster) => cluster,
+            None => return Ok(()),
+        };
+        if self.replication.primary.is_some() {
+            return Err(String::from("cluster nodes can't replicate a primary"));
+        };
+        let mut nodes: Vec<i64> = cluster.peers.iter().map(|peer| peer.node).collect();
+        nodes.push(cluster.node);
+        nodes.sort_unstable();
+        nodes.dedup();
+        if nodes.len() != cluster.peers.len() + 1 {
+            return Err(String::from("cluster nodes must have distinct identifiers"));
+        };
+        if cluster.secret_file.is_some() && cluster.secret_variable.is_some() {
+            return Err(String::from("the cluster secret can't be both read from a file and from an environment variable"));
+        };
+        if cluster.secret_file.is_none() && cluster.secret_variable.is_none() && !is_loopback(&cluster.listen) {
+            return Err(format!("listening to cluster peers on '{}' requires a secret (only loopback addresses are allowed without one)", cluster.listen));
+        };
+
+        Ok(())
+    }
+
+    /// Check that the replication secret is given either by a file or by an environment variable,
+    /// not both, and that a primary only listens to replicas without a secret on a loopback
+    /// address: snapshots contain all rules, including the credentials of their runners.
+    fn check_replication(&self) -> Result<(), String> {
+        let replication = &self.replication;
+        if replication.secret_file.is_some() && replication.secret_variable.is_some() {
+            return Err(String::from("the replication secret can't be both read from a file and from an environment variable"));
+        };
+        let address = match &replication.listen {
+            Some(address) if replication.secret_file.is_none() && replication.secret_variable.is_none() => address,
+            _ => return Ok(()),
+        };
+        if !is_loopback(address) {
+            return Err(format!("listening to replicas on '{}' requires a secret (only loopback addresses are allowed without one)", address));
+        };
+
+        Ok(())
+    }
+
+    /// Check that the encryption key is given either by a file or by an environment variable, not
+    /// both.
+    fn check_encryption(&self) -> Result<(), String> {
+        let encryption = &self.database.encryption;
+        if encryption.key_file.is_some() && encryption.key_variable.is_some() {
+            return Err(String::from("the encryption key can't be both read from a file and from an environment variable"));
+        };
+
+        Ok(())
+    }
+
+    /// Check that features unsupported by the sled backend (encryption, and listening to replicas)
+    /// aren't configured along with it.
+    fn check_backend(&self) -> Result<(), String> {
+        if let Backend::Logfile = self.database.backend {
+            return Ok(());
+        };
+        let encryption = &self.database.encryption;
+        if encryption.key_file.is_some() || encryption.key_variable.is_some() {
+            return Err(String::from("the sled backend can't be encrypted"));
+        };
+        if self.replication.listen.is_some() {
+            return Err(String::from("the sled backend can't be replicated to replicas"));
+        };
+
+        Ok(())
+    }
+
+    /// Check that a server without persistence neither listens to replicas, nor is a node of a
+    /// cluster (its replicated log being stored in the data directory).
+    fn check_persistence(&self) -> Result<(), String> {
+        if let Persistence::Disk = self.database.persistence {
+            return Ok(());
+        };
+        if self.replication.listen.is_some() {
+            return Err(String::from("a server without persistence can't be replicated to replicas"));
+        };
+        if self.cluster.is_some() {
+            return Err(String::from("a server without persistence can't be a node of a cluster"));
+        };
+
+        Ok(())
+    }
+
+    /// Check that a sharded database neither listens to replicas, nor replicates a primary, nor is
+    /// a node of a cluster (the replication works on a single storage).
+    fn check_shards(&self) -> Result<(), String> {
+        if self.database.shards == 1 {
+            return Ok(());
+        };
+        if self.replication.listen.is_some() || self.replication.primary.is_some() {
+            return Err(String::from("a sharded database can't be replicated"));
+        };
+        if self.cluster.is_some() {
+            return Err(String::from("a sharded database can't be a node of a cluster"));
+        };
+
+        Ok(())
+    }
+
     fn load(configuration_path: Option<&str>) -> Result<Self, ConfigError> {
         let mut configuration = Config::default();
 
@@ -128,3 +516,11 @@ impl Configuration {
         configuration.try_into()
     }
 }
+
+/// Check whether the given address only resolves to loopback addresses.
+fn is_loopback(address: &str) -> bool {
+    match address.to_socket_addrs() {
+        Ok(mut addresses) => addresses.all(|address| address.ip().is_loopback()),
+        Err(_) => false,
+    }
+}
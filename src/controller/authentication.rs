@@ -0,0 +1,150 @@
+//! Authentication of clients, using the names and passwords of the configured users.
+//!
+//! When no user is configured, authentication is disabled and all clients are anonymous. As soon
+//! as a user is configured, clients must authenticate before sending any request. Each connection
+//! holds a [`Session`], tracking the user it's authenticated as, along with its rate limiter: once
+//! authenticated, the limiter of a client may be shared with all other clients authenticated as the
+//! same user (read more in the [`limiter`](super::limiter) module).
+
+use openssl::memcmp;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use super::limiter::{Limiter, Limits};
+
+/// The configured users, checking the credentials sent by clients.
+pub struct Authenticator {
+    users: HashMap<String, String>,
+}
+
+impl Authenticator {
+    /// Create an authenticator with the given passwords, indexed by user name.
+    pub fn new(users: HashMap<String, String>) -> Self {
+        Self {
+            users,
+        }
+    }
+
+    /// Check whether clients must authenticate before sending requests, being the case as soon as
+    /// a user is configured.
+    pub fn is_required(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// Check the given credentials. Passwords are compared in constant time, so the time taken by
+    /// the comparison doesn't reveal how much of a password is valid.
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        match self.users.get(name) {
+            Some(expected) => expected.len() == password.len() && memcmp::eq(expected.as_bytes(), password.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+/// The authentication state of a connected client, along with its rate limiter.
+pub struct Session {
+    authenticator: Arc<Authenticator>,
+    limits: Arc<Limits>,
+    limiter: Option<Limiter>,
+    user: Option<String>,
+}
+
+impl Session {
+    /// Create the session of an anonymous client, connected from the given address.
+    pub fn new(authenticator: Arc<Authenticator>, limits: Arc<Limits>, address: IpAddr) -> Self {
+        let limiter = limits.limit(address);
+
+        Self {
+            authenticator,
+            limits,
+            limiter,
+            user: None,
+        }
+    }
+
+    /// Create the session of a single anonymous HTTP request, sent from the given address (read
+    /// more about the limiters of HTTP requests in the [`limiter`](super::limiter) module).
+    #[cfg_attr(not(feature = "controller-http"), allow(dead_code))]
+    pub fn request(authenticator: Arc<Authenticator>, limits: Arc<Limits>, address: IpAddr) -> Self {
+        let limiter = limits.limit_request(address);
+
+        Self {
+            authenticator,
+            limits,
+            limiter,
+            user: None,
+        }
+    }
+
+    /// Check whether the client is allowed to send requests: either it's authenticated, or
+    /// authentication is disabled.
+    pub fn is_authenticated(&self) -> bool {
+        self.user.is_some() || !self.authenticator.is_required()
+    }
+
+    /// Try to authenticate the client with the given credentials, returning whether they're valid.
+    /// On failure, the client keeps its previous authentication state.
+    pub fn authenticate(&mut self, name: &str, password: &str) -> bool {
+        if !self.authenticator.authenticate(name, password) {
+            return false;
+        };
+
+        self.limits.authenticate(&mut self.limiter, name);
+        self.user = Some(name.to_string());
+
+        true
+    }
+
+    /// Get the rate limiter of the client, if rate limiting is enabled.
+    pub fn get_limiter(&self) -> Option<&Limiter> {
+        self.limiter.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::limiter::{Policy, Scope};
+
+    fn authenticator() -> Arc<Authenticator> {
+        let mut users = HashMap::new();
+        users.insert(String::from("producer"), String::from("secret"));
+
+        Arc::new(Authenticator::new(users))
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let authenticator = authenticator();
+        assert!(authenticator.is_required());
+        assert!(authenticator.authenticate("producer", "secret"));
+        assert!(!authenticator.authenticate("producer", "secreT"));
+        assert!(!authenticator.authenticate("producer", "secret2"));
+        assert!(!authenticator.authenticate("consumer", "secret"));
+        assert!(!Authenticator::new(HashMap::new()).is_required());
+    }
+
+    #[test]
+    fn test_session() {
+        let limits = Arc::new(Limits::new(1, 1, Policy::Reject, Scope::Identity));
+        let address = IpAddr::from([127, 0, 0, 1]);
+
+        // Anonymous sessions are allowed when authentication is disabled.
+        let session = Session::new(Arc::new(Authenticator::new(HashMap::new())), limits.clone(), address);
+        assert!(session.is_authenticated());
+
+        // Otherwise, sessions must authenticate with valid credentials.
+        let mut session = Session::new(authenticator(), limits.clone(), address);
+        assert!(!session.is_authenticated());
+        assert!(!session.authenticate("producer", "wrong"));
+        assert!(!session.is_authenticated());
+        assert!(session.authenticate("producer", "secret"));
+        assert!(session.is_authenticated());
+
+        // Once authenticated, the session shares the bucket of its user.
+        let mut other = Session::new(authenticator(), limits, IpAddr::from([10, 0, 0, 1]));
+        assert!(other.authenticate("producer", "secret"));
+        assert!(session.get_limiter().unwrap().acquire().is_ok());
+        assert!(other.get_limiter().unwrap().acquire().is_err());
+    }
+}
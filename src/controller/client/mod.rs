@@ -1,40 +1,112 @@
-mod parser;
-mod request;
+pub mod request;
 
-use crate::query::{Request, Response};
+use crate::execution::runner::Runner;
+use crate::query::{Error as QueryError, Request, Response};
 use crate::query::Client as ClientIdentifier;
+use crate::query::output::{JobStatus, Output};
+use crossbeam_channel::Sender as CrossbeamSender;
 use log::debug;
-use parser::{Error, parse};
+use super::authentication::Session;
+use super::limiter::Policy as LimiterPolicy;
+use kairoi_protocol::{DATETIME_FORMAT, Error, format as format_message, parse};
 use request::Builder;
-use request::Chainable;
-use request::job::Set as JobSet;
-use request::rule::Set as RuleSet;
+use std::io;
 use std::io::{ErrorKind, Read, Write};
 use std::net::TcpStream;
-use std::sync::mpsc::{Receiver, Sender};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::Receiver;
 use std::sync::mpsc::RecvTimeoutError;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// A stream connected to a client, either through TCP or through a Unix socket.
+pub trait Stream: Read + Write + Send + 'static {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
 
 pub struct Client {}
 
 impl Client {
     /// Spawn a new thread, creating a client with the given identifier to handle the given stream.
     /// Use the given producer to send request to the database, and receive confirmations on the
-    /// given consumer.
-    pub fn spawn(identifier: ClientIdentifier, mut stream: TcpStream, producer: Sender<Request>, consumer: Receiver<Response>) -> () {
+    /// given consumer. When the session has a limiter, every request read from the stream must
+    /// first acquire a token from it. When authentication is required, requests are rejected
+    /// until the client authenticates with an `AUTH` request.
+    pub fn spawn<S: Stream>(identifier: ClientIdentifier, mut stream: S, producer: CrossbeamSender<Request>, consumer: Receiver<Response>, mut session: Session) -> () {
         thread::spawn(move || {
             stream.set_nonblocking(true).unwrap();
-            let builders_chain: Vec<Box<dyn Chainable>> = vec![Box::new(JobSet::new()), Box::new(RuleSet::new())];
-            let builder = Builder::new(builders_chain);
+            let builder = Builder::with_all_instructions();
             let mut input = String::new();
             let mut bytes_to_parse: Option<Vec<u8>> = None;
+            let mut delayed_until: Option<Instant> = None;
 
             loop {
+                // While delayed by the rate limiter, stop reading requests, so the client is slowed
+                // down by the TCP flow control.
+                let delayed = match delayed_until {
+                    Some(until) => Instant::now() < until,
+                    None => false,
+                };
+
                 // Try to retrieve a request from the given input.
                 match parse(&input) {
+                    _ if delayed => {},
                     Ok((input_left, (request_identifier, arguments))) => {
+                        if let Some(limiter) = session.get_limiter() {
+                            if let Err(wait) = limiter.acquire() {
+                                match limiter.get_policy() {
+                                    LimiterPolicy::Delay => {
+                                        debug!("Delaying request {:?} from client {} for {:?} (rate limited).", &request_identifier, identifier, wait);
+                                        delayed_until = Some(Instant::now() + wait);
+
+                                        continue;
+                                    },
+                                    LimiterPolicy::Reject => {
+                                        debug!("Rejecting request {:?} from client {} (rate limited).", &request_identifier, identifier);
+                                        input = input_left.to_string();
+                                        match stream.write_all(format_message(&request_identifier, &[String::from("ERROR"), String::from("RATE_LIMITED")]).as_bytes()) {
+                                            Ok(_) => continue,
+                                            Err(_) => panic!("An unexpected error occurred while writing a client response."),
+                                        };
+                                    },
+                                };
+                            };
+                        };
+
                         input = input_left.to_string();
+                        // Authenticate the client, or reject its request if it isn't authenticated.
+                        let reply = match arguments.as_slice() {
+                            [command, name, password] if command.eq_ignore_ascii_case("AUTH") => {
+                                debug!("Authenticating client {} as {:?}.", identifier, name);
+                                match session.authenticate(name, password) {
+                                    true => Some(vec![String::from("OK")]),
+                                    false => Some(vec![String::from("ERROR"), String::from("UNAUTHORIZED")]),
+                                }
+                            },
+                            [command, ..] if command.eq_ignore_ascii_case("AUTH") => Some(vec![String::from("ERROR")]),
+                            _ if !session.is_authenticated() => Some(vec![String::from("ERROR"), String::from("UNAUTHENTICATED")]),
+                            _ => None,
+                        };
+                        if let Some(reply) = reply {
+                            match stream.write_all(format_message(&request_identifier, &reply).as_bytes()) {
+                                Ok(_) => continue,
+                                Err(_) => panic!("An unexpected error occurred while writing a client response."),
+                            };
+                        };
                         // Construct a request from the given arguments and send it to the database.
                         debug!("Reading request {:?} {:?} from client {}.", &request_identifier, &arguments, identifier);
                         match builder.build(&identifier, &request_identifier, &arguments) {
@@ -47,7 +119,7 @@ impl Client {
                             Err(_) => {
                                 // Send an error response to the client.
                                 debug!("Invalid request {:?} {:?} from client {}.", &request_identifier, &arguments, identifier);
-                                match stream.write_all(format!("{} ERROR\n", &request_identifier).to_string().as_bytes()) {
+                                match stream.write_all(format_message(&request_identifier, &[String::from("ERROR")]).as_bytes()) {
                                     Ok(_) => continue,
                                     Err(_) => panic!("An unexpected error occurred while writing a client response."),
                                 };
@@ -59,42 +131,44 @@ impl Client {
                 };
 
                 // Read more data from the stream of the connected client.
-                let mut buffer = [0; 2048];
-                match stream.read(&mut buffer) {
-                    Ok(0) => {
-                        debug!("EOF reached for client {}.", identifier);
-                        return;
-                    },
-                    Ok(length) => {
-                        let buffer = match bytes_to_parse {
-                            Some(bytes_to_parse) => {
-                                let mut copied_buffer = vec![0; bytes_to_parse.len() + length];
-                                copied_buffer[0..bytes_to_parse.len()].copy_from_slice(&bytes_to_parse);
-                                copied_buffer[bytes_to_parse.len()..bytes_to_parse.len() + length].copy_from_slice(&buffer[0..length]);
-
-                                copied_buffer
-                            },
-                            None => {
-                                let mut copied_buffer = vec![0; length];
-                                copied_buffer[..].copy_from_slice(&buffer[..length]);
+                if !delayed {
+                    let mut buffer = [0; 2048];
+                    match stream.read(&mut buffer) {
+                        Ok(0) => {
+                            debug!("EOF reached for client {}.", identifier);
+                            return;
+                        },
+                        Ok(length) => {
+                            let buffer = match bytes_to_parse {
+                                Some(bytes_to_parse) => {
+                                    let mut copied_buffer = vec![0; bytes_to_parse.len() + length];
+                                    copied_buffer[0..bytes_to_parse.len()].copy_from_slice(&bytes_to_parse);
+                                    copied_buffer[bytes_to_parse.len()..bytes_to_parse.len() + length].copy_from_slice(&buffer[0..length]);
 
-                                copied_buffer
-                            },
-                        };
-                        let (output, left_bytes) = Client::from_utf8_lossy(&buffer);
-                        input.push_str(&output);
-                        match left_bytes {
-                            Some(left_bytes) => {
-                                bytes_to_parse = Some(left_bytes.to_vec());
-                            },
-                            None => {
-                                bytes_to_parse = None;
-                            },
-                        };
-                    },
-                    Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
-                    Err(ref error) if error.kind() == ErrorKind::WouldBlock => {},
-                    Err(_) => panic!("An unexpected error occurred while reading a client request."),
+                                    copied_buffer
+                                },
+                                None => {
+                                    let mut copied_buffer = vec![0; length];
+                                    copied_buffer[..].copy_from_slice(&buffer[..length]);
+
+                                    copied_buffer
+                                },
+                            };
+                            let (output, left_bytes) = Client::from_utf8_lossy(&buffer);
+                            input.push_str(&output);
+                            match left_bytes {
+                                Some(left_bytes) => {
+                                    bytes_to_parse = Some(left_bytes.to_vec());
+                                },
+                                None => {
+                                    bytes_to_parse = None;
+                                },
+                            };
+                        },
+                        Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+                        Err(ref error) if error.kind() == ErrorKind::WouldBlock => {},
+                        Err(_) => panic!("An unexpected error occurred while reading a client request."),
+                    };
                 };
 
                 // Pull all instruction confirmations and send responses.
@@ -102,11 +176,7 @@ impl Client {
                     match consumer.recv_timeout(Duration::from_micros(100)) {
                         Ok(response) => {
                             debug!("Sending {:?} to client {}.", &response, identifier);
-                            let output = match response.get_result() {
-                                Ok(_) => String::from("OK"),
-                                Err(_) => String::from("ERROR"),
-                            };
-                            let message = format!("{} {}\n", &response.get_request().get_identifier(), output).to_string();
+                            let message = Client::format(&response);
                             match stream.write_all(message.as_bytes()) {
                                 Ok(_) => {},
                                 Err(_) => panic!("An unexpected error occurred while writing a client response."),
@@ -120,6 +190,92 @@ impl Client {
         });
     }
 
+    /// Format the given response as a message of the Kairoi Client Protocol. Outputs of handled
+    /// instructions are appended as arguments after the `OK` argument.
+    fn format(response: &Response) -> String {
+        let mut arguments = Vec::new();
+
+        match response.get_result() {
+            Ok(output) => {
+                arguments.push(String::from("OK"));
+                match output {
+                    Output::None => {},
+                    Output::Job(job) => {
+                        arguments.push(job.identifier.clone());
+                        arguments.push(job.execution.format(DATETIME_FORMAT).to_string());
+                        arguments.push(String::from(match job.status {
+                            JobStatus::Planned => "planned",
+                            JobStatus::Triggered => "triggered",
+                            JobStatus::Executed => "executed",
+                            JobStatus::Failed => "failed",
+                        }));
+                    },
+                    Output::Rules(rules) => {
+                        for rule in rules {
+                            arguments.push(rule.identifier.clone());
+                            arguments.push(rule.pattern.clone());
+                            match &rule.runner {
+                                Runner::Shell { command } => {
+                                    arguments.push(String::from("shell"));
+                                    arguments.push(command.clone());
+                                },
+                                Runner::Amqp { dsn, exchange, routing_key } => {
+                                    arguments.push(String::from("amqp"));
+                                    arguments.push(dsn.clone());
+                                    arguments.push(exchange.clone());
+                                    arguments.push(routing_key.clone());
+                                },
+                            };
+                        };
+                    },
+                    Output::Compaction(compaction) => {
+                        match &compaction.running {
+                            Some(progress) => {
+                                arguments.push(String::from("running"));
+                                arguments.push(progress.started.format(DATETIME_FORMAT).to_string());
+                                arguments.push(progress.processed_bytes.to_string());
+                                arguments.push(progress.total_bytes.to_string());
+                            },
+                            None => arguments.push(String::from("idle")),
+                        };
+                        match &compaction.last {
+                            Some(result) => {
+                                arguments.push(String::from(match result.success {
+                                    true => "success",
+                                    false => "failure",
+                                }));
+                                arguments.push(result.finished.format(DATETIME_FORMAT).to_string());
+                                arguments.push(result.entries.to_string());
+                            },
+                            None => arguments.push(String::from("none")),
+                        };
+                    },
+                    Output::Transfer(transfer) => {
+                        arguments.push(transfer.jobs.to_string());
+                        arguments.push(transfer.rules.to_string());
+                        arguments.push(transfer.skipped.to_string());
+                    },
+                };
+            },
+            Err(error) => {
+                arguments.push(String::from("ERROR"));
+                match error {
+                    QueryError::NotFound => arguments.push(String::from("NOT_FOUND")),
+                    QueryError::ReadOnly => arguments.push(String::from("READ_ONLY")),
+                    QueryError::NotLeader(leader) => {
+                        arguments.push(String::from("NOT_LEADER"));
+                        if let Some(leader) = leader {
+                            arguments.push(leader.clone());
+                        };
+                    },
+                    _ => {},
+                };
+            },
+        };
+
+        format_message(response.get_request().get_identifier(), &arguments)
+    }
+
     /// Parse the given input as utf8. Return the parsed utf8 String, and bytes left to parse if
     /// there are any.
     fn from_utf8_lossy(mut input: &[u8]) -> (String, Option<&[u8]>) {
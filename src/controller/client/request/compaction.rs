@@ -0,0 +1,51 @@
+use crate::query::instruction::Instruction;
+use super::Chainable;
+
+/// Build Compact requests from parsed arguments.
+pub struct Compact {}
+
+impl Compact {
+    /// Create a new Compact builder.
+    pub fn new() -> Compact {
+        Compact {}
+    }
+}
+
+impl Chainable for Compact {
+    fn build(&self, arguments: &Vec<String>) -> Option<Result<Instruction, ()>> {
+        // Handle all requests starting by "COMPACT".
+        if arguments.is_empty() || &arguments[0] != "COMPACT" {
+            return None
+        };
+
+        match arguments.len() {
+            1 => Some(Ok(Instruction::Compact)),
+            2 if &arguments[1] == "STATUS" => Some(Ok(Instruction::CompactStatus)),
+            _ => Some(Err(())),
+        }
+    }
+}
+
+/// Build Backup requests from parsed arguments.
+pub struct Backup {}
+
+impl Backup {
+    /// Create a new Backup builder.
+    pub fn new() -> Backup {
+        Backup {}
+    }
+}
+
+impl Chainable for Backup {
+    fn build(&self, arguments: &Vec<String>) -> Option<Result<Instruction, ()>> {
+        // Handle all requests starting by "BACKUP".
+        if arguments.is_empty() || &arguments[0] != "BACKUP" {
+            return None
+        };
+
+        match arguments.len() {
+            2 => Some(Ok(Instruction::Backup { path: arguments[1].clone() })),
+            _ => Some(Err(())),
+        }
+    }
+}
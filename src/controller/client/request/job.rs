@@ -1,5 +1,6 @@
 use chrono::offset::{TimeZone, Utc};
 use crate::query::instruction::Instruction;
+use kairoi_protocol::DATETIME_FORMAT;
 use log::debug;
 use super::Chainable;
 
@@ -20,7 +21,7 @@ impl Chainable for Set {
         if instruction == "SET" && arguments.len() == 3 {
             let identifier = &arguments[1];
             let execution = &arguments[2];
-            let execution = match Utc.datetime_from_str(execution, "%F %T") {
+            let execution = match Utc.datetime_from_str(execution, DATETIME_FORMAT) {
                 Ok(execution) => execution,
                 Err(_) => {
                     debug!("Unable to build date from string {}.", execution);
@@ -38,3 +39,51 @@ impl Chainable for Set {
         }
     }
 }
+
+/// Build Job Get requests from parsed arguments.
+pub struct Get {}
+
+impl Get {
+    /// Create a new Job Get builder.
+    pub fn new() -> Get {
+        Get {}
+    }
+}
+
+impl Chainable for Get {
+    fn build(&self, arguments: &Vec<String>) -> Option<Result<Instruction, ()>> {
+        let instruction = &arguments[0];
+
+        if instruction == "GET" && arguments.len() == 2 {
+            Some(Ok(Instruction::Get {
+                identifier: arguments[1].clone(),
+            }))
+        } else {
+            None
+        }
+    }
+}
+
+/// Build Job Unset requests from parsed arguments.
+pub struct Unset {}
+
+impl Unset {
+    /// Create a new Job Unset builder.
+    pub fn new() -> Unset {
+        Unset {}
+    }
+}
+
+impl Chainable for Unset {
+    fn build(&self, arguments: &Vec<String>) -> Option<Result<Instruction, ()>> {
+        let instruction = &arguments[0];
+
+        if instruction == "UNSET" && arguments.len() == 2 {
+            Some(Ok(Instruction::Unset {
+                identifier: arguments[1].clone(),
+            }))
+        } else {
+            None
+        }
+    }
+}
@@ -1,5 +1,8 @@
+pub mod compaction;
 pub mod job;
+pub mod replication;
 pub mod rule;
+pub mod transfer;
 
 use crate::query::Client;
 use crate::query::Request;
@@ -25,6 +28,22 @@ impl Builder {
         }
     }
 
+    /// Create a new builder, handling all instructions of the Kairoi Client Protocol.
+    pub fn with_all_instructions() -> Builder {
+        Builder::new(vec![
+            Box::new(job::Set::new()),
+            Box::new(job::Get::new()),
+            Box::new(job::Unset::new()),
+            Box::new(rule::Set::new()),
+            Box::new(rule::List::new()),
+            Box::new(compaction::Compact::new()),
+            Box::new(compaction::Backup::new()),
+            Box::new(transfer::Export::new()),
+            Box::new(transfer::Import::new()),
+            Box::new(replication::Promote::new()),
+        ])
+    }
+
     /// Build a query request from the given arguments.
     pub fn build(&self, client: &Client, identifier: &String, arguments: &Vec<String>) -> Result<Request, ()> {
         // Try all builders until a request is built.
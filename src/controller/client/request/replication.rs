@@ -0,0 +1,26 @@
+use crate::query::instruction::Instruction;
+use super::Chainable;
+
+/// Build Promote requests from parsed arguments.
+pub struct Promote {}
+
+impl Promote {
+    /// Create a new Promote builder.
+    pub fn new() -> Promote {
+        Promote {}
+    }
+}
+
+impl Chainable for Promote {
+    fn build(&self, arguments: &Vec<String>) -> Option<Result<Instruction, ()>> {
+        // Handle all requests starting by "PROMOTE".
+        if arguments.is_empty() || &arguments[0] != "PROMOTE" {
+            return None
+        };
+
+        match arguments.len() {
+            1 => Some(Ok(Instruction::Promote)),
+            _ => Some(Err(())),
+        }
+    }
+}
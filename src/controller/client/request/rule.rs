@@ -70,3 +70,27 @@ impl Chainable for Set {
         }
     }
 }
+
+/// Build Rule List requests from parsed arguments.
+pub struct List {}
+
+impl List {
+    /// Create a new Rule List builder.
+    pub fn new() -> List {
+        List {}
+    }
+}
+
+impl Chainable for List {
+    fn build(&self, arguments: &Vec<String>) -> Option<Result<Instruction, ()>> {
+        // Handle all requests starting by "RULE LIST".
+        if arguments.len() < 2 || &arguments[0] != "RULE" || &arguments[1] != "LIST" {
+            return None
+        };
+
+        match arguments.len() {
+            2 => Some(Ok(Instruction::RuleList)),
+            _ => Some(Err(())),
+        }
+    }
+}
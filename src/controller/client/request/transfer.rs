@@ -0,0 +1,58 @@
+use crate::query::instruction::{ImportPolicy, Instruction};
+use super::Chainable;
+
+/// Build Export requests from parsed arguments.
+pub struct Export {}
+
+impl Export {
+    /// Create a new Export builder.
+    pub fn new() -> Export {
+        Export {}
+    }
+}
+
+impl Chainable for Export {
+    fn build(&self, arguments: &Vec<String>) -> Option<Result<Instruction, ()>> {
+        // Handle all requests starting by "EXPORT".
+        if arguments.is_empty() || &arguments[0] != "EXPORT" {
+            return None
+        };
+
+        match arguments.len() {
+            2 => Some(Ok(Instruction::Export { path: arguments[1].clone() })),
+            _ => Some(Err(())),
+        }
+    }
+}
+
+/// Build Import requests from parsed arguments.
+pub struct Import {}
+
+impl Import {
+    /// Create a new Import builder.
+    pub fn new() -> Import {
+        Import {}
+    }
+}
+
+impl Chainable for Import {
+    fn build(&self, arguments: &Vec<String>) -> Option<Result<Instruction, ()>> {
+        // Handle all requests starting by "IMPORT".
+        if arguments.is_empty() || &arguments[0] != "IMPORT" {
+            return None
+        };
+
+        // Imports fail on conflicts by default, so existing items are never modified by mistake.
+        let policy = match arguments.get(2).map(|policy| policy.as_str()) {
+            None | Some("FAIL") => ImportPolicy::Fail,
+            Some("SKIP") => ImportPolicy::Skip,
+            Some("OVERWRITE") => ImportPolicy::Overwrite,
+            Some(_) => return Some(Err(())),
+        };
+
+        match arguments.len() {
+            2 | 3 => Some(Ok(Instruction::Import { path: arguments[1].clone(), policy })),
+            _ => Some(Err(())),
+        }
+    }
+}
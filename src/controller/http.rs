@@ -0,0 +1,520 @@
+//! Kairoi's HTTP API, exposing instructions as JSON endpoints.
+//!
+//! The HTTP API is an alternative to the Kairoi Client Protocol, for clients unable to easily use
+//! a raw TCP text protocol. Each HTTP request is translated into the arguments of the equivalent
+//! Kairoi Client Protocol request, then built by the same request [`Builder`]. Both APIs thus
+//! share the exact same instructions, and the same database path.
+//!
+//! The following endpoints are available:
+//! * `PUT /jobs/{identifier}`: set a job (`SET`), with a body like `{"execution": "2020-06-17
+//!   21:47:16"}`,
+//! * `GET /jobs/{identifier}`: get a job (`GET`),
+//! * `DELETE /jobs/{identifier}`: unset a job (`UNSET`),
+//! * `PUT /rules/{identifier}`: set a rule (`RULE SET`), with a body like `{"pattern": "app.",
+//!   "runner": {"type": "shell", "command": "script.sh"}}`,
+//! * `GET /rules`: list all rules (`RULE LIST`),
+//! * `POST /compaction`: start compacting the storage (`COMPACT`),
+//! * `GET /compaction`: get the state of the compaction (`COMPACT STATUS`),
+//! * `POST /backup`: start writing a backup of the storage (`BACKUP`), with a body like
+//!   `{"path": "kairoi.backup"}`,
+//! * `POST /export`: export all jobs and rules (`EXPORT`), with a body like `{"path":
+//!   "kairoi.ndjson"}`,
+//! * `POST /import`: import jobs and rules (`IMPORT`), with a body like `{"path":
+//!   "kairoi.ndjson", "policy": "skip"}`,
+//!
+//! Paths are names of files in the backup directory of the server, without any directory.
+//! * and `POST /promote`: promote a replica to a primary (`PROMOTE`).
+//!
+//! Requests are handled by a pool of workers, each one being registered as a client of the
+//! controller, and handling a single request at a time. When authentication is required, requests
+//! must authenticate with the `Basic` HTTP authentication scheme, otherwise they are rejected with
+//! a `401 Unauthorized` response. When rate limiting is enabled, each request consumes a token from
+//! the bucket of its user, or otherwise of its address (read more in the
+//! [`limiter`](super::limiter) module). Once the bucket is empty, requests are delayed, or rejected
+//! with a `429 Too Many Requests` response.
+
+use crate::execution::runner::Runner;
+use crate::query::{Error as QueryError, Request, Response};
+use crate::query::Client as ClientIdentifier;
+use crate::query::output::{Compaction as OutputCompaction, Job as OutputJob, JobStatus as OutputJobStatus, Output, Rule as OutputRule};
+use crossbeam_channel::Sender as CrossbeamSender;
+use log::debug;
+use openssl::base64;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_json::Value;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use super::authentication::{Authenticator, Session};
+use super::client::request::Builder;
+use super::limiter::{Limits, Policy as LimiterPolicy};
+use tiny_http::{Header, Method, Server};
+
+/// The maximum size of request bodies, in bytes.
+const MAXIMUM_BODY_SIZE: u64 = 65536;
+
+pub struct Http {}
+
+impl Http {
+    /// Spawn a new worker thread, handling requests received by the given server, as the client
+    /// with the given identifier. Use the given producer to send requests to the database, and
+    /// receive confirmations on the given consumer. Requests are authenticated by the given
+    /// authenticator, and rate limited following the given limits.
+    pub fn spawn(identifier: ClientIdentifier, server: Arc<Server>, producer: CrossbeamSender<Request>, consumer: Receiver<Response>, authenticator: Arc<Authenticator>, limits: Arc<Limits>) {
+        thread::spawn(move || {
+            let builder = Builder::with_all_instructions();
+            let mut sequence: u64 = 0;
+
+            loop {
+                let mut http_request = match server.recv() {
+                    Ok(request) => request,
+                    Err(error) => {
+                        debug!("Unable to receive an HTTP request ({:?}).", error);
+
+                        continue;
+                    },
+                };
+
+                let mut body = String::new();
+                if let Err(error) = http_request.as_reader().take(MAXIMUM_BODY_SIZE).read_to_string(&mut body) {
+                    debug!("Unable to read the body of an HTTP request ({:?}).", error);
+                };
+
+                // Requests sent through a Unix socket are considered as coming from localhost.
+                let address = match http_request.remote_addr() {
+                    Some(address) => address.ip(),
+                    None => IpAddr::from(Ipv4Addr::LOCALHOST),
+                };
+                let mut session = Session::request(authenticator.clone(), limits.clone(), address);
+                let authenticated = authenticate(&mut session, http_request.headers());
+                let allowed = limit(&session, &limits);
+
+                let (status, content) = match route(http_request.method(), http_request.url(), &body) {
+                    _ if !allowed => {
+                        debug!("Rejecting an HTTP request from {} (rate limited).", address);
+
+                        (429, Some(json!({ "error": "RATE_LIMITED" })))
+                    },
+                    _ if !authenticated => (401, Some(json!({ "error": "UNAUTHENTICATED" }))),
+                    Ok(arguments) => {
+                        sequence += 1;
+                        match builder.build(&identifier, &sequence.to_string(), &arguments) {
+                            Ok(request) => {
+                                debug!("Sending {:?} to the database.", &request);
+                                if producer.send(request).is_err() {
+                                    panic!("Database channel disconnected.");
+                                };
+                                match consumer.recv() {
+                                    Ok(response) => format(&response),
+                                    Err(_) => panic!("Database channel disconnected."),
+                                }
+                            },
+                            Err(error) => invalid(error),
+                        }
+                    },
+                    Err(failure) => failure,
+                };
+
+                let result = match content {
+                    Some(content) => {
+                        let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+                        let mut response = tiny_http::Response::from_string(content.to_string())
+                            .with_status_code(status)
+                            .with_header(header)
+                        ;
+                        if status == 401 {
+                            response.add_header(Header::from_bytes(&b"WWW-Authenticate"[..], &b"Basic realm=\"kairoi\""[..]).unwrap());
+                        };
+                        http_request.respond(response)
+                    },
+                    None => http_request.respond(tiny_http::Response::empty(status)),
+                };
+                if let Err(error) = result {
+                    debug!("Unable to send an HTTP response ({:?}).", error);
+                };
+            }
+        });
+    }
+}
+
+/// Body of job set requests.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobBody {
+    execution: String,
+}
+
+/// Body of backup and export requests.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PathBody {
+    path: String,
+}
+
+/// Body of import requests.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImportBody {
+    path: String,
+    policy: Option<PolicyBody>,
+}
+
+/// Import policy representation, in import requests.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PolicyBody {
+    Skip,
+    Overwrite,
+    Fail,
+}
+
+/// Body of rule set requests.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleBody {
+    pattern: String,
+    runner: RunnerBody,
+}
+
+/// Runner representation, in rule set requests and rule list responses.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum RunnerBody {
+    Shell {
+        command: String,
+    },
+    Amqp {
+        dsn: String,
+        exchange: String,
+        routing_key: String,
+    },
+}
+
+/// An HTTP status code, with an optional JSON content.
+type Reply = (u16, Option<Value>);
+
+/// Route the given HTTP request, returning the equivalent Kairoi Client Protocol arguments, or the
+/// reply to send directly when the request doesn't match any endpoint.
+fn route(method: &Method, url: &str, body: &str) -> Result<Vec<String>, Reply> {
+    let path = match url.split('?').next() {
+        Some(path) => path,
+        None => url,
+    };
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        (Method::Put, ["jobs", identifier]) => {
+            let identifier = decode(identifier).ok_or_else(|| invalid(()))?;
+            let job: JobBody = serde_json::from_str(body).map_err(invalid)?;
+
+            Ok(vec![String::from("SET"), identifier, job.execution])
+        },
+        (Method::Get, ["jobs", identifier]) => {
+            let identifier = decode(identifier).ok_or_else(|| invalid(()))?;
+
+            Ok(vec![String::from("GET"), identifier])
+        },
+        (Method::Delete, ["jobs", identifier]) => {
+            let identifier = decode(identifier).ok_or_else(|| invalid(()))?;
+
+            Ok(vec![String::from("UNSET"), identifier])
+        },
+        (Method::Put, ["rules", identifier]) => {
+            let identifier = decode(identifier).ok_or_else(|| invalid(()))?;
+            let rule: RuleBody = serde_json::from_str(body).map_err(invalid)?;
+
+            let mut arguments = vec![String::from("RULE"), String::from("SET"), identifier, rule.pattern];
+            match rule.runner {
+                RunnerBody::Shell { command } => {
+                    arguments.extend(vec![String::from("shell"), command]);
+                },
+                RunnerBody::Amqp { dsn, exchange, routing_key } => {
+                    arguments.extend(vec![String::from("amqp"), dsn, exchange, routing_key]);
+                },
+            };
+
+            Ok(arguments)
+        },
+        (Method::Get, ["rules"]) => Ok(vec![String::from("RULE"), String::from("LIST")]),
+        (Method::Post, ["compaction"]) => Ok(vec![String::from("COMPACT")]),
+        (Method::Get, ["compaction"]) => Ok(vec![String::from("COMPACT"), String::from("STATUS")]),
+        (Method::Post, ["backup"]) => {
+            let backup: PathBody = serde_json::from_str(body).map_err(invalid)?;
+
+            Ok(vec![String::from("BACKUP"), backup.path])
+        },
+        (Method::Post, ["export"]) => {
+            let export: PathBody = serde_json::from_str(body).map_err(invalid)?;
+
+            Ok(vec![String::from("EXPORT"), export.path])
+        },
+        (Method::Post, ["import"]) => {
+            let import: ImportBody = serde_json::from_str(body).map_err(invalid)?;
+
+            let mut arguments = vec![String::from("IMPORT"), import.path];
+            if let Some(policy) = import.policy {
+                arguments.push(String::from(match policy {
+                    PolicyBody::Skip => "SKIP",
+                    PolicyBody::Overwrite => "OVERWRITE",
+                    PolicyBody::Fail => "FAIL",
+                }));
+            };
+
+            Ok(arguments)
+        },
+        (Method::Post, ["promote"]) => Ok(vec![String::from("PROMOTE")]),
+        (_, ["jobs", _]) | (_, ["rules", _]) | (_, ["rules"]) | (_, ["compaction"]) | (_, ["backup"]) | (_, ["export"]) | (_, ["import"]) | (_, ["promote"]) => Err((405, Some(json!({ "error": "METHOD_NOT_ALLOWED" })))),
+        _ => Err((404, Some(json!({ "error": "UNKNOWN_ENDPOINT" })))),
+    }
+}
+
+/// Authenticate the given session with the credentials of the given request headers, using the
+/// `Basic` HTTP authentication scheme. Return whether the request is allowed, being the case when
+/// authenticated, or when authentication isn't required.
+fn authenticate(session: &mut Session, headers: &[Header]) -> bool {
+    let header = headers.iter().find(|header| header.field.equiv("Authorization"));
+    if let Some((name, password)) = header.and_then(|header| credentials(header.value.as_str())) {
+        session.authenticate(&name, &password);
+    };
+
+    session.is_authenticated()
+}
+
+/// Acquire a token for a single request from the limiter of the given session, if rate limiting is
+/// enabled, waiting for it with the delay policy. Return whether the request is allowed.
+fn limit(session: &Session, limits: &Limits) -> bool {
+    let limiter = match session.get_limiter() {
+        Some(limiter) => limiter,
+        None => return true,
+    };
+    limits.retain(limiter);
+
+    loop {
+        match limiter.acquire() {
+            Ok(_) => return true,
+            Err(wait) => match limiter.get_policy() {
+                LimiterPolicy::Delay => thread::sleep(wait),
+                LimiterPolicy::Reject => return false,
+            },
+        };
+    }
+}
+
+/// Decode the user name and password of the given `Authorization` header, with the `Basic` HTTP
+/// authentication scheme. Return nothing if the header is not properly encoded.
+fn credentials(header: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    };
+    let decoded = String::from_utf8(base64::decode_block(encoded.trim()).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+
+    Some((name.to_string(), password.to_string()))
+}
+
+/// Create the reply for invalid requests, whatever the error is.
+fn invalid<E>(_: E) -> Reply {
+    (400, Some(json!({ "error": "INVALID_REQUEST" })))
+}
+
+/// Format the given response as an HTTP reply.
+fn format(response: &Response) -> Reply {
+    match response.get_result() {
+        Ok(Output::None) => (204, None),
+        Ok(Output::Job(job)) => (200, Some(format_job(job))),
+        Ok(Output::Rules(rules)) => (200, Some(Value::Array(rules.iter().map(format_rule).collect()))),
+        Ok(Output::Compaction(compaction)) => (200, Some(format_compaction(compaction))),
+        Ok(Output::Transfer(transfer)) => (200, Some(json!({
+            "jobs": transfer.jobs,
+            "rules": transfer.rules,
+            "skipped": transfer.skipped,
+        }))),
+        Err(QueryError::NotFound) => (404, Some(json!({ "error": "NOT_FOUND" }))),
+        Err(QueryError::Conflict) => (409, Some(json!({ "error": "CONFLICT" }))),
+        Err(QueryError::ReadOnly) => (403, Some(json!({ "error": "READ_ONLY" }))),
+        Err(QueryError::NotLeader(leader)) => (421, Some(json!({ "error": "NOT_LEADER", "leader": leader }))),
+        Err(QueryError::Failure) => (500, Some(json!({ "error": "FAILURE" }))),
+    }
+}
+
+fn format_job(job: &OutputJob) -> Value {
+    json!({
+        "identifier": job.identifier,
+        "execution": job.execution.format("%F %T").to_string(),
+        "status": match job.status {
+            OutputJobStatus::Planned => "planned",
+            OutputJobStatus::Triggered => "triggered",
+            OutputJobStatus::Executed => "executed",
+            OutputJobStatus::Failed => "failed",
+        },
+    })
+}
+
+fn format_rule(rule: &OutputRule) -> Value {
+    let runner = match rule.runner.clone() {
+        Runner::Shell { command } => RunnerBody::Shell { command },
+        Runner::Amqp { dsn, exchange, routing_key } => RunnerBody::Amqp { dsn, exchange, routing_key },
+    };
+
+    json!({
+        "identifier": rule.identifier,
+        "pattern": rule.pattern,
+        "runner": runner,
+    })
+}
+
+fn format_compaction(compaction: &OutputCompaction) -> Value {
+    json!({
+        "running": compaction.running.as_ref().map(|progress| json!({
+            "started": progress.started.format("%F %T").to_string(),
+            "processed_bytes": progress.processed_bytes,
+            "total_bytes": progress.total_bytes,
+        })),
+        "last": compaction.last.as_ref().map(|result| json!({
+            "success": result.success,
+            "finished": result.finished.format("%F %T").to_string(),
+            "entries": result.entries,
+        })),
+    })
+}
+
+/// Decode the given percent-encoded URL segment. Return nothing if the segment is not properly
+/// encoded, or if it's not valid UTF-8.
+fn decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let hexadecimal = std::str::from_utf8(bytes.get(index + 1..index + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hexadecimal, 16).ok()?);
+                index += 3;
+            },
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            },
+        };
+    };
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::unbounded;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc::channel;
+    use super::super::limiter::Scope;
+
+    /// Send a request with the given method and path to the HTTP API listening to the given
+    /// address, returning the status code of the response.
+    fn send(address: SocketAddr, method: &str, path: &str) -> u16 {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: kairoi\r\nConnection: close\r\nContent-Length: 0\r\n\r\n", method, path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        response[9..12].parse().unwrap()
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let address = server.server_addr().to_ip().unwrap();
+        let (producer, requests) = unbounded();
+        let (responses, consumer) = channel();
+        let authenticator = Arc::new(Authenticator::new(HashMap::new()));
+        let limits = Arc::new(Limits::new(1, 2, LimiterPolicy::Reject, Scope::Connection));
+        Http::spawn(1, server, producer, consumer, authenticator, limits);
+        thread::spawn(move || {
+            for request in requests {
+                responses.send(Response::new(request, Ok(Output::Rules(vec![])))).unwrap();
+            };
+        });
+
+        // Requests are handled until the bucket of the address is empty, even with a new
+        // connection for each request.
+        assert_eq!(send(address, "GET", "/rules"), 200);
+        assert_eq!(send(address, "GET", "/rules"), 200);
+        assert_eq!(send(address, "GET", "/rules"), 429);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("app.job.1"), Some(String::from("app.job.1")));
+        assert_eq!(decode("my%20job%2F%F0%9F%98%B1"), Some(String::from("my job/\u{1F631}")));
+        assert_eq!(decode("%2"), None);
+        assert_eq!(decode("%zz"), None);
+        assert_eq!(decode("%FF"), None);
+    }
+
+    #[test]
+    fn test_credentials() {
+        assert_eq!(credentials("Basic cHJvZHVjZXI6c2VjcmV0"), Some((String::from("producer"), String::from("secret"))));
+        assert_eq!(credentials("basic cHJvZHVjZXI6czpl"), Some((String::from("producer"), String::from("s:e"))));
+        assert_eq!(credentials("Bearer cHJvZHVjZXI6c2VjcmV0"), None);
+        assert_eq!(credentials("Basic cHJvZHVjZXI="), None);
+        assert_eq!(credentials("Basic %%%"), None);
+    }
+
+    #[test]
+    fn test_route() {
+        assert_eq!(
+            route(&Method::Put, "/jobs/app.job.1", r#"{"execution": "2020-06-17 21:47:16"}"#),
+            Ok(vec![String::from("SET"), String::from("app.job.1"), String::from("2020-06-17 21:47:16")]),
+        );
+        assert_eq!(
+            route(&Method::Get, "/jobs/my%20job?pretty", ""),
+            Ok(vec![String::from("GET"), String::from("my job")]),
+        );
+        assert_eq!(
+            route(&Method::Delete, "/jobs/app.job.1", ""),
+            Ok(vec![String::from("UNSET"), String::from("app.job.1")]),
+        );
+        assert_eq!(
+            route(&Method::Put, "/rules/app.rule", r#"{"pattern": "app.", "runner": {"type": "amqp", "dsn": "amqp://localhost", "exchange": "e", "routing_key": "k"}}"#),
+            Ok(vec![String::from("RULE"), String::from("SET"), String::from("app.rule"), String::from("app."), String::from("amqp"), String::from("amqp://localhost"), String::from("e"), String::from("k")]),
+        );
+        assert_eq!(
+            route(&Method::Get, "/rules", ""),
+            Ok(vec![String::from("RULE"), String::from("LIST")]),
+        );
+        assert_eq!(route(&Method::Post, "/compaction", ""), Ok(vec![String::from("COMPACT")]));
+        assert_eq!(
+            route(&Method::Post, "/backup", r#"{"path": "kairoi.backup"}"#),
+            Ok(vec![String::from("BACKUP"), String::from("kairoi.backup")]),
+        );
+        assert_eq!(
+            route(&Method::Post, "/export", r#"{"path": "kairoi.ndjson"}"#),
+            Ok(vec![String::from("EXPORT"), String::from("kairoi.ndjson")]),
+        );
+        assert_eq!(
+            route(&Method::Post, "/import", r#"{"path": "kairoi.ndjson", "policy": "overwrite"}"#),
+            Ok(vec![String::from("IMPORT"), String::from("kairoi.ndjson"), String::from("OVERWRITE")]),
+        );
+        assert_eq!(
+            route(&Method::Get, "/compaction", ""),
+            Ok(vec![String::from("COMPACT"), String::from("STATUS")]),
+        );
+        assert_eq!(route(&Method::Post, "/promote", ""), Ok(vec![String::from("PROMOTE")]));
+        // Test invalid requests.
+        assert_eq!(route(&Method::Put, "/jobs/app.job.1", r#"{"time": "2020-06-17 21:47:16"}"#).unwrap_err().0, 400);
+        assert_eq!(route(&Method::Put, "/rules/app.rule", r#"{"pattern": "app.", "runner": {"type": "cron"}}"#).unwrap_err().0, 400);
+        assert_eq!(route(&Method::Post, "/jobs/app.job.1", "").unwrap_err().0, 405);
+        assert_eq!(route(&Method::Get, "/jobs", "").unwrap_err().0, 404);
+        assert_eq!(route(&Method::Delete, "/compaction", "").unwrap_err().0, 405);
+        assert_eq!(route(&Method::Get, "/promote", "").unwrap_err().0, 405);
+        assert_eq!(route(&Method::Post, "/backup", "{}").unwrap_err().0, 400);
+        assert_eq!(route(&Method::Post, "/import", r#"{"path": "kairoi.ndjson", "policy": "merge"}"#).unwrap_err().0, 400);
+    }
+}
@@ -0,0 +1,320 @@
+//! Rate limiting of client requests, using token buckets.
+//!
+//! Each [`Limiter`] wraps a [`Bucket`] filled with tokens at a configured rate, up to a configured
+//! burst capacity. Every request read from a client consumes a single token. When the bucket is
+//! empty, the configured [`Policy`] decides whether the request is delayed until a token is
+//! available, or rejected. Limiters can be shared between multiple clients (for example, all
+//! clients connected from the same address), since the underlying bucket is synchronized.
+//!
+//! The [`Limits`] create the limiters of all clients, depending on the configured [`Scope`]: each
+//! connection may have its own bucket, or share the bucket of all connections from the same
+//! address, or the bucket of all connections authenticated as the same user (anonymous connections
+//! sharing the bucket of their address).
+//!
+//! HTTP requests aren't bound to a connection holding their limiter: each one shares the bucket of
+//! its address (or of its user, with the identity scope), whatever the scope. These buckets are
+//! retained until they're full again, so sending requests in turn doesn't refill them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// The behavior of a limiter when a client exceeds its rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    Delay,
+    Reject,
+}
+
+/// The clients sharing the same bucket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    Connection,
+    Address,
+    Identity,
+}
+
+/// The key of a bucket shared between multiple clients.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum Key {
+    Address(IpAddr),
+    User(String),
+}
+
+/// A token bucket, refilled at a constant rate up to its capacity.
+pub struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    /// Create a new full bucket, refilled with the given rate (in tokens per second), and able to
+    /// hold the given burst capacity. A burst capacity of 0 is replaced by the rate itself.
+    pub fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        let capacity = match burst {
+            0 => rate.max(1) as f64,
+            burst => burst as f64,
+        };
+
+        Self {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    /// Try to consume a token at the given instant. On failure, return the duration to wait
+    /// before a token is available again.
+    pub fn acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    /// Check whether the bucket is full at the given instant, being then the same as a new one.
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens + elapsed * self.rate >= self.capacity
+    }
+}
+
+/// A rate limiter, combining a (possibly shared) bucket with a limiting policy.
+#[derive(Clone)]
+pub struct Limiter {
+    bucket: Arc<Mutex<Bucket>>,
+    policy: Policy,
+}
+
+impl Limiter {
+    /// Create a new limiter on the given shared bucket.
+    pub fn new(bucket: Arc<Mutex<Bucket>>, policy: Policy) -> Self {
+        Self {
+            bucket,
+            policy,
+        }
+    }
+
+    /// Try to consume a token for a single request. On failure, return the duration to wait
+    /// before a token is available again.
+    pub fn acquire(&self) -> Result<(), Duration> {
+        // A poisoned bucket only means another client thread panicked while holding it. Its
+        // content is still consistent, so it can safely be used.
+        let mut bucket = match self.bucket.lock() {
+            Ok(bucket) => bucket,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        bucket.acquire(Instant::now())
+    }
+
+    /// Get the policy of this limiter.
+    pub fn get_policy(&self) -> Policy {
+        self.policy
+    }
+}
+
+/// The rate limits of all clients, creating their limiters.
+pub struct Limits {
+    /// The number of requests per second allowed, 0 disabling the rate limiting.
+    rate: u32,
+    burst: u32,
+    policy: Policy,
+    scope: Scope,
+    buckets: Mutex<HashMap<Key, Weak<Mutex<Bucket>>>>,
+    /// The buckets of HTTP requests, kept until they're full again.
+    retained: Mutex<Vec<Arc<Mutex<Bucket>>>>,
+}
+
+impl Limits {
+    pub fn new(rate: u32, burst: u32, policy: Policy, scope: Scope) -> Self {
+        Self {
+            rate,
+            burst,
+            policy,
+            scope,
+            buckets: Mutex::new(HashMap::new()),
+            retained: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Create the limiter of a new anonymous client connected from the given address, if rate
+    /// limiting is enabled.
+    pub fn limit(&self, address: IpAddr) -> Option<Limiter> {
+        match self.scope {
+            Scope::Connection => self.create(None),
+            Scope::Address | Scope::Identity => self.create(Some(Key::Address(address))),
+        }
+    }
+
+    /// Create the limiter of a new anonymous HTTP request from the given address, if rate limiting
+    /// is enabled. HTTP requests aren't bound to a connection, so the connection scope shares the
+    /// bucket of the address too.
+    #[cfg_attr(not(feature = "controller-http"), allow(dead_code))]
+    pub fn limit_request(&self, address: IpAddr) -> Option<Limiter> {
+        self.create(Some(Key::Address(address)))
+    }
+
+    /// Keep the bucket of the given limiter of an HTTP request until it's full again, since no
+    /// connection holds it once the request is handled. Full buckets are forgotten.
+    #[cfg_attr(not(feature = "controller-http"), allow(dead_code))]
+    pub fn retain(&self, limiter: &Limiter) {
+        let mut retained = match self.retained.lock() {
+            Ok(retained) => retained,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = Instant::now();
+        retained.retain(|bucket| match bucket.lock() {
+            Ok(bucket) => !bucket.is_full(now),
+            Err(poisoned) => !poisoned.into_inner().is_full(now),
+        });
+
+        if !retained.iter().any(|bucket| Arc::ptr_eq(bucket, &limiter.bucket)) {
+            retained.push(limiter.bucket.clone());
+        };
+    }
+
+    /// Replace the given limiter of a client, once authenticated as the user with the given name.
+    /// Only the identity scope changes the bucket of a client: other scopes keep the same bucket,
+    /// so a client can't refill its bucket by authenticating again.
+    pub fn authenticate(&self, limiter: &mut Option<Limiter>, name: &str) {
+        if self.scope == Scope::Identity {
+            *limiter = self.create(Some(Key::User(name.to_string())));
+        };
+    }
+
+    /// Create a limiter, with its own bucket, or with the bucket shared by all clients with the
+    /// given key.
+    fn create(&self, key: Option<Key>) -> Option<Limiter> {
+        if self.rate == 0 {
+            return None;
+        };
+
+        let create = || Arc::new(Mutex::new(Bucket::new(self.rate, self.burst, Instant::now())));
+        let bucket = match key {
+            Some(key) => {
+                let mut buckets = match self.buckets.lock() {
+                    Ok(buckets) => buckets,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                // Forget buckets without any connected client.
+                buckets.retain(|_, bucket| bucket.strong_count() > 0);
+
+                match buckets.get(&key).and_then(|bucket| bucket.upgrade()) {
+                    Some(bucket) => bucket,
+                    None => {
+                        let bucket = create();
+                        buckets.insert(key, Arc::downgrade(&bucket));
+
+                        bucket
+                    },
+                }
+            },
+            None => create(),
+        };
+
+        Some(Limiter::new(bucket, self.policy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(2, 3, now);
+
+        // The bucket starts full, allowing a burst.
+        assert_eq!(bucket.acquire(now), Ok(()));
+        assert_eq!(bucket.acquire(now), Ok(()));
+        assert_eq!(bucket.acquire(now), Ok(()));
+        assert_eq!(bucket.acquire(now), Err(Duration::from_millis(500)));
+        // Tokens are refilled following the rate.
+        assert_eq!(bucket.acquire(now + Duration::from_millis(250)), Err(Duration::from_millis(250)));
+        assert_eq!(bucket.acquire(now + Duration::from_millis(500)), Ok(()));
+        // Tokens are never refilled beyond the capacity.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.acquire(later), Ok(()));
+        assert_eq!(bucket.acquire(later), Ok(()));
+        assert_eq!(bucket.acquire(later), Ok(()));
+        assert!(bucket.acquire(later).is_err());
+    }
+
+    #[test]
+    fn test_default_capacity() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(1, 0, now);
+
+        assert_eq!(bucket.acquire(now), Ok(()));
+        assert_eq!(bucket.acquire(now), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_scopes() {
+        let first = IpAddr::from([127, 0, 0, 1]);
+        let second = IpAddr::from([127, 0, 0, 2]);
+        // Check whether the two given limiters share the same bucket, holding a single token.
+        let shared = |first: Option<Limiter>, second: Option<Limiter>| {
+            assert!(first.unwrap().acquire().is_ok());
+            second.unwrap().acquire().is_err()
+        };
+
+        assert!(Limits::new(0, 1, Policy::Reject, Scope::Connection).limit(first).is_none());
+
+        let limits = Limits::new(1, 1, Policy::Reject, Scope::Connection);
+        assert!(!shared(limits.limit(first), limits.limit(first)));
+
+        let limits = Limits::new(1, 1, Policy::Reject, Scope::Address);
+        assert!(shared(limits.limit(first), limits.limit(first)));
+        assert!(!shared(limits.limit(first), limits.limit(second)));
+        let (first_limiter, mut second_limiter) = (limits.limit(first), limits.limit(first));
+        limits.authenticate(&mut second_limiter, "producer");
+        assert!(shared(first_limiter, second_limiter));
+
+        // With the identity scope, anonymous clients share the bucket of their address, and
+        // authenticated clients the bucket of their user.
+        let limits = Limits::new(1, 1, Policy::Reject, Scope::Identity);
+        assert!(shared(limits.limit(first), limits.limit(first)));
+        let (mut first_limiter, mut second_limiter) = (limits.limit(first), limits.limit(second));
+        limits.authenticate(&mut first_limiter, "producer");
+        limits.authenticate(&mut second_limiter, "producer");
+        assert!(shared(first_limiter, second_limiter));
+        let (mut first_limiter, second_limiter) = (limits.limit(first), limits.limit(first));
+        limits.authenticate(&mut first_limiter, "consumer");
+        assert!(!shared(first_limiter, second_limiter));
+    }
+
+    #[test]
+    fn test_retain() {
+        let address = IpAddr::from([127, 0, 0, 1]);
+        let limits = Limits::new(1, 1, Policy::Reject, Scope::Connection);
+
+        // HTTP requests share the bucket of their address, even with the connection scope, and
+        // retained buckets outlive their requests.
+        let limiter = limits.limit_request(address).unwrap();
+        limits.retain(&limiter);
+        assert!(limiter.acquire().is_ok());
+        drop(limiter);
+        assert!(limits.limit_request(address).unwrap().acquire().is_err());
+
+        // Full buckets are forgotten.
+        let limits = Limits::new(1, 1, Policy::Reject, Scope::Address);
+        limits.retain(&limits.limit_request(address).unwrap());
+        limits.retain(&limits.limit_request(IpAddr::from([127, 0, 0, 2])).unwrap());
+        assert_eq!(limits.retained.lock().unwrap().len(), 1);
+    }
+}
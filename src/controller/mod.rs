@@ -1,49 +1,168 @@
+mod authentication;
 mod client;
+#[cfg(feature = "controller-http")]
+mod http;
+mod limiter;
+#[cfg(feature = "controller-resp")]
+mod resp;
+mod router;
 
 use client::Client;
-use crate::query::{Request, Response};
+use crate::query::{Client as ClientIdentifier, Request, Response};
+use crossbeam_channel::{Receiver as CrossbeamReceiver, Select, Sender as CrossbeamSender, TryRecvError};
+#[cfg(feature = "controller-http")]
+use self::http::Http as HttpWorker;
+use self::authentication::{Authenticator, Session};
+use self::limiter::Limits;
+#[cfg(feature = "controller-resp")]
+use self::resp::Resp as RespClient;
+use self::router::Router;
 use std::collections::HashMap;
 use std::io;
-use std::net::TcpListener;
+use std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::mpsc::Sender;
 use std::thread;
-use std::time::{Duration, Instant};
+
+pub type RateLimitPolicy = limiter::Policy;
+pub type RateLimitScope = limiter::Scope;
+pub struct RateLimit {
+    /// The number of requests per second allowed, 0 disabling the rate limiting.
+    pub rate: u32,
+    pub burst: u32,
+    pub policy: RateLimitPolicy,
+    pub scope: RateLimitScope,
+}
+#[cfg_attr(not(feature = "controller-http"), allow(dead_code))]
+pub struct Http {
+    pub listen: String,
+    pub workers: u16,
+}
+#[cfg_attr(not(feature = "controller-resp"), allow(dead_code))]
+pub struct Resp {
+    pub listen: String,
+}
+pub struct Configuration {
+    pub listen: String,
+    /// The path of the Unix socket listened to, along with the TCP address.
+    pub unix_socket: Option<String>,
+    pub rate_limit: RateLimit,
+    pub http: Option<Http>,
+    pub resp: Option<Resp>,
+    /// The passwords of all users, indexed by name. Clients must authenticate as soon as a user
+    /// is configured.
+    pub users: HashMap<String, String>,
+}
+
+/// A connection accepted by one of the listeners of the controller, along with the address of its
+/// peer.
+enum Connection {
+    Tcp(TcpStream, IpAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "controller-resp")]
+    Resp(TcpStream, IpAddr),
+}
 
 pub struct Controller {}
 
 impl Controller {
-    /// Start the controller, spawning a thread and returning the join handle. The given listen
-    /// parameter should be a listenable address, including the port (for example
-    /// `127.0.0.1:5678`), otherwise the thread will panic.
-    pub fn start(listen: String, query_link: (Sender<Request>, Receiver<Response>)) -> thread::JoinHandle<()> {
+    /// Start the controller, spawning a thread and returning the join handle. The configured
+    /// listen parameters should be listenable addresses, including the port (for example
+    /// `127.0.0.1:5678`), otherwise the thread will panic. Requests are routed between the query
+    /// links of all shards of the database.
+    pub fn start(query_links: Vec<(CrossbeamSender<Request>, CrossbeamReceiver<Response>)>, configuration: Configuration) -> thread::JoinHandle<()> {
         thread::Builder::new().name("kairoi/ctrl".to_string()).spawn(move || {
+            let query_link = Router::start(query_links);
             let mut clients = HashMap::new();
             let mut identifier: u128 = 0;
+            let authenticator = Arc::new(Authenticator::new(configuration.users));
+            let rate_limit = configuration.rate_limit;
+            let limits = Arc::new(Limits::new(rate_limit.rate, rate_limit.burst, rate_limit.policy, rate_limit.scope));
+
+            let (connection_producer, connection_consumer) = crossbeam_channel::unbounded();
 
+            let listen = configuration.listen;
             let server = TcpListener::bind(&listen).unwrap();
-            server.set_nonblocking(true).unwrap();
 
             log::info!("Waiting for connections on {}.", &server.local_addr().unwrap());
 
+            Controller::spawn_acceptor("kairoi/tcp", connection_producer.clone(), move || {
+                server.accept().map(|(stream, address)| Connection::Tcp(stream, address.ip()))
+            });
+
+            // Connections through the Unix socket are considered as coming from localhost.
+            #[cfg(unix)]
+            if let Some(unix_server) = configuration.unix_socket.as_ref().and_then(|path| Controller::listen_unix(path)) {
+                Controller::spawn_acceptor("kairoi/unix", connection_producer.clone(), move || {
+                    unix_server.accept().map(|(stream, _)| Connection::Unix(stream))
+                });
+            };
+            #[cfg(not(unix))]
+            if let Some(path) = &configuration.unix_socket {
+                Controller::listen_unix(path);
+            };
+
+            #[cfg(feature = "controller-resp")]
+            if let Some(resp) = &configuration.resp {
+                let server = TcpListener::bind(&resp.listen).unwrap();
+                log::info!("Waiting for RESP connections on {}.", &server.local_addr().unwrap());
+
+                Controller::spawn_acceptor("kairoi/resp", connection_producer.clone(), move || {
+                    server.accept().map(|(stream, address)| Connection::Resp(stream, address.ip()))
+                });
+            };
+            #[cfg(not(feature = "controller-resp"))]
+            if configuration.resp.is_some() {
+                log::warn!("The RESP front end is configured, but Kairoi has been compiled without the `controller-resp` feature.");
+            };
+            drop(connection_producer);
+
+            if let Some(http) = &configuration.http {
+                for (worker_identifier, producer) in Controller::start_http(http, identifier, &query_link.0, &authenticator, &limits) {
+                    clients.insert(worker_identifier, producer);
+                    identifier = worker_identifier + 1;
+                };
+            };
+
             loop {
-                let previous_time = Instant::now();
+                // Wait until a connection is accepted or a response is received.
+                let mut select = Select::new();
+                select.recv(&connection_consumer);
+                select.recv(&query_link.1);
+                select.ready();
 
-                // Accept all incoming connections.
+                // Handle all accepted connections.
                 loop {
-                    match server.accept() {
-                        Ok(stream) => {
-                            // @TODO: Handle the connection.
-                            let (producer, consumer) = mpsc::channel();
-                            clients.insert(identifier, producer);
-                            Client::spawn(identifier, stream.0, query_link.0.clone(), consumer);
-                            identifier += 1;
+                    let connection = match connection_consumer.try_recv() {
+                        Ok(Ok(connection)) => connection,
+                        Ok(Err(error)) => panic!("Encountered IO error: {}", error),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => panic!("Connection channel disconnected."),
+                    };
+
+                    let (producer, consumer) = mpsc::channel();
+                    clients.insert(identifier, producer);
+                    match connection {
+                        Connection::Tcp(stream, address) => {
+                            let session = Session::new(authenticator.clone(), limits.clone(), address);
+                            Client::spawn(identifier, stream, query_link.0.clone(), consumer, session);
+                        },
+                        #[cfg(unix)]
+                        Connection::Unix(stream) => {
+                            let session = Session::new(authenticator.clone(), limits.clone(), Ipv4Addr::LOCALHOST.into());
+                            Client::spawn(identifier, stream, query_link.0.clone(), consumer, session);
                         },
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            break;
+                        #[cfg(feature = "controller-resp")]
+                        Connection::Resp(stream, address) => {
+                            let session = Session::new(authenticator.clone(), limits.clone(), address);
+                            RespClient::spawn(identifier, stream, query_link.0.clone(), consumer, session);
                         },
-                        Err(error) => panic!("Encountered IO error: {}", error),
                     };
+                    identifier += 1;
                 }
 
                 // Pull all received confirmation messages.
@@ -76,17 +195,71 @@ impl Controller {
                         },
                     }
                 }
-
-                // Put the thread asleep to run at a maximum of 128 time per second.
-                let now = Instant::now();
-                let elapsed_time = now.duration_since(previous_time);
-                match Duration::new(0, 1_000_000_000u32 / 128).checked_sub(elapsed_time) {
-                    Some(sleep_time) => {
-                        thread::sleep(sleep_time);
-                    },
-                    None => {},
-                };
             }
         }).unwrap()
     }
+
+    /// Spawn a thread with the given name, blocking on the given accept function and sending each
+    /// accepted connection (or the error failing to accept one) to the controller through the
+    /// given producer. The thread ends when the controller is gone, or after the first error.
+    fn spawn_acceptor<F>(name: &str, producer: CrossbeamSender<io::Result<Connection>>, mut accept: F) where F: FnMut() -> io::Result<Connection> + Send + 'static {
+        thread::Builder::new().name(name.to_string()).spawn(move || {
+            loop {
+                let result = accept();
+                let failed = result.is_err();
+                if producer.send(result).is_err() || failed {
+                    break;
+                };
+            };
+        }).unwrap();
+    }
+
+    /// Listen to the Unix socket at the given path, replacing the socket left by a previous server
+    /// (if any). Any other file at the given path is kept, the controller panicking instead.
+    #[cfg(unix)]
+    fn listen_unix(path: &str) -> Option<UnixListener> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(path).unwrap();
+            };
+        };
+        let server = UnixListener::bind(path).unwrap();
+        log::info!("Waiting for connections on the Unix socket {}.", path);
+
+        Some(server)
+    }
+
+    #[cfg(not(unix))]
+    fn listen_unix(_: &str) -> Option<()> {
+        log::warn!("A Unix socket is configured, but Unix sockets are not supported on this platform.");
+
+        None
+    }
+
+    /// Start the HTTP API with the given configuration, spawning its workers as clients with
+    /// identifiers starting from the given one. Return producers to notify each worker of its
+    /// responses, indexed by worker identifier. Requests are authenticated by the given
+    /// authenticator, and rate limited following the given limits.
+    #[cfg(feature = "controller-http")]
+    fn start_http(http: &Http, first_identifier: ClientIdentifier, query_producer: &CrossbeamSender<Request>, authenticator: &Arc<Authenticator>, limits: &Arc<Limits>) -> Vec<(ClientIdentifier, Sender<Response>)> {
+        let server = Arc::new(tiny_http::Server::http(&http.listen).unwrap());
+        log::info!("Waiting for HTTP requests on {}.", server.server_addr());
+
+        (0..http.workers).map(|index| {
+            let identifier = first_identifier + index as ClientIdentifier;
+            let (producer, consumer) = mpsc::channel();
+            HttpWorker::spawn(identifier, server.clone(), query_producer.clone(), consumer, authenticator.clone(), limits.clone());
+
+            (identifier, producer)
+        }).collect()
+    }
+
+    #[cfg(not(feature = "controller-http"))]
+    fn start_http(_: &Http, _: ClientIdentifier, _: &CrossbeamSender<Request>, _: &Arc<Authenticator>, _: &Arc<Limits>) -> Vec<(ClientIdentifier, Sender<Response>)> {
+        log::warn!("The HTTP API is configured, but Kairoi has been compiled without the `controller-http` feature.");
+
+        Vec::new()
+    }
 }
@@ -0,0 +1,510 @@
+//! Kairoi's RESP front end, compatible with Redis clients.
+//!
+//! This front end speaks RESP2 and RESP3 (negotiated with the `HELLO` command), so existing Redis
+//! client libraries can be used against Kairoi, including their pipelining and connection pools.
+//! Kairoi commands are translated into the arguments of the equivalent Kairoi Client Protocol
+//! request, then built by the same request [`Builder`]:
+//! * `KAIROI.SET identifier execution` (`SET`),
+//! * `KAIROI.GET identifier` (`GET`),
+//! * `KAIROI.UNSET identifier` (`UNSET`),
+//! * `KAIROI.RULESET identifier pattern runner [runner_arguments...]` (`RULE SET`),
+//! * `KAIROI.RULELIST` (`RULE LIST`),
+//! * `KAIROI.COMPACT` (`COMPACT`),
+//! * `KAIROI.COMPACTSTATUS` (`COMPACT STATUS`),
+//! * `KAIROI.BACKUP path` (`BACKUP`),
+//! * `KAIROI.EXPORT path` (`EXPORT`),
+//! * `KAIROI.IMPORT path [policy]` (`IMPORT`),
+//! * and `KAIROI.PROMOTE` (`PROMOTE`).
+//!
+//! A few connection commands expected by client libraries (`PING`, `ECHO`, `HELLO`, `AUTH`,
+//! `SELECT`, `CLIENT`, `COMMAND` and `QUIT`) are directly handled by the connection. Replies are
+//! always sent in the order in which commands have been received.
+
+mod parser;
+mod value;
+
+use crate::execution::runner::Runner;
+use crate::query::{Error as QueryError, Request, Response};
+use crate::query::Client as ClientIdentifier;
+use crate::query::instruction::Instruction;
+use crate::query::output::{JobStatus, Output};
+use crossbeam_channel::Sender as CrossbeamSender;
+use log::debug;
+use parser::{Error, parse};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+use super::authentication::Session;
+use super::client::request::Builder;
+use super::limiter::Policy as LimiterPolicy;
+use value::{Protocol, Value};
+
+/// The maximum size of replies waiting to be written, in bytes. Over this size, commands are not
+/// read anymore until the client reads its replies.
+const MAXIMUM_OUTPUT_SIZE: usize = 1024 * 1024;
+
+/// The reply to a command, queued in the order in which commands have been received.
+enum Reply {
+    /// Waiting for the database to handle the request with the given identifier, the response
+    /// being encoded with the given protocol version.
+    Waiting(String, Protocol),
+    /// Encoded and ready to be written.
+    Ready(Vec<u8>),
+}
+
+/// The action to take for a received command.
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Send a request built from the given Kairoi Client Protocol arguments to the database.
+    Request(Vec<String>),
+    /// Directly reply with the given value.
+    Reply(Value),
+    /// Authenticate the client with the given user name and password, then reply with the given
+    /// value.
+    Authenticate(String, String, Value),
+    /// Close the connection, once all replies have been written.
+    Quit,
+}
+
+pub struct Resp {}
+
+impl Resp {
+    /// Spawn a new thread, creating a client with the given identifier to handle the given RESP
+    /// stream. Use the given producer to send requests to the database, and receive confirmations
+    /// on the given consumer. When the session has a limiter, every command read from the stream
+    /// must first acquire a token from it. When authentication is required, commands are
+    /// rejected until the client authenticates with the `AUTH` or `HELLO` commands.
+    pub fn spawn(identifier: ClientIdentifier, mut stream: TcpStream, producer: CrossbeamSender<Request>, consumer: Receiver<Response>, mut session: Session) {
+        thread::spawn(move || {
+            stream.set_nonblocking(true).unwrap();
+            let builder = Builder::with_all_instructions();
+            let mut protocol = Protocol::Resp2;
+            let mut input: Vec<u8> = Vec::new();
+            let mut output: Vec<u8> = Vec::new();
+            let mut replies: VecDeque<Reply> = VecDeque::new();
+            let mut sequence: u64 = 0;
+            let mut delayed_until: Option<Instant> = None;
+            let mut closing = false;
+
+            loop {
+                // While delayed by the rate limiter, stop reading commands, so the client is slowed
+                // down by the TCP flow control.
+                let delayed = match delayed_until {
+                    Some(until) => Instant::now() < until,
+                    None => false,
+                };
+
+                // Handle all complete commands, unless the client doesn't read its replies.
+                while !delayed && !closing && output.len() < MAXIMUM_OUTPUT_SIZE {
+                    let (length, arguments) = match parse(&input) {
+                        Ok(command) => command,
+                        Err(Error::Incomplete) => break,
+                        Err(Error::Error) => {
+                            debug!("Closing client {} (protocol error).", identifier);
+                            replies.push_back(Reply::Ready(encode(&Value::Error(String::from("ERR Protocol error")), protocol)));
+                            closing = true;
+
+                            break;
+                        },
+                    };
+                    if arguments.is_empty() {
+                        input.drain(..length);
+
+                        continue;
+                    };
+
+                    if let Some(limiter) = session.get_limiter() {
+                        if let Err(wait) = limiter.acquire() {
+                            match limiter.get_policy() {
+                                LimiterPolicy::Delay => {
+                                    debug!("Delaying a command from client {} for {:?} (rate limited).", identifier, wait);
+                                    delayed_until = Some(Instant::now() + wait);
+
+                                    break;
+                                },
+                                LimiterPolicy::Reject => {
+                                    debug!("Rejecting a command from client {} (rate limited).", identifier);
+                                    input.drain(..length);
+                                    replies.push_back(Reply::Ready(encode(&Value::Error(String::from("RATE_LIMITED too many requests")), protocol)));
+
+                                    continue;
+                                },
+                            };
+                        };
+                    };
+                    input.drain(..length);
+
+                    let arguments: Result<Vec<String>, _> = arguments.into_iter().map(String::from_utf8).collect();
+                    let previous_protocol = protocol;
+                    let command = match arguments {
+                        Ok(arguments) => {
+                            match arguments[0].to_uppercase().as_str() {
+                                // Credentials are never logged.
+                                "AUTH" | "HELLO" => debug!("Reading command {:?} from client {}.", &arguments[0], identifier),
+                                _ => debug!("Reading command {:?} from client {}.", &arguments, identifier),
+                            };
+                            command(&arguments, identifier, &mut protocol)
+                        },
+                        Err(_) => Command::Reply(Value::Error(String::from("ERR arguments must be valid UTF-8"))),
+                    };
+                    // Authenticate the client, or reject its command if it isn't authenticated.
+                    // The protocol version is only switched once the client is authenticated.
+                    let command = match command {
+                        Command::Authenticate(name, password, reply) => {
+                            debug!("Authenticating client {} as {:?}.", identifier, &name);
+                            match session.authenticate(&name, &password) {
+                                true => Command::Reply(reply),
+                                false => {
+                                    protocol = previous_protocol;
+                                    Command::Reply(Value::Error(String::from("WRONGPASS invalid username-password pair")))
+                                },
+                            }
+                        },
+                        Command::Quit => Command::Quit,
+                        _ if !session.is_authenticated() => {
+                            protocol = previous_protocol;
+                            Command::Reply(Value::Error(String::from("NOAUTH Authentication required.")))
+                        },
+                        command => command,
+                    };
+                    match command {
+                        Command::Request(arguments) => {
+                            sequence += 1;
+                            let request_identifier = sequence.to_string();
+                            match builder.build(&identifier, &request_identifier, &arguments) {
+                                Ok(request) => {
+                                    debug!("Sending {:?} to the database.", &request);
+                                    if producer.send(request).is_err() {
+                                        panic!("Database channel disconnected.");
+                                    };
+                                    replies.push_back(Reply::Waiting(request_identifier, protocol));
+                                },
+                                Err(_) => {
+                                    debug!("Invalid request {:?} from client {}.", &arguments, identifier);
+                                    replies.push_back(Reply::Ready(encode(&Value::Error(String::from("ERR invalid arguments")), protocol)));
+                                },
+                            };
+                        },
+                        Command::Reply(value) | Command::Authenticate(_, _, value) => replies.push_back(Reply::Ready(encode(&value, protocol))),
+                        Command::Quit => {
+                            replies.push_back(Reply::Ready(encode(&Value::Simple(String::from("OK")), protocol)));
+                            closing = true;
+                        },
+                    };
+                };
+
+                // Read more data from the stream of the connected client.
+                if !delayed && !closing {
+                    let mut buffer = [0; 16384];
+                    match stream.read(&mut buffer) {
+                        Ok(0) => {
+                            debug!("EOF reached for client {}.", identifier);
+                            return;
+                        },
+                        Ok(length) => input.extend_from_slice(&buffer[..length]),
+                        Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+                        Err(ref error) if error.kind() == ErrorKind::WouldBlock => {},
+                        Err(error) => {
+                            debug!("Closing client {} (unable to read: {:?}).", identifier, error);
+                            return;
+                        },
+                    };
+                };
+
+                // Pull all instruction confirmations, replacing their waiting replies.
+                loop {
+                    match consumer.recv_timeout(Duration::from_micros(100)) {
+                        Ok(response) => {
+                            debug!("Sending {:?} to client {}.", &response, identifier);
+                            let request_identifier = response.get_request().get_identifier();
+                            for reply in replies.iter_mut() {
+                                if let Reply::Waiting(waiting_identifier, reply_protocol) = reply {
+                                    if waiting_identifier == request_identifier {
+                                        *reply = Reply::Ready(encode(&format(&response), *reply_protocol));
+                                        break;
+                                    };
+                                };
+                            };
+                        },
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(_) => panic!("Database channel disconnected."),
+                    }
+                }
+
+                // Write all ready replies, keeping the order of commands.
+                while let Some(Reply::Ready(_)) = replies.front() {
+                    if let Some(Reply::Ready(reply)) = replies.pop_front() {
+                        output.extend(reply);
+                    };
+                };
+                if !output.is_empty() {
+                    match stream.write(&output) {
+                        Ok(length) => {
+                            output.drain(..length);
+                        },
+                        Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+                        Err(ref error) if error.kind() == ErrorKind::WouldBlock => {},
+                        Err(error) => {
+                            debug!("Closing client {} (unable to write: {:?}).", identifier, error);
+                            return;
+                        },
+                    };
+                };
+
+                if closing && replies.is_empty() && output.is_empty() {
+                    debug!("Closing client {}.", identifier);
+                    return;
+                };
+            }
+        });
+    }
+}
+
+/// Encode the given value with the given protocol version.
+fn encode(value: &Value, protocol: Protocol) -> Vec<u8> {
+    let mut output = Vec::new();
+    value.encode(protocol, &mut output);
+
+    output
+}
+
+/// Decide the action to take for the command with the given arguments, received by the client
+/// with the given identifier. The protocol version is updated by the `HELLO` command.
+fn command(arguments: &[String], identifier: ClientIdentifier, protocol: &mut Protocol) -> Command {
+    let name = arguments[0].to_uppercase();
+    let parameters = &arguments[1..];
+    let request = |prefix: &[&str]| {
+        let mut arguments: Vec<String> = prefix.iter().map(|argument| argument.to_string()).collect();
+        arguments.extend(parameters.iter().cloned());
+
+        Command::Request(arguments)
+    };
+    let arity = || Command::Reply(Value::Error(format!("ERR wrong number of arguments for '{}' command", arguments[0])));
+    let ok = || Command::Reply(Value::Simple(String::from("OK")));
+
+    match name.as_str() {
+        "KAIROI.SET" => request(&["SET"]),
+        "KAIROI.GET" => request(&["GET"]),
+        "KAIROI.UNSET" => request(&["UNSET"]),
+        "KAIROI.RULESET" => request(&["RULE", "SET"]),
+        "KAIROI.RULELIST" => request(&["RULE", "LIST"]),
+        "KAIROI.COMPACT" => request(&["COMPACT"]),
+        "KAIROI.COMPACTSTATUS" => request(&["COMPACT", "STATUS"]),
+        "KAIROI.BACKUP" => request(&["BACKUP"]),
+        "KAIROI.EXPORT" => request(&["EXPORT"]),
+        "KAIROI.IMPORT" => request(&["IMPORT"]),
+        "KAIROI.PROMOTE" => request(&["PROMOTE"]),
+        "PING" => match parameters {
+            [] => Command::Reply(Value::Simple(String::from("PONG"))),
+            [message] => Command::Reply(Value::Bulk(message.clone())),
+            _ => arity(),
+        },
+        "ECHO" => match parameters {
+            [message] => Command::Reply(Value::Bulk(message.clone())),
+            _ => arity(),
+        },
+        "HELLO" => hello(parameters, identifier, protocol),
+        "AUTH" => match parameters {
+            [password] => Command::Authenticate(String::from("default"), password.clone(), Value::Simple(String::from("OK"))),
+            [name, password] => Command::Authenticate(name.clone(), password.clone(), Value::Simple(String::from("OK"))),
+            _ => arity(),
+        },
+        "SELECT" => match parameters {
+            [index] if index == "0" => ok(),
+            [_] => Command::Reply(Value::Error(String::from("ERR DB index is out of range"))),
+            _ => arity(),
+        },
+        "CLIENT" => match parameters.first().map(|subcommand| subcommand.to_uppercase()).as_deref() {
+            Some("SETNAME") | Some("SETINFO") => ok(),
+            Some("ID") => Command::Reply(Value::Integer(identifier as i64)),
+            _ => Command::Reply(Value::Error(String::from("ERR unknown subcommand"))),
+        },
+        "COMMAND" => Command::Reply(Value::Array(Vec::new())),
+        "QUIT" => Command::Quit,
+        _ => Command::Reply(Value::Error(format!("ERR unknown command '{}'", arguments[0]))),
+    }
+}
+
+/// Handle the `HELLO [protover [AUTH username password] [SETNAME clientname]]` command, switching
+/// to the requested protocol version. Return the action to take, authenticating the client when
+/// credentials are given.
+fn hello(parameters: &[String], identifier: ClientIdentifier, protocol: &mut Protocol) -> Command {
+    let mut parameters = parameters.iter();
+    let mut credentials = None;
+
+    if let Some(version) = parameters.next() {
+        let version = match version.as_str() {
+            "2" => Protocol::Resp2,
+            "3" => Protocol::Resp3,
+            _ => return Command::Reply(Value::Error(String::from("NOPROTO unsupported protocol version"))),
+        };
+        while let Some(option) = parameters.next() {
+            match (option.to_uppercase().as_str(), parameters.next()) {
+                ("AUTH", Some(name)) => match parameters.next() {
+                    Some(password) => credentials = Some((name.clone(), password.clone())),
+                    None => return Command::Reply(Value::Error(String::from("ERR syntax error"))),
+                },
+                ("SETNAME", Some(_)) => {},
+                _ => return Command::Reply(Value::Error(String::from("ERR syntax error"))),
+            };
+        };
+        *protocol = version;
+    };
+
+    let string = |string: &str| Value::Bulk(String::from(string));
+    let reply = Value::Map(vec![
+        (string("server"), string("kairoi")),
+        (string("version"), string(env!("CARGO_PKG_VERSION"))),
+        (string("proto"), Value::Integer(match protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        })),
+        (string("id"), Value::Integer(identifier as i64)),
+        (string("mode"), string("standalone")),
+        (string("role"), string("master")),
+        (string("modules"), Value::Array(Vec::new())),
+    ]);
+
+    match credentials {
+        Some((name, password)) => Command::Authenticate(name, password, reply),
+        None => Command::Reply(reply),
+    }
+}
+
+/// Format the given response as a value. Unknown jobs are returned as null values, like unknown
+/// keys in Redis, while other errors are returned as error values.
+fn format(response: &Response) -> Value {
+    match response.get_result() {
+        Ok(Output::None) => Value::Simple(String::from("OK")),
+        Ok(Output::Job(job)) => {
+            let string = |string: &str| Value::Bulk(String::from(string));
+            Value::Map(vec![
+                (string("identifier"), string(&job.identifier)),
+                (string("execution"), Value::Bulk(job.execution.format("%F %T").to_string())),
+                (string("status"), string(match job.status {
+                    JobStatus::Planned => "planned",
+                    JobStatus::Triggered => "triggered",
+                    JobStatus::Executed => "executed",
+                    JobStatus::Failed => "failed",
+                })),
+            ])
+        },
+        Ok(Output::Rules(rules)) => Value::Array(rules.iter().map(|rule| {
+            let mut values = vec![Value::Bulk(rule.identifier.clone()), Value::Bulk(rule.pattern.clone())];
+            match &rule.runner {
+                Runner::Shell { command } => {
+                    values.push(Value::Bulk(String::from("shell")));
+                    values.push(Value::Bulk(command.clone()));
+                },
+                Runner::Amqp { dsn, exchange, routing_key } => {
+                    values.push(Value::Bulk(String::from("amqp")));
+                    values.push(Value::Bulk(dsn.clone()));
+                    values.push(Value::Bulk(exchange.clone()));
+                    values.push(Value::Bulk(routing_key.clone()));
+                },
+            };
+
+            Value::Array(values)
+        }).collect()),
+        Ok(Output::Compaction(compaction)) => {
+            let string = |string: &str| Value::Bulk(String::from(string));
+            let running = match &compaction.running {
+                Some(progress) => Value::Map(vec![
+                    (string("started"), Value::Bulk(progress.started.format("%F %T").to_string())),
+                    (string("processed_bytes"), Value::Integer(progress.processed_bytes as i64)),
+                    (string("total_bytes"), Value::Integer(progress.total_bytes as i64)),
+                ]),
+                None => Value::Null,
+            };
+            let last = match &compaction.last {
+                Some(result) => Value::Map(vec![
+                    (string("success"), Value::Integer(result.success as i64)),
+                    (string("finished"), Value::Bulk(result.finished.format("%F %T").to_string())),
+                    (string("entries"), Value::Integer(result.entries as i64)),
+                ]),
+                None => Value::Null,
+            };
+
+            Value::Map(vec![(string("running"), running), (string("last"), last)])
+        },
+        Ok(Output::Transfer(transfer)) => {
+            let string = |string: &str| Value::Bulk(String::from(string));
+
+            Value::Map(vec![
+                (string("jobs"), Value::Integer(transfer.jobs as i64)),
+                (string("rules"), Value::Integer(transfer.rules as i64)),
+                (string("skipped"), Value::Integer(transfer.skipped as i64)),
+            ])
+        },
+        Err(QueryError::NotFound) => match response.get_request().get_instruction() {
+            Instruction::Get { .. } => Value::Null,
+            _ => Value::Error(String::from("NOT_FOUND no such job")),
+        },
+        Err(QueryError::Conflict) => match response.get_request().get_instruction() {
+            Instruction::Compact | Instruction::Backup { .. } => Value::Error(String::from("CONFLICT a compaction is already running")),
+            Instruction::Import { .. } => Value::Error(String::from("CONFLICT an imported item already exists")),
+            Instruction::Promote => Value::Error(String::from("CONFLICT the replica isn't synchronized with its primary")),
+            _ => Value::Error(String::from("CONFLICT the job is being executed")),
+        },
+        // The same error as Redis replicas, so clients can recognize it.
+        Err(QueryError::ReadOnly) => Value::Error(String::from("READONLY You can't write against a read only replica.")),
+        Err(QueryError::NotLeader(Some(leader))) => Value::Error(format!("NOTLEADER {}", leader)),
+        Err(QueryError::NotLeader(None)) => Value::Error(String::from("NOTLEADER no leader is elected")),
+        Err(QueryError::Failure) => Value::Error(String::from("ERR unable to handle the request")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(arguments: &[&str]) -> Vec<String> {
+        arguments.iter().map(|argument| argument.to_string()).collect()
+    }
+
+    #[test]
+    fn test_command() {
+        let mut protocol = Protocol::Resp2;
+        assert_eq!(
+            command(&arguments(&["kairoi.set", "app.job.1", "2020-06-17 21:47:16"]), 0, &mut protocol),
+            Command::Request(arguments(&["SET", "app.job.1", "2020-06-17 21:47:16"])),
+        );
+        assert_eq!(
+            command(&arguments(&["KAIROI.RULESET", "app.rule", "app.", "shell", "script.sh"]), 0, &mut protocol),
+            Command::Request(arguments(&["RULE", "SET", "app.rule", "app.", "shell", "script.sh"])),
+        );
+        assert_eq!(command(&arguments(&["KAIROI.RULELIST"]), 0, &mut protocol), Command::Request(arguments(&["RULE", "LIST"])));
+        assert_eq!(command(&arguments(&["kairoi.compactstatus"]), 0, &mut protocol), Command::Request(arguments(&["COMPACT", "STATUS"])));
+        assert_eq!(command(&arguments(&["KAIROI.BACKUP", "kairoi.backup"]), 0, &mut protocol), Command::Request(arguments(&["BACKUP", "kairoi.backup"])));
+        assert_eq!(command(&arguments(&["kairoi.promote"]), 0, &mut protocol), Command::Request(arguments(&["PROMOTE"])));
+        assert_eq!(command(&arguments(&["PING"]), 0, &mut protocol), Command::Reply(Value::Simple(String::from("PONG"))));
+        assert_eq!(command(&arguments(&["QUIT"]), 0, &mut protocol), Command::Quit);
+        assert!(matches!(command(&arguments(&["SET", "key", "value"]), 0, &mut protocol), Command::Reply(Value::Error(_))));
+        // Test the protocol negotiation.
+        assert!(matches!(command(&arguments(&["HELLO", "4"]), 0, &mut protocol), Command::Reply(Value::Error(_))));
+        assert_eq!(protocol, Protocol::Resp2);
+        assert!(matches!(command(&arguments(&["HELLO", "3", "SETNAME", "app"]), 7, &mut protocol), Command::Reply(Value::Map(_))));
+        assert_eq!(protocol, Protocol::Resp3);
+        // Test the authentication.
+        assert_eq!(
+            command(&arguments(&["AUTH", "user", "password"]), 0, &mut protocol),
+            Command::Authenticate(String::from("user"), String::from("password"), Value::Simple(String::from("OK"))),
+        );
+        assert_eq!(
+            command(&arguments(&["auth", "password"]), 0, &mut protocol),
+            Command::Authenticate(String::from("default"), String::from("password"), Value::Simple(String::from("OK"))),
+        );
+        assert!(matches!(command(&arguments(&["AUTH"]), 0, &mut protocol), Command::Reply(Value::Error(_))));
+        assert!(matches!(
+            command(&arguments(&["HELLO", "2", "AUTH", "user", "password", "SETNAME", "app"]), 0, &mut protocol),
+            Command::Authenticate(name, password, Value::Map(_)) if name == "user" && password == "password"
+        ));
+        assert_eq!(protocol, Protocol::Resp2);
+        assert!(matches!(command(&arguments(&["HELLO", "3", "AUTH", "user"]), 0, &mut protocol), Command::Reply(Value::Error(_))));
+        assert_eq!(protocol, Protocol::Resp2);
+    }
+}
@@ -0,0 +1,113 @@
+/// The maximum number of arguments of a single command.
+const MAXIMUM_ARGUMENTS: i64 = 1024;
+/// The maximum size of a single argument, in bytes.
+const MAXIMUM_ARGUMENT_SIZE: i64 = 1024 * 1024;
+/// The maximum size of a single line (inline commands and headers), in bytes.
+const MAXIMUM_LINE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Incomplete,
+    Error,
+}
+
+/// Parse a single command from the given input, either formatted as an array of bulk strings, or
+/// as an inline command (space separated arguments on a single line). Return the number of bytes
+/// consumed, and the arguments of the command (possibly none, for empty commands).
+pub fn parse(input: &[u8]) -> Result<(usize, Vec<Vec<u8>>), Error> {
+    match input.first() {
+        None => Err(Error::Incomplete),
+        Some(b'*') => {
+            let (header, mut position) = line(input, 0)?;
+            let count = integer(&header[1..])?;
+            if count > MAXIMUM_ARGUMENTS {
+                return Err(Error::Error);
+            };
+
+            let mut arguments = Vec::new();
+            for _ in 0..count.max(0) {
+                let (header, next) = line(input, position)?;
+                if header.first() != Some(&b'$') {
+                    return Err(Error::Error);
+                };
+                let size = integer(&header[1..])?;
+                if !(0..=MAXIMUM_ARGUMENT_SIZE).contains(&size) {
+                    return Err(Error::Error);
+                };
+                let end = next + size as usize;
+                match input.get(end..end + 2) {
+                    Some(b"\r\n") => {},
+                    Some(_) => return Err(Error::Error),
+                    None => return Err(Error::Incomplete),
+                };
+                arguments.push(input[next..end].to_vec());
+                position = end + 2;
+            };
+
+            Ok((position, arguments))
+        },
+        Some(_) => {
+            let end = match input.iter().position(|byte| *byte == b'\n') {
+                Some(end) => end,
+                None if input.len() > MAXIMUM_LINE_SIZE => return Err(Error::Error),
+                None => return Err(Error::Incomplete),
+            };
+            let arguments = input[..end]
+                .split(|byte| byte.is_ascii_whitespace())
+                .filter(|argument| !argument.is_empty())
+                .map(|argument| argument.to_vec())
+                .collect()
+            ;
+
+            Ok((end + 1, arguments))
+        },
+    }
+}
+
+/// Read the line starting at the given position, terminated by CRLF. Return the line (without
+/// its terminator) and the position of the next line.
+fn line(input: &[u8], position: usize) -> Result<(&[u8], usize), Error> {
+    let rest = &input[position.min(input.len())..];
+    match rest.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => Ok((&rest[..end], position + end + 2)),
+        None if rest.len() > MAXIMUM_LINE_SIZE => Err(Error::Error),
+        None => Err(Error::Incomplete),
+    }
+}
+
+/// Parse the given bytes as a signed decimal integer.
+fn integer(input: &[u8]) -> Result<i64, Error> {
+    match std::str::from_utf8(input).ok().and_then(|input| input.parse().ok()) {
+        Some(integer) => Ok(integer),
+        None => Err(Error::Error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        // Test arrays of bulk strings.
+        assert_eq!(
+            parse(b"*2\r\n$10\r\nKAIROI.GET\r\n$6\r\nmy job\r\n*1\r\n"),
+            Ok((33, vec![b"KAIROI.GET".to_vec(), b"my job".to_vec()])),
+        );
+        assert_eq!(parse(b"*1\r\n$4\r\n\r\n\r\n\r\n"), Ok((14, vec![b"\r\n\r\n".to_vec()])));
+        assert_eq!(parse(b"*0\r\n"), Ok((4, vec![])));
+        assert_eq!(parse(b"*2\r\n$4\r\nPING\r\n"), Err(Error::Incomplete));
+        assert_eq!(parse(b"*1\r\n$4\r\nPI"), Err(Error::Incomplete));
+        assert_eq!(parse(b"*1\r\n$4"), Err(Error::Incomplete));
+        assert_eq!(parse(b"*1\r\n:4\r\n"), Err(Error::Error));
+        assert_eq!(parse(b"*1\r\n$4\r\nPINGS\r\n"), Err(Error::Error));
+        assert_eq!(parse(b"*a\r\n"), Err(Error::Error));
+        assert_eq!(parse(b"*2000\r\n"), Err(Error::Error));
+        // Test inline commands.
+        assert_eq!(parse(b"PING\r\nPING"), Ok((6, vec![b"PING".to_vec()])));
+        assert_eq!(parse(b"  KAIROI.GET  job\n"), Ok((18, vec![b"KAIROI.GET".to_vec(), b"job".to_vec()])));
+        assert_eq!(parse(b"\r\n"), Ok((2, vec![])));
+        assert_eq!(parse(b"PING"), Err(Error::Incomplete));
+        assert_eq!(parse(b""), Err(Error::Incomplete));
+    }
+}
@@ -0,0 +1,82 @@
+/// The version of the protocol used by a connection, negotiated with the `HELLO` command.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+/// A value sent to clients.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    /// Encode this value with the given protocol version, appending it to the given output. With
+    /// RESP2, maps are encoded as flat arrays of keys and values.
+    pub fn encode(&self, protocol: Protocol, output: &mut Vec<u8>) {
+        match self {
+            Value::Simple(string) => output.extend(format!("+{}\r\n", string).as_bytes()),
+            Value::Error(string) => output.extend(format!("-{}\r\n", string).as_bytes()),
+            Value::Integer(integer) => output.extend(format!(":{}\r\n", integer).as_bytes()),
+            Value::Bulk(string) => {
+                output.extend(format!("${}\r\n", string.len()).as_bytes());
+                output.extend(string.as_bytes());
+                output.extend(b"\r\n");
+            },
+            Value::Null => match protocol {
+                Protocol::Resp2 => output.extend(b"$-1\r\n"),
+                Protocol::Resp3 => output.extend(b"_\r\n"),
+            },
+            Value::Array(values) => {
+                output.extend(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(protocol, output);
+                };
+            },
+            Value::Map(entries) => {
+                match protocol {
+                    Protocol::Resp2 => output.extend(format!("*{}\r\n", entries.len() * 2).as_bytes()),
+                    Protocol::Resp3 => output.extend(format!("%{}\r\n", entries.len()).as_bytes()),
+                };
+                for (key, value) in entries {
+                    key.encode(protocol, output);
+                    value.encode(protocol, output);
+                };
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: Value, protocol: Protocol) -> Vec<u8> {
+        let mut output = Vec::new();
+        value.encode(protocol, &mut output);
+
+        output
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(Value::Simple(String::from("OK")), Protocol::Resp2), b"+OK\r\n");
+        assert_eq!(encode(Value::Error(String::from("ERR unknown")), Protocol::Resp2), b"-ERR unknown\r\n");
+        assert_eq!(encode(Value::Integer(-3), Protocol::Resp2), b":-3\r\n");
+        assert_eq!(encode(Value::Bulk(String::from("\u{1F631}")), Protocol::Resp2), b"$4\r\n\xF0\x9F\x98\xB1\r\n");
+        assert_eq!(encode(Value::Null, Protocol::Resp2), b"$-1\r\n");
+        assert_eq!(encode(Value::Null, Protocol::Resp3), b"_\r\n");
+        let array = Value::Array(vec![Value::Bulk(String::from("a")), Value::Array(vec![])]);
+        assert_eq!(encode(array, Protocol::Resp3), b"*2\r\n$1\r\na\r\n*0\r\n");
+        let map = Value::Map(vec![(Value::Bulk(String::from("a")), Value::Integer(1))]);
+        assert_eq!(encode(map.clone(), Protocol::Resp2), b"*2\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(encode(map, Protocol::Resp3), b"%1\r\n$1\r\na\r\n:1\r\n");
+    }
+}
@@ -0,0 +1,527 @@
+//! Routing of requests between the shards of a sharded database.
+//!
+//! The router runs in its own thread, between the clients and the query links of all shards.
+//! Requests about a job are sent to the shard owning the job, so all requests about a job are
+//! handled in order by the same shard. Listing rules only requires a single shard, since all
+//! shards contain all rules. Other instructions are broadcast to all shards: rules are written to
+//! all shards, and compactions, backups, exports and imports concern the storages of all shards.
+//! The responses of all shards are merged into a single response, failing as soon as a shard
+//! fails. Backups and exports of each shard are written to their own file, suffixed by the index
+//! of the shard (like `export.ndjson.0`).
+//!
+//! Setting a rule must not leave shards with different rules. Before broadcasting it, the router
+//! reads the current definition of the rule from the first shard. When the rule is written to some
+//! shards only, the client gets the failure, and the previous definition is written back to the
+//! shards which succeeded. Rules can't be removed, so a new rule written to some shards only is
+//! kept, and an error is logged.
+//!
+//! Shards queue requests by client, and hold the requests of a client while its export or import
+//! runs in background. Requests sent by the router itself (broadcasts, reads of the current rules
+//! and restorations of rules) are thus each sent as their own client, so they neither wait for
+//! each other, nor share the frame quota of a single client.
+
+use crate::query::{Client, Error, Request, Response};
+use crate::query::instruction::Instruction;
+use crate::query::output::{Compaction, CompactionProgress, CompactionResult, Output, Rule, Transfer};
+use crate::shard::Shard;
+use crate::sync;
+use crossbeam_channel::{Receiver, Select, Sender, TryRecvError};
+use log::error;
+use std::collections::HashMap;
+use std::thread;
+
+/// A request broadcast to all shards, waiting for their responses.
+struct Broadcast {
+    request: Request,
+    results: Vec<(usize, Result<Output, Error>)>,
+    /// The definition of the rule before it's set, to restore on partial failures.
+    previous: Option<Rule>,
+}
+
+pub struct Router {
+    link: (Sender<Response>, Receiver<Request>),
+    shards: Vec<(Sender<Request>, Receiver<Response>)>,
+    /// The broadcast requests, indexed by the client they're sent as.
+    broadcasts: HashMap<Client, Broadcast>,
+    /// Rule Set requests, waiting for the current definition of their rule before being broadcast,
+    /// indexed by the client the rules are read as.
+    preparations: HashMap<Client, Request>,
+    /// The number of shards yet to restore a rule, indexed by the client the rule is restored as.
+    /// Their responses are never sent to any client.
+    rollbacks: HashMap<Client, usize>,
+    /// The next client to send a request of the router as. Client identifiers are given in
+    /// sequence from 0 to real clients, so the router takes them in sequence from the maximum.
+    sequence: Client,
+}
+
+impl Router {
+    /// Start routing requests between the query links of the given shards, spawning a thread, and
+    /// return the query link of the whole database. The query link of a single shard is returned
+    /// as is, without any routing.
+    pub fn start(mut shards: Vec<(Sender<Request>, Receiver<Response>)>) -> (Sender<Request>, Receiver<Response>) {
+        if shards.len() == 1 {
+            return shards.remove(0);
+        };
+
+        let (owning_side, reverse_side) = sync::link();
+        thread::Builder::new().name("kairoi/router".to_string()).spawn(move || {
+            let mut router = Router {
+                link: reverse_side,
+                shards,
+                broadcasts: HashMap::new(),
+                preparations: HashMap::new(),
+                rollbacks: HashMap::new(),
+                sequence: Client::MAX,
+            };
+
+            loop {
+                router.wait();
+                router.route_requests();
+                router.route_responses();
+            };
+        }).unwrap();
+
+        owning_side
+    }
+
+    /// Wait until a request or a response of a shard is received.
+    fn wait(&self) {
+        let mut select = Select::new();
+        select.recv(&self.link.1);
+        for shard in &self.shards {
+            select.recv(&shard.1);
+        };
+
+        select.ready();
+    }
+
+    /// Send all received requests to their shards.
+    fn route_requests(&mut self) {
+        loop {
+            let request = match self.link.1.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => panic!("Query channel disconnected."),
+            };
+
+            match request.get_instruction() {
+                Instruction::Set { identifier, .. } | Instruction::Get { identifier } | Instruction::Unset { identifier } => {
+                    let shard = Shard::locate(identifier, self.shards.len());
+                    self.send(shard, request);
+                },
+                Instruction::RuleList => self.send(0, request),
+                Instruction::RuleSet { .. } => self.prepare(request),
+                _ => self.broadcast(request, None),
+            };
+        };
+    }
+
+    /// Get a new client to send a request of the router as.
+    fn next_client(&mut self) -> Client {
+        let client = self.sequence;
+        self.sequence -= 1;
+
+        client
+    }
+
+    /// Read the current rules from the first shard, before broadcasting the given Rule Set request
+    /// (see [`Router::prepared`]).
+    fn prepare(&mut self, request: Request) {
+        let client = self.next_client();
+        self.send(0, Request::new(client, request.get_identifier().clone(), Instruction::RuleList));
+        self.preparations.insert(client, request);
+    }
+
+    /// Broadcast the Rule Set request waiting for the given response of the first shard, along
+    /// with the current definition of its rule. The response to the original request is returned
+    /// right away when the rules can't be read.
+    fn prepared(&mut self, response: Response) -> Option<Response> {
+        let request = self.preparations.remove(&response.get_request().get_client())?;
+        let rules = match response.get_result() {
+            Ok(Output::Rules(rules)) => rules,
+            Ok(_) => return Some(Response::new(request, Err(Error::Failure))),
+            Err(error) => return Some(Response::new(request, Err(error.clone()))),
+        };
+        let previous = match request.get_instruction() {
+            Instruction::RuleSet { identifier, .. } => rules.iter().find(|rule| &rule.identifier == identifier).cloned(),
+            _ => None,
+        };
+        self.broadcast(request, previous);
+
+        None
+    }
+
+    /// Send the given request to all shards, as a new client so their responses can be merged.
+    /// Paths of backups and exports are suffixed by the index of each shard.
+    fn broadcast(&mut self, request: Request, previous: Option<Rule>) {
+        let client = self.next_client();
+
+        for shard in 0..self.shards.len() {
+            let instruction = match request.get_instruction() {
+                Instruction::Backup { path } => Instruction::Backup {
+                    path: format!("{}.{}", path, shard),
+                },
+                Instruction::Export { path } => Instruction::Export {
+                    path: format!("{}.{}", path, shard),
+                },
+                instruction => instruction.clone(),
+            };
+            self.send(shard, Request::new(client, request.get_identifier().clone(), instruction));
+        };
+        self.broadcasts.insert(client, Broadcast {
+            request,
+            results: Vec::with_capacity(self.shards.len()),
+            previous,
+        });
+    }
+
+    /// Send the given request to the shard with the given index.
+    fn send(&self, shard: usize, request: Request) {
+        if self.shards[shard].0.send(request).is_err() {
+            panic!("Query channel disconnected.");
+        };
+    }
+
+    /// Forward all received responses to the clients, once the responses of all shards to
+    /// broadcast requests are received.
+    fn route_responses(&mut self) {
+        let mut responses = Vec::new();
+        for (index, shard) in self.shards.iter().enumerate() {
+            loop {
+                match shard.1.try_recv() {
+                    Ok(response) => responses.push((index, response)),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => panic!("Query channel disconnected."),
+                };
+            };
+        };
+
+        for (shard, response) in responses {
+            let client = response.get_request().get_client();
+            let response = if self.preparations.contains_key(&client) {
+                match self.prepared(response) {
+                    Some(response) => response,
+                    None => continue,
+                }
+            } else if self.broadcasts.contains_key(&client) {
+                match self.gather(shard, response) {
+                    Some(response) => response,
+                    None => continue,
+                }
+            } else if self.rollbacks.contains_key(&client) {
+                self.restored(shard, response);
+
+                continue;
+            } else {
+                response
+            };
+            if self.link.0.send(response).is_err() {
+                panic!("Query channel disconnected.");
+            };
+        };
+    }
+
+    /// Gather the given response of the shard with the given index to a broadcast request,
+    /// returning the merged response to the original request once all shards responded.
+    fn gather(&mut self, shard: usize, response: Response) -> Option<Response> {
+        let client = response.get_request().get_client();
+        let broadcast = self.broadcasts.get_mut(&client)?;
+        broadcast.results.push((shard, response.get_result().clone()));
+        if broadcast.results.len() < self.shards.len() {
+            return None;
+        };
+
+        let broadcast = self.broadcasts.remove(&client)?;
+        if let Instruction::RuleSet { .. } = broadcast.request.get_instruction() {
+            if broadcast.results.iter().any(|(_, result)| result.is_err()) {
+                self.rollback(&broadcast);
+            };
+        };
+        let result = merge(broadcast.results.into_iter().map(|(_, result)| result).collect());
+
+        Some(Response::new(broadcast.request, result))
+    }
+
+    /// Restore the previous definition of the rule of the given Rule Set broadcast on all shards
+    /// which succeeded to write it, so all shards keep the same rules.
+    fn rollback(&mut self, broadcast: &Broadcast) {
+        let succeeded: Vec<usize> = broadcast.results.iter().filter(|(_, result)| result.is_ok()).map(|(shard, _)| *shard).collect();
+        if succeeded.is_empty() {
+            return;
+        };
+
+        let rule = match (&broadcast.previous, broadcast.request.get_instruction()) {
+            (Some(rule), _) => rule,
+            (None, Instruction::RuleSet { identifier, .. }) => {
+                error!("Unable to restore the rule {:?} on the shards {:?} (it didn't exist before), rules differ between shards.", identifier, succeeded);
+
+                return;
+            },
+            (None, _) => return,
+        };
+        let client = self.next_client();
+        for shard in &succeeded {
+            self.send(*shard, Request::new(client, broadcast.request.get_identifier().clone(), Instruction::RuleSet {
+                identifier: rule.identifier.clone(),
+                pattern: rule.pattern.clone(),
+                runner: rule.runner.clone(),
+            }));
+        };
+        self.rollbacks.insert(client, succeeded.len());
+    }
+
+    /// Handle the given response of the shard with the given index to the restoration of a rule,
+    /// forgetting the restoration once all its shards responded.
+    fn restored(&mut self, shard: usize, response: Response) {
+        if let Err(error) = response.get_result() {
+            error!("Unable to restore the rule on the shard {} ({:?}), rules differ between shards.", shard, error);
+        };
+
+        let client = response.get_request().get_client();
+        if let Some(remaining) = self.rollbacks.get_mut(&client) {
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.rollbacks.remove(&client);
+            };
+        };
+    }
+}
+
+/// Merge the results of all shards to a broadcast request. The first failure of a shard is
+/// returned, if any.
+fn merge(results: Vec<Result<Output, Error>>) -> Result<Output, Error> {
+    let mut merged = Output::None;
+    for result in results {
+        merged = match (merged, result?) {
+            (Output::Compaction(first), Output::Compaction(second)) => Output::Compaction(merge_compactions(first, second)),
+            (Output::Transfer(first), Output::Transfer(second)) => Output::Transfer(Transfer {
+                jobs: first.jobs + second.jobs,
+                rules: first.rules + second.rules,
+                skipped: first.skipped + second.skipped,
+            }),
+            (_, output) => output,
+        };
+    };
+
+    Ok(merged)
+}
+
+/// Merge the compaction states of two shards: a compaction is running as long as it's running on
+/// a shard, and the last compaction only succeeded if it succeeded on both shards.
+fn merge_compactions(first: Compaction, second: Compaction) -> Compaction {
+    let running = match (first.running, second.running) {
+        (Some(first), Some(second)) => Some(CompactionProgress {
+            started: first.started.min(second.started),
+            processed_bytes: first.processed_bytes + second.processed_bytes,
+            total_bytes: first.total_bytes + second.total_bytes,
+        }),
+        (first, second) => first.or(second),
+    };
+    let last = match (first.last, second.last) {
+        (Some(first), Some(second)) => Some(CompactionResult {
+            success: first.success && second.success,
+            finished: first.finished.max(second.finished),
+            entries: first.entries + second.entries,
+        }),
+        (first, second) => first.or(second),
+    };
+
+    Compaction {
+        running,
+        last,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::offset::{TimeZone, Utc};
+    use crate::execution::runner::Runner;
+
+    /// The query link of a shard: the side of the router, and the side of the shard.
+    type Link = ((Sender<Request>, Receiver<Response>), (Sender<Response>, Receiver<Request>));
+
+    fn link() -> Link {
+        sync::link()
+    }
+
+    fn rule(pattern: &str) -> Instruction {
+        Instruction::RuleSet {
+            identifier: String::from("rule"),
+            pattern: String::from(pattern),
+            runner: Runner::Shell { command: String::from("true") },
+        }
+    }
+
+    #[test]
+    fn test_routing() {
+        let (first, first_shard) = link();
+        let (second, second_shard) = link();
+        let (producer, consumer) = Router::start(vec![first, second]);
+
+        // Requests about a job are sent to its shard.
+        let identifier = String::from("app.job.1");
+        let shard = &[&first_shard, &second_shard][Shard::locate(&identifier, 2)];
+        producer.send(Request::new(1, String::from("r1"), Instruction::Get { identifier: identifier.clone() })).unwrap();
+        let request = shard.1.recv().unwrap();
+        assert_eq!(request.get_identifier(), "r1");
+        shard.0.send(Response::new(request, Err(Error::NotFound))).unwrap();
+        let response = consumer.recv().unwrap();
+        assert_eq!(response.get_request().get_client(), 1);
+        assert_eq!(response.get_result(), &Err(Error::NotFound));
+
+        // Rules are written to all shards once their current definition is read, and the response is
+        // only sent once all shards responded.
+        producer.send(Request::new(2, String::from("r2"), rule("app."))).unwrap();
+        let list_request = first_shard.1.recv().unwrap();
+        assert!(matches!(list_request.get_instruction(), Instruction::RuleList));
+        let list_client = list_request.get_client();
+        first_shard.0.send(Response::new(list_request, Ok(Output::Rules(vec![])))).unwrap();
+        let first_request = first_shard.1.recv().unwrap();
+        let second_request = second_shard.1.recv().unwrap();
+        assert_ne!(first_request.get_client(), 2);
+        assert_ne!(first_request.get_client(), list_client);
+        assert_eq!(first_request.get_client(), second_request.get_client());
+        first_shard.0.send(Response::new(first_request, Ok(Output::None))).unwrap();
+        assert!(consumer.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+        second_shard.0.send(Response::new(second_request, Ok(Output::None))).unwrap();
+        let response = consumer.recv().unwrap();
+        assert_eq!(response.get_request().get_client(), 2);
+        assert_eq!(response.get_request().get_identifier(), "r2");
+        assert_eq!(response.get_result(), &Ok(Output::None));
+
+        // Exports of each shard are written to their own file.
+        producer.send(Request::new(3, String::from("r3"), Instruction::Export { path: String::from("export") })).unwrap();
+        match first_shard.1.recv().unwrap().get_instruction() {
+            Instruction::Export { path } => assert_eq!(path, "export.0"),
+            _ => panic!("The export hasn't been broadcast."),
+        };
+        match second_shard.1.recv().unwrap().get_instruction() {
+            Instruction::Export { path } => assert_eq!(path, "export.1"),
+            _ => panic!("The export hasn't been broadcast."),
+        };
+    }
+
+    #[test]
+    fn test_partial_rule_set() {
+        let (first, first_shard) = link();
+        let (second, second_shard) = link();
+        let (producer, consumer) = Router::start(vec![first, second]);
+        let previous = Rule {
+            identifier: String::from("rule"),
+            pattern: String::from("app."),
+            runner: Runner::Shell { command: String::from("true") },
+        };
+
+        // When a shard fails to write the rule, the client gets the failure...
+        producer.send(Request::new(1, String::from("r1"), rule("other."))).unwrap();
+        let list_request = first_shard.1.recv().unwrap();
+        first_shard.0.send(Response::new(list_request, Ok(Output::Rules(vec![previous])))).unwrap();
+        let first_request = first_shard.1.recv().unwrap();
+        let second_request = second_shard.1.recv().unwrap();
+        let broadcast_client = first_request.get_client();
+        first_shard.0.send(Response::new(first_request, Ok(Output::None))).unwrap();
+        second_shard.0.send(Response::new(second_request, Err(Error::Failure))).unwrap();
+        let response = consumer.recv().unwrap();
+        assert_eq!(response.get_request().get_client(), 1);
+        assert_eq!(response.get_result(), &Err(Error::Failure));
+
+        // ...and the previous definition is restored on the shards which succeeded only.
+        let rollback_request = first_shard.1.recv().unwrap();
+        assert_ne!(rollback_request.get_client(), broadcast_client);
+        match rollback_request.get_instruction() {
+            Instruction::RuleSet { pattern, .. } => assert_eq!(pattern, "app."),
+            _ => panic!("The rule hasn't been restored."),
+        };
+        assert!(second_shard.1.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+        first_shard.0.send(Response::new(rollback_request, Ok(Output::None))).unwrap();
+        assert!(consumer.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+
+        // When the rules can't be read, nothing is written.
+        producer.send(Request::new(2, String::from("r2"), rule("other."))).unwrap();
+        let list_request = first_shard.1.recv().unwrap();
+        first_shard.0.send(Response::new(list_request, Err(Error::Failure))).unwrap();
+        assert_eq!(consumer.recv().unwrap().get_result(), &Err(Error::Failure));
+        assert!(first_shard.1.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+        assert!(second_shard.1.recv_timeout(std::time::Duration::from_millis(50)).is_err());
+    }
+
+    #[test]
+    fn test_broadcast_clients() {
+        let (first, first_shard) = link();
+        let (second, second_shard) = link();
+        let (producer, consumer) = Router::start(vec![first, second]);
+
+        // An export running in background holds the requests of its client on each shard...
+        producer.send(Request::new(1, String::from("r1"), Instruction::Export { path: String::from("export") })).unwrap();
+        let first_export = first_shard.1.recv().unwrap();
+        let second_export = second_shard.1.recv().unwrap();
+
+        // ...so reading the rules before setting one, and compacting, are sent as other clients.
+        producer.send(Request::new(2, String::from("r2"), rule("app."))).unwrap();
+        let list_request = first_shard.1.recv().unwrap();
+        assert!(matches!(list_request.get_instruction(), Instruction::RuleList));
+        assert_ne!(list_request.get_client(), first_export.get_client());
+        producer.send(Request::new(3, String::from("r3"), Instruction::Compact)).unwrap();
+        let compact_request = first_shard.1.recv().unwrap();
+        assert!(matches!(compact_request.get_instruction(), Instruction::Compact));
+        assert_ne!(compact_request.get_client(), first_export.get_client());
+        assert_ne!(compact_request.get_client(), list_request.get_client());
+        let second_compact_request = second_shard.1.recv().unwrap();
+        assert_eq!(second_compact_request.get_client(), compact_request.get_client());
+
+        // Each response is gathered for its own broadcast, whatever the order of the responses.
+        first_shard.0.send(Response::new(list_request, Ok(Output::Rules(vec![])))).unwrap();
+        let first_rule_request = first_shard.1.recv().unwrap();
+        let second_rule_request = second_shard.1.recv().unwrap();
+        first_shard.0.send(Response::new(first_rule_request, Ok(Output::None))).unwrap();
+        second_shard.0.send(Response::new(second_rule_request, Ok(Output::None))).unwrap();
+        let response = consumer.recv().unwrap();
+        assert_eq!(response.get_request().get_client(), 2);
+        assert_eq!(response.get_result(), &Ok(Output::None));
+
+        let transfer = Output::Transfer(Transfer { jobs: 1, rules: 0, skipped: 0 });
+        first_shard.0.send(Response::new(first_export, Ok(transfer.clone()))).unwrap();
+        first_shard.0.send(Response::new(compact_request, Ok(Output::None))).unwrap();
+        second_shard.0.send(Response::new(second_compact_request, Ok(Output::None))).unwrap();
+        let response = consumer.recv().unwrap();
+        assert_eq!(response.get_request().get_client(), 3);
+        assert_eq!(response.get_result(), &Ok(Output::None));
+        second_shard.0.send(Response::new(second_export, Ok(transfer))).unwrap();
+        let response = consumer.recv().unwrap();
+        assert_eq!(response.get_request().get_client(), 1);
+        assert_eq!(response.get_result(), &Ok(Output::Transfer(Transfer { jobs: 2, rules: 0, skipped: 0 })));
+    }
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(merge(vec![Ok(Output::None), Ok(Output::None)]), Ok(Output::None));
+        assert_eq!(merge(vec![Ok(Output::None), Err(Error::Conflict), Err(Error::Failure)]), Err(Error::Conflict));
+        assert_eq!(
+            merge(vec![
+                Ok(Output::Transfer(Transfer { jobs: 2, rules: 1, skipped: 0 })),
+                Ok(Output::Transfer(Transfer { jobs: 3, rules: 0, skipped: 1 })),
+            ]),
+            Ok(Output::Transfer(Transfer { jobs: 5, rules: 1, skipped: 1 })),
+        );
+
+        let started = Utc.ymd(2020, 6, 17).and_hms(21, 47, 16);
+        let finished = Utc.ymd(2020, 6, 17).and_hms(21, 48, 16);
+        assert_eq!(
+            merge(vec![
+                Ok(Output::Compaction(Compaction {
+                    running: Some(CompactionProgress { started, processed_bytes: 10, total_bytes: 100 }),
+                    last: Some(CompactionResult { success: true, finished, entries: 5 }),
+                })),
+                Ok(Output::Compaction(Compaction {
+                    running: None,
+                    last: Some(CompactionResult { success: false, finished: started, entries: 3 }),
+                })),
+            ]),
+            Ok(Output::Compaction(Compaction {
+                running: Some(CompactionProgress { started, processed_bytes: 10, total_bytes: 100 }),
+                last: Some(CompactionResult { success: false, finished, entries: 8 }),
+            })),
+        );
+    }
+}
@@ -7,7 +7,7 @@
 
 pub mod protocol;
 
-use crossbeam_channel::{Receiver as CrossbeamReceiver, Sender as CrossbeamSender, TryRecvError};
+use crossbeam_channel::{Receiver as CrossbeamReceiver, Select, Sender as CrossbeamSender, TryRecvError};
 use self::protocol::{Request as ProtocolRequest, Response as ProtocolResponse, Runner as ProtocolRunner};
 use std::collections::HashMap;
 use std::result::Result as StdResult;
@@ -47,11 +47,16 @@ impl Client {
 
         self.triggered.insert(identifier, request.clone());
 
-        if let Err(_) = self.execution_link.0.send(request) {
+        if self.execution_link.0.send(request).is_err() {
             panic!("Execution channel disconnected.");
         };
     }
 
+    /// Add the execution link to the given selection, so the database can wait for results.
+    pub fn watch<'a>(&'a self, select: &mut Select<'a>) {
+        select.recv(&self.execution_link.1);
+    }
+
     /// Pull all execution results received through the execution link.
     pub fn pull_results(&mut self) -> Vec<Result> {
         let mut responses = Vec::new();
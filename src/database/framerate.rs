@@ -1,53 +1,72 @@
 //! Framerate limitation for the database.
 //!
-//! This module provide a [`Clock`] structure, that can be used to trigger callbacks, following
-//! a configured framerate. The clock can be run using the [`Clock::start`] method. While this
-//! clock is limiting the number of tick per second occurring (it is guaranted that it won't exceed
-//! the configured framerate), it also tries to maximize it. Executed callbacks are timed, and
-//! elapsed time are subtracted from theoretical frame durations.
+//! This module provide a [`Clock`] structure, that can be used to limit the number of frames
+//! executed per second. The database doesn't tick at a fixed rate: it waits until there is
+//! something to do, then starts a new frame with [`Clock::tick`]. The clock only puts the thread
+//! asleep when the previous frame started less than a frame duration ago, so the configured
+//! framerate is never exceeded, while an idle database starts its next frame right away.
 //!
 //! # Examples
 //!
 //! ```
-//! let clock = Clock::ticking_at(60);
-//! clock.start(|| {
-//!     println!("One Hello, 60 times per second.");
-//! });
+//! let mut clock = Clock::with_framerate(60);
+//! loop {
+//!     clock.tick();
+//!     println!("One Hello, at most 60 times per second.");
+//! }
 //! ```
 
 use std::time::{Duration, Instant};
 use std::thread;
 
 pub struct Clock {
-    framerate: u16,
+    frame: Duration,
+    previous: Option<Instant>,
 }
 
 impl Clock {
-    /// Create a new clock, ticking at the given framerate (in tick per second).
+    /// Create a new clock, ticking at most at the given framerate (in tick per second).
     pub fn with_framerate(framerate: u16) -> Self {
         Clock {
-            framerate: framerate,
+            frame: Duration::new(0, 1_000_000_000u32 / framerate as u32),
+            previous: None,
         }
     }
 
-    /// Start this clock, ticking at a rate following the configured framerate, and calling the
-    /// given callback at each tick. This method is never ending.
-    pub fn start(self, mut callback: impl FnMut () -> ()) -> () {
-        loop {
-            let previous_time = Instant::now();
+    /// Start a new frame, putting the thread asleep until a full frame duration has elapsed since
+    /// the start of the previous one.
+    pub fn tick(&mut self) {
+        if let Some(previous) = self.previous {
+            if let Some(sleep_time) = self.frame.checked_sub(previous.elapsed()) {
+                thread::sleep(sleep_time);
+            };
+        };
 
-            callback();
+        self.previous = Some(Instant::now());
+    }
+}
 
-            // Put the thread asleep to run at a maximum of 128 time per second.
-            let now = Instant::now();
-            let elapsed_time = now.duration_since(previous_time);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            match Duration::new(0, 1_000_000_000u32 / self.framerate as u32).checked_sub(elapsed_time) {
-                Some(sleep_time) => {
-                    thread::sleep(sleep_time);
-                },
-                None => {},
-            };
-        };
+    #[test]
+    fn test_tick() {
+        let mut clock = Clock::with_framerate(10);
+
+        // The first frame starts right away.
+        let start = Instant::now();
+        clock.tick();
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // The next frame waits for the end of the previous one.
+        clock.tick();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // A frame starting after a full frame duration doesn't wait.
+        thread::sleep(Duration::from_millis(150));
+        let start = Instant::now();
+        clock.tick();
+        assert!(start.elapsed() < Duration::from_millis(50));
     }
 }
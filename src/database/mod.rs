@@ -3,17 +3,53 @@
 //! The Database is one of the 3 mains components of the Kairoi architecture. It uses channels to
 //! receive queries from the Controller, to send query responses to the Controller, to send
 //! execution requests to the Processor, and to receive execution responses from the Processor. The
-//! database runs in its own process, at its own framerate. The job and rule storage is delegated
-//! to a [`Storage`]. The [`Storage`] is initialized at the start of the database process.
+//! database runs in its own process. The job and rule storage is delegated to a [`Storage`]. The
+//! [`Storage`] is initialized at the start of the database process.
+//!
+//! The database is event-driven: it blocks until a query or an execution response is received,
+//! until the storage has something to report (a commit confirmation, or writes replicated from the
+//! primary or the cluster), or until the execution of the next planned job. It then handles
+//! everything at once in a frame. Frames never exceed the configured framerate: under load, a
+//! frame handles all queries received since the previous one, while an idle database reacts right
+//! away.
+//!
+//! All writes of a frame are committed at once, at the end of the frame. Writes are done by the
+//! [`Storage`] in a dedicated thread, so the database never waits for the file system: the query
+//! responses and execution requests of each frame are kept aside until its commit is confirmed,
+//! so they never refer to writes that could be lost. With the `Frame` fsync policy, it allows a
+//! single file system synchronization per frame (group commit), instead of one per write. A
+//! failed commit stops the database: since some writes may have been lost, the state in memory
+//! can't be trusted anymore, and it's restored from the file system on restart.
+//!
+//! When replicating a primary, the database is a replica: it applies the writes of its primary
+//! at each frame instead of triggering jobs, and rejects write instructions. Once promoted, it
+//! triggers jobs like a primary, starting with jobs left in the `triggered` state by the primary
+//! (like when booting up).
+//!
+//! A database may be a shard of a sharded database, owning a part of all jobs (and all rules): it
+//! then only receives the requests about its jobs, and persists them in its own subdirectory of
+//! the data directory. Shards are independent: each one runs its own frames, in its own thread.
+//!
+//! In a cluster, the database behaves like a replica until elected as the leader, and applies the
+//! writes committed by the cluster at each frame. Once elected, it behaves like a promoted replica,
+//! triggering jobs left in the `triggered` state by the previous leader. When it loses the
+//! leadership, frames waiting for their commit are rejected: their query responses are failures,
+//! and their job executions are never triggered.
 
 mod framerate;
 mod query;
 mod storage;
 pub mod execution;
+pub mod offline;
+
+pub use self::storage::administration;
+pub use self::storage::{Backend, ClusterConfiguration, CompactionTrigger, Fsync, Key, Keyring, Secret};
 
 use chrono::DateTime;
 use chrono::offset::Utc;
-use crate::query::{Request as QueryRequest, Response as QueryResponse};
+use crate::query::{Error as QueryError, Request as QueryRequest, Response as QueryResponse};
+use crate::shard::Shard;
+use crossbeam_channel::{Receiver, Select, Sender};
 use log::debug;
 use self::execution::Client as ExecutionClient;
 use self::execution::Receiver as UnderlyingExecutionReceiver;
@@ -24,8 +60,17 @@ use self::framerate::Clock;
 use self::query::Handler as QueryHandler;
 use self::storage::{Job, JobStatus, Runner, Storage};
 use self::storage::Configuration as StorageConfiguration;
-use std::sync::mpsc::{Receiver, Sender};
+use std::collections::VecDeque;
+use std::path::PathBuf;
 use std::thread;
+use std::time::Duration;
+
+/// The outcome of a frame, waiting for the commit of its writes.
+struct Frame {
+    sequence: u64,
+    responses: Vec<QueryResponse>,
+    to_trigger: Vec<(String, ExecutionRunner)>,
+}
 
 pub struct Database {
     storage: Storage,
@@ -33,13 +78,61 @@ pub struct Database {
     query_handler: QueryHandler,
     current_datetime: DateTime<Utc>,
     unhandeld_results: Vec<ExecutionResult>,
+    /// Jobs to trigger once the current frame is committed, with their runner.
+    to_trigger: Vec<(String, ExecutionRunner)>,
+    uncommitted: VecDeque<Frame>,
+    /// Whether the database was a replica at the end of the last frame.
+    replica: bool,
 }
 
 pub type ExecutionSender = UnderlyingExecutionSender;
 pub type ExecutionReceiver = UnderlyingExecutionReceiver;
 pub struct Configuration {
-    pub storage_persistence_fsync_on_persist: bool,
+    pub storage_persistence_backend: Backend,
+    pub storage_persistence_data_directory: PathBuf,
+    pub storage_persistence_fsync: Fsync,
+    pub storage_persistence_compaction_trigger: CompactionTrigger,
+    pub storage_persistence_keyring: Keyring,
+    /// The directory of backups, if any.
+    pub storage_backup_directory: Option<PathBuf>,
+    pub replication_listen: Option<String>,
+    pub replication_primary: Option<String>,
+    /// The secret shared by the primary and its replicas, if any.
+    pub replication_secret: Option<Secret>,
+    pub cluster: Option<ClusterConfiguration>,
     pub framerate: u16,
+    pub client_frame_quota: usize,
+    /// The shard of the database, owning a part of all jobs.
+    pub shard: Shard,
+}
+
+impl Configuration {
+    /// Split this configuration into the configurations of all shards, each one persisting its
+    /// jobs in its own `shard.<index>` subdirectory of the data directory. A single shard keeps
+    /// the configuration as is.
+    pub fn split(self) -> Vec<Configuration> {
+        let count = self.shard.count;
+        if count == 1 {
+            return vec![self];
+        };
+
+        (0..count).map(|index| Configuration {
+            storage_persistence_backend: self.storage_persistence_backend,
+            storage_persistence_data_directory: self.storage_persistence_data_directory.join(format!("shard.{}", index)),
+            storage_persistence_fsync: self.storage_persistence_fsync,
+            storage_persistence_compaction_trigger: self.storage_persistence_compaction_trigger,
+            storage_persistence_keyring: self.storage_persistence_keyring.clone(),
+            storage_backup_directory: self.storage_backup_directory.clone(),
+            // Sharded databases are never replicated.
+            replication_listen: None,
+            replication_primary: None,
+            replication_secret: None,
+            cluster: None,
+            framerate: self.framerate,
+            client_frame_quota: self.client_frame_quota,
+            shard: Shard::new(index, count),
+        }).collect()
+    }
 }
 
 impl Database {
@@ -49,91 +142,181 @@ impl Database {
         execution_link: (ExecutionSender, ExecutionReceiver),
         configuration: Configuration,
     ) -> thread::JoinHandle<()> {
-        thread::Builder::new().name("kairoi/db".to_string()).spawn(move || {
+        let name = match configuration.shard.count {
+            1 => String::from("kairoi/db"),
+            _ => format!("kairoi/db.{}", configuration.shard.index),
+        };
+
+        thread::Builder::new().name(name).spawn(move || {
             let mut database = Database {
                 storage: Storage::new(StorageConfiguration {
-                    persistence_fsync_on_persist: configuration.storage_persistence_fsync_on_persist,
+                    persistence_backend: configuration.storage_persistence_backend,
+                    persistence_data_directory: configuration.storage_persistence_data_directory,
+                    persistence_fsync: configuration.storage_persistence_fsync,
+                    persistence_compaction_trigger: configuration.storage_persistence_compaction_trigger,
+                    persistence_keyring: configuration.storage_persistence_keyring,
+                    backup_directory: configuration.storage_backup_directory,
+                    replication_listen: configuration.replication_listen,
+                    replication_primary: configuration.replication_primary,
+                    replication_secret: configuration.replication_secret,
+                    cluster: configuration.cluster,
+                    shard: configuration.shard,
                 }),
                 execution_client: ExecutionClient::new(execution_link),
-                query_handler: QueryHandler::new(query_link),
+                query_handler: QueryHandler::new(query_link, configuration.client_frame_quota),
                 current_datetime: Utc::now(),
                 unhandeld_results: Vec::new(),
+                to_trigger: Vec::new(),
+                uncommitted: VecDeque::new(),
+                replica: false,
             };
 
-            match database.storage.initialize() {
-                Ok(triggered_jobs) => {
-                    // Re-process "triggered" jobs when booting up (causing duplicated job
-                    // executions), because there is no way to know results of previous executions.
-                    database.trigger_execution(triggered_jobs);
-                },
+            let triggered_jobs = match database.storage.initialize() {
+                Ok(triggered_jobs) => triggered_jobs,
                 Err(_) => {
                     panic!("Unable to initialize the storage from data persisted to the file system.");
                 },
             };
+            if database.storage.start_replication().is_err() {
+                panic!("Unable to start the replication.");
+            };
+            database.replica = database.storage.is_replica();
+            // Re-process "triggered" jobs when booting up (causing duplicated job executions),
+            // because there is no way to know results of previous executions. Replicas leave them
+            // to their primary, and cluster nodes to their leader.
+            if !database.replica {
+                database.trigger_execution(triggered_jobs);
+            };
+            database.commit();
 
-            let clock = Clock::with_framerate(configuration.framerate);
-            clock.start(|| {
+            let mut clock = Clock::with_framerate(configuration.framerate);
+            loop {
+                clock.tick();
                 database.current_datetime = Utc::now();
 
                 database.query_handler.handle(&database.current_datetime, &mut database.storage);
 
-                let jobs = database.storage.get_jobs_to_execute(&database.current_datetime);
-                database.trigger_execution(jobs);
-                database.handle_results();
-            });
+                database.storage.pull_replicated();
+                if database.storage.is_replica() {
+                    database.replica = true;
+                } else {
+                    if database.replica {
+                        // The database has just been promoted (or elected).
+                        database.replica = false;
+                        let jobs = database.storage.get_triggered_jobs();
+                        database.trigger_execution(jobs);
+                    };
+                    let jobs = database.storage.pop_jobs_to_execute(&database.current_datetime);
+                    database.trigger_execution(jobs);
+                    database.handle_results();
+                };
+                database.commit();
+                database.wait();
+            };
         }).unwrap()
     }
 
+    /// Wait until there is something to do: a query, an execution response (unless replicating),
+    /// something reported by the storage, or the execution of the next planned job. Received
+    /// messages are left to the next frame.
+    fn wait(&self) {
+        let mut select = Select::new();
+        self.query_handler.watch(&mut select);
+        self.storage.watch(&mut select);
+        if !self.replica {
+            self.execution_client.watch(&mut select);
+        };
+
+        match self.get_timeout() {
+            Some(timeout) => {
+                let _ = select.ready_timeout(timeout);
+            },
+            None => {
+                select.ready();
+            },
+        };
+    }
+
+    /// Get the maximum duration to wait for the next frame, or None to wait indefinitely. There is
+    /// no wait when requests exceeded their client quota during the last frame, or when execution
+    /// results couldn't be written yet: the next frame handles them, as soon as the framerate
+    /// allows it.
+    fn get_timeout(&self) -> Option<Duration> {
+        if self.query_handler.is_pending() || !self.unhandeld_results.is_empty() {
+            return Some(Duration::from_secs(0));
+        };
+        if self.replica {
+            return None;
+        };
+
+        self.storage.get_next_execution().map(|execution| {
+            (execution - Utc::now()).to_std().unwrap_or_else(|_| Duration::from_secs(0))
+        })
+    }
+
+    /// Commit all writes of the current frame, keeping its query responses and job executions
+    /// aside until the commit is confirmed. Then, send query responses and trigger job executions
+    /// of all frames whose commit has been confirmed, and send failures for all frames whose
+    /// commit has been rejected.
+    fn commit(&mut self) {
+        self.uncommitted.push_back(Frame {
+            sequence: self.storage.commit(),
+            responses: self.query_handler.take_responses(),
+            to_trigger: self.to_trigger.drain(..).collect(),
+        });
+
+        let committed = match self.storage.committed() {
+            Ok(sequence) => sequence,
+            Err(_) => panic!("Unable to write data to the file system."),
+        };
+        let rejected = self.storage.rejected();
+        while let Some(frame) = self.uncommitted.pop_front() {
+            if frame.sequence <= committed {
+                self.query_handler.respond(frame.responses);
+                for (job, runner) in frame.to_trigger {
+                    self.execution_client.trigger(job, runner);
+                };
+            } else if frame.sequence <= rejected {
+                self.query_handler.respond(frame.responses.into_iter().map(|response| {
+                    QueryResponse::new(response.get_request().clone(), Err(QueryError::Failure))
+                }).collect());
+            } else {
+                self.uncommitted.push_front(frame);
+
+                break;
+            };
+        };
+    }
+
     /// Check every waiting job, and trigger the execution when needed.
     fn trigger_execution(&mut self, jobs: Vec<Job>) {
         let mut triggering = Vec::with_capacity(jobs.len());
-        let mut failing = Vec::with_capacity(jobs.len());
-        for job in &jobs {
+        let mut failing = Vec::new();
+        for job in jobs {
             // Find a matching Runner.
             match self.storage.pair(job.get_identifier()) {
                 Some(rule) => {
-                    triggering.push((
-                        job,
-                        rule,
-                    ));
-                },
-                None => {
-                    failing.push(job);
+                    debug!("TRIGGER {:?} with {:?} at {}.", job, rule, &self.current_datetime);
+                    let runner = ExecutionRunner::from(rule.get_runner().clone());
+                    triggering.push((job, runner));
                 },
+                None => failing.push(job),
             };
         };
 
         // Trigger all jobs that have been paired with a runner.
-        for (job, rule) in &triggering {
-            debug!("TRIGGER {:?} with {:?} at {}.", job, &rule, &self.current_datetime);
-            let runner = rule.get_runner().clone();
-            let modified = Job::new(
-                job.get_identifier().clone(),
-                job.get_execution().clone(),
-                JobStatus::Triggered,
-            );
-            if let Err(_) = self.storage.set_job(modified) {
+        for (job, runner) in triggering {
+            if self.storage.set_job_status(&job, JobStatus::Triggered).is_err() {
                 continue;
             };
-
-            self.execution_client.trigger(
-                job.get_identifier().clone(),
-                ExecutionRunner::from(runner),
-            );
+            self.to_trigger.push((job.into_identifier(), runner));
         };
 
         // Mark all jobs that haven't as failed.
-        for job in &failing {
+        for job in failing {
             debug!("Unable to find a Rule pairing {:?}.", job);
             debug!("MARK AS FAILED {:?} at {}.", job, &self.current_datetime);
-            let job = Job::new(
-                job.get_identifier().clone(),
-                job.get_execution().clone(),
-                JobStatus::Failed,
-            );
-            if let Err(_) = self.storage.set_job(job) {
-                continue;
-            }
+            let _ = self.storage.set_job_status(&job, JobStatus::Failed);
         };
     }
 
@@ -151,28 +334,20 @@ impl Database {
         results.retain(|response| {
             match self.storage.get_job(&response.job) {
                 Some(job) => {
-                    let job = match response.result {
+                    let status = match response.result {
                         Ok(_) => {
                             debug!("MARK AS EXECUTED {:?} at {}.", job, &self.current_datetime);
 
-                            Job::new(
-                                job.get_identifier().clone(),
-                                job.get_execution().clone(),
-                                JobStatus::Executed,
-                            )
+                            JobStatus::Executed
                         },
                         Err(_) => {
                             debug!("MARK AS FAILED {:?} at {}.", job, &self.current_datetime);
 
-                            Job::new(
-                                job.get_identifier().clone(),
-                                job.get_execution().clone(),
-                                JobStatus::Failed,
-                            )
+                            JobStatus::Failed
                         },
                     };
 
-                    match self.storage.set_job(job) {
+                    match self.storage.set_job_status(&job, status) {
                         Ok(_) => false,
                         Err(_) => true,
                     }
@@ -194,3 +369,96 @@ impl From<Runner> for ExecutionRunner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as ChronoDuration;
+    use crate::query::instruction::Instruction;
+    use crossbeam_channel::unbounded;
+    use super::execution::protocol::Response as ExecutionResponse;
+    use std::time::Instant;
+
+    /// Create a database without persistence, along with the channels sending it requests and
+    /// execution responses (a disconnected channel would always wake the database up).
+    fn database() -> (Database, Sender<QueryRequest>, Sender<ExecutionResponse>) {
+        let (request_producer, request_consumer) = unbounded();
+        let (response_producer, _) = unbounded();
+        let (execution_producer, _) = unbounded();
+        let (execution_response_producer, execution_consumer) = unbounded();
+        let mut storage = Storage::new(StorageConfiguration {
+            persistence_backend: Backend::Ephemeral,
+            persistence_data_directory: PathBuf::from("data"),
+            persistence_fsync: Fsync::Frame,
+            persistence_compaction_trigger: CompactionTrigger::Entries(1000),
+            persistence_keyring: Keyring::default(),
+            backup_directory: None,
+            replication_listen: None,
+            replication_primary: None,
+            replication_secret: None,
+            cluster: None,
+            shard: Shard::new(0, 1),
+        });
+        assert!(storage.initialize().is_ok());
+
+        let database = Database {
+            storage,
+            execution_client: ExecutionClient::new((execution_producer, execution_consumer)),
+            query_handler: QueryHandler::new((response_producer, request_consumer), 100),
+            current_datetime: Utc::now(),
+            unhandeld_results: Vec::new(),
+            to_trigger: Vec::new(),
+            uncommitted: VecDeque::new(),
+            replica: false,
+        };
+
+        (database, request_producer, execution_response_producer)
+    }
+
+    /// Plan a job in the given database, to be executed after the given delay.
+    fn plan(database: &mut Database, identifier: &str, delay: ChronoDuration) {
+        let job = Job::new(identifier.to_string(), Utc::now() + delay, JobStatus::Planned);
+        assert!(database.storage.set_job(job).is_ok());
+    }
+
+    #[test]
+    fn test_get_timeout() {
+        let (mut database, _requests, _responses) = database();
+
+        // Without any planned job, the database waits indefinitely.
+        assert_eq!(database.get_timeout(), None);
+
+        // Otherwise, it waits until the execution of the next planned job.
+        plan(&mut database, "job.1", ChronoDuration::seconds(60));
+        plan(&mut database, "job.2", ChronoDuration::seconds(10));
+        let timeout = database.get_timeout().unwrap();
+        assert!(timeout > Duration::from_secs(9) && timeout <= Duration::from_secs(10));
+
+        // Overdue jobs are triggered right away.
+        plan(&mut database, "job.3", ChronoDuration::seconds(-10));
+        assert_eq!(database.get_timeout(), Some(Duration::from_secs(0)));
+
+        // Replicas never trigger jobs.
+        database.replica = true;
+        assert_eq!(database.get_timeout(), None);
+    }
+
+    #[test]
+    fn test_wait() {
+        let (mut database, requests, _responses) = database();
+
+        // The database wakes up for the next planned job.
+        plan(&mut database, "job.1", ChronoDuration::milliseconds(100));
+        let start = Instant::now();
+        database.wait();
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // A request wakes it up right away, even when the next job is far away.
+        plan(&mut database, "job.1", ChronoDuration::seconds(60));
+        requests.send(QueryRequest::new(1, String::from("r1"), Instruction::Get { identifier: String::from("job.1") })).unwrap();
+        let start = Instant::now();
+        database.wait();
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,110 @@
+//! Offline access to the storage of a stopped database, for administration commands.
+//!
+//! The storage is initialized exactly like when the database starts: its data directory is locked
+//! (so a running server can't use it at the same time), and its logfiles are loaded. Writes go
+//! through the same persistence path than the database ones, and are fully written before
+//! returning. A sharded database is handled as a whole: the storages of all shards are initialized
+//! at once.
+
+use std::io::{BufRead, Write};
+use std::thread;
+use std::time::Duration;
+use super::Configuration;
+use super::storage::Storage;
+use super::storage::Configuration as StorageConfiguration;
+use super::storage::transfer;
+
+pub use super::storage::transfer::{Error as TransferError, Policy as ImportPolicy, Transfer};
+
+/// The delay between two checks of the commit of offline writes.
+const COMMIT_POLLING_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The storage can't be initialized (its data directory may be used by a running server).
+    UninitializableStorage,
+    /// The storage is replicated by a cluster: it can only be written through its leader.
+    Clustered,
+    Transfer(TransferError),
+}
+
+/// Export all jobs and rules of the database with the given configuration to the given output, as
+/// NDJSON.
+pub fn export(configuration: Configuration, output: &mut dyn Write) -> Result<Transfer, Error> {
+    let storages = initialize(configuration)?;
+
+    let mut total = Transfer::default();
+    for storage in &storages {
+        let transfer = transfer::export(storage, output).map_err(Error::Transfer)?;
+        add(&mut total, transfer);
+    };
+
+    Ok(total)
+}
+
+/// Import all jobs and rules from the given NDJSON input into the database with the given
+/// configuration, handling conflicts with the given policy. Nodes of a cluster can't be imported
+/// into, since their writes wouldn't be replicated. Each shard imports the whole input, keeping
+/// the jobs it owns: with the `Fail` policy, shards imported before a conflicting one keep their
+/// imported items.
+pub fn import(configuration: Configuration, input: &mut dyn BufRead, policy: ImportPolicy) -> Result<Transfer, Error> {
+    if configuration.cluster.is_some() {
+        return Err(Error::Clustered);
+    };
+    let mut storages = initialize(configuration)?;
+    let mut data = Vec::new();
+    if input.read_to_end(&mut data).is_err() {
+        return Err(Error::Transfer(TransferError::Io));
+    };
+
+    let mut total = Transfer::default();
+    for storage in &mut storages {
+        let transfer = transfer::import(storage, &mut data.as_slice(), policy).map_err(Error::Transfer)?;
+        let sequence = storage.commit();
+        loop {
+            match storage.committed() {
+                Ok(committed) if committed >= sequence => break,
+                Ok(_) => thread::sleep(COMMIT_POLLING_INTERVAL),
+                Err(_) => return Err(Error::Transfer(TransferError::PersistenceFailure)),
+            };
+        };
+        add(&mut total, transfer);
+    };
+
+    Ok(total)
+}
+
+/// Add the numbers of items transferred by a shard to the given total.
+fn add(total: &mut Transfer, transfer: Transfer) {
+    total.jobs += transfer.jobs;
+    total.rules += transfer.rules;
+    total.skipped += transfer.skipped;
+}
+
+/// Create and initialize the storages of all shards of the database with the given configuration.
+fn initialize(configuration: Configuration) -> Result<Vec<Storage>, Error> {
+    configuration.split().into_iter().map(initialize_shard).collect()
+}
+
+/// Create and initialize the storage of the shard with the given configuration.
+fn initialize_shard(configuration: Configuration) -> Result<Storage, Error> {
+    let mut storage = Storage::new(StorageConfiguration {
+        persistence_backend: configuration.storage_persistence_backend,
+        persistence_data_directory: configuration.storage_persistence_data_directory,
+        persistence_fsync: configuration.storage_persistence_fsync,
+        persistence_compaction_trigger: configuration.storage_persistence_compaction_trigger,
+        persistence_keyring: configuration.storage_persistence_keyring,
+        backup_directory: configuration.storage_backup_directory,
+        // Offline commands never replicate.
+        replication_listen: None,
+        replication_primary: None,
+        replication_secret: None,
+        cluster: None,
+        shard: configuration.shard,
+    });
+
+    match storage.initialize() {
+        Ok(_) => Ok(storage),
+        Err(_) => Err(Error::UninitializableStorage),
+    }
+}
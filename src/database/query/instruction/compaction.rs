@@ -0,0 +1,115 @@
+use chrono::DateTime;
+use chrono::offset::Utc;
+use crate::database::storage::{BackupPathError, CompactError, Compaction, CompactionProgress, CompactionResult, Storage};
+use crate::query::Error;
+use crate::query::output::{Compaction as OutputCompaction, CompactionProgress as OutputCompactionProgress, CompactionResult as OutputCompactionResult, Output};
+use log::{debug, error};
+
+/// Handle Compact instructions.
+pub struct Compact {}
+
+impl Compact {
+    /// Start compacting the given storage in background.
+    pub fn handle(current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<Output, Error> {
+        match storage.compact() {
+            Ok(_) => {
+                debug!("COMPACT at {}.", current_datetime);
+
+                Ok(Output::None)
+            },
+            Err(CompactError::AlreadyRunning) => {
+                debug!("Unable to COMPACT at {} (already running).", current_datetime);
+
+                Err(Error::Conflict)
+            },
+            Err(CompactError::Failure) => Err(Error::Failure),
+        }
+    }
+}
+
+/// Handle Backup instructions.
+pub struct Backup {}
+
+impl Backup {
+    /// Start compacting the given storage in background, writing a backup of it to the file with
+    /// the given name in the backup directory once done.
+    pub fn handle(path: &str, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<Output, Error> {
+        let resolved = match storage.get_backup_path(path) {
+            Ok(resolved) => resolved,
+            Err(BackupPathError::Disabled) => {
+                error!("Unable to BACKUP {} (no backup directory is configured).", path);
+
+                return Err(Error::Failure);
+            },
+            Err(BackupPathError::InvalidName) => {
+                error!("Unable to BACKUP {} (only a file name is allowed, without any directory).", path);
+
+                return Err(Error::Failure);
+            },
+            Err(BackupPathError::Existing) => {
+                debug!("Unable to BACKUP {} at {} (the file already exists).", path, current_datetime);
+
+                return Err(Error::Conflict);
+            },
+            // Only existing files can be missing.
+            Err(BackupPathError::Missing) => return Err(Error::Failure),
+        };
+
+        match storage.backup(resolved) {
+            Ok(_) => {
+                debug!("BACKUP {} at {}.", path, current_datetime);
+
+                Ok(Output::None)
+            },
+            Err(CompactError::AlreadyRunning) => {
+                debug!("Unable to BACKUP {} at {} (a compaction is already running).", path, current_datetime);
+
+                Err(Error::Conflict)
+            },
+            Err(CompactError::Failure) => Err(Error::Failure),
+        }
+    }
+}
+
+/// Handle Compact Status instructions.
+pub struct Status {}
+
+impl Status {
+    /// Retrieve the progress of the running compaction and the result of the last one from the
+    /// given storage.
+    pub fn handle(storage: &mut Storage) -> Result<Output, Error> {
+        Ok(Output::Compaction(OutputCompaction::from(storage.get_compaction())))
+    }
+}
+
+/// Convert Compaction into OutputCompaction.
+impl From<Compaction> for OutputCompaction {
+    fn from(compaction: Compaction) -> Self {
+        Self {
+            running: compaction.running.map(OutputCompactionProgress::from),
+            last: compaction.last.map(OutputCompactionResult::from),
+        }
+    }
+}
+
+/// Convert CompactionProgress into OutputCompactionProgress.
+impl From<CompactionProgress> for OutputCompactionProgress {
+    fn from(progress: CompactionProgress) -> Self {
+        Self {
+            started: progress.started,
+            processed_bytes: progress.processed_bytes,
+            total_bytes: progress.total_bytes,
+        }
+    }
+}
+
+/// Convert CompactionResult into OutputCompactionResult.
+impl From<CompactionResult> for OutputCompactionResult {
+    fn from(result: CompactionResult) -> Self {
+        Self {
+            success: result.success,
+            finished: result.finished,
+            entries: result.entries,
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use chrono::DateTime;
 use chrono::offset::Utc;
 use crate::database::storage::{Job, JobStatus, Storage};
+use crate::query::Error;
+use crate::query::output::{Job as OutputJob, JobStatus as OutputJobStatus, Output};
 use log::debug;
 
 /// Handle Job Set instructions.
@@ -8,7 +10,7 @@ pub struct Set {}
 
 impl Set {
     /// Register a Job with the given identifier and execution time to the given context.
-    pub fn handle(identifier: &String, execution: &DateTime<Utc>, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<(), ()> {
+    pub fn handle(identifier: &String, execution: &DateTime<Utc>, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<Output, Error> {
         let job = Job::new(
             identifier.clone(),
             *execution,
@@ -22,14 +24,14 @@ impl Set {
                     JobStatus::Triggered => {
                         debug!("Unable to SET {:?} at {} (in status Triggered).", &job, current_datetime);
 
-                        Err(())
+                        Err(Error::Conflict)
                     },
                     _ => {
                         debug!("SET {:?} at {}.", &job, current_datetime);
 
                         match storage.set_job(job) {
-                            Ok(_) => Ok(()),
-                            Err(_) => Err(()),
+                            Ok(_) => Ok(Output::None),
+                            Err(_) => Err(Error::Failure),
                         }
                     },
                 }
@@ -39,10 +41,72 @@ impl Set {
                 debug!("SET {:?} at {}.", &job, current_datetime);
 
                 match storage.set_job(job) {
-                    Ok(_) => Ok(()),
-                    Err(_) => Err(()),
+                    Ok(_) => Ok(Output::None),
+                    Err(_) => Err(Error::Failure),
                 }
             },
         }
     }
 }
+
+/// Handle Job Get instructions.
+pub struct Get {}
+
+impl Get {
+    /// Retrieve the Job with the given identifier from the given context.
+    pub fn handle(identifier: &str, storage: &Storage) -> Result<Output, Error> {
+        match storage.get_job(identifier) {
+            Some(job) => Ok(Output::Job(OutputJob::from(job))),
+            None => Err(Error::NotFound),
+        }
+    }
+}
+
+/// Handle Job Unset instructions.
+pub struct Unset {}
+
+impl Unset {
+    /// Remove the Job with the given identifier from the given context.
+    pub fn handle(identifier: &str, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<Output, Error> {
+        match storage.get_job(identifier) {
+            Some(current) => {
+                // A job in status Triggered can't be removed, since it's being executed.
+                match current.get_status() {
+                    JobStatus::Triggered => {
+                        debug!("Unable to UNSET {:?} at {} (in status Triggered).", current, current_datetime);
+
+                        Err(Error::Conflict)
+                    },
+                    _ => {
+                        debug!("UNSET {:?} at {}.", current, current_datetime);
+
+                        match storage.remove_job(identifier) {
+                            Ok(_) => Ok(Output::None),
+                            Err(_) => Err(Error::Failure),
+                        }
+                    },
+                }
+            },
+            None => Err(Error::NotFound),
+        }
+    }
+}
+
+/// Convert Job into OutputJob.
+impl From<Job> for OutputJob {
+    fn from(job: Job) -> Self {
+        let execution = *job.get_execution();
+        let status = match job.get_status() {
+            JobStatus::Planned => OutputJobStatus::Planned,
+            JobStatus::Triggered => OutputJobStatus::Triggered,
+            JobStatus::Executed => OutputJobStatus::Executed,
+            JobStatus::Failed => OutputJobStatus::Failed,
+        };
+
+        Self {
+            identifier: job.into_identifier(),
+            execution,
+            status,
+        }
+    }
+}
@@ -1,23 +1,88 @@
+mod compaction;
 mod job;
+mod replication;
 mod rule;
+mod transfer;
 
-use crate::database::storage::Storage;
+use crate::database::storage::{Role, Storage};
+use crate::query::Error;
 use crate::query::instruction::Instruction;
+use crate::query::output::Output;
+use compaction::Backup;
+use compaction::Compact;
+use compaction::Status as CompactStatus;
+use job::Get as JobGet;
 use job::Set as JobSet;
+use job::Unset as JobUnset;
+use replication::Promote;
+use rule::List as RuleList;
 use rule::Set as RuleSet;
+use transfer::Export;
+use transfer::Import;
+pub use transfer::Background;
 use chrono::DateTime;
 use chrono::offset::Utc;
 
+/// The outcome of an instruction: either its operation result, or a transfer running in
+/// background, giving the operation result once done.
+pub enum Outcome {
+    Done(Result<Output, Error>),
+    Background(Background),
+}
+
 /// Match instructions with statically associated handlers, execute them and return operation
 /// results.
 pub struct Handler {}
 
 impl Handler {
-    /// Handle the given instruction and return the operation result.
-    pub fn handle(instruction: &Instruction, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<(), ()> {
-        match instruction {
+    /// Handle the given instruction and return its outcome.
+    pub fn handle(instruction: &Instruction, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Outcome {
+        if Self::writes(instruction) {
+            if let Err(error) = Self::check_writable(storage) {
+                return Outcome::Done(Err(error));
+            };
+        };
+
+        let result = match instruction {
             Instruction::Set { identifier, execution } => JobSet::handle(identifier, execution, current_datetime, storage),
+            Instruction::Get { identifier } => JobGet::handle(identifier, storage),
+            Instruction::Unset { identifier } => JobUnset::handle(identifier, current_datetime, storage),
             Instruction::RuleSet { identifier, pattern, runner } => RuleSet::handle(identifier, pattern, runner, current_datetime, storage),
+            Instruction::RuleList => RuleList::handle(storage),
+            Instruction::Compact => Compact::handle(current_datetime, storage),
+            Instruction::CompactStatus => CompactStatus::handle(storage),
+            Instruction::Backup { path } => Backup::handle(path, current_datetime, storage),
+            Instruction::Export { path } => return Outcome::from(Export::handle(path, current_datetime, storage)),
+            Instruction::Import { path, policy } => return Outcome::from(Import::handle(path, policy, current_datetime, storage)),
+            Instruction::Promote => Promote::handle(current_datetime, storage),
+        };
+
+        Outcome::Done(result)
+    }
+
+    /// Check whether the given storage accepts writes of jobs and rules, being the case unless
+    /// it's a replica or a cluster follower.
+    fn check_writable(storage: &Storage) -> Result<(), Error> {
+        match storage.get_role() {
+            Role::Primary => Ok(()),
+            Role::Replica => Err(Error::ReadOnly),
+            Role::Follower(leader) => Err(Error::NotLeader(leader)),
+        }
+    }
+
+    /// Check whether the given instruction writes jobs or rules, which is forbidden on replicas
+    /// and cluster followers.
+    fn writes(instruction: &Instruction) -> bool {
+        matches!(instruction, Instruction::Set { .. } | Instruction::Unset { .. } | Instruction::RuleSet { .. } | Instruction::Import { .. })
+    }
+}
+
+/// Convert the result of a transfer started in background into Outcome.
+impl From<Result<Background, Error>> for Outcome {
+    fn from(started: Result<Background, Error>) -> Self {
+        match started {
+            Ok(background) => Outcome::Background(background),
+            Err(error) => Outcome::Done(Err(error)),
         }
     }
 }
@@ -0,0 +1,33 @@
+use chrono::DateTime;
+use chrono::offset::Utc;
+use crate::database::storage::{PromoteError, Storage};
+use crate::query::Error;
+use crate::query::output::Output;
+use log::debug;
+
+/// Handle Promote instructions.
+pub struct Promote {}
+
+impl Promote {
+    /// Promote the given storage to a primary, if it's a replica. Cluster nodes can't be promoted.
+    pub fn handle(current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<Output, Error> {
+        match storage.promote() {
+            Ok(_) => {
+                debug!("PROMOTE at {}.", current_datetime);
+
+                Ok(Output::None)
+            },
+            Err(PromoteError::NotSynchronized) => {
+                debug!("Unable to PROMOTE at {} (not synchronized with the primary).", current_datetime);
+
+                Err(Error::Conflict)
+            },
+            Err(PromoteError::Clustered) => {
+                debug!("Unable to PROMOTE at {} (the leader is elected by the cluster).", current_datetime);
+
+                Err(Error::Conflict)
+            },
+            Err(PromoteError::Failure) => Err(Error::Failure),
+        }
+    }
+}
@@ -2,6 +2,8 @@ use chrono::DateTime;
 use chrono::offset::Utc;
 use crate::database::storage::{Rule, Runner, Storage};
 use crate::execution::runner::Runner as ExecutionRunner;
+use crate::query::Error;
+use crate::query::output::{Output, Rule as OutputRule};
 use log::debug;
 
 /// Handle Rule Set instructions.
@@ -10,17 +12,30 @@ pub struct Set {}
 impl Set {
     /// Register a Rule with the given identifier, pattern and runner configuration to the given
     /// execution context.
-    pub fn handle(identifier: &str, pattern: &str, runner: &ExecutionRunner, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<(), ()> {
+    pub fn handle(identifier: &str, pattern: &str, runner: &ExecutionRunner, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<Output, Error> {
         let rule = Rule::new(identifier.to_string(), pattern.to_string(), Runner::from(runner.clone()));
         debug!("RULE SET {:?} at {}.", &rule, current_datetime);
 
         match storage.set_rule(rule) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(()),
+            Ok(_) => Ok(Output::None),
+            Err(_) => Err(Error::Failure),
         }
     }
 }
 
+/// Handle Rule List instructions.
+pub struct List {}
+
+impl List {
+    /// Retrieve all Rules from the given execution context, ordered by identifier.
+    pub fn handle(storage: &Storage) -> Result<Output, Error> {
+        let mut rules: Vec<OutputRule> = storage.get_rules().into_iter().map(OutputRule::from).collect();
+        rules.sort_by(|a, b| a.identifier.cmp(&b.identifier));
+
+        Ok(Output::Rules(rules))
+    }
+}
+
 /// Convert ExecutionRunner into Runner.
 impl From<ExecutionRunner> for Runner {
     fn from(runner: ExecutionRunner) -> Self {
@@ -30,3 +45,17 @@ impl From<ExecutionRunner> for Runner {
         }
     }
 }
+
+/// Convert Rule into OutputRule.
+impl From<&Rule> for OutputRule {
+    fn from(rule: &Rule) -> Self {
+        Self {
+            identifier: rule.get_identifier().clone(),
+            pattern: rule.get_pattern().clone(),
+            runner: match rule.get_runner().clone() {
+                Runner::Amqp { dsn, exchange, routing_key } => ExecutionRunner::Amqp { dsn, exchange, routing_key },
+                Runner::Shell { command } => ExecutionRunner::Shell { command },
+            },
+        }
+    }
+}
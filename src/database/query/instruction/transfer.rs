@@ -0,0 +1,309 @@
+use chrono::DateTime;
+use chrono::offset::Utc;
+use crate::database::storage::{BackupPathError, Storage};
+use crate::database::storage::transfer;
+use crate::database::storage::transfer::{Error as TransferError, Items, Policy, Transfer};
+use crate::query::Error;
+use crate::query::instruction::ImportPolicy;
+use crate::query::output::{Output, Transfer as OutputTransfer};
+use crossbeam_channel::{Receiver, Select, TryRecvError, bounded};
+use log::{debug, error};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::thread;
+
+/// A transfer whose file is read or written in background, so the database thread never waits
+/// for the file system. Its result is known once [`poll`] returns it.
+pub enum Background {
+    /// An export, whose items have already been formatted, and are written in background.
+    Export {
+        path: String,
+        transfer: Transfer,
+        receiver: Receiver<bool>,
+    },
+    /// An import, whose items are read in background, and still have to be applied.
+    Import {
+        path: String,
+        policy: Policy,
+        receiver: Receiver<Result<Items, TransferError>>,
+    },
+}
+
+impl Background {
+    /// Add this transfer to the given selection, so the database can wait for its completion.
+    pub fn watch<'a>(&'a self, select: &mut Select<'a>) {
+        match self {
+            Background::Export { receiver, .. } => select.recv(receiver),
+            Background::Import { receiver, .. } => select.recv(receiver),
+        };
+    }
+
+    /// Get the result of this transfer, if done. Once read, imported items are written to the
+    /// given storage.
+    pub fn poll(&self, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Option<Result<Output, Error>> {
+        match self {
+            Background::Export { path, transfer, receiver } => match receiver.try_recv() {
+                Ok(true) => {
+                    debug!("EXPORT {} at {}.", path, current_datetime);
+
+                    Some(Ok(Output::Transfer(OutputTransfer::from(transfer.clone()))))
+                },
+                Ok(false) | Err(TryRecvError::Disconnected) => {
+                    error!("Unable to EXPORT to '{}' (the file can't be written).", path);
+
+                    Some(Err(Error::Failure))
+                },
+                Err(TryRecvError::Empty) => None,
+            },
+            Background::Import { path, policy, receiver } => match receiver.try_recv() {
+                Ok(read) => Some(Import::apply(path, *policy, read, current_datetime, storage)),
+                Err(TryRecvError::Disconnected) => {
+                    error!("Unable to IMPORT '{}' (the file can't be read).", path);
+
+                    Some(Err(Error::Failure))
+                },
+                Err(TryRecvError::Empty) => None,
+            },
+        }
+    }
+}
+
+/// Handle Export instructions.
+pub struct Export {}
+
+impl Export {
+    /// Export all jobs and rules of the given storage to the new file with the given name in the
+    /// backup directory, as NDJSON. Items are formatted right away, and written in background.
+    pub fn handle(path: &str, current_datetime: &DateTime<Utc>, storage: &Storage) -> Result<Background, Error> {
+        let resolved = match storage.get_backup_path(path) {
+            Ok(resolved) => resolved,
+            Err(BackupPathError::Disabled) => {
+                error!("Unable to EXPORT to {} (no backup directory is configured).", path);
+
+                return Err(Error::Failure);
+            },
+            Err(BackupPathError::InvalidName) => {
+                error!("Unable to EXPORT to {} (only a file name is allowed, without any directory).", path);
+
+                return Err(Error::Failure);
+            },
+            Err(BackupPathError::Existing) => {
+                debug!("Unable to EXPORT to {} at {} (the file already exists).", path, current_datetime);
+
+                return Err(Error::Conflict);
+            },
+            // Only existing files can be missing.
+            Err(BackupPathError::Missing) => return Err(Error::Failure),
+        };
+
+        let mut content = Vec::new();
+        let transfer = match transfer::export(storage, &mut content) {
+            Ok(transfer) => transfer,
+            Err(_) => return Err(Error::Failure),
+        };
+        let (sender, receiver) = bounded(1);
+        let spawned = thread::Builder::new().name("kairoi/export".to_string()).spawn(move || {
+            let _ = sender.send(Self::write(&resolved, &content));
+        });
+        if spawned.is_err() {
+            error!("Unable to EXPORT to '{}' (the background thread can't be started).", path);
+
+            return Err(Error::Failure);
+        };
+
+        Ok(Background::Export {
+            path: path.to_string(),
+            transfer,
+            receiver,
+        })
+    }
+
+    /// Write the given content to the new file at the given path, and synchronize it. An existing
+    /// file is never overwritten (it may have been created since the path has been resolved).
+    fn write(path: &Path, content: &[u8]) -> bool {
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+
+        file.write_all(content).is_ok() && file.sync_all().is_ok()
+    }
+}
+
+/// Handle Import instructions.
+pub struct Import {}
+
+impl Import {
+    /// Import all jobs and rules of the NDJSON file with the given name in the backup directory
+    /// into the given storage, handling conflicts with existing items following the given policy.
+    /// The file is read in background, and its items are written once read (see
+    /// [`Background::poll`]).
+    pub fn handle(path: &str, policy: &ImportPolicy, current_datetime: &DateTime<Utc>, storage: &Storage) -> Result<Background, Error> {
+        let resolved = match storage.get_existing_backup_path(path) {
+            Ok(resolved) => resolved,
+            Err(BackupPathError::Disabled) => {
+                error!("Unable to IMPORT {} (no backup directory is configured).", path);
+
+                return Err(Error::Failure);
+            },
+            Err(BackupPathError::InvalidName) => {
+                error!("Unable to IMPORT {} (only a file name is allowed, without any directory).", path);
+
+                return Err(Error::Failure);
+            },
+            Err(BackupPathError::Missing) => {
+                debug!("Unable to IMPORT {} at {} (the file doesn't exist).", path, current_datetime);
+
+                return Err(Error::NotFound);
+            },
+            // Only new files can already exist.
+            Err(BackupPathError::Existing) => return Err(Error::Failure),
+        };
+
+        let (sender, receiver) = bounded(1);
+        let spawned = thread::Builder::new().name("kairoi/import".to_string()).spawn(move || {
+            let _ = sender.send(Self::read(&resolved));
+        });
+        if spawned.is_err() {
+            error!("Unable to IMPORT '{}' (the background thread can't be started).", path);
+
+            return Err(Error::Failure);
+        };
+
+        Ok(Background::Import {
+            path: path.to_string(),
+            policy: Policy::from(*policy),
+            receiver,
+        })
+    }
+
+    /// Read all items of the file at the given path.
+    fn read(path: &Path) -> Result<Items, TransferError> {
+        match File::open(path) {
+            Ok(file) => transfer::read(&mut BufReader::new(file)),
+            Err(_) => Err(TransferError::Io),
+        }
+    }
+
+    /// Write the items read from the file with the given name into the given storage. The role of
+    /// the storage is checked again, since it may have changed while reading the file.
+    fn apply(path: &str, policy: Policy, read: Result<Items, TransferError>, current_datetime: &DateTime<Utc>, storage: &mut Storage) -> Result<Output, Error> {
+        super::Handler::check_writable(storage)?;
+
+        match read.and_then(|items| transfer::apply(storage, items, policy)) {
+            Ok(transfer) => {
+                debug!("IMPORT {} at {}.", path, current_datetime);
+
+                Ok(Output::Transfer(OutputTransfer::from(transfer)))
+            },
+            Err(TransferError::Conflict(identifier)) => {
+                debug!("Unable to IMPORT {} at {} ({:?} already exists).", path, current_datetime, identifier);
+
+                Err(Error::Conflict)
+            },
+            Err(TransferError::InvalidRecord(line)) => {
+                error!("Unable to IMPORT '{}' (the line {} isn't a valid record).", path, line);
+
+                Err(Error::Failure)
+            },
+            Err(TransferError::Io) => {
+                error!("Unable to IMPORT '{}' (the file can't be read).", path);
+
+                Err(Error::Failure)
+            },
+            Err(TransferError::PersistenceFailure) => Err(Error::Failure),
+        }
+    }
+}
+
+/// Convert ImportPolicy into Policy.
+impl From<ImportPolicy> for Policy {
+    fn from(policy: ImportPolicy) -> Self {
+        match policy {
+            ImportPolicy::Skip => Self::Skip,
+            ImportPolicy::Overwrite => Self::Overwrite,
+            ImportPolicy::Fail => Self::Fail,
+        }
+    }
+}
+
+/// Convert Transfer into OutputTransfer.
+impl From<Transfer> for OutputTransfer {
+    fn from(transfer: Transfer) -> Self {
+        Self {
+            jobs: transfer.jobs,
+            rules: transfer.rules,
+            skipped: transfer.skipped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::offset::TimeZone;
+    use crate::database::storage::{Backend, CompactionTrigger, Configuration, Fsync, Job, JobStatus, Keyring};
+    use crate::shard::Shard;
+    use std::path::PathBuf;
+
+    fn start(backup_directory: &Path) -> Storage {
+        let mut storage = Storage::new(Configuration {
+            persistence_backend: Backend::Ephemeral,
+            persistence_data_directory: PathBuf::from("data"),
+            persistence_fsync: Fsync::Frame,
+            persistence_compaction_trigger: CompactionTrigger::Entries(1000),
+            persistence_keyring: Keyring::default(),
+            backup_directory: Some(backup_directory.to_path_buf()),
+            replication_listen: None,
+            replication_primary: None,
+            replication_secret: None,
+            cluster: None,
+            shard: Shard::new(0, 1),
+        });
+        assert!(storage.initialize().is_ok());
+
+        storage
+    }
+
+    /// Wait for the given transfer running in background, and get its result.
+    fn wait(background: Background, storage: &mut Storage) -> Result<Output, Error> {
+        let mut select = Select::new();
+        background.watch(&mut select);
+        select.ready();
+        drop(select);
+
+        background.poll(&Utc::now(), storage).unwrap()
+    }
+
+    #[test]
+    fn test_background_transfer() {
+        let directory = std::env::temp_dir().join(format!("kairoi-test-transfer-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let now = Utc::now();
+        let mut exported = start(&directory);
+        let job = Job::new(String::from("app.job.1"), Utc.ymd(2020, 6, 17).and_hms(21, 47, 16), JobStatus::Planned);
+        assert!(exported.set_job(job.clone()).is_ok());
+
+        // Files are written to, and read from, the backup directory only.
+        let background = Export::handle("export", &now, &exported).ok().unwrap();
+        assert_eq!(wait(background, &mut exported), Ok(Output::Transfer(OutputTransfer { jobs: 1, rules: 0, skipped: 0 })));
+        assert!(directory.join("export").is_file());
+        assert!(matches!(Export::handle("export", &now, &exported), Err(Error::Conflict)));
+        assert!(matches!(Export::handle("../export", &now, &exported), Err(Error::Failure)));
+
+        let mut imported = start(&directory);
+        assert!(matches!(Import::handle("missing", &ImportPolicy::Fail, &now, &imported), Err(Error::NotFound)));
+        assert!(matches!(Import::handle("../export", &ImportPolicy::Fail, &now, &imported), Err(Error::Failure)));
+        let background = Import::handle("export", &ImportPolicy::Fail, &now, &imported).ok().unwrap();
+        assert!(imported.get_job("app.job.1").is_none());
+        assert_eq!(wait(background, &mut imported), Ok(Output::Transfer(OutputTransfer { jobs: 1, rules: 0, skipped: 0 })));
+        assert_eq!(imported.get_job("app.job.1"), Some(job));
+
+        // Conflicts are checked once the file is read.
+        let background = Import::handle("export", &ImportPolicy::Fail, &now, &imported).ok().unwrap();
+        assert_eq!(wait(background, &mut imported), Err(Error::Conflict));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
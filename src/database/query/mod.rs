@@ -3,48 +3,215 @@ mod instruction;
 use chrono::DateTime;
 use chrono::offset::Utc;
 use crate::database::storage::Storage;
-use crate::query::{Request, Response};
+use crate::query::{Client, Request, Response};
+use crossbeam_channel::{Receiver, Select, Sender, TryRecvError};
+use instruction::{Background, Outcome};
 use instruction::Handler as InstructionHandler;
-use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::collections::{HashMap, VecDeque};
 
 pub struct Handler {
     producer: Sender<Response>,
     consumer: Receiver<Request>,
+    client_frame_quota: usize,
+    pending: HashMap<Client, VecDeque<Request>>,
+    clients: VecDeque<Client>,
+    /// The requests whose transfer is running in background. Further requests of their clients
+    /// are kept queued until it's done, so each client still gets its responses in order.
+    background: Vec<(Request, Background)>,
+    responses: Vec<Response>,
 }
 
 impl Handler {
-    /// Create a new handler on the given query link.
-    pub fn new(query_link: (Sender<Response>, Receiver<Request>)) -> Handler {
+    /// Create a new handler on the given query link. At each frame, the handler handles at most
+    /// the given quota of requests per client, leaving remaining requests for the next frames.
+    pub fn new(query_link: (Sender<Response>, Receiver<Request>), client_frame_quota: usize) -> Handler {
         Handler {
             producer: query_link.0,
             consumer: query_link.1,
+            client_frame_quota,
+            pending: HashMap::new(),
+            clients: VecDeque::new(),
+            background: Vec::new(),
+            responses: Vec::new(),
         }
     }
 
-    /// Handle the query link.
+    /// Handle the query link. Responses are kept until [`take_responses`] is called, so they can
+    /// be sent once their writes are written to the file system.
     pub fn handle(&mut self, current_datetime: &DateTime<Utc>, storage: &mut Storage) {
-        self.receive_requests(current_datetime, storage);
+        self.handle_background(current_datetime, storage);
+        self.receive_requests();
+        self.handle_requests(current_datetime, storage);
     }
 
-    /// Pull all received queries and handle them.
-    fn receive_requests(&mut self, current_datetime: &DateTime<Utc>, storage: &mut Storage) {
-        let mut requests = Vec::new();
+    /// Respond to requests whose transfer running in background is done.
+    fn handle_background(&mut self, current_datetime: &DateTime<Utc>, storage: &mut Storage) {
+        let mut running = Vec::new();
+        for (request, background) in self.background.drain(..) {
+            match background.poll(current_datetime, storage) {
+                Some(result) => self.responses.push(Response::new(request, result)),
+                None => running.push((request, background)),
+            };
+        };
+        self.background = running;
+    }
+
+    /// Check whether the given client waits for the response of a transfer running in background.
+    fn is_waiting(&self, client: &Client) -> bool {
+        self.background.iter().any(|(request, _)| request.get_client() == *client)
+    }
 
+    /// Pull all received queries, queuing them by client.
+    fn receive_requests(&mut self) {
         loop {
             match self.consumer.try_recv() {
-                Ok(request) => requests.push(request),
+                Ok(request) => {
+                    let client = request.get_client();
+                    if !self.pending.contains_key(&client) {
+                        self.clients.push_back(client);
+                    };
+                    self.pending.entry(client).or_default().push_back(request);
+                },
                 Err(error) => match error {
                     TryRecvError::Empty => break,
                     TryRecvError::Disconnected => panic!("Query channel disconnected."),
                 },
             };
         };
+    }
 
-        for request in &requests {
-            let result = InstructionHandler::handle(request.get_instruction(), current_datetime, storage);
-            if let Err(_) = self.producer.send(Response::new(request.clone(), result)) {
+    /// Check whether requests are queued, exceeding the quota of their client for the last frame.
+    /// Requests of clients waiting for a transfer running in background aren't pending: they're
+    /// handled once the transfer is done.
+    pub fn is_pending(&self) -> bool {
+        self.pending.keys().any(|client| !self.is_waiting(client))
+    }
+
+    /// Add the query link and the transfers running in background to the given selection, so the
+    /// database can wait for requests, and for the completion of transfers.
+    pub fn watch<'a>(&'a self, select: &mut Select<'a>) {
+        select.recv(&self.consumer);
+        for (_, background) in &self.background {
+            background.watch(select);
+        };
+    }
+
+    /// Take all responses of requests handled since the last call.
+    pub fn take_responses(&mut self) -> Vec<Response> {
+        self.responses.drain(..).collect()
+    }
+
+    /// Send the given responses on the query link.
+    pub fn respond(&self, responses: Vec<Response>) {
+        for response in responses {
+            if self.producer.send(response).is_err() {
                 panic!("Query channel disconnected.");
             };
         };
     }
+
+    /// Handle queued requests, taking one request of each client in turn, until all queues are
+    /// empty or each client has consumed its quota for this frame. This way, a client sending a
+    /// burst of requests can't monopolize a frame, delaying requests of all other clients. Clients
+    /// waiting for a transfer running in background are skipped.
+    fn handle_requests(&mut self, current_datetime: &DateTime<Utc>, storage: &mut Storage) {
+        for _ in 0..self.client_frame_quota {
+            let mut handled = false;
+
+            for client in &self.clients {
+                if self.is_waiting(client) {
+                    continue;
+                };
+                let request = match self.pending.get_mut(client).and_then(|queue| queue.pop_front()) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                handled = true;
+
+                match InstructionHandler::handle(request.get_instruction(), current_datetime, storage) {
+                    Outcome::Done(result) => self.responses.push(Response::new(request, result)),
+                    Outcome::Background(background) => self.background.push((request, background)),
+                };
+            };
+
+            if !handled {
+                break;
+            };
+        };
+
+        // Forget clients without any request left, and rotate the others, so no client is always
+        // served first.
+        let pending = &mut self.pending;
+        pending.retain(|_, queue| !queue.is_empty());
+        self.clients.retain(|client| pending.contains_key(client));
+        if !self.clients.is_empty() {
+            self.clients.rotate_left(1);
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::storage::{Backend, CompactionTrigger, Configuration, Fsync, Keyring};
+    use crate::query::instruction::Instruction;
+    use crate::query::output::Output;
+    use crate::shard::Shard;
+    use crate::sync;
+    use std::path::{Path, PathBuf};
+
+    fn start(backup_directory: &Path) -> Storage {
+        let mut storage = Storage::new(Configuration {
+            persistence_backend: Backend::Ephemeral,
+            persistence_data_directory: PathBuf::from("data"),
+            persistence_fsync: Fsync::Frame,
+            persistence_compaction_trigger: CompactionTrigger::Entries(1000),
+            persistence_keyring: Keyring::default(),
+            backup_directory: Some(backup_directory.to_path_buf()),
+            replication_listen: None,
+            replication_primary: None,
+            replication_secret: None,
+            cluster: None,
+            shard: Shard::new(0, 1),
+        });
+        assert!(storage.initialize().is_ok());
+
+        storage
+    }
+
+    /// Get the identifiers of the given responses.
+    fn identifiers(responses: &[Response]) -> Vec<&str> {
+        responses.iter().map(|response| response.get_request().get_identifier().as_str()).collect()
+    }
+
+    #[test]
+    fn test_background_client() {
+        let directory = std::env::temp_dir().join(format!("kairoi-test-query-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut storage = start(&directory);
+        let (link, handler_link) = sync::link();
+        let mut handler = Handler::new(handler_link, 100);
+
+        // A client waiting for its export running in background doesn't hold other clients...
+        link.0.send(Request::new(1, String::from("r1"), Instruction::Export { path: String::from("export") })).unwrap();
+        link.0.send(Request::new(1, String::from("r2"), Instruction::RuleList)).unwrap();
+        link.0.send(Request::new(2, String::from("r3"), Instruction::RuleList)).unwrap();
+        handler.handle(&Utc::now(), &mut storage);
+        let responses = handler.take_responses();
+        assert_eq!(identifiers(&responses), vec!["r3"]);
+        assert_eq!(responses[0].get_result(), &Ok(Output::Rules(vec![])));
+        assert!(!handler.is_pending());
+
+        // ...and its requests are handled in order once the export is done.
+        while !handler.background.is_empty() {
+            let mut select = Select::new();
+            handler.watch(&mut select);
+            select.ready();
+            drop(select);
+            handler.handle(&Utc::now(), &mut storage);
+        };
+        assert_eq!(identifiers(&handler.take_responses()), vec!["r1", "r2"]);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
 }
@@ -0,0 +1,557 @@
+//! Offline administration of logfiles, while the server is stopped.
+//!
+//! This module gives access to the content of logfiles (`logfile`, `logfile.to_compress` and
+//! `logfile.compressed`), reusing the same reader and encoder than the persistent storage, and
+//! allows running the compression process outside of the server. It also allows verifying the
+//! integrity of logfiles, and repairing them by removing their invalid parts, and restoring backups
+//! written by the server. Processes modifying logfiles should lock their data directory first,
+//! like the server does. Encrypted logfiles are read with the configured keyring, and rewritten
+//! logfiles are encrypted with its current key.
+
+use serde_json::json;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions, create_dir_all, metadata, rename};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use super::filesystem::Disk;
+use super::{Keyring, LockError, Paths, Progress, Storage, logfile};
+use super::encoder::{Decoded, Encoder, JobStatus, Runner};
+
+pub use super::logfile::VERSION as LOGFILE_VERSION;
+
+/// All logfiles, in the order in which their entries have been written.
+pub const LOGFILES: [&str; 3] = ["logfile.compressed", "logfile.to_compress", "logfile"];
+
+#[derive(Debug)]
+pub enum Error {
+    /// The file can't be read or written.
+    UnreadableFile,
+    /// The file contains an invalid entry followed by valid ones, at the given offset.
+    CorruptedFile(u64),
+    /// The file has been written with an unknown format version.
+    UnsupportedVersion(u8),
+    /// The file contains an entry that can't be decrypted with the keyring, at the given offset.
+    UndecryptableFile(u64),
+    /// The entry at the given index can't be decoded.
+    UndecodableEntry(usize),
+    /// The data directory is already used by another process.
+    LockedDirectory,
+    /// The compression process failed.
+    CompressionFailure,
+    /// The data directory already contains logfiles.
+    ExistingData,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    Text,
+    Ndjson,
+}
+
+/// Statistics about the entries of a logfile.
+#[derive(Debug, Default, PartialEq)]
+pub struct Statistics {
+    pub size: u64,
+    pub entries: usize,
+    pub jobs: usize,
+    pub job_removals: usize,
+    pub rules: usize,
+    /// The number of entries that can't be decoded.
+    pub invalid: usize,
+    /// The number of distinct items concerned by entries.
+    pub subjects: usize,
+}
+
+impl Statistics {
+    /// Get the ratio of entries superseded by a more recent entry about the same item, between 0
+    /// (no duplication) and 1.
+    pub fn get_duplication_ratio(&self) -> f64 {
+        match self.entries {
+            0 => 0.0,
+            entries => 1.0 - (self.subjects + self.invalid) as f64 / entries as f64,
+        }
+    }
+}
+
+/// The result of the verification of a logfile.
+#[derive(Debug, PartialEq)]
+pub struct Verification {
+    /// The format version of the logfile.
+    pub version: u8,
+    pub entries: usize,
+    /// The number of entries that can't be decoded.
+    pub invalid: usize,
+    /// All invalid parts in the middle of the logfile, as offsets and lengths.
+    pub corrupted: Vec<(u64, u64)>,
+    /// The invalid part at the end of the logfile (typically, an entry whose write has been
+    /// interrupted by a crash), as an offset and a length.
+    pub torn: Option<(u64, u64)>,
+}
+
+impl Verification {
+    /// Check whether the logfile can be loaded by the server without losing any data.
+    pub fn is_healthy(&self) -> bool {
+        self.invalid == 0 && self.corrupted.is_empty() && self.torn.is_none()
+    }
+}
+
+/// The result of the repair of a logfile.
+#[derive(Debug, PartialEq)]
+pub struct Repair {
+    /// The number of bytes removed from invalid parts of the logfile.
+    pub removed_bytes: u64,
+    /// The number of removed entries that can't be decoded.
+    pub removed_entries: usize,
+    /// Whether the logfile has been upgraded from an older format version.
+    pub upgraded: bool,
+}
+
+/// Write all decoded entries of the logfile at the given path to the given output, in the given
+/// format. Entries that can't be decoded are written as invalid entries.
+pub fn dump(path: &Path, keyring: &Keyring, format: DumpFormat, output: &mut dyn Write) -> Result<(), Error> {
+    let encoder = Encoder::new();
+    let name = path.display().to_string();
+    let content = read(path, keyring)?;
+
+    for (index, entry) in content.entries.iter().enumerate() {
+        let line = match (encoder.decode_version(entry, content.version), format) {
+            (Ok(Decoded::Job(job)), DumpFormat::Text) => format!("{}#{} job {:?} {} {}", name, index, job.identifier, job.execution.format("%F %T%.f UTC"), format_status(&job.status)),
+            (Ok(Decoded::Job(job)), DumpFormat::Ndjson) => json!({
+                "file": name,
+                "index": index,
+                "type": "job",
+                "identifier": job.identifier,
+                "execution": job.execution.to_rfc3339(),
+                "status": format_status(&job.status),
+            }).to_string(),
+            (Ok(Decoded::JobRemoval(removal)), DumpFormat::Text) => format!("{}#{} job_removal {:?}", name, index, removal.identifier),
+            (Ok(Decoded::JobRemoval(removal)), DumpFormat::Ndjson) => json!({
+                "file": name,
+                "index": index,
+                "type": "job_removal",
+                "identifier": removal.identifier,
+            }).to_string(),
+            (Ok(Decoded::Rule(rule)), DumpFormat::Text) => format!("{}#{} rule {:?} {:?} {}", name, index, rule.identifier, rule.pattern, match &rule.runner {
+                Runner::Shell { command } => format!("shell {:?}", command),
+                Runner::Amqp { dsn, exchange, routing_key } => format!("amqp {:?} {:?} {:?}", dsn, exchange, routing_key),
+            }),
+            (Ok(Decoded::Rule(rule)), DumpFormat::Ndjson) => json!({
+                "file": name,
+                "index": index,
+                "type": "rule",
+                "identifier": rule.identifier,
+                "pattern": rule.pattern,
+                "runner": match &rule.runner {
+                    Runner::Shell { command } => json!({ "type": "shell", "command": command }),
+                    Runner::Amqp { dsn, exchange, routing_key } => json!({ "type": "amqp", "dsn": dsn, "exchange": exchange, "routing_key": routing_key }),
+                },
+            }).to_string(),
+            (Err(_), DumpFormat::Text) => format!("{}#{} invalid ({} bytes)", name, index, entry.len()),
+            (Err(_), DumpFormat::Ndjson) => json!({
+                "file": name,
+                "index": index,
+                "type": "invalid",
+                "size": entry.len(),
+            }).to_string(),
+        };
+        if writeln!(output, "{}", line).is_err() {
+            return Err(Error::UnreadableFile);
+        };
+    };
+
+    Ok(())
+}
+
+/// Compute statistics about the entries of the logfile at the given path.
+pub fn statistics(path: &Path, keyring: &Keyring) -> Result<Statistics, Error> {
+    let encoder = Encoder::new();
+    let content = read(path, keyring)?;
+    let mut statistics = Statistics {
+        size: metadata(path).map(|metadata| metadata.len()).unwrap_or(0),
+        entries: content.entries.len(),
+        ..Statistics::default()
+    };
+    let mut subjects = HashSet::new();
+
+    for entry in &content.entries {
+        match encoder.decode_version(entry, content.version) {
+            Ok(decoded) => {
+                match decoded {
+                    Decoded::Job(_) => statistics.jobs += 1,
+                    Decoded::JobRemoval(_) => statistics.job_removals += 1,
+                    Decoded::Rule(_) => statistics.rules += 1,
+                };
+                subjects.insert(decoded.get_subject());
+            },
+            Err(_) => statistics.invalid += 1,
+        };
+    };
+    statistics.subjects = subjects.len();
+
+    Ok(statistics)
+}
+
+/// Verify the integrity of the logfile at the given path.
+pub fn verify(path: &Path, keyring: &Keyring) -> Result<Verification, Error> {
+    let encoder = Encoder::new();
+    let salvaged = salvage(path, keyring)?;
+    let version = salvaged.version;
+    let mut corrupted = salvaged.skipped;
+    let torn = match corrupted.last() {
+        Some((offset, length)) if offset + length == salvaged.size => corrupted.pop(),
+        _ => None,
+    };
+
+    Ok(Verification {
+        version,
+        entries: salvaged.entries.len(),
+        invalid: salvaged.entries.iter().filter(|entry| encoder.decode_version(entry, version).is_err()).count(),
+        corrupted,
+        torn,
+    })
+}
+
+/// Repair the logfile at the given path, removing all its invalid parts and entries that can't be
+/// decoded, and migrating it to the current format version. The logfile is left untouched when
+/// there is nothing to repair. Entries that can't be decrypted are never removed: the repair fails
+/// instead.
+pub fn repair(path: &Path, keyring: &Keyring) -> Result<Repair, Error> {
+    let encoder = Encoder::new();
+    let salvaged = salvage(path, keyring)?;
+    let total = salvaged.entries.len();
+    let entries: Vec<logfile::Parsed> = salvaged.entries.iter().filter_map(|entry| encoder.migrate(entry, salvaged.version).ok()).collect();
+    let repair = Repair {
+        removed_bytes: salvaged.skipped.iter().map(|(_, length)| length).sum(),
+        removed_entries: total - entries.len(),
+        upgraded: salvaged.version < logfile::VERSION,
+    };
+
+    let damaged = repair.removed_bytes > 0 || repair.removed_entries > 0 || repair.upgraded;
+    if damaged && logfile::rewrite(&Disk, path, &entries, keyring).is_err() {
+        return Err(Error::UnreadableFile);
+    };
+
+    Ok(repair)
+}
+
+/// Migrate the logfile at the given path to the current format version, like the server does when
+/// initializing. Return the format version the logfile was using, or None if it already used the
+/// current one.
+pub fn migrate(path: &Path, keyring: &Keyring) -> Result<Option<u8>, Error> {
+    let encoder = Encoder::new();
+    let content = read(path, keyring)?;
+    if content.version == logfile::VERSION {
+        return Ok(None);
+    };
+
+    let mut entries = Vec::with_capacity(content.entries.len());
+    for (index, entry) in content.entries.iter().enumerate() {
+        match encoder.migrate(entry, content.version) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => return Err(Error::UndecodableEntry(index)),
+        };
+    };
+    if logfile::rewrite(&Disk, path, &entries, keyring).is_err() {
+        return Err(Error::UnreadableFile);
+    };
+
+    Ok(Some(content.version))
+}
+
+/// Take the exclusive lock on the given data directory, like the server does when initializing.
+/// The lock is held until the returned file is closed.
+pub fn lock(directory: &Path) -> Result<File, Error> {
+    match super::lock(directory) {
+        Ok(file) => Ok(file),
+        Err(LockError::Locked) => Err(Error::LockedDirectory),
+        Err(LockError::Failure) => Err(Error::UnreadableFile),
+    }
+}
+
+/// Compress all logfiles of the given data directory into `logfile.compressed`, like the server
+/// does in background. The data directory is locked during the whole process, and logfiles are
+/// first migrated to the current format version. An interrupted compression
+/// (`logfile.to_compress` still existing) is resumed first, then `logfile` is compressed. All
+/// entries are encrypted with the current key of the given keyring, rotating keys like the server.
+pub fn compact(directory: &Path, keyring: &Keyring) -> Result<(), Error> {
+    let _lock = lock(directory)?;
+    let paths = Paths::new(directory);
+
+    for path in &[&paths.compressed, &paths.to_compress, &paths.logfile] {
+        if exists(path)? {
+            migrate(path, keyring)?;
+        };
+    };
+
+    if exists(&paths.to_compress)? {
+        Storage::compress(&Disk, &paths, keyring, &Progress::default()).map_err(|_| Error::CompressionFailure)?;
+    };
+
+    if exists(&paths.logfile)? {
+        if rename(&paths.logfile, &paths.to_compress).is_err() {
+            return Err(Error::UnreadableFile);
+        };
+        Storage::compress(&Disk, &paths, keyring, &Progress::default()).map_err(|_| Error::CompressionFailure)?;
+    };
+
+    Ok(())
+}
+
+/// Restore the backup at the given path into the given data directory, as its
+/// `logfile.compressed`, returning the number of restored entries. The data directory is created
+/// if needed, and locked during the whole process. It must not contain any logfile, so existing
+/// data are never overwritten. The backup is fully verified (and migrated to the current format
+/// version if needed) before being restored. It's decrypted with the given keyring, and encrypted
+/// with its current key once restored.
+pub fn restore(backup: &Path, directory: &Path, keyring: &Keyring) -> Result<usize, Error> {
+    if create_dir_all(directory).is_err() {
+        return Err(Error::UnreadableFile);
+    };
+    let _lock = lock(directory)?;
+    for file in &LOGFILES {
+        if exists(&directory.join(file))? {
+            return Err(Error::ExistingData);
+        };
+    };
+
+    let encoder = Encoder::new();
+    let content = read(backup, keyring)?;
+    if let Some(offset) = content.torn {
+        return Err(Error::CorruptedFile(offset));
+    };
+    let mut entries = Vec::with_capacity(content.entries.len());
+    for (index, entry) in content.entries.iter().enumerate() {
+        match encoder.migrate(entry, content.version) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => return Err(Error::UndecodableEntry(index)),
+        };
+    };
+    if logfile::rewrite(&Disk, &Paths::new(directory).compressed, &entries, keyring).is_err() {
+        return Err(Error::UnreadableFile);
+    };
+
+    Ok(entries.len())
+}
+
+/// Read all raw entries of the logfile at the given path, ignoring its torn entry if any.
+fn read(path: &Path, keyring: &Keyring) -> Result<logfile::Content, Error> {
+    let mut file = match OpenOptions::new().read(true).open(path) {
+        Ok(file) => file,
+        Err(_) => return Err(Error::UnreadableFile),
+    };
+
+    match logfile::Reader::new(&mut file, keyring).read() {
+        Ok(content) => Ok(content),
+        Err(error) => Err(Error::from(error)),
+    }
+}
+
+/// Read all raw entries of the logfile at the given path, skipping its invalid parts.
+fn salvage(path: &Path, keyring: &Keyring) -> Result<logfile::Salvaged, Error> {
+    let mut file = match OpenOptions::new().read(true).open(path) {
+        Ok(file) => file,
+        Err(_) => return Err(Error::UnreadableFile),
+    };
+
+    match logfile::Reader::new(&mut file, keyring).salvage() {
+        Ok(salvaged) => Ok(salvaged),
+        Err(error) => Err(Error::from(error)),
+    }
+}
+
+/// Check whether the file at the given path exists.
+fn exists(path: &Path) -> Result<bool, Error> {
+    match metadata(path) {
+        Ok(_) => Ok(true),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(false),
+        Err(_) => Err(Error::UnreadableFile),
+    }
+}
+
+/// Convert logfile ReadError into Error.
+impl From<logfile::ReadError> for Error {
+    fn from(error: logfile::ReadError) -> Self {
+        match error {
+            logfile::ReadError::CorruptedFile(offset) | logfile::ReadError::UncheckedTail(offset) => Self::CorruptedFile(offset),
+            logfile::ReadError::UnsupportedVersion(version) => Self::UnsupportedVersion(version),
+            logfile::ReadError::Undecryptable(offset) => Self::UndecryptableFile(offset),
+            logfile::ReadError::UnreadableFile => Self::UnreadableFile,
+        }
+    }
+}
+
+fn format_status(status: &JobStatus) -> &'static str {
+    match status {
+        JobStatus::Planned => "planned",
+        JobStatus::Triggered => "triggered",
+        JobStatus::Executed => "executed",
+        JobStatus::Failed => "failed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::offset::{TimeZone, Utc};
+    use super::super::encoder::{Encodable, Job, JobRemoval, Rule};
+
+    #[test]
+    fn test_dump_and_statistics() {
+        let path = std::env::temp_dir().join(format!("kairoi-test-administration-{}", std::process::id()));
+        let encoder = Encoder::new();
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut writer = logfile::Writer::new(&mut file, &Keyring::default());
+        let entries = vec![
+            Encodable::Job(Job { identifier: String::from("job.1"), execution: Utc.timestamp(1592430436, 0), status: JobStatus::Planned }),
+            Encodable::Rule(Rule { identifier: String::from("job.1"), pattern: String::from("job."), runner: Runner::Shell { command: String::from("script.sh") } }),
+            Encodable::JobRemoval(JobRemoval { identifier: String::from("job.1") }),
+        ];
+        for entry in entries {
+            writer.write(&encoder.encode(entry).unwrap()).ok().unwrap();
+        };
+        writer.write(&[9, 9]).ok().unwrap();
+        drop(file);
+
+        let mut output = Vec::new();
+        dump(&path, &Keyring::default(), DumpFormat::Text, &mut output).unwrap();
+        let name = path.display().to_string();
+        assert_eq!(String::from_utf8(output).unwrap(), format!(
+            "{0}#0 job \"job.1\" 2020-06-17 21:47:16 UTC planned\n{0}#1 rule \"job.1\" \"job.\" shell \"script.sh\"\n{0}#2 job_removal \"job.1\"\n{0}#3 invalid (2 bytes)\n",
+            name,
+        ));
+        let mut output = Vec::new();
+        dump(&path, &Keyring::default(), DumpFormat::Ndjson, &mut output).unwrap();
+        let first_line = String::from_utf8(output).unwrap().lines().next().unwrap().to_string();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&first_line).unwrap(),
+            json!({ "file": name, "index": 0, "type": "job", "identifier": "job.1", "execution": "2020-06-17T21:47:16+00:00", "status": "planned" }),
+        );
+
+        let statistics = statistics(&path, &Keyring::default()).unwrap();
+        assert_eq!(statistics, Statistics { size: 98, entries: 4, jobs: 1, job_removals: 1, rules: 1, invalid: 1, subjects: 2 });
+        assert_eq!(statistics.get_duplication_ratio(), 0.25);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_verify_and_repair() {
+        let path = std::env::temp_dir().join(format!("kairoi-test-administration-repair-{}", std::process::id()));
+        let encoder = Encoder::new();
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut writer = logfile::Writer::new(&mut file, &Keyring::default());
+        for index in 0..3 {
+            let job = Encodable::Job(Job { identifier: format!("job.{}", index), execution: Utc.timestamp(1592430436, 0), status: JobStatus::Planned });
+            writer.write(&encoder.encode(job).unwrap()).ok().unwrap();
+        };
+        drop(file);
+        // Corrupt the second entry (each entry being 30 bytes long, after the 5 bytes header), and
+        // add a torn entry.
+        let mut content = std::fs::read(&path).unwrap();
+        content[5 + 30 + 10] ^= 1;
+        content.extend(&[0, 0, 0, 10, 0]);
+        std::fs::write(&path, &content).unwrap();
+
+        let verification = verify(&path, &Keyring::default()).unwrap();
+        assert_eq!(verification, Verification { version: logfile::VERSION, entries: 2, invalid: 0, corrupted: vec![(35, 30)], torn: Some((95, 5)) });
+        assert!(!verification.is_healthy());
+
+        assert_eq!(repair(&path, &Keyring::default()).unwrap(), Repair { removed_bytes: 35, removed_entries: 0, upgraded: false });
+        assert!(verify(&path, &Keyring::default()).unwrap().is_healthy());
+        assert_eq!(repair(&path, &Keyring::default()).unwrap(), Repair { removed_bytes: 0, removed_entries: 0, upgraded: false });
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 5 + 30 * 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_migrate() {
+        let path = std::env::temp_dir().join(format!("kairoi-test-administration-migrate-{}", std::process::id()));
+        // Write a logfile with the format version 1 (no header, no checksums, and job execution
+        // timestamps as nanoseconds).
+        let entry = [0, 0, 4, 116, 111, 116, 111, 22, 71, 187, 92, 238, 225, 80, 0, 0];
+        let mut content = vec![0, 0, 0, entry.len() as u8];
+        content.extend(&entry);
+        std::fs::write(&path, &content).unwrap();
+
+        assert_eq!(migrate(&path, &Keyring::default()).unwrap(), Some(1));
+        assert_eq!(migrate(&path, &Keyring::default()).unwrap(), None);
+        let content = read(&path, &Keyring::default()).unwrap();
+        assert_eq!(content.version, logfile::VERSION);
+        assert_eq!(
+            Encoder::new().decode(&content.entries[0]),
+            Ok(Decoded::Job(Job { identifier: String::from("toto"), execution: Utc.ymd(2020, 11, 15).and_hms(16, 30, 0), status: JobStatus::Planned })),
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_lock() {
+        let directory = std::env::temp_dir().join(format!("kairoi-test-administration-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let first = lock(&directory).unwrap();
+        assert!(matches!(lock(&directory), Err(Error::LockedDirectory)));
+        assert!(matches!(compact(&directory, &Keyring::default()), Err(Error::LockedDirectory)));
+        drop(first);
+        assert!(lock(&directory).is_ok());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_restore() {
+        let backup = std::env::temp_dir().join(format!("kairoi-test-administration-backup-{}", std::process::id()));
+        let directory = std::env::temp_dir().join(format!("kairoi-test-administration-restore-{}", std::process::id()));
+        let encoder = Encoder::new();
+        let entries: Vec<logfile::Parsed> = (0..3).map(|index| {
+            let job = Encodable::Job(Job { identifier: format!("job.{}", index), execution: Utc.timestamp(1592430436, 0), status: JobStatus::Planned });
+
+            encoder.encode(job).unwrap()
+        }).collect();
+        logfile::rewrite(&Disk, &backup, &entries, &Keyring::default()).ok().unwrap();
+
+        assert_eq!(restore(&backup, &directory, &Keyring::default()).unwrap(), 3);
+        assert_eq!(read(&directory.join("logfile.compressed"), &Keyring::default()).unwrap().entries, entries);
+        // Existing data are never overwritten.
+        assert!(matches!(restore(&backup, &directory, &Keyring::default()), Err(Error::ExistingData)));
+        std::fs::remove_dir_all(&directory).unwrap();
+        // A damaged backup is never restored.
+        let mut content = std::fs::read(&backup).unwrap();
+        content.truncate(content.len() - 1);
+        std::fs::write(&backup, &content).unwrap();
+        assert!(matches!(restore(&backup, &directory, &Keyring::default()), Err(Error::CorruptedFile(_))));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[test]
+    fn test_compact_rotates_keys() {
+        let directory = std::env::temp_dir().join(format!("kairoi-test-administration-rotation-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let old = logfile::Key::new([1; 32]);
+        let new = logfile::Key::new([2; 32]);
+        let encoder = Encoder::new();
+        let entries: Vec<logfile::Parsed> = (0..3).map(|index| {
+            let job = Encodable::Job(Job { identifier: format!("job.{}", index), execution: Utc.timestamp(1592430436, 0), status: JobStatus::Planned });
+
+            encoder.encode(job).unwrap()
+        }).collect();
+        let paths = Paths::new(&directory);
+        logfile::rewrite(&Disk, &paths.logfile, &entries, &Keyring::new(Some(old.clone()), vec![])).ok().unwrap();
+
+        // Without the old key, the logfile can't be read.
+        let rotated = Keyring::new(Some(new.clone()), vec![]);
+        assert!(matches!(statistics(&paths.logfile, &rotated), Err(Error::UndecryptableFile(5))));
+        assert!(matches!(repair(&paths.logfile, &rotated), Err(Error::UndecryptableFile(5))));
+
+        // Once compacted with the old key as a previous key, only the new key is needed.
+        compact(&directory, &Keyring::new(Some(new), vec![old])).unwrap();
+        assert_eq!(read(&paths.compressed, &rotated).unwrap().entries.len(), 3);
+        assert!(matches!(read(&paths.compressed, &Keyring::default()), Err(Error::UndecryptableFile(5))));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+//! Persistence backends, storing entries on behalf of the writer thread.
+//!
+//! The writer thread (and the cluster it drives) only uses its storage through the [`Backend`]
+//! trait, so the storage engine can be chosen with the configured [`Kind`]:
+//!
+//! * `Logfile`, the append-only logfile (see [`Storage`]), supporting all features,
+//! * `Sled`, an embedded key-value store (see the [`sled`](super::sled) module), available with
+//!   the `backend-sled` feature. It can't be encrypted, nor send snapshots to replicas,
+//! * and `Ephemeral`, persisting nothing at all (see [`Ephemeral`]): the data directory is never
+//!   touched, and all data is lost when the server stops.
+//!
+//! All backends store entries in their encoded form (see the [`encoder`](super::encoder) module),
+//! so their entries can be exchanged: backups are always written as compressed logfiles, and can
+//! be restored to any data directory.
+
+use log::error;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use super::{CompactError, Compaction, Configuration, Entry, InitializationResult, PersistError, PersistResult, Storage, logfile};
+
+/// The storage engine of the persistence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Logfile,
+    #[cfg(feature = "backend-sled")]
+    Sled,
+    Ephemeral,
+}
+
+pub trait Backend: Send {
+    /// Initialize the backend by retrieving persisted data. On success, it returns the last entry
+    /// for each persisted item (removed items being omitted). This method should only be used
+    /// once, and before any other one.
+    fn initialize(&mut self) -> InitializationResult;
+
+    /// Persist the given entry.
+    fn persist(&mut self, entry: Entry) -> PersistResult;
+
+    /// Make entries persisted since the last commit durable, following the configured fsync
+    /// policy. It's called regularly (typically, once per database frame). A failure means that
+    /// some entries persisted since the last commit may be lost.
+    fn commit(&mut self) -> PersistResult;
+
+    /// Remove all persisted entries. It's used by replicas before receiving a new snapshot from
+    /// their primary, and by cluster nodes before installing a snapshot of their leader.
+    fn reset(&mut self) -> PersistResult;
+
+    /// Read all persisted entries, in their encoded form. When several entries concern the same
+    /// item, the latest one is the right one.
+    fn entries(&self) -> Result<Vec<logfile::Parsed>, PersistError>;
+
+    /// Start compacting persisted entries on demand. It fails if a compaction is already running.
+    fn compact(&mut self) -> Result<(), CompactError>;
+
+    /// Write a backup of all persisted entries to the given path, as a compressed logfile. It
+    /// fails if a compaction is already running.
+    fn backup(&mut self, path: PathBuf) -> Result<(), CompactError>;
+
+    /// Get the state of the compaction.
+    fn get_compaction(&mut self) -> Compaction;
+
+    /// Start replicating this backend to the replica connected through the given stream. Backends
+    /// unable to send a snapshot close the connection instead.
+    fn replicate(&mut self, stream: TcpStream) {
+        error!("Unable to send a snapshot to a replica with this persistence backend.");
+        drop(stream);
+    }
+}
+
+/// Create the backend of the configured kind, with the given configuration.
+pub fn create(configuration: Configuration) -> Box<dyn Backend> {
+    match configuration.backend {
+        Kind::Logfile => Box::new(Storage::new(configuration)),
+        #[cfg(feature = "backend-sled")]
+        Kind::Sled => Box::new(super::sled::Storage::new(configuration)),
+        Kind::Ephemeral => Box::new(Ephemeral),
+    }
+}
+
+/// A backend persisting nothing, for servers only keeping their data in memory (like in tests).
+/// All writes succeed right away, and there is never anything to read.
+pub struct Ephemeral;
+
+impl Backend for Ephemeral {
+    fn initialize(&mut self) -> InitializationResult {
+        Ok(Vec::new())
+    }
+
+    fn persist(&mut self, _entry: Entry) -> PersistResult {
+        Ok(())
+    }
+
+    fn commit(&mut self) -> PersistResult {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> PersistResult {
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<logfile::Parsed>, PersistError> {
+        Ok(Vec::new())
+    }
+
+    /// Do nothing, since there is nothing to compact.
+    fn compact(&mut self) -> Result<(), CompactError> {
+        Ok(())
+    }
+
+    /// Fail, since there is nothing to back up.
+    fn backup(&mut self, _path: PathBuf) -> Result<(), CompactError> {
+        error!("Unable to write a backup without persistence.");
+
+        Err(CompactError::Failure)
+    }
+
+    fn get_compaction(&mut self) -> Compaction {
+        Compaction {
+            running: None,
+            last: None,
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! The persisted Raft log of a cluster node, stored in its data directory.
+//!
+//! Two logfiles are used: `raft.state`, containing a single entry with the hard state of the node
+//! (rewritten at each change), and `raft.log`, containing the log entries following the compacted
+//! ones, along with their index. Appended entries are synchronized to the file system before
+//! returning, since they must be durable before answering the leader.
+//!
+//! Truncations and compactions atomically rewrite the whole `raft.log`. The hard state is always
+//! written first, and entries with an index lower or equal to the compacted index are ignored when
+//! loading: a crash between both writes is harmless. Both logfiles are encrypted with the keyring
+//! of the storage, like its own logfiles.
+
+use log::{error, warn};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use super::super::filesystem::Disk;
+use super::super::logfile;
+use super::super::logfile::Keyring;
+use super::raft::{HardState, LogEntry};
+
+pub enum LoadError {
+    CorruptedFile,
+    Undecryptable,
+    UnreadableFile,
+}
+
+pub struct Log {
+    state_path: PathBuf,
+    log_path: PathBuf,
+    file: Option<File>,
+    keyring: Keyring,
+}
+
+impl Log {
+    /// Create a new Log, stored in the given data directory and encrypted with the given keyring.
+    pub fn new(directory: &Path, keyring: &Keyring) -> Log {
+        Log {
+            state_path: directory.join("raft.state"),
+            log_path: directory.join("raft.log"),
+            file: None,
+            keyring: keyring.clone(),
+        }
+    }
+
+    /// Load the hard state and all log entries following the compacted ones. A missing file is
+    /// considered empty, and a torn entry at the end of a file is removed.
+    pub fn load(&mut self) -> Result<(HardState, Vec<LogEntry>), LoadError> {
+        let state = match load(&self.state_path, &self.keyring)?.last() {
+            Some(entry) => match decode_state(entry) {
+                Some(state) => state,
+                None => return Err(corrupted(&self.state_path)),
+            },
+            None => HardState::default(),
+        };
+
+        let mut entries = Vec::new();
+        for entry in load(&self.log_path, &self.keyring)? {
+            let (index, entry) = match decode_entry(&entry) {
+                Some(decoded) => decoded,
+                None => return Err(corrupted(&self.log_path)),
+            };
+            if index <= state.compacted {
+                continue;
+            };
+            if index != state.compacted + 1 + entries.len() as u64 {
+                return Err(corrupted(&self.log_path));
+            };
+            entries.push(entry);
+        };
+
+        Ok((state, entries))
+    }
+
+    /// Persist the given hard state.
+    pub fn save_state(&mut self, state: &HardState) -> Result<(), ()> {
+        if logfile::rewrite(&Disk, &self.state_path, &[encode_state(state)], &self.keyring).is_err() {
+            error!("Unable to write '{}'.", self.state_path.display());
+
+            return Err(());
+        };
+
+        Ok(())
+    }
+
+    /// Append the given entries to the log, along with their index.
+    pub fn append(&mut self, entries: &[(u64, LogEntry)]) -> Result<(), ()> {
+        if self.file.is_none() {
+            self.file = OpenOptions::new().append(true).create(true).open(&self.log_path).ok();
+        };
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                error!("Unable to open '{}'.", self.log_path.display());
+
+                return Err(());
+            },
+        };
+
+        let mut writer = logfile::Writer::new(file, &self.keyring);
+        let written = entries.iter().try_for_each(|(index, entry)| writer.write(&encode_entry(*index, entry))).and_then(|_| writer.sync());
+        if written.is_err() {
+            error!("Unable to write '{}'.", self.log_path.display());
+            // The file is reopened with the next entries.
+            self.file = None;
+
+            return Err(());
+        };
+
+        Ok(())
+    }
+
+    /// Replace the whole log by the given entries, along with their index.
+    pub fn rewrite<'a>(&mut self, entries: impl Iterator<Item = (u64, &'a LogEntry)>) -> Result<(), ()> {
+        self.file = None;
+        let encoded: Vec<logfile::Parsed> = entries.map(|(index, entry)| encode_entry(index, entry)).collect();
+
+        if logfile::rewrite(&Disk, &self.log_path, &encoded, &self.keyring).is_err() {
+            error!("Unable to write '{}'.", self.log_path.display());
+
+            return Err(());
+        };
+
+        Ok(())
+    }
+}
+
+/// Load all entries of the logfile at the given path, if it exists, decrypting them with the given
+/// keyring.
+fn load(path: &Path, keyring: &Keyring) -> Result<Vec<logfile::Parsed>, LoadError> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(_) => return Err(LoadError::UnreadableFile),
+    };
+
+    let content = match logfile::Reader::new(&mut file, keyring).read() {
+        Ok(content) if content.version == logfile::VERSION => content,
+        Ok(_) | Err(logfile::ReadError::CorruptedFile(_)) | Err(logfile::ReadError::UnsupportedVersion(_)) | Err(logfile::ReadError::UncheckedTail(_)) => return Err(corrupted(path)),
+        Err(logfile::ReadError::Undecryptable(offset)) => {
+            error!("'{}' contains an entry at offset {} that can't be decrypted with the configured keys.", path.display(), offset);
+
+            return Err(LoadError::Undecryptable);
+        },
+        Err(logfile::ReadError::UnreadableFile) => return Err(LoadError::UnreadableFile),
+    };
+    if let Some(length) = content.torn {
+        warn!("Removing the torn entry at the end of '{}' (starting at offset {}).", path.display(), length);
+        if logfile::truncate(&mut file, length).is_err() {
+            return Err(LoadError::UnreadableFile);
+        };
+    };
+
+    Ok(content.entries)
+}
+
+fn corrupted(path: &Path) -> LoadError {
+    error!("'{}' is corrupted.", path.display());
+
+    LoadError::CorruptedFile
+}
+
+/// Encode the given hard state: its term, the node it voted for (0 for none), and the index and
+/// term of the last compacted entry, all as big-endian 64 bits integers.
+fn encode_state(state: &HardState) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(32);
+    encoded.extend(state.term.to_be_bytes());
+    encoded.extend(state.voted_for.unwrap_or(0).to_be_bytes());
+    encoded.extend(state.compacted.to_be_bytes());
+    encoded.extend(state.compacted_term.to_be_bytes());
+
+    encoded
+}
+
+fn decode_state(data: &[u8]) -> Option<HardState> {
+    if data.len() != 32 {
+        return None;
+    };
+
+    Some(HardState {
+        term: read_u64(data, 0)?,
+        voted_for: Some(read_u64(data, 8)?).filter(|node| *node != 0),
+        compacted: read_u64(data, 16)?,
+        compacted_term: read_u64(data, 24)?,
+    })
+}
+
+/// Encode the given log entry with its index: the index and the term as big-endian 64 bits
+/// integers, followed by each persistence entry, prefixed by its size as a big-endian 32 bits
+/// integer.
+fn encode_entry(index: u64, entry: &LogEntry) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(16 + entry.entries.iter().map(|data| 4 + data.len()).sum::<usize>());
+    encoded.extend(index.to_be_bytes());
+    encoded.extend(entry.term.to_be_bytes());
+    for data in &entry.entries {
+        encoded.extend((data.len() as u32).to_be_bytes());
+        encoded.extend(data);
+    };
+
+    encoded
+}
+
+fn decode_entry(data: &[u8]) -> Option<(u64, LogEntry)> {
+    let index = read_u64(data, 0)?;
+    let term = read_u64(data, 8)?;
+
+    let mut entries = Vec::new();
+    let mut position = 16;
+    while position < data.len() {
+        let size = u32::from_be_bytes(data.get(position..position + 4)?.try_into().ok()?) as usize;
+        position += 4;
+        entries.push(data.get(position..position + size)?.to_vec());
+        position += size;
+    };
+
+    Some((index, LogEntry { term, entries }))
+}
+
+fn read_u64(data: &[u8], position: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(position..position + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let state = HardState { term: 3, voted_for: Some(2), compacted: 10, compacted_term: 2 };
+        assert_eq!(decode_state(&encode_state(&state)), Some(state));
+        let state = HardState { term: 1, voted_for: None, compacted: 0, compacted_term: 0 };
+        assert_eq!(decode_state(&encode_state(&state)), Some(state));
+        assert_eq!(decode_state(&[0; 31]), None);
+
+        let entry = LogEntry { term: 4, entries: vec![vec![1, 2], vec![], vec![3]] };
+        assert_eq!(decode_entry(&encode_entry(7, &entry)), Some((7, entry)));
+        let entry = LogEntry { term: 4, entries: Vec::new() };
+        assert_eq!(decode_entry(&encode_entry(8, &entry)), Some((8, entry)));
+        assert_eq!(decode_entry(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 1]), None);
+    }
+
+    #[test]
+    fn test_load() {
+        let directory = std::env::temp_dir().join(format!("kairoi-test-raft-log-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let entry = |term: u64| LogEntry { term, entries: vec![vec![term as u8]] };
+
+        let keyring = Keyring::new(Some(logfile::Key::new([1; 32])), vec![]);
+        let mut log = Log::new(&directory, &keyring);
+        assert!(matches!(log.load(), Ok((HardState { term: 0, voted_for: None, compacted: 0, compacted_term: 0 }, entries)) if entries.is_empty()));
+        log.append(&[(1, entry(1)), (2, entry(1))]).unwrap();
+        log.append(&[(3, entry(2))]).unwrap();
+        let (_, entries) = log.load().ok().unwrap();
+        assert_eq!(entries, vec![entry(1), entry(1), entry(2)]);
+
+        // Compacted entries are ignored, even before the log is rewritten.
+        let state = HardState { term: 2, voted_for: Some(1), compacted: 2, compacted_term: 1 };
+        log.save_state(&state).unwrap();
+        let (loaded, entries) = log.load().ok().unwrap();
+        assert_eq!(loaded, state);
+        assert_eq!(entries, vec![entry(2)]);
+        let kept = entry(2);
+        log.rewrite(vec![(3, &kept)].into_iter()).unwrap();
+        log.append(&[(4, entry(3))]).unwrap();
+        let (_, entries) = log.load().ok().unwrap();
+        assert_eq!(entries, vec![entry(2), entry(3)]);
+
+        // A gap in the log is a corruption.
+        log.append(&[(6, entry(3))]).unwrap();
+        assert!(matches!(log.load(), Err(LoadError::CorruptedFile)));
+        // The log can't be loaded without its key.
+        let mut log = Log::new(&directory, &Keyring::default());
+        assert!(matches!(log.load(), Err(LoadError::Undecryptable)));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+//! Clustered mode, replicating the persistent storage between several nodes with the Raft
+//! consensus algorithm (see the [`raft`] module).
+//!
+//! The [`Cluster`] runs in the writer thread, in place of direct writes to the logfile: entries
+//! persisted during a frame are proposed all at once by the commit of the frame, as a single entry
+//! of the replicated log, and the commit is only confirmed once this entry is committed by the
+//! cluster (stored by a majority of nodes). Committed entries are then written to the logfile of
+//! each node, which is the state machine of the replicated log. Only the leader accepts proposals:
+//! commits of other nodes are rejected.
+//!
+//! The database thread follows the cluster through [`Event`]s: leadership changes, and entries
+//! committed by other nodes, to apply in memory. When this node stops being the leader, its writes
+//! waiting to be committed may never be, so the database reloads all entries from the logfile,
+//! and the commits waiting for confirmation are rejected (their writes may still be committed by
+//! the next leader).
+//!
+//! The replicated log is compacted once applied to the logfile, keeping recent entries for lagging
+//! followers. A follower missing compacted entries receives a snapshot of the logfiles instead,
+//! sent in chunks.
+//!
+//! Nodes authenticate each other with the secret shared by the cluster, if any (read more in the
+//! [`handshake`](super::handshake) module).
+
+mod log;
+mod raft;
+mod transport;
+
+use ::log::{error, info};
+use crossbeam_channel::Sender as CrossbeamSender;
+use self::log::{Log, LoadError};
+use self::raft::Node;
+use self::transport::Peers;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use super::encoder::{Encodable, Encoder};
+use super::{Backend, Entry, InitializationError, Keyring, Secret};
+
+pub use self::raft::Envelope;
+
+/// The interval between two ticks of the Raft node.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// The number of applied entries kept in the replicated log when compacting it, for followers
+/// lagging behind.
+const LOG_RETENTION: u64 = 1000;
+/// The maximum size of the entries sent in a single snapshot message, in bytes.
+const SNAPSHOT_CHUNK_SIZE: usize = 1 << 20;
+
+pub struct Configuration {
+    /// The identifier of this node, unique in the cluster.
+    pub node: u64,
+    /// The address to listen to the other nodes.
+    pub listen: String,
+    /// The client address of this node, advertised to clients sending writes to another node.
+    pub address: String,
+    /// All other nodes of the cluster, with their identifier and the address they listen to.
+    pub peers: Vec<(u64, String)>,
+    /// The secret shared by all nodes of the cluster, if any.
+    pub secret: Option<Secret>,
+}
+
+/// A change of the cluster, to be followed by the database.
+pub enum Event {
+    /// This node became the leader: all committed entries have been applied, and it accepts
+    /// writes.
+    Leader,
+    /// This node follows the leader with the given client address, if known, rejecting writes.
+    Follower(Option<String>),
+    /// All entries must be forgotten: all entries of the logfile follow.
+    Reset,
+    /// An entry has been committed by the cluster.
+    Entry(Entry),
+}
+
+/// The outcome of a commit proposed to the cluster.
+pub enum Outcome {
+    Committed(u64),
+    /// The commit with the given sequence, and all previous ones not yet committed, have been
+    /// rejected.
+    Rejected(u64),
+}
+
+/// A commit proposed to the cluster, with the index and term of its log entry.
+struct Proposal {
+    index: u64,
+    term: u64,
+    sequence: u64,
+}
+
+pub struct Cluster {
+    node: Node,
+    log: Log,
+    peers: Peers,
+    encoder: Encoder,
+    /// The entries persisted since the last commit.
+    pending: Vec<Entry>,
+    proposals: VecDeque<Proposal>,
+    events: CrossbeamSender<Event>,
+    /// Whether this node was the leader, as last told to the database.
+    leading: bool,
+    /// The client address of the leader, as last told to the database.
+    leader_address: Option<String>,
+    last_tick: Instant,
+}
+
+impl Cluster {
+    /// Start the node with the given configuration, loading its replicated log from the given
+    /// data directory (encrypted with the given keyring). Messages received from other nodes are
+    /// wrapped with the given function, and sent to the given inbox.
+    pub fn start<M: Send + 'static>(configuration: Configuration, directory: &Path, keyring: &Keyring, inbox: Sender<M>, wrap: fn(Envelope) -> M, events: CrossbeamSender<Event>) -> Result<Cluster, InitializationError> {
+        let mut log = Log::new(directory, keyring);
+        let (state, entries) = match log.load() {
+            Ok(loaded) => loaded,
+            Err(LoadError::CorruptedFile) => return Err(InitializationError::CorruptedFile),
+            Err(LoadError::Undecryptable) => return Err(InitializationError::UndecryptableFile),
+            Err(LoadError::UnreadableFile) => return Err(InitializationError::UnreadableFile),
+        };
+        if transport::listen(&configuration.listen, configuration.secret.clone(), inbox, wrap).is_err() {
+            error!("Unable to listen to cluster peers on {}.", configuration.listen);
+
+            return Err(InitializationError::UnavailableAddress);
+        };
+
+        info!("Starting as the node {} of a cluster of {} nodes.", configuration.node, configuration.peers.len() + 1);
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_nanos() as u64).unwrap_or(0) ^ configuration.node;
+        let peers = configuration.peers.iter().map(|(node, _)| *node).collect();
+
+        Ok(Cluster {
+            node: Node::new(configuration.node, peers, configuration.address, state, entries, seed),
+            log,
+            peers: Peers::connect(&configuration.peers, configuration.secret),
+            encoder: Encoder::new(),
+            pending: Vec::new(),
+            proposals: VecDeque::new(),
+            events,
+            leading: false,
+            leader_address: None,
+            last_tick: Instant::now(),
+        })
+    }
+
+    /// Queue the given entry, to be proposed with the next commit.
+    pub fn persist(&mut self, entry: Entry) {
+        self.pending.push(entry);
+    }
+
+    /// Propose all entries persisted since the last commit, as the commit with the given
+    /// sequence. It fails if this node isn't the leader.
+    pub fn commit(&mut self, sequence: u64) -> Result<(), ()> {
+        let pending: Vec<Entry> = self.pending.drain(..).collect();
+        if !self.node.is_ready() {
+            return Err(());
+        };
+
+        let mut entries = Vec::with_capacity(pending.len());
+        for entry in pending {
+            match self.encoder.encode(Encodable::from(entry)) {
+                Ok(encoded) => entries.push(encoded),
+                Err(_) => return Err(()),
+            };
+        };
+        let index = self.node.propose(entries).ok_or(())?;
+        self.proposals.push_back(Proposal {
+            index,
+            term: self.node.get_term(),
+            sequence,
+        });
+
+        Ok(())
+    }
+
+    /// Handle the given message received from another node.
+    pub fn step(&mut self, envelope: Envelope) {
+        self.node.step(envelope);
+    }
+
+    /// Tick the node when due, then persist all changes of the node, apply committed entries to
+    /// the given storage, and send messages. It returns the outcome of proposed commits. It fails
+    /// when the replicated log or the storage can't be written.
+    pub fn process(&mut self, storage: &mut dyn Backend) -> Result<Vec<Outcome>, ()> {
+        if self.last_tick.elapsed() >= TICK_INTERVAL {
+            self.last_tick = Instant::now();
+            self.node.tick();
+        };
+
+        let ready = self.node.ready();
+        if let Some(state) = &ready.state {
+            self.log.save_state(state)?;
+        };
+        if ready.rewrite {
+            self.log.rewrite(self.node.get_entries())?;
+        } else if !ready.appended.is_empty() {
+            self.log.append(&ready.appended)?;
+        };
+
+        if let Some(snapshot) = ready.snapshot {
+            if storage.reset().is_err() {
+                error!("Unable to remove persisted entries before installing a snapshot.");
+
+                return Err(());
+            };
+            self.send(Event::Reset);
+            for data in snapshot {
+                self.apply(storage, &data, true)?;
+            };
+        };
+
+        let mut outcomes = Vec::new();
+        for (index, entry) in &ready.committed {
+            let proposed = self.proposals.front().map_or(false, |proposal| proposal.index == *index && proposal.term == entry.term);
+            for data in &entry.entries {
+                self.apply(storage, data, !proposed)?;
+            };
+            if proposed {
+                if let Some(proposal) = self.proposals.pop_front() {
+                    outcomes.push(Outcome::Committed(proposal.sequence));
+                };
+            };
+        };
+        if !ready.committed.is_empty() && storage.commit().is_err() {
+            error!("Unable to synchronize the logfile to the file system.");
+
+            return Err(());
+        };
+
+        self.follow_leadership(storage, &mut outcomes)?;
+
+        let mut snapshot = None;
+        for envelope in ready.messages {
+            if let raft::Message::Snapshot { .. } = envelope.message {
+                if snapshot.is_none() {
+                    snapshot = Some(storage.entries().map_err(|_| error!("Unable to read the logfiles for a snapshot."))?);
+                };
+                let entries = snapshot.as_deref().unwrap_or_default();
+                for chunk in envelope.split(entries, SNAPSHOT_CHUNK_SIZE) {
+                    self.peers.send(chunk);
+                };
+
+                continue;
+            };
+            self.peers.send(envelope);
+        };
+
+        let applied = self.node.get_applied();
+        if applied >= self.node.get_compacted() + 2 * LOG_RETENTION {
+            self.node.compact(applied - LOG_RETENTION);
+        };
+
+        Ok(outcomes)
+    }
+
+    /// Tell the database about leadership changes. When this node stops being the leader, all
+    /// waiting proposals are rejected, and the database reloads all entries from the logfile.
+    fn follow_leadership(&mut self, storage: &mut dyn Backend, outcomes: &mut Vec<Outcome>) -> Result<(), ()> {
+        let leading = self.node.is_ready();
+        let leader_address = self.node.get_leader_address().cloned();
+        if leading == self.leading && (leading || leader_address == self.leader_address) {
+            return Ok(());
+        };
+
+        if self.leading && !leading {
+            info!("This node isn't the leader of the cluster anymore.");
+            if let Some(proposal) = self.proposals.back() {
+                outcomes.push(Outcome::Rejected(proposal.sequence));
+            };
+            self.proposals.clear();
+
+            self.send(Event::Reset);
+            let entries = storage.entries().map_err(|_| error!("Unable to read the logfiles."))?;
+            for data in entries {
+                match self.encoder.decode(&data) {
+                    Ok(decoded) => self.send(Event::Entry(Entry::from(decoded))),
+                    Err(_) => {
+                        error!("Unable to decode a persisted entry.");
+
+                        return Err(());
+                    },
+                };
+            };
+        };
+        if leading && !self.leading {
+            info!("This node is now the leader of the cluster.");
+        };
+
+        self.send(match leading {
+            true => Event::Leader,
+            false => Event::Follower(leader_address.clone()),
+        });
+        self.leading = leading;
+        self.leader_address = leader_address;
+
+        Ok(())
+    }
+
+    /// Write the given encoded entry, committed by the cluster, to the given storage. The database
+    /// is told about it when notified.
+    fn apply(&self, storage: &mut dyn Backend, data: &[u8], notified: bool) -> Result<(), ()> {
+        let decode = || match self.encoder.decode(data) {
+            Ok(decoded) => Ok(Entry::from(decoded)),
+            Err(_) => {
+                error!("Unable to decode an entry committed by the cluster.");
+
+                Err(())
+            },
+        };
+
+        if storage.persist(decode()?).is_err() {
+            error!("Unable to persist an entry committed by the cluster.");
+
+            return Err(());
+        };
+        if notified {
+            self.send(Event::Entry(decode()?));
+        };
+
+        Ok(())
+    }
+
+    fn send(&self, event: Event) {
+        // The database thread stopping is detected by the writer thread.
+        let _ = self.events.send(event);
+    }
+}
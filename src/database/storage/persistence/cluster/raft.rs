@@ -0,0 +1,987 @@
+//! The Raft consensus algorithm, replicating a log between the nodes of a cluster.
+//!
+//! A [`Node`] is a pure state machine: it never uses the network, the file system or the clock by
+//! itself. It's driven by [`Node::tick`], called at a regular interval, by [`Node::step`] for each
+//! message received from another node, and by [`Node::propose`] to append new entries as the
+//! leader. All resulting changes are then collected with [`Node::ready`]: the caller must persist
+//! the hard state and the log before sending messages, then apply committed entries.
+//!
+//! Elections use randomized timeouts. A new leader appends an empty entry to the log, since
+//! entries of previous terms are only committed along with an entry of the current term. The
+//! leader is ready to accept proposals once this entry is applied: all entries of previous terms
+//! have been applied by then. A leader which didn't hear from a majority of the cluster during an
+//! election timeout steps down, so a leader isolated by a network partition stops accepting
+//! proposals that could never be committed.
+//!
+//! The beginning of the log can be compacted once applied. Followers lagging behind the compacted
+//! part of the log receive a snapshot of the state machine instead, split in chunks (see
+//! [`Envelope::split`]) so no message holds the whole state machine. A follower only installs a
+//! snapshot once all its chunks are received in order: after a lost chunk, the snapshot is sent
+//! again, since the follower still misses the compacted entries.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// The number of ticks between two heartbeats sent by the leader.
+const HEARTBEAT_TICKS: u32 = 2;
+/// The minimum number of ticks without hearing from a leader before starting an election. The
+/// actual timeout is randomized between this minimum and twice this minimum.
+const ELECTION_TICKS: u32 = 10;
+/// The maximum number of log entries sent in a single message.
+const MAX_ENTRIES: usize = 256;
+
+pub type NodeId = u64;
+
+/// An entry of the replicated log: a batch of encoded persistence entries, all committed at once.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LogEntry {
+    pub term: u64,
+    /// The encoded persistence entries. It's empty for the entry appended by a new leader.
+    pub entries: Vec<Vec<u8>>,
+}
+
+/// A message exchanged between nodes.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        /// The client address of the leader, to redirect clients to.
+        address: String,
+        previous_index: u64,
+        previous_term: u64,
+        entries: Vec<LogEntry>,
+        commit: u64,
+    },
+    /// The response to `AppendEntries` and `Snapshot` messages. On success, the index is the last
+    /// index known to match the leader's log. On failure, it's the index from which the leader
+    /// should try again.
+    Appended {
+        term: u64,
+        success: bool,
+        index: u64,
+    },
+    /// A chunk of the whole state machine, up to the given index. The entries are encoded
+    /// persistence entries, the latest entry of an item being the right one. The offset is the
+    /// number of entries sent by the previous chunks, and the last chunk is `done`.
+    Snapshot {
+        term: u64,
+        address: String,
+        index: u64,
+        last_term: u64,
+        offset: u64,
+        entries: Vec<Vec<u8>>,
+        done: bool,
+    },
+}
+
+impl Message {
+    fn get_term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. } => *term,
+            Message::Vote { term, .. } => *term,
+            Message::AppendEntries { term, .. } => *term,
+            Message::Appended { term, .. } => *term,
+            Message::Snapshot { term, .. } => *term,
+        }
+    }
+}
+
+/// A message, along with its sender and recipient.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Envelope {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub message: Message,
+}
+
+impl Envelope {
+    /// Split this message in chunks holding the given entries, with at most the given size of
+    /// entries each (in bytes, a single entry exceeding it being sent alone), when it's a
+    /// `Snapshot` message. Other messages are kept whole.
+    pub fn split(self, entries: &[Vec<u8>], size: usize) -> Vec<Envelope> {
+        let (term, address, index, last_term) = match self.message {
+            Message::Snapshot { term, address, index, last_term, .. } => (term, address, index, last_term),
+            message => return vec![Envelope { message, ..self }],
+        };
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        let mut length = 0;
+        for (position, entry) in entries.iter().enumerate() {
+            if position > start && length + entry.len() > size {
+                chunks.push(&entries[start..position]);
+                start = position;
+                length = 0;
+            };
+            length += entry.len();
+        };
+        chunks.push(&entries[start..]);
+
+        let (from, to, count) = (self.from, self.to, chunks.len());
+        let mut offset = 0;
+        chunks.into_iter().enumerate().map(|(position, chunk)| {
+            let envelope = Envelope {
+                from,
+                to,
+                message: Message::Snapshot {
+                    term,
+                    address: address.clone(),
+                    index,
+                    last_term,
+                    offset,
+                    entries: chunk.to_vec(),
+                    done: position + 1 == count,
+                },
+            };
+            offset += chunk.len() as u64;
+
+            envelope
+        }).collect()
+    }
+}
+
+/// The state of a node that must be persisted before sending any message.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    /// The index of the last compacted entry of the log.
+    pub compacted: u64,
+    /// The term of the last compacted entry of the log.
+    pub compacted_term: u64,
+}
+
+/// All changes of a node since the last call to [`Node::ready`].
+#[derive(Debug, Default, PartialEq)]
+pub struct Ready {
+    /// The new hard state to persist, if it changed.
+    pub state: Option<HardState>,
+    /// Whether the whole log must be rewritten (after a truncation or a compaction). Appended
+    /// entries are then omitted.
+    pub rewrite: bool,
+    /// The entries to append to the persisted log, along with their index.
+    pub appended: Vec<(u64, LogEntry)>,
+    /// The snapshot received from the leader, replacing the whole state machine.
+    pub snapshot: Option<Vec<Vec<u8>>>,
+    /// The newly committed entries, to apply to the state machine in order, along with their
+    /// index.
+    pub committed: Vec<(u64, LogEntry)>,
+    /// The messages to send, once everything else is persisted. `Snapshot` messages are left
+    /// empty: they must be split in chunks holding the state machine, as applied up to the last
+    /// committed entry of this ready (see [`Envelope::split`]).
+    pub messages: Vec<Envelope>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// The chunks of a snapshot received so far by a follower.
+struct Receiving {
+    term: u64,
+    index: u64,
+    entries: Vec<Vec<u8>>,
+}
+
+/// The replication progress of a follower, tracked by the leader.
+struct Progress {
+    /// The index of the next entry to send.
+    next: u64,
+    /// The index of the last entry known to be replicated.
+    matched: u64,
+}
+
+pub struct Node {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    /// The client address of this node, sent to followers when leader.
+    address: String,
+    state: HardState,
+    /// All entries of the log following the compacted ones.
+    entries: Vec<LogEntry>,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<NodeId>,
+    leader_address: Option<String>,
+    votes: HashSet<NodeId>,
+    progress: HashMap<NodeId, Progress>,
+    /// The peers heard from by the leader since the last check of its quorum.
+    active: HashSet<NodeId>,
+    /// The index of the entry appended by this node when it became leader.
+    first_index: u64,
+    elapsed: u32,
+    timeout: u32,
+    random: u64,
+    state_changed: bool,
+    rewrite: bool,
+    unpersisted: Option<u64>,
+    snapshot: Option<Vec<Vec<u8>>>,
+    receiving: Option<Receiving>,
+    messages: Vec<Envelope>,
+}
+
+impl Node {
+    /// Create a new follower node, with its persisted hard state and log entries (following the
+    /// compacted ones). The given seed randomizes election timeouts.
+    pub fn new(id: NodeId, peers: Vec<NodeId>, address: String, state: HardState, entries: Vec<LogEntry>, seed: u64) -> Node {
+        let mut node = Node {
+            id,
+            peers,
+            address,
+            commit: state.compacted,
+            applied: state.compacted,
+            state,
+            entries,
+            role: Role::Follower,
+            leader: None,
+            leader_address: None,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            active: HashSet::new(),
+            first_index: 0,
+            elapsed: 0,
+            timeout: ELECTION_TICKS,
+            // The xorshift state must never be zero.
+            random: seed | 1,
+            state_changed: false,
+            rewrite: false,
+            unpersisted: None,
+            snapshot: None,
+            receiving: None,
+            messages: Vec::new(),
+        };
+        node.reset_timeout();
+
+        node
+    }
+
+    /// Check whether this node is the leader, ready to accept proposals.
+    pub fn is_ready(&self) -> bool {
+        self.role == Role::Leader && self.applied >= self.first_index
+    }
+
+    /// Get the current term.
+    pub fn get_term(&self) -> u64 {
+        self.state.term
+    }
+
+    /// Get the client address of the current leader, if known.
+    pub fn get_leader_address(&self) -> Option<&String> {
+        self.leader_address.as_ref()
+    }
+
+    /// Get the index of the last entry applied to the state machine.
+    pub fn get_applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// Get the index of the last compacted entry.
+    pub fn get_compacted(&self) -> u64 {
+        self.state.compacted
+    }
+
+    /// Get all entries of the log following the compacted ones, along with their index.
+    pub fn get_entries(&self) -> impl Iterator<Item = (u64, &LogEntry)> {
+        let compacted = self.state.compacted;
+
+        self.entries.iter().enumerate().map(move |(offset, entry)| (compacted + 1 + offset as u64, entry))
+    }
+
+    /// Advance the logical clock of this node by a tick.
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+
+        if self.role != Role::Leader {
+            if self.elapsed >= self.timeout {
+                self.campaign();
+            };
+
+            return;
+        };
+
+        if self.elapsed % HEARTBEAT_TICKS == 0 {
+            self.broadcast_append();
+        };
+        if self.elapsed >= self.timeout {
+            if self.active.len() + 1 < self.quorum() {
+                warn!("Stepping down from the leadership of the term {}, without hearing from a majority of the cluster.", self.state.term);
+                self.become_follower(self.state.term, None);
+            } else {
+                self.active.clear();
+            };
+            self.elapsed = 0;
+        };
+    }
+
+    /// Append the given encoded persistence entries to the log, as a single log entry. It returns
+    /// the index of the new log entry, or nothing if this node isn't a ready leader.
+    pub fn propose(&mut self, entries: Vec<Vec<u8>>) -> Option<u64> {
+        if !self.is_ready() {
+            return None;
+        };
+
+        self.append(LogEntry {
+            term: self.state.term,
+            entries,
+        });
+        self.broadcast_append();
+        self.advance_commit();
+
+        Some(self.last_index())
+    }
+
+    /// Compact the log up to the given index, which must be applied.
+    pub fn compact(&mut self, index: u64) {
+        if index <= self.state.compacted || index > self.applied {
+            return;
+        };
+
+        self.state.compacted_term = self.term_at(index).unwrap_or(self.state.compacted_term);
+        self.entries.drain(..(index - self.state.compacted) as usize);
+        self.state.compacted = index;
+        self.state_changed = true;
+        self.rewrite = true;
+    }
+
+    /// Handle the given message, received from another node.
+    pub fn step(&mut self, envelope: Envelope) {
+        let from = envelope.from;
+        if envelope.to != self.id || !self.peers.contains(&from) {
+            return;
+        };
+
+        let term = envelope.message.get_term();
+        if term > self.state.term {
+            let leader = match envelope.message {
+                Message::AppendEntries { .. } | Message::Snapshot { .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(term, leader);
+        };
+        if term < self.state.term {
+            // Stale nodes are told about the current term.
+            match envelope.message {
+                Message::RequestVote { .. } => self.send(from, Message::Vote { term: self.state.term, granted: false }),
+                Message::AppendEntries { .. } | Message::Snapshot { .. } => self.send(from, Message::Appended { term: self.state.term, success: false, index: 0 }),
+                _ => {},
+            };
+
+            return;
+        };
+
+        match envelope.message {
+            Message::RequestVote { last_index, last_term, .. } => {
+                let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+                let granted = up_to_date && self.state.voted_for.map_or(true, |voted_for| voted_for == from);
+                if granted {
+                    self.state.voted_for = Some(from);
+                    self.state_changed = true;
+                    self.elapsed = 0;
+                };
+
+                self.send(from, Message::Vote { term, granted });
+            },
+            Message::Vote { granted, .. } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    };
+                };
+            },
+            Message::AppendEntries { address, previous_index, previous_term, entries, commit, .. } => {
+                if self.role == Role::Leader {
+                    return;
+                };
+                self.follow(from, address);
+                self.append_entries(from, previous_index, previous_term, entries, commit);
+            },
+            Message::Snapshot { address, index, last_term, offset, entries, done, .. } => {
+                if self.role == Role::Leader {
+                    return;
+                };
+                self.follow(from, address);
+                if let Some(entries) = self.receive_snapshot(term, index, offset, entries, done) {
+                    self.install_snapshot(from, index, last_term, entries);
+                };
+            },
+            Message::Appended { success, index, .. } => {
+                if self.role == Role::Leader {
+                    self.active.insert(from);
+                    self.appended(from, success, index);
+                };
+            },
+        };
+    }
+
+    /// Collect all changes since the last call.
+    pub fn ready(&mut self) -> Ready {
+        let mut ready = Ready::default();
+
+        if self.state_changed {
+            self.state_changed = false;
+            ready.state = Some(self.state.clone());
+        };
+        if self.rewrite {
+            self.rewrite = false;
+            self.unpersisted = None;
+            ready.rewrite = true;
+        } else if let Some(first) = self.unpersisted.take() {
+            ready.appended = (first..=self.last_index()).filter_map(|index| Some((index, self.entry(index)?.clone()))).collect();
+        };
+        ready.snapshot = self.snapshot.take();
+        if self.commit > self.applied {
+            ready.committed = (self.applied + 1..=self.commit).filter_map(|index| Some((index, self.entry(index)?.clone()))).collect();
+            self.applied = self.commit;
+        };
+        ready.messages = self.messages.drain(..).collect();
+
+        ready
+    }
+
+    /// Start an election for a new term.
+    fn campaign(&mut self) {
+        self.state.term += 1;
+        self.state.voted_for = Some(self.id);
+        self.state_changed = true;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.leader_address = None;
+        self.votes = HashSet::new();
+        self.votes.insert(self.id);
+        self.reset_timeout();
+        info!("Starting an election for the term {}.", self.state.term);
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+
+            return;
+        };
+        let (last_index, last_term) = (self.last_index(), self.last_term());
+        for peer in self.peers.clone() {
+            self.send(peer, Message::RequestVote { term: self.state.term, last_index, last_term });
+        };
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.state.term {
+            self.state.term = term;
+            self.state.voted_for = None;
+            self.state_changed = true;
+        };
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.leader_address = None;
+        self.progress.clear();
+    }
+
+    fn become_leader(&mut self) {
+        info!("Elected as the leader of the term {}.", self.state.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.leader_address = Some(self.address.clone());
+        self.active.clear();
+        self.elapsed = 0;
+        let next = self.last_index() + 1;
+        self.progress = self.peers.iter().map(|peer| (*peer, Progress { next, matched: 0 })).collect();
+
+        self.append(LogEntry {
+            term: self.state.term,
+            entries: Vec::new(),
+        });
+        self.first_index = self.last_index();
+        self.broadcast_append();
+        self.advance_commit();
+    }
+
+    /// Follow the given leader, with the given client address, for the current term.
+    fn follow(&mut self, leader: NodeId, address: String) {
+        self.role = Role::Follower;
+        self.leader = Some(leader);
+        self.leader_address = Some(address);
+        self.elapsed = 0;
+    }
+
+    /// Append the given entries sent by the leader after the given previous entry, if the log
+    /// contains it.
+    fn append_entries(&mut self, leader: NodeId, previous_index: u64, previous_term: u64, entries: Vec<LogEntry>, commit: u64) {
+        if previous_index > self.last_index() {
+            self.send(leader, Message::Appended { term: self.state.term, success: false, index: self.last_index() });
+
+            return;
+        };
+        // Compacted entries are committed, so they always match the leader's log.
+        if let Some(term) = self.term_at(previous_index).filter(|_| previous_index > self.state.compacted) {
+            if term != previous_term {
+                // Skip all entries of the conflicting term at once.
+                let mut index = previous_index;
+                while index - 1 > self.state.compacted && self.term_at(index - 1) == Some(term) {
+                    index -= 1;
+                };
+                self.send(leader, Message::Appended { term: self.state.term, success: false, index: index - 1 });
+
+                return;
+            };
+        };
+
+        let last = previous_index + entries.len() as u64;
+        for (index, entry) in (previous_index + 1..).zip(entries) {
+            if index <= self.state.compacted {
+                continue;
+            };
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    // Conflicting entries are never committed, and are removed.
+                    self.entries.truncate((index - self.state.compacted - 1) as usize);
+                    self.rewrite = true;
+                    self.append(entry);
+                },
+                None => self.append(entry),
+            };
+        };
+        if commit > self.commit {
+            self.commit = self.commit.max(commit.min(last));
+        };
+
+        self.send(leader, Message::Appended { term: self.state.term, success: true, index: last });
+    }
+
+    /// Collect the given chunk of a snapshot sent by the leader, returning all entries of the
+    /// snapshot once its last chunk is received. A chunk not following the previous one (after a
+    /// lost chunk) is ignored, until the leader sends the snapshot again.
+    fn receive_snapshot(&mut self, term: u64, index: u64, offset: u64, entries: Vec<Vec<u8>>, done: bool) -> Option<Vec<Vec<u8>>> {
+        let mut receiving = match self.receiving.take() {
+            Some(receiving) if receiving.term == term && receiving.index == index && receiving.entries.len() as u64 == offset => receiving,
+            _ if offset == 0 => Receiving {
+                term,
+                index,
+                entries: Vec::new(),
+            },
+            _ => {
+                warn!("Ignoring a chunk of a snapshot of the leader, following a lost chunk.");
+
+                return None;
+            },
+        };
+        receiving.entries.extend(entries);
+        if !done {
+            self.receiving = Some(receiving);
+
+            return None;
+        };
+
+        Some(receiving.entries)
+    }
+
+    /// Replace the state machine and the log by the given snapshot sent by the leader, unless all
+    /// its entries are already committed.
+    fn install_snapshot(&mut self, leader: NodeId, index: u64, last_term: u64, entries: Vec<Vec<u8>>) {
+        if index > self.commit {
+            info!("Installing a snapshot of the leader, up to the index {}.", index);
+            self.state.compacted = index;
+            self.state.compacted_term = last_term;
+            self.state_changed = true;
+            self.entries.clear();
+            self.rewrite = true;
+            self.commit = index;
+            self.applied = index;
+            self.snapshot = Some(entries);
+        };
+
+        self.send(leader, Message::Appended { term: self.state.term, success: true, index });
+    }
+
+    /// Handle the response of the given follower to entries or a snapshot.
+    fn appended(&mut self, follower: NodeId, success: bool, index: u64) {
+        let progress = match self.progress.get_mut(&follower) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if success {
+            progress.matched = progress.matched.max(index);
+            progress.next = progress.next.max(index + 1);
+            self.advance_commit();
+        } else {
+            progress.next = index + 1;
+            progress.matched = progress.matched.min(index);
+        };
+
+        if self.progress.get(&follower).map_or(false, |progress| progress.next <= self.last_index()) {
+            self.send_append(follower);
+        };
+    }
+
+    /// Commit all entries stored by a majority of the cluster, when they include an entry of the
+    /// current term.
+    fn advance_commit(&mut self) {
+        for index in (self.commit + 1..=self.last_index()).rev() {
+            if self.term_at(index) != Some(self.state.term) {
+                break;
+            };
+            let replicated = 1 + self.progress.values().filter(|progress| progress.matched >= index).count();
+            if replicated >= self.quorum() {
+                self.commit = index;
+
+                break;
+            };
+        };
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        };
+    }
+
+    /// Send all entries the given follower is missing (or a snapshot, if they are compacted).
+    /// Entries are considered sent right away: on failure, the follower tells where to restart.
+    fn send_append(&mut self, follower: NodeId) {
+        let next = match self.progress.get(&follower) {
+            Some(progress) => progress.next,
+            None => return,
+        };
+
+        let (message, next) = if next <= self.state.compacted {
+            (Message::Snapshot {
+                term: self.state.term,
+                address: self.address.clone(),
+                index: self.applied,
+                last_term: self.term_at(self.applied).unwrap_or(0),
+                offset: 0,
+                entries: Vec::new(),
+                done: true,
+            }, self.applied + 1)
+        } else {
+            let previous_index = next - 1;
+            let entries: Vec<LogEntry> = (next..=self.last_index()).take(MAX_ENTRIES).filter_map(|index| self.entry(index).cloned()).collect();
+            let next = next + entries.len() as u64;
+
+            (Message::AppendEntries {
+                term: self.state.term,
+                address: self.address.clone(),
+                previous_index,
+                previous_term: self.term_at(previous_index).unwrap_or(0),
+                entries,
+                commit: self.commit,
+            }, next)
+        };
+        if let Some(progress) = self.progress.get_mut(&follower) {
+            progress.next = next;
+        };
+
+        self.send(follower, message);
+    }
+
+    fn send(&mut self, to: NodeId, message: Message) {
+        self.messages.push(Envelope {
+            from: self.id,
+            to,
+            message,
+        });
+    }
+
+    fn append(&mut self, entry: LogEntry) {
+        self.entries.push(entry);
+        let index = self.last_index();
+        self.unpersisted.get_or_insert(index);
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        match index > self.state.compacted {
+            true => self.entries.get((index - self.state.compacted - 1) as usize),
+            false => None,
+        }
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index == self.state.compacted {
+            true => Some(self.state.compacted_term),
+            false => self.entry(index).map(|entry| entry.term),
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.state.compacted + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or(0)
+    }
+
+    /// The number of nodes forming a majority of the cluster.
+    fn quorum(&self) -> usize {
+        (self.peers.len() + 1) / 2 + 1
+    }
+
+    /// Draw a new randomized election timeout, and restart counting.
+    fn reset_timeout(&mut self) {
+        // Xorshift, which is enough to desynchronize nodes.
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.timeout = ELECTION_TICKS + (self.random % ELECTION_TICKS as u64) as u32;
+        self.elapsed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cluster of nodes, delivering messages between connected nodes.
+    struct Cluster {
+        nodes: HashMap<NodeId, Node>,
+        /// The nodes whose messages are dropped.
+        isolated: HashSet<NodeId>,
+        committed: HashMap<NodeId, Vec<(u64, LogEntry)>>,
+        snapshots: HashMap<NodeId, Vec<Vec<u8>>>,
+    }
+
+    impl Cluster {
+        fn new(size: u64) -> Cluster {
+            let ids: Vec<NodeId> = (1..=size).collect();
+            let nodes = ids.iter().map(|id| {
+                let peers = ids.iter().copied().filter(|peer| peer != id).collect();
+
+                (*id, Node::new(*id, peers, format!("node-{}", id), HardState::default(), Vec::new(), *id))
+            }).collect();
+
+            Cluster {
+                nodes,
+                isolated: HashSet::new(),
+                committed: HashMap::new(),
+                snapshots: HashMap::new(),
+            }
+        }
+
+        fn node(&mut self, id: NodeId) -> &mut Node {
+            self.nodes.get_mut(&id).unwrap()
+        }
+
+        /// Deliver all messages, until there is none left.
+        fn deliver(&mut self) {
+            loop {
+                let mut envelopes = Vec::new();
+                for (id, node) in self.nodes.iter_mut() {
+                    let ready = node.ready();
+                    self.committed.entry(*id).or_default().extend(ready.committed);
+                    if let Some(snapshot) = ready.snapshot {
+                        self.snapshots.insert(*id, snapshot);
+                    };
+                    envelopes.extend(ready.messages);
+                };
+                if envelopes.is_empty() {
+                    return;
+                };
+                for envelope in envelopes {
+                    if self.isolated.contains(&envelope.from) || self.isolated.contains(&envelope.to) {
+                        continue;
+                    };
+                    self.node(envelope.to).step(envelope);
+                };
+            };
+        }
+
+        /// Tick the given node until it starts an election, and deliver all messages.
+        fn elect(&mut self, id: NodeId) {
+            let node = self.node(id);
+            let term = node.state.term;
+            while node.state.term == term {
+                node.tick();
+            };
+            self.deliver();
+        }
+
+        fn committed(&self, id: NodeId) -> Vec<Vec<Vec<u8>>> {
+            self.committed[&id].iter().map(|(_, entry)| entry.entries.clone()).filter(|entries| !entries.is_empty()).collect()
+        }
+    }
+
+    #[test]
+    fn test_election() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(2);
+        assert!(cluster.node(2).is_ready());
+        assert_eq!(cluster.node(2).get_leader_address(), Some(&String::from("node-2")));
+        assert!(!cluster.node(1).is_ready());
+        assert_eq!(cluster.node(1).get_leader_address(), Some(&String::from("node-2")));
+        assert_eq!(cluster.node(3).leader, Some(2));
+        assert_eq!(cluster.node(3).state.term, 1);
+        assert_eq!(cluster.node(3).state.voted_for, Some(2));
+
+        // A single node elects itself.
+        let mut cluster = Cluster::new(1);
+        cluster.elect(1);
+        assert!(cluster.node(1).is_ready());
+    }
+
+    #[test]
+    fn test_replication() {
+        let mut cluster = Cluster::new(3);
+        assert_eq!(cluster.node(1).propose(vec![vec![1]]), None);
+        cluster.elect(1);
+        assert_eq!(cluster.node(1).propose(vec![vec![1], vec![2]]), Some(2));
+        assert_eq!(cluster.node(1).propose(vec![vec![3]]), Some(3));
+        cluster.deliver();
+        assert_eq!(cluster.committed(1), vec![vec![vec![1], vec![2]], vec![vec![3]]]);
+        // Followers learn about the commit with the next heartbeat.
+        cluster.node(1).tick();
+        cluster.node(1).tick();
+        cluster.deliver();
+        for id in 2..=3 {
+            assert_eq!(cluster.committed(id), vec![vec![vec![1], vec![2]], vec![vec![3]]]);
+        };
+
+        // A majority is enough to commit.
+        cluster.isolated.insert(3);
+        cluster.node(1).propose(vec![vec![4]]);
+        cluster.deliver();
+        assert_eq!(cluster.committed(1).len(), 3);
+        cluster.isolated.clear();
+        cluster.node(1).tick();
+        cluster.node(1).tick();
+        cluster.deliver();
+        assert_eq!(cluster.committed(3).len(), 3);
+    }
+
+    #[test]
+    fn test_leadership_change() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        cluster.node(1).propose(vec![vec![1]]);
+        cluster.deliver();
+
+        // The isolated leader keeps entries it can't commit.
+        cluster.isolated.insert(1);
+        cluster.node(1).propose(vec![vec![2]]);
+        cluster.deliver();
+        assert_eq!(cluster.committed(1).len(), 1);
+
+        // A new leader is elected by the majority, and commits entries of the previous term.
+        cluster.elect(2);
+        assert!(cluster.node(2).is_ready());
+        assert_eq!(cluster.committed(2), vec![vec![vec![1]]]);
+        cluster.node(2).propose(vec![vec![3]]);
+        cluster.deliver();
+
+        // The old leader steps down once it sees the new term, dropping its conflicting entry.
+        cluster.isolated.clear();
+        cluster.node(2).tick();
+        cluster.node(2).tick();
+        cluster.deliver();
+        assert!(!cluster.node(1).is_ready());
+        assert_eq!(cluster.committed(1), vec![vec![vec![1]], vec![vec![3]]]);
+        let logs: Vec<Vec<LogEntry>> = (1..=3).map(|id| cluster.node(id).entries.clone()).collect();
+        assert_eq!(logs[0], logs[1]);
+        assert_eq!(logs[1], logs[2]);
+    }
+
+    #[test]
+    fn test_vote_requires_an_up_to_date_log() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        cluster.isolated.insert(3);
+        cluster.node(1).propose(vec![vec![1]]);
+        cluster.deliver();
+        cluster.isolated.clear();
+
+        // The node 3 misses an entry: the node 2 refuses to vote for it.
+        cluster.isolated.insert(1);
+        cluster.elect(3);
+        assert!(!cluster.node(3).is_ready());
+        cluster.elect(2);
+        assert!(cluster.node(2).is_ready());
+    }
+
+    #[test]
+    fn test_leader_steps_down_without_quorum() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        cluster.isolated.insert(1);
+        // The quorum is checked at each election timeout, the first check counting the peers
+        // heard from during the election.
+        for _ in 0..4 * ELECTION_TICKS {
+            cluster.node(1).tick();
+        };
+        cluster.deliver();
+        assert!(!cluster.node(1).is_ready());
+        assert_eq!(cluster.node(1).get_leader_address(), None);
+    }
+
+    #[test]
+    fn test_compaction_and_snapshot() {
+        let mut cluster = Cluster::new(3);
+        cluster.elect(1);
+        cluster.isolated.insert(3);
+        for value in 0..5 {
+            cluster.node(1).propose(vec![vec![value]]);
+        };
+        cluster.deliver();
+        cluster.node(1).compact(4);
+        assert_eq!(cluster.node(1).get_compacted(), 4);
+        let ready = cluster.node(1).ready();
+        assert!(ready.rewrite);
+        assert_eq!(ready.state.map(|state| (state.compacted, state.compacted_term)), Some((4, 1)));
+        assert_eq!(cluster.node(1).get_entries().map(|(index, _)| index).collect::<Vec<u64>>(), vec![5, 6]);
+
+        // The lagging follower receives a snapshot, then the following entries.
+        cluster.isolated.clear();
+        cluster.node(1).tick();
+        cluster.node(1).tick();
+        cluster.deliver();
+        assert_eq!(cluster.snapshots.get(&3), Some(&Vec::new()));
+        assert_eq!(cluster.node(3).get_compacted(), 6);
+        assert_eq!(cluster.node(3).get_applied(), 6);
+        cluster.node(1).propose(vec![vec![5]]);
+        cluster.deliver();
+        cluster.node(1).tick();
+        cluster.node(1).tick();
+        cluster.deliver();
+        assert_eq!(cluster.committed(3), vec![vec![vec![5]]]);
+    }
+    #[test]
+    fn test_snapshot_chunks() {
+        let snapshot = |offset, entries: Vec<Vec<u8>>, done| Envelope {
+            from: 1,
+            to: 2,
+            message: Message::Snapshot { term: 1, address: String::from("node-1"), index: 4, last_term: 1, offset, entries, done },
+        };
+        let entries = vec![vec![1, 2], vec![3], vec![4, 5, 6, 7], vec![8]];
+        let chunks = snapshot(0, Vec::new(), true).split(&entries, 3);
+        assert_eq!(chunks, vec![
+            snapshot(0, vec![vec![1, 2], vec![3]], false),
+            snapshot(2, vec![vec![4, 5, 6, 7]], false),
+            snapshot(3, vec![vec![8]], true),
+        ]);
+        assert_eq!(snapshot(0, Vec::new(), true).split(&[], 3), vec![snapshot(0, Vec::new(), true)]);
+        let vote = Envelope { from: 1, to: 2, message: Message::Vote { term: 1, granted: true } };
+        assert_eq!(vote.clone().split(&entries, 3), vec![vote]);
+
+        // The snapshot is installed once all chunks are received in order.
+        let mut follower = Node::new(2, vec![1, 3], String::from("node-2"), HardState::default(), Vec::new(), 2);
+        follower.step(chunks[0].clone());
+        follower.step(chunks[2].clone());
+        assert_eq!(follower.ready().snapshot, None);
+        for chunk in &chunks {
+            follower.step(chunk.clone());
+        };
+        assert_eq!(follower.ready().snapshot, Some(entries));
+        assert_eq!(follower.get_applied(), 4);
+    }
+}
@@ -0,0 +1,217 @@
+//! The transport of Raft messages between the nodes of a cluster, through TCP connections.
+//!
+//! Each node listens to its peers at its cluster address, and connects to each one of its peers:
+//! messages always flow in a single direction on a connection. Messages are sent as JSON
+//! documents, one per line. Since Raft tolerates lost messages, messages sent to a peer that can't
+//! be reached are dropped, and the connection is attempted again with a following message.
+//!
+//! Each connection starts with a handshake, through which the connecting node proves it knows the
+//! secret of the cluster (if any), before any message is sent.
+
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::raft::{Envelope, NodeId};
+use super::super::handshake::{self, Error as HandshakeError, Secret};
+
+/// The maximum duration of a connection attempt to a peer.
+const CONNECTION_TIMEOUT: Duration = Duration::from_millis(500);
+/// The minimum duration between two connection attempts to a peer.
+const RECONNECTION_INTERVAL: Duration = Duration::from_secs(1);
+/// The maximum duration of a write to a peer, before considering the connection lost.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Listen to peers at the given address, in a dedicated thread (`kairoi/cluster`), challenging
+/// them with the given secret. Each received message is wrapped with the given function, and sent
+/// to the given inbox.
+pub fn listen<M: Send + 'static>(address: &str, secret: Option<Secret>, inbox: Sender<M>, wrap: fn(Envelope) -> M) -> Result<(), ()> {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(_) => return Err(()),
+    };
+    info!("Listening to cluster peers on {}.", address);
+
+    thread::Builder::new().name("kairoi/cluster".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let inbox = inbox.clone();
+                    let secret = secret.clone();
+                    let spawned = thread::Builder::new().name("kairoi/peer".to_string()).spawn(move || authenticate(stream, secret, inbox, wrap));
+                    if spawned.is_err() {
+                        error!("Unable to start receiving messages from a peer.");
+                    };
+                },
+                Err(error) => warn!("Unable to accept the connection of a peer: {}.", error),
+            };
+        };
+    }).unwrap();
+
+    Ok(())
+}
+
+/// Challenge the peer connected through the given stream with the given secret, then receive its
+/// messages.
+fn authenticate<M>(mut stream: TcpStream, secret: Option<Secret>, inbox: Sender<M>, wrap: fn(Envelope) -> M) {
+    let address = match stream.peer_addr() {
+        Ok(address) => address.to_string(),
+        Err(_) => String::from("unknown"),
+    };
+    match handshake::challenge(&mut stream, secret.as_ref()) {
+        Ok(_) => receive(stream, inbox, wrap),
+        Err(HandshakeError::Rejected) => warn!("Rejected the peer {} (invalid secret).", address),
+        Err(_) => warn!("Unable to authenticate the peer {}.", address),
+    };
+}
+
+/// Receive messages from the given stream, until the connection is lost or the inbox is dropped.
+fn receive<M>(stream: TcpStream, inbox: Sender<M>, wrap: fn(Envelope) -> M) {
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        match serde_json::from_str(&line) {
+            Ok(envelope) => if inbox.send(wrap(envelope)).is_err() {
+                return;
+            },
+            Err(_) => {
+                warn!("Invalid message received from a peer, closing the connection.");
+
+                return;
+            },
+        };
+    };
+}
+
+/// The connections to all peers of a node, each one handled by a dedicated thread
+/// (`kairoi/peer`).
+pub struct Peers {
+    senders: HashMap<NodeId, Sender<Envelope>>,
+}
+
+impl Peers {
+    /// Start sending messages to the given peers, with their cluster address, authenticating with
+    /// the given secret.
+    pub fn connect(peers: &[(NodeId, String)], secret: Option<Secret>) -> Peers {
+        let mut senders = HashMap::new();
+        for (node, address) in peers {
+            let (sender, receiver) = channel();
+            let (node, address, secret) = (*node, address.clone(), secret.clone());
+            thread::Builder::new().name("kairoi/peer".to_string()).spawn(move || send(node, address, secret, receiver)).unwrap();
+            senders.insert(node, sender);
+        };
+
+        Peers {
+            senders,
+        }
+    }
+
+    /// Send the given message to its recipient, in background.
+    pub fn send(&self, envelope: Envelope) {
+        if let Some(sender) = self.senders.get(&envelope.to) {
+            // The thread only stops once the sender is dropped.
+            let _ = sender.send(envelope);
+        };
+    }
+}
+
+/// Send all messages received from the given receiver to the given peer, until the receiver is
+/// disconnected.
+fn send(node: NodeId, address: String, secret: Option<Secret>, messages: Receiver<Envelope>) {
+    let mut writer: Option<BufWriter<TcpStream>> = None;
+    let mut last_attempt: Option<Instant> = None;
+
+    while let Ok(envelope) = messages.recv() {
+        if writer.is_none() {
+            if last_attempt.map_or(false, |attempt| attempt.elapsed() < RECONNECTION_INTERVAL) {
+                continue;
+            };
+            last_attempt = Some(Instant::now());
+            let mut stream = match connect(&address) {
+                Some(stream) => stream,
+                None => {
+                    debug!("Unable to connect to the peer {} ({}).", node, address);
+
+                    continue;
+                },
+            };
+            writer = match handshake::answer(&mut stream, secret.as_ref()) {
+                Ok(_) => {
+                    info!("Connected to the peer {} ({}).", node, address);
+
+                    Some(BufWriter::new(stream))
+                },
+                Err(HandshakeError::SecretRequired) => {
+                    error!("Unable to authenticate to the peer {} ({}): a secret is required.", node, address);
+
+                    continue;
+                },
+                Err(HandshakeError::Rejected) => {
+                    error!("Unable to authenticate to the peer {} ({}): the secret is invalid.", node, address);
+
+                    continue;
+                },
+                Err(HandshakeError::Io) => {
+                    debug!("Unable to authenticate to the peer {} ({}).", node, address);
+
+                    continue;
+                },
+            };
+        };
+
+        if let Some(stream) = &mut writer {
+            // Send all messages already waiting at once.
+            let written = Some(envelope).into_iter().chain(messages.try_iter()).try_for_each(|envelope| {
+                serde_json::to_writer(&mut *stream, &envelope).map_err(|_| ())?;
+                stream.write_all(b"\n").map_err(|_| ())
+            }).and_then(|_| stream.flush().map_err(|_| ()));
+            if written.is_err() {
+                warn!("Lost the connection to the peer {} ({}).", node, address);
+                writer = None;
+            };
+        };
+    };
+}
+
+fn connect(address: &str) -> Option<TcpStream> {
+    let address = address.to_socket_addrs().ok()?.next()?;
+    let stream = TcpStream::connect_timeout(&address, CONNECTION_TIMEOUT).ok()?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT)).ok()?;
+    stream.set_nodelay(true).ok()?;
+
+    Some(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::raft::Message;
+
+    fn vote(from: NodeId) -> Envelope {
+        Envelope {
+            from,
+            to: 1,
+            message: Message::Vote { term: 1, granted: true },
+        }
+    }
+
+    #[test]
+    fn test_authentication() {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let (inbox, received) = channel();
+        assert!(listen(&address, Some(Secret::new("secret")), inbox, |envelope| envelope).is_ok());
+
+        // Only messages of peers knowing the secret are received.
+        let intruder = Peers::connect(&[(1, address.clone())], Some(Secret::new("other")));
+        intruder.send(vote(3));
+        let peer = Peers::connect(&[(1, address)], Some(Secret::new("secret")));
+        peer.send(vote(2));
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)), Ok(vote(2)));
+        assert!(received.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
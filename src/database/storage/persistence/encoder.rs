@@ -6,8 +6,9 @@ use nom::combinator::{all_consuming, flat_map};
 use nom::Err as NomErr;
 use nom::error::{Error, ErrorKind};
 use nom::IResult;
-use nom::number::complete::{be_i64, be_u16, be_u8};
+use nom::number::complete::{be_i64, be_u16, be_u32, be_u8};
 use nom::sequence::tuple;
+use super::logfile::VERSION;
 
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum JobStatus {
@@ -23,6 +24,11 @@ pub struct Job {
     pub execution: DateTime<Utc>,
     pub status: JobStatus,
 }
+/// Job removals to be encoded and decoded.
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub struct JobRemoval {
+    pub identifier: String,
+}
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum Runner {
     Amqp {
@@ -44,6 +50,7 @@ pub struct Rule {
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum Decoded {
     Job(Job),
+    JobRemoval(JobRemoval),
     Rule(Rule),
 }
 #[cfg_attr(test, derive(Debug, PartialEq))]
@@ -53,11 +60,19 @@ pub enum DecodeError {
 pub type DecodeResult = Result<Decoded, DecodeError>;
 pub enum Encodable {
     Job(Job),
+    JobRemoval(JobRemoval),
     Rule(Rule),
 }
 pub type EncodeResult = Result<Vec<u8>, ()>;
 
 /// Encode values that must be stored in a file as decodable binary.
+///
+/// Values are always encoded using the current format version of logfiles, but values encoded
+/// with older format versions can still be decoded (see [`decode_version`]):
+/// - versions 1 and 2 encode job execution timestamps as nanoseconds on 8 bytes (limiting them to
+///   the year 2262),
+/// - versions 3 and 4 encode job execution timestamps as seconds on 8 bytes and nanoseconds on 4
+///   bytes (version 4 only changes the logfile format, adding encryption).
 pub struct Encoder {}
 
 impl Encoder {
@@ -71,21 +86,46 @@ impl Encoder {
     pub fn encode(&self, value: Encodable) -> EncodeResult {
         match value {
             Encodable::Job(job) => self.encode_job(&job),
+            Encodable::JobRemoval(removal) => self.encode_job_removal(&removal),
             Encodable::Rule(rule) => self.encode_rule(&rule),
         }
     }
 
-    /// Decode the given data into a Decoded enum. See specialized encode methods for more details
-    /// on how each type of value is encoded.
+    /// Decode the given data, encoded with the current format version, into a Decoded enum. See
+    /// specialized encode methods for more details on how each type of value is encoded.
     pub fn decode<'a>(&self, data: &'a [u8]) -> DecodeResult {
+        self.decode_version(data, VERSION)
+    }
+
+    /// Migrate the given data, encoded with the given format version, to the current format
+    /// version.
+    pub fn migrate(&self, data: &[u8], version: u8) -> EncodeResult {
+        match self.decode_version(data, version) {
+            Ok(Decoded::Job(job)) => self.encode_job(&job),
+            Ok(Decoded::JobRemoval(removal)) => self.encode_job_removal(&removal),
+            Ok(Decoded::Rule(rule)) => self.encode_rule(&rule),
+            Err(_) => Err(()),
+        }
+    }
+
+    /// Decode the given data, encoded with the given format version, into a Decoded enum.
+    pub fn decode_version<'a>(&self, data: &'a [u8], version: u8) -> DecodeResult {
         // Handle job entries.
         let job = |input: &'a [u8]| -> IResult<&'a [u8], Decoded> {
             let entry_type_job = tag([0]);
             let job_identifier = sized_utf8_string();
             let job_timestamp = |input: &'a [u8]| -> IResult<&'a [u8], DateTime<Utc>> {
-                let (input, timestamp) = be_i64(input)?;
+                if version < 3 {
+                    let (input, timestamp) = be_i64(input)?;
 
-                Ok((input, Utc.timestamp(timestamp / 1_000_000_000, (timestamp % 1_000_000_000) as u32)))
+                    return Ok((input, Utc.timestamp(timestamp / 1_000_000_000, (timestamp % 1_000_000_000) as u32)));
+                };
+
+                let (input_left, (seconds, nanoseconds)) = tuple((be_i64, be_u32))(input)?;
+                match Utc.timestamp_opt(seconds, nanoseconds).single() {
+                    Some(timestamp) => Ok((input_left, timestamp)),
+                    None => Err(NomErr::Error(Error { input, code: ErrorKind::Verify })),
+                }
             };
             let job_status = |input: &'a [u8]| -> IResult<&'a [u8], JobStatus> {
                 let (input_left, status) = be_u8(input)?;
@@ -137,7 +177,16 @@ impl Encoder {
             Ok((input, Decoded::Rule(Rule { identifier, pattern, runner })))
         };
 
-        match all_consuming(alt((job, rule)))(data) {
+        // Handle job removal entries.
+        let job_removal = |input: &'a [u8]| -> IResult<&'a [u8], Decoded> {
+            let entry_type_job_removal = tag([2]);
+            let job_identifier = sized_utf8_string();
+            let (input, (_, identifier)) = tuple((entry_type_job_removal, job_identifier))(input)?;
+
+            Ok((input, Decoded::JobRemoval(JobRemoval { identifier })))
+        };
+
+        match all_consuming(alt((job, rule, job_removal)))(data) {
             Ok((_, decoded)) => Ok(decoded),
             Err(_) => Err(DecodeError::InvalidData)
         }
@@ -149,7 +198,8 @@ impl Encoder {
     /// - [u8: 1]: the type of this value (0 for jobs),
     /// - [u8: 2]: the size of the job's identifier string as big-endian,
     /// - [u8: identifier_size]: the identifier of the job,
-    /// - [u8: 8]: the execution timestamp of the job (with nanoseconds precision) as big-endian,
+    /// - [u8: 8]: the execution timestamp of the job (in seconds) as big-endian,
+    /// - [u8: 4]: the nanoseconds of the execution timestamp of the job as big-endian,
     /// - [u8: 1]: the status of the job (0 = planned, 1 = triggered, 2 = executed, 3 = failed).
     fn encode_job(&self, job: &Job) -> EncodeResult {
         let identifier_size = match job.identifier.len() > u16::MAX as usize {
@@ -157,12 +207,13 @@ impl Encoder {
             false => job.identifier.len() as u16,
         };
 
-        let mut result = vec![0; 12 + identifier_size as usize];
+        let mut result = vec![0; 16 + identifier_size as usize];
         result[0] = 0;
         result[1..3].copy_from_slice(&identifier_size.to_be_bytes());
         result[3..(3 + identifier_size as usize)].copy_from_slice(job.identifier.as_bytes());
-        result[(3 + identifier_size as usize)..(11 + identifier_size as usize)].copy_from_slice(&job.execution.timestamp_nanos().to_be_bytes());
-        result[11 + identifier_size as usize] = match job.status {
+        result[(3 + identifier_size as usize)..(11 + identifier_size as usize)].copy_from_slice(&job.execution.timestamp().to_be_bytes());
+        result[(11 + identifier_size as usize)..(15 + identifier_size as usize)].copy_from_slice(&job.execution.timestamp_subsec_nanos().to_be_bytes());
+        result[15 + identifier_size as usize] = match job.status {
             JobStatus::Planned => 0,
             JobStatus::Triggered => 1,
             JobStatus::Executed => 2,
@@ -172,6 +223,26 @@ impl Encoder {
         Ok(result)
     }
 
+    /// Encode the given job removal into an array of bytes.
+    ///
+    /// A job removal is encoded concatenating the following arrays of bytes:
+    /// - [u8: 1]: the type of this value (2 for job removals),
+    /// - [u8: 2]: the size of the removed job's identifier string as big-endian,
+    /// - [u8: identifier_size]: the identifier of the removed job.
+    fn encode_job_removal(&self, removal: &JobRemoval) -> EncodeResult {
+        let identifier_size = match removal.identifier.len() > u16::MAX as usize {
+            true => return Err(()),
+            false => removal.identifier.len() as u16,
+        };
+
+        let mut result = vec![0; 3 + identifier_size as usize];
+        result[0] = 2;
+        result[1..3].copy_from_slice(&identifier_size.to_be_bytes());
+        result[3..].copy_from_slice(removal.identifier.as_bytes());
+
+        Ok(result)
+    }
+
     /// Encode the given rule into an array of bytes.
     ///
     /// A rule is encoded concatenating the following arrays of bytes:
@@ -273,11 +344,11 @@ mod tests {
         // Test job encoding.
         assert_eq!(
             encoder.encode(Encodable::Job(Job { identifier: String::from("toto"), execution: Utc.ymd(2020, 11, 15).and_hms(16, 30, 00), status: JobStatus::Planned })),
-            Ok(vec![0, 0, 4, 116, 111, 116, 111, 22, 71, 187, 92, 238, 225, 80, 0, 0]),
+            Ok(vec![0, 0, 4, 116, 111, 116, 111, 0, 0, 0, 0, 95, 177, 87, 136, 0, 0, 0, 0, 0]),
         );
         assert_eq!(
             encoder.encode(Encodable::Job(Job { identifier: String::from("tatat"), execution: Utc.ymd(2020, 11, 15).and_hms(16, 30, 00), status: JobStatus::Executed })),
-            Ok(vec![0, 0, 5, 116, 97, 116, 97, 116, 22, 71, 187, 92, 238, 225, 80, 0, 2]),
+            Ok(vec![0, 0, 5, 116, 97, 116, 97, 116, 0, 0, 0, 0, 95, 177, 87, 136, 0, 0, 0, 0, 2]),
         );
         assert_eq!(
             encoder.encode(Encodable::Rule(Rule { identifier: String::from("t"), pattern: String::from("toto"), runner: Runner::Shell { command: String::from("titi") }})),
@@ -287,6 +358,10 @@ mod tests {
             encoder.encode(Encodable::Rule(Rule { identifier: String::from("ta"), pattern: String::from("tot"), runner: Runner::Amqp { dsn: String::from("titit"), exchange: String::from(""), routing_key: String::from("a") }})),
             Ok(vec![1, 0, 2, 116, 97, 0, 3, 116, 111, 116, 1, 0, 5, 116, 105, 116, 105, 116, 0, 0, 0, 1, 97]),
         );
+        assert_eq!(
+            encoder.encode(Encodable::JobRemoval(JobRemoval { identifier: String::from("toto") })),
+            Ok(vec![2, 0, 4, 116, 111, 116, 111]),
+        );
     }
 
     #[test]
@@ -295,11 +370,11 @@ mod tests {
 
         // Test basic valid buffers.
         assert_eq!(
-            encoder.decode(&vec![0, 0, 4, 116, 111, 116, 111, 22, 71, 187, 92, 238, 225, 80, 0, 0]),
+            encoder.decode(&vec![0, 0, 4, 116, 111, 116, 111, 0, 0, 0, 0, 95, 177, 87, 136, 0, 0, 0, 0, 0]),
             Ok(Decoded::Job(Job { identifier: String::from("toto"), execution: Utc.ymd(2020, 11, 15).and_hms(16, 30, 00), status: JobStatus::Planned })),
         );
         assert_eq!(
-            encoder.decode(&vec![0, 0, 5, 116, 97, 116, 97, 116, 22, 71, 187, 92, 238, 225, 80, 0, 2]),
+            encoder.decode(&vec![0, 0, 5, 116, 97, 116, 97, 116, 0, 0, 0, 0, 95, 177, 87, 136, 0, 0, 0, 0, 2]),
             Ok(Decoded::Job(Job { identifier: String::from("tatat"), execution: Utc.ymd(2020, 11, 15).and_hms(16, 30, 00), status: JobStatus::Executed })),
         );
         assert_eq!(
@@ -310,6 +385,10 @@ mod tests {
             encoder.decode(&vec![1, 0, 2, 116, 97, 0, 3, 116, 111, 116, 1, 0, 5, 116, 105, 116, 105, 116, 0, 0, 0, 1, 97]),
             Ok(Decoded::Rule(Rule { identifier: String::from("ta"), pattern: String::from("tot"), runner: Runner::Amqp { dsn: String::from("titit"), exchange: String::from(""), routing_key: String::from("a") }})),
         );
+        assert_eq!(
+            encoder.decode(&[2, 0, 4, 116, 111, 116, 111]),
+            Ok(Decoded::JobRemoval(JobRemoval { identifier: String::from("toto") })),
+        );
         // Test invalid entries.
         assert_eq!(
             encoder.decode(&vec![]),
@@ -320,8 +399,49 @@ mod tests {
             Err(DecodeError::InvalidData),
         );
         assert_eq!(
-            encoder.decode(&vec![0, 0, 4, 116, 111, 116, 111, 22, 71, 187, 92, 238, 225, 80, 0, 0, 255]),
+            encoder.decode(&vec![0, 0, 4, 116, 111, 116, 111, 0, 0, 0, 0, 95, 177, 87, 136, 0, 0, 0, 0, 0, 255]),
             Err(DecodeError::InvalidData),
         );
     }
+
+    #[test]
+    fn test_decode_older_versions() {
+        let encoder = Encoder::new();
+
+        // Versions 1 and 2 encode job execution timestamps as nanoseconds.
+        for version in 1..=2 {
+            assert_eq!(
+                encoder.decode_version(&[0, 0, 4, 116, 111, 116, 111, 22, 71, 187, 92, 238, 225, 80, 0, 0], version),
+                Ok(Decoded::Job(Job { identifier: String::from("toto"), execution: Utc.ymd(2020, 11, 15).and_hms(16, 30, 00), status: JobStatus::Planned })),
+            );
+        };
+        assert_eq!(
+            encoder.migrate(&[0, 0, 4, 116, 111, 116, 111, 22, 71, 187, 92, 238, 225, 80, 0, 0], 2),
+            Ok(vec![0, 0, 4, 116, 111, 116, 111, 0, 0, 0, 0, 95, 177, 87, 136, 0, 0, 0, 0, 0]),
+        );
+        // Jobs of version 2 before 2262 are decoded with the same datetime once migrated to
+        // version 3, only the execution timestamp being encoded differently.
+        let job = Job { identifier: String::from("toto"), execution: Utc.ymd(2262, 4, 11).and_hms_nano(23, 47, 16, 854_775_807), status: JobStatus::Triggered };
+        let mut version_2 = vec![0, 0, 4, 116, 111, 116, 111];
+        version_2.extend(&job.execution.timestamp_nanos().to_be_bytes());
+        version_2.push(1);
+        let migrated = encoder.migrate(&version_2, 2).unwrap();
+        assert_eq!(migrated[..7], version_2[..7]);
+        assert_eq!(migrated[19..], version_2[15..]);
+        assert_eq!(encoder.decode_version(&migrated, 3), Ok(Decoded::Job(job)));
+        assert_eq!(
+            encoder.migrate(&[2, 0, 4, 116, 111, 116, 111], 1),
+            Ok(vec![2, 0, 4, 116, 111, 116, 111]),
+        );
+        assert_eq!(encoder.migrate(&[0, 0, 4], 2), Err(()));
+    }
+
+    #[test]
+    fn test_encode_distant_execution() {
+        let encoder = Encoder::new();
+        let job = Job { identifier: String::from("toto"), execution: Utc.ymd(9999, 12, 31).and_hms_nano(23, 59, 59, 123_456_789), status: JobStatus::Planned };
+        let encoded = encoder.encode(Encodable::Job(Job { identifier: job.identifier.clone(), execution: job.execution, status: JobStatus::Planned })).unwrap();
+
+        assert_eq!(encoder.decode(&encoded), Ok(Decoded::Job(job)));
+    }
 }
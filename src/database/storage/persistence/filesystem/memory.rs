@@ -0,0 +1,382 @@
+//! An in-memory file system, injecting failures in the persistent storage.
+//!
+//! Each file has a content, changed by writes, and a durable content: the content it would have
+//! after a power loss, updated by `sync_data` and `sync_all` only. Directory operations (creating,
+//! renaming and removing files) are considered durable right away, like on journaling file
+//! systems. Open files keep their content once renamed or removed, like on the real file system.
+//!
+//! Every operation modifying the file system (writing, synchronizing, truncating, creating,
+//! renaming or removing a file) is a step, numbered from 0. A [`Fault`] can be injected at any
+//! step, making its operation fail: a write only writes the first half of its data, and other
+//! operations do nothing. After a power loss, all operations fail until [`Memory::restart`].
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use super::{Filesystem, Handle, Lock, Mode};
+use super::super::LockError;
+
+/// A failure injected at a given step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// The operation fails, following operations succeeding again.
+    Failure,
+    /// The power is lost during the operation: it fails, and so does everything until restarted.
+    PowerLoss,
+}
+
+#[derive(Default)]
+struct File {
+    content: Vec<u8>,
+    durable: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    files: HashMap<PathBuf, Arc<Mutex<File>>>,
+    faults: HashMap<usize, Fault>,
+    /// The number of steps done so far.
+    steps: usize,
+    /// The number of successful file synchronizations so far.
+    synchronizations: usize,
+    /// Whether the power has been lost.
+    down: bool,
+    /// The number of restarts, invalidating files opened before them.
+    generation: usize,
+}
+
+/// An in-memory file system. Clones share the same files.
+#[derive(Clone, Default)]
+pub struct Memory {
+    state: Arc<Mutex<State>>,
+}
+
+impl Memory {
+    /// Create a new empty file system.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of steps done so far.
+    pub fn get_steps(&self) -> usize {
+        self.state().steps
+    }
+
+    /// Get the number of successful file synchronizations so far.
+    pub fn get_synchronizations(&self) -> usize {
+        self.state().synchronizations
+    }
+
+    /// Inject the given fault at the given step.
+    pub fn inject(&self, step: usize, fault: Fault) {
+        self.state().faults.insert(step, fault);
+    }
+
+    /// Check whether the power has been lost.
+    pub fn is_down(&self) -> bool {
+        self.state().down
+    }
+
+    /// Restart the file system after a power loss (or simulate a power loss followed by a restart,
+    /// when the power isn't lost yet): all files lose their content not synchronized, and files
+    /// opened before are invalidated. Injected faults are forgotten.
+    pub fn restart(&self) {
+        let mut state = self.state();
+        for file in state.files.values() {
+            let mut file = lock(file);
+            file.content = file.durable.clone();
+        };
+        state.faults.clear();
+        state.down = false;
+        state.generation += 1;
+    }
+
+    /// Get the content of the file at the given path, if it exists.
+    pub fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        self.state().files.get(path).map(|file| lock(file).content.clone())
+    }
+
+    /// Get the paths of all existing files.
+    pub fn list(&self) -> HashSet<PathBuf> {
+        self.state().files.keys().cloned().collect()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Check that the power isn't lost, and that files opened with the given generation are still
+    /// valid.
+    fn check(&self, generation: Option<usize>) -> Result<()> {
+        let state = self.state();
+        if state.down || generation.map_or(false, |generation| generation != state.generation) {
+            return Err(Error::new(ErrorKind::Other, "power lost"));
+        };
+
+        Ok(())
+    }
+
+    /// Do a step, returning the fault injected at this step, if any.
+    fn step(&self, generation: Option<usize>) -> Result<Option<Fault>> {
+        self.check(generation)?;
+        let mut state = self.state();
+        let step = state.steps;
+        let fault = state.faults.remove(&step);
+        state.steps += 1;
+        if fault == Some(Fault::PowerLoss) {
+            state.down = true;
+        };
+
+        Ok(fault)
+    }
+}
+
+fn lock(file: &Mutex<File>) -> MutexGuard<'_, File> {
+    file.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn failure() -> Error {
+    Error::new(ErrorKind::Other, "injected failure")
+}
+
+fn not_found() -> Error {
+    Error::new(ErrorKind::NotFound, "no such file")
+}
+
+impl Filesystem for Memory {
+    fn open(&self, path: &Path, mode: Mode) -> Result<Box<dyn Handle>> {
+        self.check(None)?;
+        let existing = self.state().files.get(path).cloned();
+        let file = match (existing, mode) {
+            (Some(file), Mode::Create) => {
+                if self.step(None)?.is_some() {
+                    return Err(failure());
+                };
+                lock(&file).content.clear();
+
+                file
+            },
+            (Some(file), _) => file,
+            (None, Mode::Read) | (None, Mode::Update) => return Err(not_found()),
+            (None, _) => {
+                if self.step(None)?.is_some() {
+                    return Err(failure());
+                };
+                let file = Arc::new(Mutex::new(File::default()));
+                self.state().files.insert(path.to_path_buf(), Arc::clone(&file));
+
+                file
+            },
+        };
+
+        Ok(Box::new(MemoryHandle {
+            filesystem: self.clone(),
+            file,
+            position: 0,
+            append: mode == Mode::Append,
+            generation: self.state().generation,
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if !self.state().files.contains_key(from) {
+            self.check(None)?;
+
+            return Err(not_found());
+        };
+        if self.step(None)?.is_some() {
+            return Err(failure());
+        };
+        let mut state = self.state();
+        if let Some(file) = state.files.remove(from) {
+            state.files.insert(to.to_path_buf(), file);
+        };
+
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        if !self.state().files.contains_key(path) {
+            self.check(None)?;
+
+            return Err(not_found());
+        };
+        if self.step(None)?.is_some() {
+            return Err(failure());
+        };
+        self.state().files.remove(path);
+
+        Ok(())
+    }
+
+    fn size(&self, path: &Path) -> Result<u64> {
+        self.check(None)?;
+        match self.state().files.get(path) {
+            Some(file) => Ok(lock(file).content.len() as u64),
+            None => Err(not_found()),
+        }
+    }
+
+    fn create_dir_all(&self, _path: &Path) -> Result<()> {
+        self.check(None)
+    }
+
+    fn lock(&self, _directory: &Path) -> std::result::Result<Lock, LockError> {
+        match self.check(None) {
+            Ok(_) => Ok(Box::new(())),
+            Err(_) => Err(LockError::Failure),
+        }
+    }
+}
+
+/// A file open in a [`Memory`] file system.
+struct MemoryHandle {
+    filesystem: Memory,
+    file: Arc<Mutex<File>>,
+    position: u64,
+    append: bool,
+    generation: usize,
+}
+
+impl Read for MemoryHandle {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.filesystem.check(Some(self.generation))?;
+        let file = lock(&self.file);
+        let start = (self.position as usize).min(file.content.len());
+        let read = buffer.len().min(file.content.len() - start);
+        buffer[..read].copy_from_slice(&file.content[start..start + read]);
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Write for MemoryHandle {
+    fn write(&mut self, buffer: &[u8]) -> Result<usize> {
+        let fault = self.filesystem.step(Some(self.generation))?;
+        let mut file = lock(&self.file);
+        if self.append {
+            self.position = file.content.len() as u64;
+        };
+        let written = match fault {
+            Some(_) => &buffer[..buffer.len() / 2],
+            None => buffer,
+        };
+        let start = self.position as usize;
+        if file.content.len() < start + written.len() {
+            file.content.resize(start + written.len(), 0);
+        };
+        file.content[start..start + written.len()].copy_from_slice(written);
+        self.position += written.len() as u64;
+
+        match fault {
+            Some(_) => Err(failure()),
+            None => Ok(written.len()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.filesystem.check(Some(self.generation))
+    }
+}
+
+impl Seek for MemoryHandle {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64> {
+        self.filesystem.check(Some(self.generation))?;
+        let length = lock(&self.file).content.len() as i64;
+        let position = match position {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => length + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "negative position"));
+        };
+        self.position = position as u64;
+
+        Ok(self.position)
+    }
+}
+
+impl Handle for MemoryHandle {
+    fn sync_data(&mut self) -> Result<()> {
+        if self.filesystem.step(Some(self.generation))?.is_some() {
+            return Err(failure());
+        };
+        let mut file = lock(&self.file);
+        file.durable = file.content.clone();
+        self.filesystem.state().synchronizations += 1;
+
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        self.sync_data()
+    }
+
+    fn set_len(&mut self, length: u64) -> Result<()> {
+        if self.filesystem.step(Some(self.generation))?.is_some() {
+            return Err(failure());
+        };
+        lock(&self.file).content.resize(length as usize, 0);
+
+        Ok(())
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.filesystem.check(Some(self.generation))?;
+
+        Ok(lock(&self.file).content.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_loss() {
+        let filesystem = Memory::new();
+        let path = Path::new("file");
+        let mut file = filesystem.open(path, Mode::Append).unwrap();
+        file.write_all(b"synchronized").unwrap();
+        file.sync_data().unwrap();
+        assert_eq!(filesystem.get_synchronizations(), 1);
+        file.write_all(b" lost").unwrap();
+        assert_eq!(filesystem.read(path).unwrap(), b"synchronized lost".to_vec());
+
+        filesystem.inject(filesystem.get_steps() + 1, Fault::PowerLoss);
+        file.write_all(b" torn").unwrap();
+        assert!(file.write_all(b" write").is_err());
+        assert_eq!(filesystem.read(path).unwrap(), b"synchronized lost torn wr".to_vec());
+        assert!(filesystem.is_down());
+        assert!(filesystem.open(path, Mode::Read).is_err());
+
+        filesystem.restart();
+        assert!(file.write_all(b"stale").is_err());
+        assert_eq!(filesystem.read(path).unwrap(), b"synchronized".to_vec());
+        let mut content = Vec::new();
+        filesystem.open(path, Mode::Read).unwrap().read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"synchronized".to_vec());
+    }
+
+    #[test]
+    fn test_failures() {
+        let filesystem = Memory::new();
+        let mut file = filesystem.open(Path::new("file"), Mode::Create).unwrap();
+        file.write_all(b"content").unwrap();
+
+        filesystem.inject(filesystem.get_steps(), Fault::Failure);
+        assert!(file.sync_data().is_err());
+        filesystem.inject(filesystem.get_steps(), Fault::Failure);
+        assert!(filesystem.rename(Path::new("file"), Path::new("moved")).is_err());
+        assert!(filesystem.read(Path::new("moved")).is_none());
+        assert!(matches!(filesystem.rename(Path::new("missing"), Path::new("moved")), Err(error) if error.kind() == ErrorKind::NotFound));
+
+        filesystem.restart();
+        assert_eq!(filesystem.read(Path::new("file")).unwrap(), Vec::<u8>::new());
+        filesystem.rename(Path::new("file"), Path::new("moved")).unwrap();
+        assert_eq!(filesystem.list(), vec![PathBuf::from("moved")].into_iter().collect());
+    }
+}
@@ -0,0 +1,144 @@
+//! The file system used by the persistent storage.
+//!
+//! All file operations of the logfile storage (including its compression process) and of the
+//! logfile reader and writer go through the [`Filesystem`] trait, and open files through the
+//! [`Handle`] trait. The server uses the real file system, with [`Disk`]. Tests use an in-memory
+//! file system able to inject failures and to simulate power losses (see the [`memory`] module),
+//! checking that the storage is always kept in a recoverable state.
+
+#[cfg(test)]
+pub mod memory;
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Result, Seek, Write};
+use std::path::Path;
+use super::LockError;
+
+/// The way a file is opened.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Read an existing file.
+    Read,
+    /// Read and write an existing file.
+    Update,
+    /// Read and write a file, creating it when it doesn't exist.
+    UpdateOrCreate,
+    /// Append to a file, creating it when it doesn't exist.
+    Append,
+    /// Write a file, emptying it when it exists.
+    Create,
+}
+
+/// An open file.
+pub trait Handle: Read + Write + Seek + Send {
+    /// Synchronize the content of the file to the storage device.
+    fn sync_data(&mut self) -> Result<()>;
+
+    /// Synchronize the content and the metadata of the file to the storage device.
+    fn sync_all(&mut self) -> Result<()>;
+
+    /// Truncate or extend the file to the given length.
+    fn set_len(&mut self, length: u64) -> Result<()>;
+
+    /// Get the size of the file, in bytes.
+    fn size(&self) -> Result<u64>;
+}
+
+/// A lock held on a data directory, released when dropped.
+pub type Lock = Box<dyn Send>;
+
+pub trait Filesystem: Send + Sync {
+    /// Open the file at the given path, in the given mode.
+    fn open(&self, path: &Path, mode: Mode) -> Result<Box<dyn Handle>>;
+
+    /// Atomically move the file at the given path to the given destination, replacing it.
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Remove the file at the given path.
+    fn remove_file(&self, path: &Path) -> Result<()>;
+
+    /// Get the size of the file at the given path, in bytes.
+    fn size(&self, path: &Path) -> Result<u64>;
+
+    /// Create the directory at the given path, along with all its missing parents.
+    fn create_dir_all(&self, path: &Path) -> Result<()>;
+
+    /// Take the exclusive lock on the given data directory, preventing other processes from using
+    /// it.
+    fn lock(&self, directory: &Path) -> std::result::Result<Lock, LockError>;
+}
+
+/// The real file system.
+pub struct Disk;
+
+impl Filesystem for Disk {
+    fn open(&self, path: &Path, mode: Mode) -> Result<Box<dyn Handle>> {
+        let mut options = OpenOptions::new();
+        match mode {
+            Mode::Read => options.read(true),
+            Mode::Update => options.read(true).write(true),
+            Mode::UpdateOrCreate => options.read(true).write(true).create(true),
+            Mode::Append => options.append(true).create(true),
+            Mode::Create => options.write(true).create(true).truncate(true),
+        };
+
+        Ok(Box::new(options.open(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn size(&self, path: &Path) -> Result<u64> {
+        fs::metadata(path).map(|metadata| metadata.len())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn lock(&self, directory: &Path) -> std::result::Result<Lock, LockError> {
+        Ok(Box::new(super::lock(directory)?))
+    }
+}
+
+impl Handle for File {
+    fn sync_data(&mut self) -> Result<()> {
+        File::sync_data(self)
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        File::sync_all(self)
+    }
+
+    fn set_len(&mut self, length: u64) -> Result<()> {
+        File::set_len(self, length)
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.metadata().map(|metadata| metadata.len())
+    }
+}
+
+impl<H: Handle + ?Sized> Handle for Box<H> {
+    fn sync_data(&mut self) -> Result<()> {
+        (**self).sync_data()
+    }
+
+    fn sync_all(&mut self) -> Result<()> {
+        (**self).sync_all()
+    }
+
+    fn set_len(&mut self, length: u64) -> Result<()> {
+        (**self).set_len(length)
+    }
+
+    fn size(&self) -> Result<u64> {
+        (**self).size()
+    }
+}
@@ -0,0 +1,163 @@
+//! Authentication of the connections between servers (from replicas to their primary, and between
+//! nodes of a cluster), with a secret shared by all of them.
+//!
+//! Right after the connection, the accepting server sends a challenge: a byte telling whether a
+//! secret is required (`1`) or not (`0`), followed by 32 random bytes. When a secret is required,
+//! the connecting server answers with the HMAC-SHA256 of the random bytes, keyed with the secret,
+//! so the secret itself is never sent. The accepting server then sends a single byte (`1`) to
+//! accept the connection, or closes it when the answer is wrong, or doesn't come in time.
+//!
+//! The connection isn't encrypted: the secret prevents unknown servers from reading or writing
+//! data, but the network must still be trusted not to eavesdrop.
+
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const NONCE_LENGTH: usize = 32;
+const ANSWER_LENGTH: usize = 32;
+const ACCEPTED: u8 = 1;
+/// The maximum duration to wait for the challenge or its answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A secret shared by servers, authenticating their connections.
+#[derive(Clone)]
+pub struct Secret {
+    key: Vec<u8>,
+}
+
+impl Secret {
+    /// Create a secret from the given text.
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: secret.as_bytes().to_vec(),
+        }
+    }
+
+    /// Compute the answer to the challenge with the given random bytes.
+    fn answer(&self, nonce: &[u8]) -> Option<Vec<u8>> {
+        let key = PKey::hmac(&self.key).ok()?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+        signer.update(nonce).ok()?;
+
+        signer.sign_to_vec().ok()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The connection failed, or timed out.
+    Io,
+    /// The connecting server doesn't know the secret (or the accepting server doesn't know the
+    /// same one).
+    Rejected,
+    /// The accepting server requires a secret, but none is configured.
+    SecretRequired,
+}
+
+/// Challenge the server connected through the given stream, when a secret is given. It fails when
+/// the server doesn't prove it knows the secret.
+pub fn challenge(stream: &mut TcpStream, secret: Option<&Secret>) -> Result<(), Error> {
+    let mut nonce = [0; NONCE_LENGTH];
+    if secret.is_some() && rand_bytes(&mut nonce).is_err() {
+        return Err(Error::Io);
+    };
+    let mut message = vec![secret.is_some() as u8];
+    message.extend(&nonce);
+    if stream.write_all(&message).is_err() || stream.flush().is_err() {
+        return Err(Error::Io);
+    };
+
+    let secret = match secret {
+        Some(secret) => secret,
+        None => return Ok(()),
+    };
+    let mut answer = [0; ANSWER_LENGTH];
+    read_exact(stream, &mut answer)?;
+    match secret.answer(&nonce) {
+        Some(expected) if memcmp::eq(&expected, &answer) => {},
+        Some(_) => return Err(Error::Rejected),
+        None => return Err(Error::Io),
+    };
+    match stream.write_all(&[ACCEPTED]).and_then(|_| stream.flush()) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::Io),
+    }
+}
+
+/// Answer the challenge of the server accepting the connection of the given stream, with the given
+/// secret (if any).
+pub fn answer(stream: &mut TcpStream, secret: Option<&Secret>) -> Result<(), Error> {
+    let mut challenge = [0; 1 + NONCE_LENGTH];
+    read_exact(stream, &mut challenge)?;
+    if challenge[0] == 0 {
+        return Ok(());
+    };
+
+    let answer = match secret {
+        Some(secret) => secret.answer(&challenge[1..]).ok_or(Error::Io)?,
+        None => return Err(Error::SecretRequired),
+    };
+    if stream.write_all(&answer).and_then(|_| stream.flush()).is_err() {
+        return Err(Error::Io);
+    };
+    let mut accepted = [0];
+    match read_exact(stream, &mut accepted) {
+        Ok(_) if accepted[0] == ACCEPTED => Ok(()),
+        _ => Err(Error::Rejected),
+    }
+}
+
+/// Read exactly enough bytes to fill the given buffer from the given stream, in time.
+fn read_exact(stream: &mut TcpStream, buffer: &mut [u8]) -> Result<(), Error> {
+    let timeout = stream.read_timeout().unwrap_or(None);
+    if stream.set_read_timeout(Some(TIMEOUT)).is_err() {
+        return Err(Error::Io);
+    };
+    let read = stream.read_exact(buffer);
+    if stream.set_read_timeout(timeout).is_err() {
+        return Err(Error::Io);
+    };
+
+    read.map_err(|_| Error::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Run a handshake between two servers with the given secrets, returning the results of both
+    /// sides.
+    fn handshake(accepting: Option<&str>, connecting: Option<&str>) -> (Result<(), Error>, Result<(), Error>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let connecting = connecting.map(Secret::new);
+        let connected = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+
+            answer(&mut stream, connecting.as_ref())
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let challenged = challenge(&mut stream, accepting.map(Secret::new).as_ref());
+        drop(stream);
+
+        (challenged, connected.join().unwrap())
+    }
+
+    #[test]
+    fn test_handshake() {
+        assert_eq!(handshake(None, None), (Ok(()), Ok(())));
+        assert_eq!(handshake(None, Some("secret")), (Ok(()), Ok(())));
+        assert_eq!(handshake(Some("secret"), Some("secret")), (Ok(()), Ok(())));
+        assert_eq!(handshake(Some("secret"), Some("other")), (Err(Error::Rejected), Err(Error::Rejected)));
+        assert_eq!(handshake(Some("secret"), None), (Err(Error::Io), Err(Error::SecretRequired)));
+    }
+}
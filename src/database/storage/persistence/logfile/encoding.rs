@@ -3,74 +3,268 @@ use nom::IResult;
 use nom::number::streaming::be_u32;
 use nom::bytes::streaming::take;
 use nom::Err as NomErr;
+use nom::error::{Error, ErrorKind};
+use openssl::rand::rand_bytes;
+use openssl::sha::sha256;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+
+/// The magic number starting all versioned logfiles.
+pub const MAGIC: [u8; 4] = *b"KRLF";
+/// The format version used to write logfiles. Version 1 is the original format, without header
+/// nor checksums. Version 2 adds the header and checksums. Version 3 only changes the encoding of
+/// job execution datetimes (see the persistence encoder). Version 4 prefixes the data of each entry
+/// by its encryption scheme.
+pub const VERSION: u8 = 4;
+/// The size of the header of versioned logfiles (the magic number followed by the version).
+pub const HEADER_SIZE: usize = MAGIC.len() + 1;
+
+/// The encryption scheme of entries stored in plain bytes.
+const PLAINTEXT: u8 = 0;
+/// The encryption scheme of entries encrypted with AES-256-GCM.
+const AES_256_GCM: u8 = 1;
+/// The size of encryption keys, in bytes.
+pub const KEY_SIZE: usize = 32;
+/// The size of key fingerprints, identifying the key of encrypted entries.
+const FINGERPRINT_SIZE: usize = 4;
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
 
 pub type Entry = [u8];
 pub type Encoded = Vec<u8>;
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum EncodeError {
     MaximumSizeReached,
+    EncryptionFailure,
 }
 pub type EncodeResult = Result<Encoded, EncodeError>;
 
+/// An AES-256 key, identified in encrypted entries by its fingerprint (the beginning of its
+/// SHA-256 hash).
+#[derive(Clone)]
+pub struct Key {
+    bytes: [u8; KEY_SIZE],
+    fingerprint: [u8; FINGERPRINT_SIZE],
+}
+
+impl Key {
+    /// Create a new key with the given bytes.
+    pub fn new(bytes: [u8; KEY_SIZE]) -> Self {
+        let mut fingerprint = [0; FINGERPRINT_SIZE];
+        fingerprint.copy_from_slice(&sha256(&bytes)[0..FINGERPRINT_SIZE]);
+
+        Self { bytes, fingerprint }
+    }
+
+    /// Parse a key written as 64 hexadecimal characters, ignoring surrounding whitespaces.
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.len() != KEY_SIZE * 2 || !text.is_ascii() {
+            return None;
+        };
+
+        let mut bytes = [0; KEY_SIZE];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&text[index * 2..index * 2 + 2], 16).ok()?;
+        };
+
+        Some(Self::new(bytes))
+    }
+
+    /// Get the fingerprint of this key, as hexadecimal characters.
+    pub fn get_fingerprint(&self) -> String {
+        self.fingerprint.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// The keys used to encrypt and decrypt entries. New entries are encrypted with the current key
+/// (or written in plain bytes when there is none), and entries are decrypted with any key of the
+/// keyring: previous keys are kept to read entries written before a key rotation.
+#[derive(Clone, Default)]
+pub struct Keyring {
+    current: Option<Key>,
+    previous: Vec<Key>,
+}
+
+impl Keyring {
+    /// Create a new keyring, encrypting new entries with the given current key.
+    pub fn new(current: Option<Key>, previous: Vec<Key>) -> Self {
+        Self { current, previous }
+    }
+
+    /// Get the number of bytes added to the data of entries by their encryption scheme.
+    fn overhead(&self) -> usize {
+        match self.current {
+            Some(_) => 1 + FINGERPRINT_SIZE + NONCE_SIZE + TAG_SIZE,
+            None => 1,
+        }
+    }
+
+    /// Prefix the given data by its encryption scheme, encrypting it with the current key if
+    /// there is one. Encrypted data are made of the scheme and the key fingerprint (both
+    /// authenticated as additional data), a random nonce, the ciphertext and the tag.
+    fn seal(&self, data: &[u8]) -> Result<Vec<u8>, EncodeError> {
+        let key = match &self.current {
+            Some(key) => key,
+            None => {
+                let mut sealed = Vec::with_capacity(1 + data.len());
+                sealed.push(PLAINTEXT);
+                sealed.extend(data);
+
+                return Ok(sealed);
+            },
+        };
+
+        let mut nonce = [0; NONCE_SIZE];
+        let mut tag = [0; TAG_SIZE];
+        let mut sealed = Vec::with_capacity(self.overhead() + data.len());
+        sealed.push(AES_256_GCM);
+        sealed.extend(key.fingerprint);
+        let ciphertext = rand_bytes(&mut nonce)
+            .and_then(|_| encrypt_aead(Cipher::aes_256_gcm(), &key.bytes, Some(&nonce[..]), &sealed, data, &mut tag))
+            .map_err(|_| EncodeError::EncryptionFailure)?;
+        sealed.extend(nonce);
+        sealed.extend(ciphertext);
+        sealed.extend(tag);
+
+        Ok(sealed)
+    }
+
+    /// Get the data of the given sealed data, decrypting it if needed. It fails when it has been
+    /// encrypted with an unknown key, or when it has been altered.
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        match sealed.split_first() {
+            Some((&PLAINTEXT, data)) => Some(data.to_vec()),
+            Some((&AES_256_GCM, encrypted)) if encrypted.len() >= FINGERPRINT_SIZE + NONCE_SIZE + TAG_SIZE => {
+                let (fingerprint, encrypted) = encrypted.split_at(FINGERPRINT_SIZE);
+                let (nonce, encrypted) = encrypted.split_at(NONCE_SIZE);
+                let (ciphertext, tag) = encrypted.split_at(encrypted.len() - TAG_SIZE);
+                let key = self.current.iter().chain(self.previous.iter()).find(|key| &key.fingerprint[..] == fingerprint)?;
+
+                decrypt_aead(Cipher::aes_256_gcm(), &key.bytes, Some(nonce), &sealed[0..1 + FINGERPRINT_SIZE], ciphertext, tag).ok()
+            },
+            _ => None,
+        }
+    }
+}
+
 /// Encode logfile entries into self-contained entries (entries that can be inserted to and read
-/// from the logfile).
-pub struct Encoder {}
+/// from the logfile), encrypted with the current key of the given keyring.
+pub struct Encoder {
+    keyring: Keyring,
+}
 
 impl Encoder {
-    /// Create a new encoder.
-    pub fn new() -> Self {
-        Self {}
+    /// Create a new encoder, using the given keyring.
+    pub fn new(keyring: Keyring) -> Self {
+        Self { keyring }
+    }
+
+    /// Encode the header that must start all logfiles written with the current format version.
+    pub fn encode_header(&self) -> Encoded {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+
+        header
     }
 
     /// Encode an entry to be appended to a logfile. Return an error if the data is too big to be
-    /// encoded (the maximum entry's length is 2^32).
+    /// encoded (the maximum entry's length is 2^32, including its encryption scheme), or if it
+    /// can't be encrypted. The entry is followed by the CRC32C checksum of its length and data.
     pub fn encode(&self, entry: &Entry) -> EncodeResult {
-        match entry.len() <= u32::MAX as usize {
-            true => {
-                let mut encoded = vec![0; 4 + entry.len()];
-                encoded[0..4].copy_from_slice(&(entry.len() as u32).to_be_bytes());
-                encoded[4..].copy_from_slice(entry);
+        if entry.len() > u32::MAX as usize - self.keyring.overhead() {
+            return Err(EncodeError::MaximumSizeReached);
+        };
 
-                Ok(encoded)
-            },
-            false => Err(EncodeError::MaximumSizeReached),
-        }
+        let sealed = self.keyring.seal(entry)?;
+        let mut encoded = Vec::with_capacity(4 + sealed.len() + 4);
+        encoded.extend((sealed.len() as u32).to_be_bytes());
+        encoded.extend(sealed);
+        let checksum = crc32c(&encoded);
+        encoded.extend(checksum.to_be_bytes());
+
+        Ok(encoded)
+    }
+}
+
+#[cfg_attr(test, derive(Debug, PartialEq))]
+pub enum HeaderError {
+    /// The input is too short to know whether it starts with a header.
+    Incomplete,
+    /// The input starts with a header, for an unknown format version.
+    UnsupportedVersion(u8),
+}
+pub type HeaderResult = Result<(usize, u8), HeaderError>;
+
+/// Parse the header at the beginning of the given logfile content, returning its size and the
+/// format version of the logfile. Logfiles without header use the format version 1.
+pub fn parse_header(input: &[u8]) -> HeaderResult {
+    let length = input.len().min(MAGIC.len());
+    if input[0..length] != MAGIC[0..length] {
+        return Ok((0, 1));
+    };
+
+    match input.get(MAGIC.len()) {
+        Some(version) if *version >= 2 && *version <= VERSION => Ok((HEADER_SIZE, *version)),
+        Some(version) => Err(HeaderError::UnsupportedVersion(*version)),
+        None => Err(HeaderError::Incomplete),
     }
 }
 
 pub type Parsed = Vec<u8>;
 #[cfg_attr(test, derive(Debug, PartialEq))]
 pub enum ParseError<'a> {
-    CorruptedContent,
+    /// The input starts with an invalid entry. It contains the collection of entries that have
+    /// been parsed before it, and the input left (starting with the invalid entry).
+    CorruptedContent(Vec<Parsed>, &'a [u8]),
     Incomplete(Vec<Parsed>, &'a [u8]),
+    /// The input starts with a valid entry that can't be decrypted with the keyring (it has been
+    /// encrypted with an unknown key, or altered).
+    Undecryptable(Vec<Parsed>, &'a [u8]),
 }
 pub type ParseResult<'a> = Result<Vec<Parsed>, ParseError<'a>>;
 
-/// Parse entries from the binary content of logfiles.
-pub struct Parser {}
+/// Parse entries from the binary content of logfiles (following their header), decrypting them
+/// with the given keyring.
+pub struct Parser {
+    checksummed: bool,
+    sealed: bool,
+    keyring: Keyring,
+}
 
 impl Parser {
-    /// Create a new parser.
-    pub fn new() -> Self {
-        Self {}
+    /// Create a new parser, for logfiles using the given format version.
+    pub fn new(version: u8, keyring: Keyring) -> Self {
+        Self {
+            checksummed: version >= 2,
+            sealed: version >= 4,
+            keyring,
+        }
     }
 
     /// Parse the given input, returning a collection of entries. If the input is incomplete, it
     /// returns a ParseError::Incomplete containing the collection of entries that have been parsed
-    /// and the input left. If the input is invalid, it returns a ParseError::CorruptedContent.
+    /// and the input left. If an entry is invalid (its checksum doesn't match), it returns a
+    /// ParseError::CorruptedContent, and if it can't be decrypted, a ParseError::Undecryptable.
     pub fn parse<'a>(&self, input: &'a [u8]) -> ParseResult<'a> {
         let mut entries = Vec::new();
         let mut input = input;
 
         while input.len() > 0 {
             match self.parse_entry(input) {
-                Ok((input_left, entry)) => {
+                Ok((input_left, data)) => {
+                    let entry = match self.sealed {
+                        true => match self.keyring.open(data) {
+                            Some(entry) => entry,
+                            None => return Err(ParseError::Undecryptable(entries, input)),
+                        },
+                        false => data.to_vec(),
+                    };
                     entries.push(entry);
                     input = input_left;
                 },
                 Err(error) => match error {
                     NomErr::Incomplete(_) => return Err(ParseError::Incomplete(entries, input)),
-                    _ => return Err(ParseError::CorruptedContent),
+                    _ => return Err(ParseError::CorruptedContent(entries, input)),
                 },
             };
         };
@@ -78,78 +272,234 @@ impl Parser {
         Ok(entries)
     }
 
-    fn parse_entry<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], Parsed> {
-        let entry = |input: &'a [u8]| -> IResult<&'a [u8], Parsed> {
-            let (input, output) = flat_map(be_u32, take)(input)?;
+    /// Check whether entries are protected by a checksum, which isn't the case for logfiles using
+    /// the format version 1.
+    pub fn is_checksummed(&self) -> bool {
+        self.checksummed
+    }
 
-            Ok((input, output.to_vec()))
+    /// Find the position of the first valid and complete entry in the given input, whether it can
+    /// be decrypted or not. Only checksummed entries can be found this way: it always returns None
+    /// for logfiles using the format version 1.
+    pub fn find_entry(&self, input: &[u8]) -> Option<usize> {
+        if !self.checksummed {
+            return None;
         };
 
-        entry(input)
+        (0..input.len()).find(|position| self.parse_entry(&input[*position..]).is_ok())
+    }
+
+    fn parse_entry<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], &'a [u8]> {
+        let (input_left, output) = flat_map(be_u32, take)(input)?;
+        if !self.checksummed {
+            return Ok((input_left, output));
+        };
+
+        let (input_left, checksum) = be_u32(input_left)?;
+        match crc32c(&input[0..4 + output.len()]) == checksum {
+            true => Ok((input_left, output)),
+            false => Err(NomErr::Error(Error::new(input, ErrorKind::Verify))),
+        }
     }
 }
 
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+/// Compute the lookup table of the CRC32C (Castagnoli) checksum, at compilation time.
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = match value & 1 {
+                1 => (value >> 1) ^ 0x82F6_3B78,
+                _ => value >> 1,
+            };
+            bit += 1;
+        };
+        table[index] = value;
+        index += 1;
+    };
+
+    table
+}
+
+/// Compute the CRC32C (Castagnoli) checksum of the given data.
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut checksum = !0;
+    for byte in data {
+        checksum = CRC32C_TABLE[((checksum ^ *byte as u32) & 0xFF) as usize] ^ (checksum >> 8);
+    };
+
+    !checksum
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Encode the given data as a version 2 entry, without using the encoder.
+    fn entry(data: &[u8]) -> Vec<u8> {
+        let mut entry = (data.len() as u32).to_be_bytes().to_vec();
+        entry.extend(data);
+        let checksum = crc32c(&entry);
+        entry.extend(&checksum.to_be_bytes());
+
+        entry
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
     #[test]
     fn test_encode() {
-        let encoder = Encoder::new();
+        let encoder = Encoder::new(Keyring::default());
 
-        // Test valid entries.
+        assert_eq!(encoder.encode_header(), b"KRLF\x04".to_vec());
+        // Test valid entries, prefixed by the plaintext scheme.
         assert_eq!(
-            encoder.encode(&vec![]),
-            Ok(vec![0, 0, 0, 0]),
+            encoder.encode(&[]),
+            Ok(entry(&[PLAINTEXT])),
         );
         assert_eq!(
-            encoder.encode(&vec![0]),
-            Ok(vec![0, 0, 0, 1, 0]),
+            encoder.encode(&[0]),
+            Ok(entry(&[PLAINTEXT, 0])),
         );
         assert_eq!(
-            encoder.encode(&vec![0, 1, 2, 3, 4, 5, 6, 7]),
-            Ok(vec![0, 0, 0, 8, 0, 1, 2, 3, 4, 5, 6, 7]),
+            encoder.encode(&[0, 1, 2, 3, 4, 5, 6, 7]),
+            Ok(entry(&[PLAINTEXT, 0, 1, 2, 3, 4, 5, 6, 7])),
         );
+        assert_eq!(&entry(&[0])[0..5], &[0, 0, 0, 1, 0]);
         // Test entries reaching maximum size.
         assert_eq!(
-            encoder.encode(&vec![0; 4294967296]),
+            encoder.encode(&vec![0; 4294967295]),
             Err(EncodeError::MaximumSizeReached),
         );
     }
 
+    #[test]
+    fn test_key() {
+        let key = Key::parse(" 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n").unwrap();
+        assert_eq!(key.bytes[1], 1);
+        assert_eq!(key.bytes[31], 31);
+        assert_eq!(key.get_fingerprint().len(), FINGERPRINT_SIZE * 2);
+        assert_ne!(key.get_fingerprint(), Key::new([0; KEY_SIZE]).get_fingerprint());
+        assert!(Key::parse("000102").is_none());
+        assert!(Key::parse(&"zz".repeat(KEY_SIZE)).is_none());
+        assert!(Key::parse(&"é".repeat(KEY_SIZE)).is_none());
+    }
+
+    #[test]
+    fn test_encryption() {
+        let old = Key::new([1; KEY_SIZE]);
+        let new = Key::new([2; KEY_SIZE]);
+        let old_keyring = Keyring::new(Some(old.clone()), vec![]);
+        let rotated_keyring = Keyring::new(Some(new.clone()), vec![old]);
+        let new_keyring = Keyring::new(Some(new), vec![]);
+
+        let encrypted = Encoder::new(old_keyring.clone()).encode(b"secret").unwrap();
+        assert_eq!(encrypted.len(), 4 + 1 + FINGERPRINT_SIZE + NONCE_SIZE + 6 + TAG_SIZE + 4);
+        assert_eq!(encrypted[4], AES_256_GCM);
+        assert!(!encrypted.windows(6).any(|window| window == b"secret"));
+        // The same data is never encrypted twice the same way.
+        assert_ne!(Encoder::new(old_keyring.clone()).encode(b"secret").unwrap(), encrypted);
+
+        // Entries are decrypted with any key of the keyring, and plaintext entries are still read.
+        let plaintext = Encoder::new(Keyring::default()).encode(b"public").unwrap();
+        let input = [plaintext, encrypted.clone()].concat();
+        assert_eq!(
+            Parser::new(VERSION, old_keyring).parse(&input),
+            Ok(vec![b"public".to_vec(), b"secret".to_vec()]),
+        );
+        assert_eq!(
+            Parser::new(VERSION, rotated_keyring.clone()).parse(&input),
+            Ok(vec![b"public".to_vec(), b"secret".to_vec()]),
+        );
+
+        // Entries encrypted with an unknown key, or altered, can't be decrypted.
+        let parser = Parser::new(VERSION, new_keyring);
+        assert_eq!(
+            parser.parse(&input),
+            Err(ParseError::Undecryptable(vec![b"public".to_vec()], &input[input.len() - encrypted.len()..])),
+        );
+        let mut altered = encrypted[4..encrypted.len() - 4].to_vec();
+        altered[1 + FINGERPRINT_SIZE + NONCE_SIZE] ^= 1;
+        assert_eq!(
+            Parser::new(VERSION, rotated_keyring.clone()).parse(&entry(&altered)),
+            Err(ParseError::Undecryptable(vec![], &entry(&altered))),
+        );
+        // Undecryptable entries can still be found.
+        assert_eq!(parser.find_entry(&input[1..]), Some(input.len() - encrypted.len() - 1));
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(parse_header(b"KRLF\x02\x00"), Ok((5, 2)));
+        assert_eq!(parse_header(&[0, 0, 0, 1, 0]), Ok((0, 1)));
+        assert_eq!(parse_header(&[0]), Ok((0, 1)));
+        assert_eq!(parse_header(&[]), Err(HeaderError::Incomplete));
+        assert_eq!(parse_header(b"KR"), Err(HeaderError::Incomplete));
+        assert_eq!(parse_header(b"KRLF"), Err(HeaderError::Incomplete));
+        assert_eq!(parse_header(b"KRLF\x09"), Err(HeaderError::UnsupportedVersion(9)));
+    }
+
     #[test]
     fn test_parse() {
-        let parser = Parser::new();
+        let parser = Parser::new(2, Keyring::default());
 
         // Test basic valid buffers.
         assert_eq!(
-            parser.parse(&vec![0, 0, 0, 1, 0]),
+            parser.parse(&entry(&[0])),
             Ok(vec![vec![0]]),
         );
         assert_eq!(
-            parser.parse(&vec![0, 0, 0, 1, 0, 0, 0, 0, 1, 1]),
+            parser.parse(&[entry(&[0]), entry(&[1])].concat()),
             Ok(vec![vec![0], vec![1]]),
         );
+        // Test incomplete buffers.
         assert_eq!(
-            parser.parse(&vec![0, 0, 0, 8, 0, 1, 2, 3, 4, 5, 6, 7]),
-            Ok(vec![vec![0, 1, 2, 3, 4, 5, 6, 7]]),
+            parser.parse(&[0]),
+            Err(ParseError::Incomplete(vec![], &[0])),
         );
-        // Test incomplete buffers.
         assert_eq!(
-            parser.parse(&vec![0]),
-            Err(ParseError::Incomplete(vec![], &vec![0])),
+            parser.parse(&[0, 0, 0, 1, 0]),
+            Err(ParseError::Incomplete(vec![], &[0, 0, 0, 1, 0])),
+        );
+        let input = [entry(&[0]), vec![0, 0, 0, 2]].concat();
+        assert_eq!(
+            parser.parse(&input),
+            Err(ParseError::Incomplete(vec![vec![0]], &[0, 0, 0, 2])),
         );
+        // Test corrupted buffers.
+        let mut corrupted = entry(&[1, 2]);
+        corrupted[5] = 3;
+        let input = [entry(&[0]), corrupted.clone(), entry(&[4])].concat();
         assert_eq!(
-            parser.parse(&vec![0, 0, 0, 1]),
-            Err(ParseError::Incomplete(vec![], &vec![0, 0, 0, 1])),
+            parser.parse(&input),
+            Err(ParseError::CorruptedContent(vec![vec![0]], &input[9..])),
         );
+        // Test finding the next valid entry after a corrupted one.
+        assert_eq!(parser.find_entry(&input[10..]), Some(9));
+        assert_eq!(parser.find_entry(&corrupted[1..]), None);
+    }
+
+    #[test]
+    fn test_parse_version_1() {
+        let parser = Parser::new(1, Keyring::default());
+
         assert_eq!(
-            parser.parse(&vec![0, 0, 0, 8, 0, 1, 2, 3]),
-            Err(ParseError::Incomplete(vec![], &vec![0, 0, 0, 8, 0, 1, 2, 3])),
+            parser.parse(&[0, 0, 0, 1, 0, 0, 0, 0, 1, 1]),
+            Ok(vec![vec![0], vec![1]]),
         );
         assert_eq!(
-            parser.parse(&vec![0, 0, 0, 1, 0, 0, 0, 0, 2]),
-            Err(ParseError::Incomplete(vec![vec![0]], &vec![0, 0, 0, 2])),
+            parser.parse(&[0, 0, 0, 1, 0, 0, 0, 0, 2]),
+            Err(ParseError::Incomplete(vec![vec![0]], &[0, 0, 0, 2])),
         );
+        assert_eq!(parser.find_entry(&[0, 0, 0, 1, 0]), None);
     }
 }
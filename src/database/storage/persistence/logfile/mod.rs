@@ -1,39 +1,111 @@
 mod encoding;
 
-use std::fs::File;
-use std::io::{Error as IoError, ErrorKind, Read, Seek, SeekFrom, Write};
-use self::encoding::{Encoder, ParseError, Parser};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{Error as IoError, ErrorKind, SeekFrom, copy as copy_content};
+use std::path::{Path, PathBuf};
+use self::encoding::{Encoder, HeaderError, ParseError, Parser, parse_header};
+use super::filesystem::{Filesystem, Handle, Mode};
+
+pub use self::encoding::{Key, Keyring, VERSION};
 
 pub type Parsed = encoding::Parsed;
 pub enum ReadError {
-    CorruptedFile,
+    /// The file contains an invalid entry followed by valid ones, at the given offset.
+    CorruptedFile(u64),
+    /// The file has been written with an unknown format version.
+    UnsupportedVersion(u8),
+    /// The file contains an entry that can't be decrypted with the keyring, at the given offset.
+    Undecryptable(u64),
+    /// The file ends with an incomplete entry at the given offset, but has no checksums (format
+    /// version 1): whether it's a torn entry or a corruption hiding valid entries can't be known.
+    UncheckedTail(u64),
     UnreadableFile,
 }
-pub type ReadAllResult = Result<Vec<Parsed>, ReadError>;
+/// The content of a logfile.
+pub struct Content {
+    pub entries: Vec<Parsed>,
+    /// The format version of the logfile.
+    pub version: u8,
+    /// When the logfile ends with an incomplete or invalid entry (typically, a write interrupted
+    /// by a crash), the length of the file without this torn entry.
+    pub torn: Option<u64>,
+}
+pub type ReadResult = Result<Content, ReadError>;
+/// The content of a logfile, read while skipping all invalid parts.
+pub struct Salvaged {
+    pub entries: Vec<Parsed>,
+    /// The format version of the logfile.
+    pub version: u8,
+    /// All skipped parts of the file, as offsets and lengths.
+    pub skipped: Vec<(u64, u64)>,
+    pub size: u64,
+}
+pub type SalvageResult = Result<Salvaged, ReadError>;
+
+/// The size of chunks read from logfiles.
+const CHUNK_SIZE: usize = 8192;
 
 /// Read logfile entries from files.
 ///
-/// Logfiles use a simple specific format to be able to store any data. New entries are
-/// concatenated to olders. Each entry is an array of bytes, where the first 4 bytes are the size
-/// (using the big-endian format) of the data, and the rest is the data.
+/// Logfiles use a simple specific format to be able to store any data. They start with a header,
+/// made of the magic number `KRLF` followed by a format version byte. New entries are concatenated
+/// to olders. Each entry is an array of bytes, where the first 4 bytes are the size (using the
+/// big-endian format) of the data, followed by the data, and finally by the CRC32C checksum of
+/// both the size and the data (on 4 bytes, using the big-endian format). Logfiles written before
+/// the introduction of the header (format version 1) have neither header nor checksums. Since the
+/// format version 4, the data starts with its encryption scheme: entries are either stored in
+/// plain bytes, or encrypted with one of the keys of the [`Keyring`].
 pub struct Reader<'a> {
-    file: &'a mut File,
+    file: &'a mut dyn Handle,
+    keyring: Keyring,
 }
 
 impl<'a> Reader<'a> {
-    /// Create a new logfile reader on the given open file.
-    pub fn new(file: &'a mut File) -> Self {
-        Self { file }
+    /// Create a new logfile reader on the given open file, decrypting entries with the given
+    /// keyring.
+    pub fn new(file: &'a mut dyn Handle, keyring: &Keyring) -> Self {
+        Self { file, keyring: keyring.clone() }
+    }
+
+    /// Iterate over all entries from the beginning of the logfile, reading the file progressively.
+    /// Only a few chunks of the file are kept in memory at once, whatever its size. Unlike
+    /// [`read`], a torn entry at the end of the logfile is considered as a corruption: the
+    /// iteration stops with a ReadError::CorruptedFile at the first invalid or incomplete entry
+    /// (or with a ReadError::Undecryptable at the first entry that can't be decrypted).
+    pub fn entries(&mut self) -> Result<Entries<'_>, ReadError> {
+        self.file.seek(SeekFrom::Start(0))?;
+
+        Ok(Entries {
+            file: self.file,
+            keyring: self.keyring.clone(),
+            parser: None,
+            to_parse: Vec::new(),
+            parsed: VecDeque::new(),
+            position: 0,
+            failure: None,
+            terminated: false,
+        })
     }
 
-    /// Read all entries from the beginning of the logfile.
-    pub fn all(&mut self) -> ReadAllResult {
+    /// Read all entries from the beginning of the logfile, tolerating a torn entry at its end. All
+    /// entries are loaded in memory.
+    ///
+    /// An entry is considered torn when it's incomplete or invalid, and when no valid entry can be
+    /// found after it. Otherwise, the logfile is corrupted in its middle, and a
+    /// ReadError::CorruptedFile is returned. An entry that can't be decrypted is never considered
+    /// torn: a ReadError::Undecryptable is returned. Logfiles without checksums (format version 1)
+    /// can't tell a torn entry from a corrupted size hiding the following entries, so bytes left at
+    /// their end are never removed: a ReadError::UncheckedTail is returned.
+    pub fn read(&mut self) -> ReadResult {
         self.file.seek(SeekFrom::Start(0))?;
 
-        // Read the whole content of the file incrementally.
-        let parser = Parser::new();
+        // Read the whole content of the file incrementally. The offset is the position in the
+        // file of the first byte left to parse.
+        let mut version = None;
+        let mut offset = 0;
         let mut to_parse = Vec::new();
-        let mut buffer = [0; 8192];
+        let mut buffer = [0; CHUNK_SIZE];
         let mut results = Vec::new();
         loop {
             let read = match self.file.read(&mut buffer) {
@@ -42,29 +114,271 @@ impl<'a> Reader<'a> {
                 Err(_) => return Err(ReadError::UnreadableFile),
             };
             if read == 0 {
-                if to_parse.len() > 0 {
-                    return Err(ReadError::CorruptedFile);
-                }
                 break;
             }
-
             to_parse.extend(&buffer[0..read]);
-            to_parse = match parser.parse(&to_parse) {
-                Ok(entries) => {
-                    results.extend(entries);
 
-                    Vec::new()
+            let parser = match version {
+                Some(version) => Parser::new(version, self.keyring.clone()),
+                None => match parse_header(&to_parse) {
+                    Ok((size, parsed)) => {
+                        to_parse.drain(0..size);
+                        offset += size as u64;
+                        version = Some(parsed);
+
+                        Parser::new(parsed, self.keyring.clone())
+                    },
+                    Err(HeaderError::Incomplete) => continue,
+                    Err(HeaderError::UnsupportedVersion(version)) => return Err(ReadError::UnsupportedVersion(version)),
                 },
-                Err(ParseError::Incomplete(entries, input_left)) => {
-                    results.extend(entries);
+            };
+            let (entries, consumed, corrupted) = match parser.parse(&to_parse) {
+                Ok(entries) => (entries, to_parse.len(), false),
+                Err(ParseError::Incomplete(entries, input_left)) => (entries, to_parse.len() - input_left.len(), false),
+                Err(ParseError::CorruptedContent(entries, input_left)) => (entries, to_parse.len() - input_left.len(), true),
+                Err(ParseError::Undecryptable(_, input_left)) => {
+                    return Err(ReadError::Undecryptable(offset + (to_parse.len() - input_left.len()) as u64));
+                },
+            };
+            results.extend(entries);
+            to_parse.drain(0..consumed);
+            offset += consumed as u64;
+
+            // Everything after an invalid entry must be analyzed to know if it's a torn entry.
+            if corrupted {
+                self.file.read_to_end(&mut to_parse)?;
+                break;
+            };
+        };
+
+        // Files without any header (being empty, or with an incomplete header) are considered as
+        // using the current format version.
+        let version = version.unwrap_or(VERSION);
+        let parser = Parser::new(version, self.keyring.clone());
+        let torn = match to_parse.is_empty() {
+            true => None,
+            false if !parser.is_checksummed() => return Err(ReadError::UncheckedTail(offset)),
+            false => match parser.find_entry(&to_parse[1..]) {
+                Some(_) => return Err(ReadError::CorruptedFile(offset)),
+                None => Some(offset),
+            },
+        };
 
-                    input_left.to_vec()
+        Ok(Content { entries: results, version, torn })
+    }
+
+    /// Read all valid entries of the logfile, skipping invalid parts. Invalid parts can only be
+    /// skipped in checksummed logfiles: in older logfiles, everything after an invalid part is
+    /// skipped. Entries that can't be decrypted are never skipped: a ReadError::Undecryptable is
+    /// returned instead, since they are valid for another keyring. The whole file is loaded in
+    /// memory.
+    pub fn salvage(&mut self) -> SalvageResult {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut content = Vec::new();
+        self.file.read_to_end(&mut content)?;
+
+        let (mut position, version) = match parse_header(&content) {
+            Ok(header) => header,
+            Err(HeaderError::Incomplete) => (0, VERSION),
+            Err(HeaderError::UnsupportedVersion(version)) => return Err(ReadError::UnsupportedVersion(version)),
+        };
+        let parser = Parser::new(version, self.keyring.clone());
+        let mut entries = Vec::new();
+        let mut skipped = Vec::new();
+        while position < content.len() {
+            match parser.parse(&content[position..]) {
+                Ok(parsed) => {
+                    entries.extend(parsed);
+                    position = content.len();
                 },
-                Err(ParseError::CorruptedContent) => return Err(ReadError::CorruptedFile),
+                Err(ParseError::Incomplete(parsed, input_left)) | Err(ParseError::CorruptedContent(parsed, input_left)) => {
+                    entries.extend(parsed);
+                    let start = content.len() - input_left.len();
+                    let length = match parser.find_entry(&input_left[1..]) {
+                        Some(next) => next + 1,
+                        None => input_left.len(),
+                    };
+                    skipped.push((start as u64, length as u64));
+                    position = start + length;
+                },
+                Err(ParseError::Undecryptable(_, input_left)) => {
+                    return Err(ReadError::Undecryptable((content.len() - input_left.len()) as u64));
+                },
+            };
+        };
+
+        Ok(Salvaged { entries, version, skipped, size: content.len() as u64 })
+    }
+}
+
+/// An iterator over the entries of a logfile, created by [`Reader::entries`].
+pub struct Entries<'a> {
+    file: &'a mut dyn Handle,
+    keyring: Keyring,
+    parser: Option<Parser>,
+    /// The bytes read from the file but not parsed yet.
+    to_parse: Vec<u8>,
+    /// The entries parsed but not returned yet.
+    parsed: VecDeque<Parsed>,
+    /// The position in the file of the first byte left to parse.
+    position: u64,
+    /// The error stopping the iteration, once all previous entries have been returned.
+    failure: Option<ReadError>,
+    terminated: bool,
+}
+
+impl<'a> Entries<'a> {
+    /// Get the number of bytes of the logfile read and parsed so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Read the next chunk of the file and parse all complete entries it contains.
+    fn fill(&mut self) -> Result<(), ReadError> {
+        let mut buffer = [0; CHUNK_SIZE];
+        let read = loop {
+            match self.file.read(&mut buffer) {
+                Ok(read) => break read,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return Err(ReadError::UnreadableFile),
             };
         };
+        // Bytes left at the end of the file are a torn entry (or a torn header).
+        if read == 0 {
+            self.terminated = true;
+            if !self.to_parse.is_empty() {
+                self.failure = Some(ReadError::CorruptedFile(self.position));
+            };
+
+            return Ok(());
+        };
+        self.to_parse.extend(&buffer[0..read]);
+
+        let parser = match &self.parser {
+            Some(parser) => parser,
+            None => match parse_header(&self.to_parse) {
+                Ok((size, version)) => {
+                    self.to_parse.drain(0..size);
+                    self.position += size as u64;
+
+                    self.parser.get_or_insert(Parser::new(version, self.keyring.clone()))
+                },
+                Err(HeaderError::Incomplete) => return Ok(()),
+                Err(HeaderError::UnsupportedVersion(version)) => return Err(ReadError::UnsupportedVersion(version)),
+            },
+        };
+        let (entries, consumed) = match parser.parse(&self.to_parse) {
+            Ok(entries) => (entries, self.to_parse.len()),
+            Err(ParseError::Incomplete(entries, input_left)) => (entries, self.to_parse.len() - input_left.len()),
+            Err(ParseError::CorruptedContent(entries, input_left)) => {
+                let consumed = self.to_parse.len() - input_left.len();
+                self.terminated = true;
+                self.failure = Some(ReadError::CorruptedFile(self.position + consumed as u64));
+
+                (entries, consumed)
+            },
+            Err(ParseError::Undecryptable(entries, input_left)) => {
+                let consumed = self.to_parse.len() - input_left.len();
+                self.terminated = true;
+                self.failure = Some(ReadError::Undecryptable(self.position + consumed as u64));
 
-        return Ok(results);
+                (entries, consumed)
+            },
+        };
+        self.parsed.extend(entries);
+        self.to_parse.drain(0..consumed);
+        self.position += consumed as u64;
+
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Parsed, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.parsed.pop_front() {
+                return Some(Ok(entry));
+            };
+            if let Some(failure) = self.failure.take() {
+                return Some(Err(failure));
+            };
+            if self.terminated {
+                return None;
+            };
+            if let Err(error) = self.fill() {
+                self.terminated = true;
+
+                return Some(Err(error));
+            };
+        }
+    }
+}
+
+/// Parse logfile content received progressively, in chunks of any size (like a replication
+/// stream). Unlike files, streams can't end with a torn entry: any invalid entry is a corruption.
+/// Streams are never encrypted (see [`encode`]): encrypted entries are rejected as undecryptable.
+pub struct StreamParser {
+    parser: Option<Parser>,
+    version: u8,
+    /// The bytes received but not parsed yet.
+    to_parse: Vec<u8>,
+    /// The position in the stream of the first byte left to parse.
+    position: u64,
+}
+
+impl StreamParser {
+    /// Create a new parser, for a stream starting with a logfile header.
+    pub fn new() -> Self {
+        Self {
+            parser: None,
+            version: VERSION,
+            to_parse: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Get the format version of the stream, once its header has been parsed.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Parse the given chunk of the stream, returning all entries completed by it. It fails with a
+    /// ReadError::CorruptedFile at the first invalid entry.
+    pub fn parse(&mut self, chunk: &[u8]) -> Result<Vec<Parsed>, ReadError> {
+        self.to_parse.extend(chunk);
+
+        let parser = match &self.parser {
+            Some(parser) => parser,
+            None => match parse_header(&self.to_parse) {
+                // Streams always start with a header.
+                Ok((0, _)) => return Err(ReadError::CorruptedFile(0)),
+                Ok((size, version)) => {
+                    self.to_parse.drain(0..size);
+                    self.position += size as u64;
+                    self.version = version;
+
+                    self.parser.get_or_insert(Parser::new(version, Keyring::default()))
+                },
+                Err(HeaderError::Incomplete) => return Ok(Vec::new()),
+                Err(HeaderError::UnsupportedVersion(version)) => return Err(ReadError::UnsupportedVersion(version)),
+            },
+        };
+        let (entries, consumed) = match parser.parse(&self.to_parse) {
+            Ok(entries) => (entries, self.to_parse.len()),
+            Err(ParseError::Incomplete(entries, input_left)) => (entries, self.to_parse.len() - input_left.len()),
+            Err(ParseError::CorruptedContent(_, input_left)) => {
+                return Err(ReadError::CorruptedFile(self.position + (self.to_parse.len() - input_left.len()) as u64));
+            },
+            Err(ParseError::Undecryptable(_, input_left)) => {
+                return Err(ReadError::Undecryptable(self.position + (self.to_parse.len() - input_left.len()) as u64));
+            },
+        };
+        self.to_parse.drain(0..consumed);
+        self.position += consumed as u64;
+
+        Ok(entries)
     }
 }
 
@@ -83,52 +397,55 @@ pub enum WriteError {
 }
 pub type WriteResult = Result<(), WriteError>;
 
-/// Write logfile entries to files.
+/// Write logfile entries to files, using the current format version, and encrypting them with the
+/// current key of the keyring (if any).
 pub struct Writer<'a> {
-    file: &'a mut File,
+    file: &'a mut dyn Handle,
     encoder: Encoder,
+    started: bool,
 }
 
 impl<'a> Writer<'a> {
     /// Create a new logfile writer on the given open file. The file should be open with write
-    /// privileges.
-    pub fn new(file: &'a mut File) -> Self {
+    /// privileges. When the file is empty, the logfile header is written along with the first
+    /// entry.
+    pub fn new(file: &'a mut dyn Handle, keyring: &Keyring) -> Self {
         Self {
             file,
-            encoder: Encoder::new(),
+            encoder: Encoder::new(keyring.clone()),
+            started: false,
         }
     }
 
     /// Write the given entry to the logfile at the current cursor position. This method verifies
     /// that data are synchronized to the file system before returning.
     pub fn write_sync(&mut self, entry: &[u8]) -> WriteResult {
-        let entry = match self.encoder.encode(entry) {
-            Ok(entry) => entry,
-            Err(_) => return Err(WriteError::InvalidData),
-        };
+        self.write(entry)?;
 
-        match self.file.write_all(&entry) {
-            Ok(_) => match self.file.sync_data() {
-                Ok(_) => Ok(()),
-                Err(_) => {
-                    Err(WriteError::WriteFailure)
-                },
-            },
-            Err(_) => {
-                Err(WriteError::WriteFailure)
-            },
-        }
+        self.sync()
     }
 
     /// Write the given entry to the logfile at the current cursor position.
     pub fn write(&mut self, entry: &[u8]) -> WriteResult {
-        let entry = match self.encoder.encode(entry) {
-            Ok(entry) => entry,
+        let mut encoded = match self.started {
+            true => Vec::new(),
+            false => match self.file.size() {
+                Ok(0) => self.encoder.encode_header(),
+                Ok(_) => Vec::new(),
+                Err(_) => return Err(WriteError::WriteFailure),
+            },
+        };
+        match self.encoder.encode(entry) {
+            Ok(entry) => encoded.extend(entry),
             Err(_) => return Err(WriteError::InvalidData),
         };
 
-        match self.file.write_all(&entry) {
-            Ok(_) => Ok(()),
+        match self.file.write_all(&encoded) {
+            Ok(_) => {
+                self.started = true;
+
+                Ok(())
+            },
             Err(_) => Err(WriteError::WriteFailure),
         }
     }
@@ -142,3 +459,267 @@ impl<'a> Writer<'a> {
         }
     }
 }
+
+/// Encode the header starting all logfiles (and streams using the logfile format) written with
+/// the current format version.
+pub fn encode_header() -> Vec<u8> {
+    Encoder::new(Keyring::default()).encode_header()
+}
+
+/// Encode the given entry in plain bytes, to be appended to a stream using the logfile format.
+/// Encryption only applies to data at rest: each receiver encrypts entries with its own keyring
+/// when writing them.
+pub fn encode(entry: &[u8]) -> Result<Vec<u8>, WriteError> {
+    match Encoder::new(Keyring::default()).encode(entry) {
+        Ok(encoded) => Ok(encoded),
+        Err(_) => Err(WriteError::InvalidData),
+    }
+}
+
+/// Replace the logfile at the given path of the given file system by a new logfile, containing the
+/// given entries and using the current format version and the current key of the given keyring.
+/// The new logfile is written aside, then atomically moved: on failure, the original logfile is
+/// left untouched.
+pub fn rewrite(filesystem: &dyn Filesystem, path: &Path, entries: &[Parsed], keyring: &Keyring) -> WriteResult {
+    let temporary_path = aside(path, ".rewriting");
+
+    let mut file = match filesystem.open(&temporary_path, Mode::Create) {
+        Ok(file) => file,
+        Err(_) => return Err(WriteError::WriteFailure),
+    };
+    let mut writer = Writer::new(&mut file, keyring);
+    for entry in entries {
+        writer.write(entry)?;
+    };
+    writer.sync()?;
+    drop(file);
+
+    match filesystem.rename(&temporary_path, path) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(WriteError::WriteFailure),
+    }
+}
+
+/// Copy the logfile at the given path of the given file system to the given destination. The copy
+/// is written aside and synchronized, then atomically moved: the destination never contains a
+/// partial copy.
+pub fn copy(filesystem: &dyn Filesystem, path: &Path, destination: &Path) -> WriteResult {
+    let temporary_path = aside(destination, ".copying");
+
+    let copied = filesystem.open(path, Mode::Read)
+        .and_then(|mut source| {
+            let mut copy = filesystem.open(&temporary_path, Mode::Create)?;
+            copy_content(&mut source, &mut copy)?;
+
+            copy.sync_all()
+        })
+        .and_then(|_| filesystem.rename(&temporary_path, destination))
+    ;
+    match copied {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            let _ = filesystem.remove_file(&temporary_path);
+
+            Err(WriteError::WriteFailure)
+        },
+    }
+}
+
+/// Get the path of a temporary file written aside the given path, with the given suffix.
+fn aside(path: &Path, suffix: &str) -> PathBuf {
+    let mut temporary_path = OsString::from(path);
+    temporary_path.push(suffix);
+
+    PathBuf::from(temporary_path)
+}
+
+/// Truncate the given logfile to the given length, removing its torn entry.
+pub fn truncate(file: &mut dyn Handle, length: u64) -> WriteResult {
+    match file.set_len(length).and_then(|_| file.sync_data()) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(WriteError::WriteFailure),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use super::super::filesystem::Disk;
+
+    /// Create a logfile containing the given raw content in the temporary directory.
+    fn create(name: &str, content: &[u8]) -> (PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("kairoi-test-logfile-{}-{}", name, std::process::id()));
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.write_all(content).unwrap();
+
+        (path, file)
+    }
+
+    /// Encode the given entries with the current format version.
+    fn encode(entries: &[&[u8]]) -> Vec<u8> {
+        let encoder = Encoder::new(Keyring::default());
+        let mut content = encoder.encode_header();
+        for entry in entries {
+            content.extend(encoder.encode(entry).ok().unwrap());
+        };
+
+        content
+    }
+
+    #[test]
+    fn test_read_torn_entry() {
+        let mut content = encode(&[&[1], &[2, 2]]);
+        let length = content.len() as u64;
+        content.extend(&encode(&[&[3, 3, 3]])[5..12]);
+        let (path, mut file) = create("torn", &content);
+
+        let read = Reader::new(&mut file, &Keyring::default()).read().ok().unwrap();
+        assert_eq!(read.entries, vec![vec![1], vec![2, 2]]);
+        assert_eq!(read.version, VERSION);
+        assert_eq!(read.torn, Some(length));
+        let entries: Result<Vec<_>, _> = Reader::new(&mut file, &Keyring::default()).entries().ok().unwrap().collect();
+        assert!(matches!(entries, Err(ReadError::CorruptedFile(offset)) if offset == length));
+
+        truncate(&mut file, length).ok().unwrap();
+        let entries: Result<Vec<_>, _> = Reader::new(&mut file, &Keyring::default()).entries().ok().unwrap().collect();
+        assert_eq!(entries.ok().unwrap(), vec![vec![1], vec![2, 2]]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_corrupted_entry() {
+        let mut content = encode(&[&[1], &[2, 2], &[3, 3, 3]]);
+        // Alter the data of the second entry.
+        content[5 + 10 + 5] = 9;
+        let (path, mut file) = create("corrupted", &content);
+
+        assert!(matches!(Reader::new(&mut file, &Keyring::default()).read(), Err(ReadError::CorruptedFile(15))));
+        let salvaged = Reader::new(&mut file, &Keyring::default()).salvage().ok().unwrap();
+        assert_eq!(salvaged.entries, vec![vec![1], vec![3, 3, 3]]);
+        assert_eq!(salvaged.skipped, vec![(15, 11)]);
+        assert_eq!(salvaged.size, content.len() as u64);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_and_rewrite_version_1() {
+        let (path, mut file) = create("version-1", &[0, 0, 0, 1, 1, 0, 0, 0, 2, 2, 2, 0, 0]);
+
+        // Without checksums, the bytes left at the end can't be proven to be a torn entry.
+        assert!(matches!(Reader::new(&mut file, &Keyring::default()).read(), Err(ReadError::UncheckedTail(11))));
+        let salvaged = Reader::new(&mut file, &Keyring::default()).salvage().ok().unwrap();
+        assert_eq!(salvaged.entries, vec![vec![1], vec![2, 2]]);
+        assert_eq!(salvaged.version, 1);
+        assert_eq!(salvaged.skipped, vec![(11, 2)]);
+
+        rewrite(&Disk, &path, &salvaged.entries, &Keyring::default()).ok().unwrap();
+        let mut file = OpenOptions::new().read(true).open(&path).unwrap();
+        let read = Reader::new(&mut file, &Keyring::default()).read().ok().unwrap();
+        assert_eq!(read.entries, vec![vec![1], vec![2, 2]]);
+        assert_eq!(read.version, VERSION);
+        assert_eq!(read.torn, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_entries() {
+        // An entry larger than a chunk must be read across several chunks.
+        let large = vec![7; CHUNK_SIZE * 2 + 3];
+        let content = encode(&[&[1], &large, &[2, 2]]);
+        let (path, mut file) = create("entries", &content);
+
+        let mut reader = Reader::new(&mut file, &Keyring::default());
+        let mut entries = reader.entries().ok().unwrap();
+        assert_eq!(entries.next().unwrap().ok().unwrap(), vec![1]);
+        assert_eq!(entries.next().unwrap().ok().unwrap(), large);
+        assert_eq!(entries.next().unwrap().ok().unwrap(), vec![2, 2]);
+        assert!(entries.next().is_none());
+        assert_eq!(entries.position(), content.len() as u64);
+        std::fs::remove_file(&path).unwrap();
+
+        // The iteration stops at the first corrupted entry, after returning all previous ones.
+        let mut content = encode(&[&[1], &[2, 2], &[3, 3, 3]]);
+        content[5 + 10 + 5] = 9;
+        let (path, mut file) = create("entries-corrupted", &content);
+        let mut reader = Reader::new(&mut file, &Keyring::default());
+        let entries: Vec<_> = reader.entries().ok().unwrap().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].as_ref().ok().unwrap(), &vec![1]);
+        assert!(matches!(entries[1], Err(ReadError::CorruptedFile(15))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_copy() {
+        let content = encode(&[&[1], &[2, 2]]);
+        let (path, _) = create("copy", &content);
+        let destination = path.with_extension("copied");
+
+        copy(&Disk, &path, &destination).ok().unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), content);
+        assert!(copy(&Disk, &path.with_extension("missing"), &destination).is_err());
+        assert_eq!(std::fs::read(&destination).unwrap(), content);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&destination).unwrap();
+    }
+
+    #[test]
+    fn test_stream_parser() {
+        let mut content = encode(&[&[1], &[2, 2], &[3, 3, 3]]);
+
+        // Entries are returned once complete, whatever the size of chunks.
+        let mut parser = StreamParser::new();
+        let mut entries = Vec::new();
+        for byte in &content {
+            entries.extend(parser.parse(&[*byte]).ok().unwrap());
+        };
+        assert_eq!(entries, vec![vec![1], vec![2, 2], vec![3, 3, 3]]);
+        assert_eq!(parser.version(), VERSION);
+
+        // Alter the data of the second entry.
+        content[5 + 10 + 5] = 9;
+        let mut parser = StreamParser::new();
+        assert!(matches!(parser.parse(&content), Err(ReadError::CorruptedFile(15))));
+        assert!(matches!(StreamParser::new().parse(&[0, 0, 0, 1, 1]), Err(ReadError::CorruptedFile(0))));
+        assert!(matches!(StreamParser::new().parse(b"KRLF\x09"), Err(ReadError::UnsupportedVersion(9))));
+    }
+
+    #[test]
+    fn test_read_encrypted() {
+        let keyring = Keyring::new(Some(Key::new([1; encoding::KEY_SIZE])), vec![]);
+        let (path, mut file) = create("encrypted", &[]);
+        let mut writer = Writer::new(&mut file, &keyring);
+        writer.write(&[1]).ok().unwrap();
+        writer.write(&[2, 2]).ok().unwrap();
+        writer.sync().ok().unwrap();
+
+        let read = Reader::new(&mut file, &keyring).read().ok().unwrap();
+        assert_eq!(read.entries, vec![vec![1], vec![2, 2]]);
+        let entries: Result<Vec<_>, _> = Reader::new(&mut file, &keyring).entries().ok().unwrap().collect();
+        assert_eq!(entries.ok().unwrap(), vec![vec![1], vec![2, 2]]);
+
+        // Entries encrypted with an unknown key are never skipped nor considered torn.
+        let unknown = Keyring::new(Some(Key::new([2; encoding::KEY_SIZE])), vec![]);
+        assert!(matches!(Reader::new(&mut file, &unknown).read(), Err(ReadError::Undecryptable(5))));
+        assert!(matches!(Reader::new(&mut file, &unknown).salvage(), Err(ReadError::Undecryptable(5))));
+        let entries: Vec<_> = Reader::new(&mut file, &unknown).entries().ok().unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        assert!(matches!(entries[0], Err(ReadError::Undecryptable(5))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_empty_and_torn_header() {
+        let (path, mut file) = create("empty", &[]);
+        let read = Reader::new(&mut file, &Keyring::default()).read().ok().unwrap();
+        assert_eq!((read.entries.len(), read.version, read.torn), (0, VERSION, None));
+        std::fs::remove_file(&path).unwrap();
+
+        let (path, mut file) = create("torn-header", b"KRL");
+        let read = Reader::new(&mut file, &Keyring::default()).read().ok().unwrap();
+        assert_eq!((read.entries.len(), read.version, read.torn), (0, VERSION, Some(0)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -5,23 +5,59 @@
 //! it from growing infinitely. This compressing process is fully error-proof: the file system is
 //! always kept in a recoverable state, in case of system failure.
 //!
-//! When compressing, the process duplicates a lot of entries in memory, thus increasing the memory
-//! usage. The current implementation is optimized for better compressing smaller logfiles, since
-//! the entire logfile to compress is loaded in memory. It should, however, be noted that for now,
-//! the full content of the compressed file is copied at each compression process, potentially
-//! generating high IO pressure following the size of the database.
+//! When compressing, the process keeps the latest entry of each item of the logfile to compress in
+//! memory, while the compressed file is streamed: the memory usage is proportional to the recent
+//! logfile, not to the whole database. It should, however, be noted that for now, the full content
+//! of the compressed file is copied at each compression process, potentially generating high IO
+//! pressure following the size of the database.
+//!
+//! The database thread doesn't use the storage directly, but through a [`Writer`], running it in a
+//! dedicated thread so file system operations never stall the database.
+//!
+//! The logfile storage is the default persistence [`Backend`]: the writer may use another one,
+//! like an embedded sled store (see the [`backend`] module). The following sections only describe
+//! the logfile storage.
 //!
 //! # Internals
 //!
+//! ## Logfile Integrity
+//!
+//! Each logfile entry is followed by its CRC32C checksum. When initializing, an invalid or
+//! incomplete entry at the end of a logfile is considered as a write interrupted by a crash: it's
+//! removed from the file, with a warning. An invalid entry followed by valid ones can't be caused
+//! by a crash, so the initialization fails instead of silently losing data. For the same reason,
+//! when a write fails while the storage is running, what may have been written of the entry is
+//! truncated right away (and before any other write to the logfile, when it fails).
+//!
+//! ## Format Versions
+//!
+//! All logfiles start with a header, containing a magic number and the format version used to
+//! write them. The encoder keeps decoders for all older format versions. Logfiles using an older
+//! format version are migrated when initializing, before being used: all their entries are decoded
+//! with their format version, encoded with the current one, and written to a new logfile,
+//! atomically replacing the old one.
+//!
+//! ## Synchronization
+//!
+//! Depending on the configured [`Fsync`] policy, the logfile is either synchronized to the file
+//! system after each entry, or by [`Storage::commit`]. Before closing the logfile (when it's moved
+//! to be compressed, or after a write failure), pending entries are synchronized, so a commit
+//! always covers all entries persisted since the previous one.
+//!
 //! ## Compression Process
 //!
 //! When persisting an entry in the persistent storage, a check is made to verify if the logfile
-//! contains 5000 or more entries and if there is no compression process running. If it happens, a
-//! new compression process is launched. Its first step is to flush the logfile to the file
-//! `logfile.to_compress`. This flush happens synchronously with the current persist operation.
+//! reached the configured [`CompactionTrigger`] and if there is no compression process running. If
+//! it happens, a new compression process is launched. Its first step is to flush the logfile to the
+//! file `logfile.to_compress`. This flush happens synchronously with the current persist operation.
 //! Once it's done, a background process is started, and the persist operation succeeds. On
 //! subsequent persist operations, a check is made to verify that the background compression
-//! process has been terminated.
+//! process has been terminated. A compression process can also be started on demand, with
+//! [`Storage::compact`].
+//!
+//! The background process shares its progress (the number of bytes of logfiles processed, over the
+//! total size of logfiles to process) with the storage. Along with the result of the last terminated
+//! process, it's available through [`Storage::get_compaction`].
 //!
 //! The background process manipulates the 3 following files:
 //!
@@ -30,49 +66,151 @@
 //! * and `logfile.compressing`, a temporary file, filled by the compression process, that will
 //!   serve as the new compressed file.
 //!
-//! The whole content of `logfile.to_compress` and `logfile.compressed` is currently loaded in
-//! memory. While this is a deliberate choice for the first file, the second file could completely
-//! be read as a stream (it's an improvement for later).
-//!
-//! The background process starts by reading entries from `logfile.to_compress` and apply a
-//! deduplication on them (keeping only the latest entry for a given item). It then goes through
-//! all entries from `logfile.compressed`, and insert entries being absent from
-//! `logfile.to_compress` into `logfile.compressing`. After that, it inserts all deduplicated
-//! entries from `logfile.to_compress` into `logfile.compressing`. Finally, it moves
-//! `logfile.compressing` to replace `logfile.compressed`, deletes `logfile.to_compress`, and
+//! The background process starts by streaming entries from `logfile.to_compress` and apply a
+//! deduplication on them in memory (keeping only the latest entry for a given item). It then
+//! streams all entries from `logfile.compressed`, one at a time, and insert entries being absent
+//! from `logfile.to_compress` into `logfile.compressing`. After that, it inserts all deduplicated
+//! entries from `logfile.to_compress` into `logfile.compressing`, except removal entries (once the
+//! removed item is absent from the compressed file, there is nothing left to remove). Finally, it
+//! moves `logfile.compressing` to replace `logfile.compressed`, deletes `logfile.to_compress`, and
 //! notifies the main process that everything went well.
 //!
 //! At any time, the background process can fail, keeping `logfile.compressed` and
 //! `logfile.to_compress` in their original state. It can then be resumed without any data loss.
-//! The only loss that can occur happens if we flush the logfile again during a compression process
-//! (it would overwrite the content of `logfile.to_compress` with the new content of `logfile`,
-//! thus losing it). To prevent this, the initialization process is improved to load the content of
-//! `logfile.to_compress` and resume compressing if needed. It should be noted that, in order for
+//! The only loss that can occur happens if we flush the logfile again before the compression
+//! process succeeded (it would overwrite the content of `logfile.to_compress` with the new content
+//! of `logfile`, thus losing it). To prevent this, the next compression process started while
+//! `logfile.to_compress` exists resumes it instead of flushing the logfile, and the initialization
+//! process is improved to load the content of `logfile.to_compress` and resume compressing if
+//! needed. It should be noted that, in order for
 //! this process to work, all 3 logfiles must be on the same file system, supporting the atomic
 //! file move.
+//!
+//! ## Backups
+//!
+//! A backup is a compression process started on demand with [`Storage::backup`], copying the new
+//! `logfile.compressed` to the backup file once it has replaced the old one. Since the logfile is
+//! flushed when the process starts, the backup is a consistent snapshot of the storage at that
+//! time, and since no other compression process can start while it's running, the copied file
+//! can't be replaced during the copy. The backup file is written aside and atomically moved, so it
+//! only appears once complete. It's a regular compressed logfile: restoring it only requires to
+//! use it as the `logfile.compressed` of an empty data directory.
+//!
+//! ## Replication
+//!
+//! The storage can be replicated to replicas, connecting through TCP (see the [`replication`]
+//! module for the replication stream itself). When a replica connects, [`Storage::replicate`]
+//! opens all logfiles for its snapshot right away, along with the number of entries of
+//! `logfile` at that time: open files can still be read once replaced or removed by a compression
+//! process, so the snapshot is consistent. `logfile.to_compress` is opened before
+//! `logfile.compressed`, so a compression process terminating in between only sends some entries
+//! twice, instead of losing them. Then, each entry written by [`Storage::persist`] is fed to all
+//! replicas, once synchronized to the file system: with the `Frame` and `EverySecond` fsync
+//! policies, entries are held until the next synchronization by [`Storage::commit`], so a replica
+//! never holds entries its primary may lose in a crash. When the synchronization fails, all
+//! replicas are disconnected instead, and receive a new snapshot once reconnected.
+//!
+//! On the replica side, a [`Follower`] receives the replication stream. Before receiving a new
+//! snapshot, the replica removes all its logfiles with [`Storage::reset`], then it persists
+//! received entries like its own. Since the snapshot replaces all data, the data directory of a
+//! primary (including a promoted replica) contains a `primary` file (see [`mark_primary`]), and a
+//! replica refuses to use such a directory.
+//!
+//! ## Clustered Mode
+//!
+//! In clustered mode, the storage is the state machine of a log replicated between several nodes
+//! (see the [`cluster`] module). The writer thread proposes persisted entries to the cluster
+//! instead of writing them, and writes entries to the logfile once committed by the cluster, on
+//! all nodes. The replicated log is stored aside, in `raft.state` and `raft.log`. Since committed
+//! entries are applied to the logfile in order, reading all logfiles gives the state machine as
+//! applied so far: it's used for the snapshots sent to lagging nodes, with [`Storage::entries`].
+//!
+//! ## Encryption
+//!
+//! Entries of all logfiles may be encrypted with AES-256-GCM, using the current key of the
+//! configured [`Keyring`]: each entry is encrypted on its own, with a random nonce, and starts with
+//! the fingerprint of its key. Entries are decrypted with any key of the keyring, so a logfile may
+//! contain entries written in plain bytes or with several keys. Rotating the key only requires to
+//! configure the old key as a previous key: since the compression process rewrites every live
+//! entry, all entries are encrypted with the new key once compressed (and the old key can be
+//! removed). Replication streams are never encrypted, each node encrypting entries with its own
+//! keyring.
+//!
+//! ## Data Directory
+//!
+//! All files are stored in the configured data directory (created when initializing if needed).
+//! When initializing, the storage takes an exclusive lock on the `lock` file of this directory,
+//! and keeps it during its whole lifetime. Two servers (or a server and an offline administration
+//! command) can thus never use the same data directory at the same time. The lock is an advisory
+//! lock held by the process: it's automatically released when the process stops, even after a
+//! crash.
+//!
+//! ## File System
+//!
+//! The storage (including its compression process) only uses the file system through the
+//! [`Filesystem`] trait (see the [`filesystem`] module). Tests run it on an in-memory file system
+//! injecting failed writes, synchronizations and renames, and power losses at every step of
+//! persisting and compressing entries, checking that no acknowledged entry is ever lost.
 
+pub mod administration;
 mod background;
+mod backend;
+mod cluster;
 mod encoder;
+mod filesystem;
+mod handshake;
 mod logfile;
+mod replication;
+#[cfg(feature = "backend-sled")]
+mod sled;
+mod writer;
 
 use background::{Process, Status, TaskError, TaskResult};
-use log::{debug, error};
+use chrono::{DateTime, offset::Utc};
+use log::{debug, error, info, warn};
+use fs2::FileExt;
 use self::encoder::{Decoded, Encodable, Encoder};
+use self::filesystem::{Disk, Filesystem, Handle, Lock, Mode};
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions, remove_file, rename};
-use std::io::ErrorKind;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender, channel};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+pub use self::backend::{Backend, Kind as BackendKind};
+pub use self::cluster::{Configuration as ClusterConfiguration, Event as ClusterEvent};
+pub use self::handshake::Secret;
+pub use self::logfile::{Key, Keyring};
+pub use self::replication::{Event as ReplicationEvent, Follower};
+pub use self::writer::Writer;
+
+/// The name of the file marking a data directory as written by a primary.
+const PRIMARY_MARKER: &str = "primary";
 
 pub type Job = encoder::Job;
+pub type JobRemoval = encoder::JobRemoval;
 pub type JobStatus = encoder::JobStatus;
 pub type Rule = encoder::Rule;
 pub type Runner = encoder::Runner;
 pub enum Entry {
     Job(Job),
+    JobRemoval(JobRemoval),
     Rule(Rule),
 }
 pub enum InitializationError {
+    CorruptedFile,
+    /// A logfile contains an entry encrypted with a key absent from the keyring.
+    UndecryptableFile,
     InvalidEntry,
+    LockedDirectory,
     UnreadableFile,
+    /// The address to listen to cluster peers can't be bound.
+    UnavailableAddress,
 }
 pub type InitializationResult = Result<Vec<Entry>, InitializationError>;
 pub enum PersistError {
@@ -81,197 +219,476 @@ pub enum PersistError {
 }
 pub type PersistResult = Result<(), PersistError>;
 pub struct Configuration {
-    pub fsync_on_persist: bool,
+    pub backend: BackendKind,
+    pub data_directory: PathBuf,
+    pub fsync: Fsync,
+    pub compaction_trigger: CompactionTrigger,
+    /// The keys encrypting and decrypting entries of logfiles.
+    pub keyring: Keyring,
+    /// The cluster replicating the storage, if any.
+    pub cluster: Option<ClusterConfiguration>,
+}
+/// When persisted entries are synchronized to the file system.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fsync {
+    /// Each entry is synchronized before [`Storage::persist`] returns.
+    Always,
+    /// Entries are synchronized all at once, by [`Storage::commit`].
+    Frame,
+    /// Entries are synchronized by [`Storage::commit`], at most once per second.
+    EverySecond,
+    /// Entries are never explicitly synchronized, leaving it to the operating system.
+    Never,
+}
+/// The condition starting a compression process, checked after each persisted entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionTrigger {
+    /// The logfile contains at least the given number of entries.
+    Entries(usize),
+    /// The logfile weighs at least the given number of bytes.
+    Size(u64),
+    /// The logfile weighs at least the given ratio of `logfile.compressed`, and at least the given
+    /// minimum size in bytes. Since entries of `logfile.compressed` are unique, this ratio
+    /// estimates the share of stale entries in the whole storage.
+    Ratio {
+        ratio: f64,
+        minimum_size: u64,
+    },
+}
+pub enum CompactError {
+    /// A compression process is already running.
+    AlreadyRunning,
+    Failure,
+}
+/// The state of the compression process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compaction {
+    pub running: Option<CompactionProgress>,
+    pub last: Option<CompactionResult>,
+}
+/// The progress of a running compression process, in bytes of logfiles.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionProgress {
+    pub started: DateTime<Utc>,
+    pub processed_bytes: u64,
+    pub total_bytes: u64,
+}
+/// The result of a terminated compression process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionResult {
+    pub success: bool,
+    pub finished: DateTime<Utc>,
+    /// The number of entries written to `logfile.compressed`.
+    pub entries: usize,
+}
+pub enum ListenError {
+    /// The address to listen to replicas can't be bound.
+    UnavailableAddress,
+}
+pub enum LockError {
+    /// The directory is already locked by another process.
+    Locked,
+    Failure,
+}
+
+/// The paths of all files used by the persistent storage, in its data directory.
+#[derive(Clone)]
+struct Paths {
+    logfile: PathBuf,
+    to_compress: PathBuf,
+    compressed: PathBuf,
+    compressing: PathBuf,
+}
+
+impl Paths {
+    /// Create the paths of all files in the given data directory.
+    fn new(directory: &Path) -> Self {
+        Self {
+            logfile: directory.join("logfile"),
+            to_compress: directory.join("logfile.to_compress"),
+            compressed: directory.join("logfile.compressed"),
+            compressing: directory.join("logfile.compressing"),
+        }
+    }
 }
 
-const COMPRESSION_THRESHOLD: usize = 5000;
+/// The progress of a compression process, shared with its background process.
+#[derive(Default)]
+struct Progress {
+    /// The number of bytes of logfiles already processed.
+    processed: AtomicU64,
+    /// The number of bytes of logfiles to process.
+    total: AtomicU64,
+    /// The number of entries written to the new compressed logfile.
+    written: AtomicUsize,
+}
+
+/// A compression process, started at a given datetime, and writing a backup file if it's given.
+struct Compression {
+    process: Process,
+    progress: Arc<Progress>,
+    started: DateTime<Utc>,
+    backup: Option<PathBuf>,
+}
 
 pub struct Storage {
     encoder: Encoder,
-    file: Option<File>,
-    process: Option<Process>,
+    filesystem: Arc<dyn Filesystem>,
+    file: Option<Box<dyn Handle>>,
+    lock: Option<Lock>,
+    paths: Paths,
+    compression: Option<Compression>,
+    last_compaction: Option<CompactionResult>,
     logfile_size: usize,
+    logfile_bytes: u64,
+    compressed_bytes: u64,
+    /// Whether entries have been written to the logfile since its last synchronization.
+    unsynchronized: bool,
+    /// Whether synchronizing the logfile failed since the last commit.
+    synchronization_failed: bool,
+    /// The length to truncate the logfile to, when a write failed and left a torn entry at its end.
+    truncation: Option<u64>,
+    last_synchronization: Instant,
+    /// The feeds of all connected replicas, receiving each persisted entry.
+    replicas: Vec<Sender<Vec<u8>>>,
+    /// The entries written since the last synchronization, fed to replicas once synchronized.
+    unshipped: Vec<Vec<u8>>,
     configuration: Configuration,
 }
 
 impl Storage {
     /// Create a new Storage.
     pub fn new(configuration: Configuration) -> Storage {
+        Self::with_filesystem(configuration, Arc::new(Disk))
+    }
+
+    /// Create a new Storage, using the given file system.
+    fn with_filesystem(configuration: Configuration, filesystem: Arc<dyn Filesystem>) -> Storage {
         Storage {
             encoder: Encoder::new(),
+            filesystem,
             file: None,
-            process: None,
+            lock: None,
+            paths: Paths::new(&configuration.data_directory),
+            compression: None,
+            last_compaction: None,
             logfile_size: 0,
+            logfile_bytes: 0,
+            compressed_bytes: 0,
+            unsynchronized: false,
+            synchronization_failed: false,
+            truncation: None,
+            last_synchronization: Instant::now(),
+            replicas: Vec::new(),
+            unshipped: Vec::new(),
             configuration: configuration,
         }
     }
 
-    /// Initialize the persistent storage by retrieving data from the file system. On success, it
-    /// returns the last log entry for each persisted item. This method should only be used once,
-    /// and before any [`persist`] call.
-    pub fn initialize(&mut self) -> InitializationResult {
-        let mut entries = Vec::new();
-
-        // Load entries from "logfile.compressed", if it exists.
-        match OpenOptions::new().read(true).open("logfile.compressed") {
-            Ok(mut file) => {
-                let mut reader = logfile::Reader::new(&mut file);
-                let loaded = match reader.all() {
-                    Ok(entries) => entries,
-                    Err(_) => return Err(InitializationError::UnreadableFile),
-                };
-                debug!("{:?} entries have been read from 'logfile.compressed'.", loaded.len());
-                entries.extend(loaded);
-            },
-            Err(error) if (error.kind() == ErrorKind::NotFound) => (),
+    /// Load all entries from the logfile at the given path, if it exists. A torn entry at the end
+    /// of the logfile (left by a crash during a write) is removed, and a logfile using an older
+    /// format version is migrated to the current one. A logfile corrupted in its middle is never
+    /// loaded, nor a logfile containing an entry that can't be decrypted with the given keyring.
+    fn load(filesystem: &dyn Filesystem, path: &Path, keyring: &Keyring) -> Result<Option<Vec<logfile::Parsed>>, InitializationError> {
+        let mut file = match filesystem.open(path, Mode::Update) {
+            Ok(file) => file,
+            Err(error) if (error.kind() == ErrorKind::NotFound) => return Ok(None),
             Err(_) => return Err(InitializationError::UnreadableFile),
         };
 
-        // If "logfile.to_compress" exists, load entries from it and resume compressing.
-        match OpenOptions::new().read(true).open("logfile.to_compress") {
-            Ok(mut file) => {
-                let mut reader = logfile::Reader::new(&mut file);
-                let loaded = match reader.all() {
-                    Ok(entries) => entries,
-                    Err(_) => return Err(InitializationError::UnreadableFile),
-                };
-                debug!("{:?} entries have been read from 'logfile.to_compress'.", loaded.len());
-                entries.extend(loaded);
-                drop(file);
+        let content = match logfile::Reader::new(&mut file, keyring).read() {
+            Ok(content) => content,
+            Err(logfile::ReadError::CorruptedFile(offset)) => {
+                error!("'{}' is corrupted at offset {}. It can be inspected with `kairoi logfile verify`, and repaired with `kairoi logfile repair`.", path.display(), offset);
 
-                debug!("Resuming the compression process.");
-                self.process = Some(Process::execute(Self::compress));
+                return Err(InitializationError::CorruptedFile);
             },
-            Err(error) if (error.kind() == ErrorKind::NotFound) => (),
-            Err(_) => return Err(InitializationError::UnreadableFile),
-        };
+            Err(logfile::ReadError::UnsupportedVersion(version)) => {
+                error!("'{}' uses the unsupported format version {}.", path.display(), version);
 
-        // Load entries from "logfile".
-        match OpenOptions::new().read(true).open("logfile") {
-            Ok(mut file) => {
-                let mut reader = logfile::Reader::new(&mut file);
-                let loaded = match reader.all() {
-                    Ok(entries) => entries,
-                    Err(_) => return Err(InitializationError::UnreadableFile),
-                };
-                debug!("{:?} entries have been read from 'logfile'.", loaded.len());
-                self.logfile_size = loaded.len();
-                entries.extend(loaded);
+                return Err(InitializationError::UnreadableFile);
             },
-            Err(error) if (error.kind() == ErrorKind::NotFound) => (),
-            Err(_) => return Err(InitializationError::UnreadableFile),
+            Err(logfile::ReadError::Undecryptable(offset)) => {
+                error!("'{}' contains an entry at offset {} that can't be decrypted with the configured keys.", path.display(), offset);
+
+                return Err(InitializationError::UndecryptableFile);
+            },
+            Err(logfile::ReadError::UncheckedTail(offset)) => {
+                error!("'{}' uses the format version 1, without checksums, and ends with an incomplete entry at offset {}: it can be a torn entry as well as a corruption hiding the following entries. It can be inspected with `kairoi logfile verify`, and repaired with `kairoi logfile repair`.", path.display(), offset);
+
+                return Err(InitializationError::CorruptedFile);
+            },
+            Err(logfile::ReadError::UnreadableFile) => return Err(InitializationError::UnreadableFile),
         };
+        debug!("{:?} entries have been read from '{}'.", content.entries.len(), path.display());
 
-        debug!("Starting to decode all {:?} entries read.", entries.len());
-        let mut unique_results = HashMap::new();
-        for entry in entries {
-            match self.encoder.decode(&entry) {
-                Ok(decoded) => unique_results.insert(decoded.get_subject(), Entry::from(decoded)),
+        if let Some(length) = content.torn {
+            warn!("Removing the torn entry at the end of '{}' (starting at offset {}).", path.display(), length);
+            if logfile::truncate(&mut file, length).is_err() {
+                error!("Unable to truncate '{}'.", path.display());
+
+                return Err(InitializationError::UnreadableFile);
+            };
+        };
+        drop(file);
+
+        if content.version == logfile::VERSION {
+            return Ok(Some(content.entries));
+        };
+
+        info!("Migrating '{}' from the format version {} to {}.", path.display(), content.version, logfile::VERSION);
+        let encoder = Encoder::new();
+        let mut entries = Vec::with_capacity(content.entries.len());
+        for entry in &content.entries {
+            match encoder.migrate(entry, content.version) {
+                Ok(entry) => entries.push(entry),
                 Err(_) => return Err(InitializationError::InvalidEntry),
             };
         };
-        let mut results = Vec::with_capacity(unique_results.len());
-        for entry in unique_results.into_values() {
-            results.push(entry);
+        if logfile::rewrite(filesystem, path, &entries, keyring).is_err() {
+            error!("Unable to migrate '{}'.", path.display());
+
+            return Err(InitializationError::UnreadableFile);
         };
 
-        Ok(results)
+        Ok(Some(entries))
     }
 
-    /// Persist the given entry to this storage. When needed, it may start the background process
-    /// for compressing the logfile.
-    pub fn persist(&mut self, entry: Entry) -> PersistResult {
-        let encoded = match self.encoder.encode(Encodable::from(entry)) {
-            Ok(entry) => entry,
-            Err(_) => return Err(PersistError::EncodingFailure),
-        };
+    /// Open all logfiles, in the order their entries have been persisted, along with the number
+    /// of entries to read (all entries of the logfile when there is none). Since they are open,
+    /// they can be read even once replaced or removed by a compression process.
+    fn snapshot(&self) -> Result<replication::Logfiles, ()> {
+        // "logfile.to_compress" must be opened first (see the Replication section).
+        let to_compress = open(&*self.filesystem, &self.paths.to_compress)?;
+        let compressed = open(&*self.filesystem, &self.paths.compressed)?;
+        let logfile = open(&*self.filesystem, &self.paths.logfile)?;
 
-        // I'm not sure how to borrow this mutable reference on file properly. It should exist
-        // since we create it, but still there is a second match here. It may be improved.
-        if let None = self.file {
-            self.file = match OpenOptions::new().append(true).create(true).open("logfile") {
-                Ok(file) => Some(file),
-                Err(_) => return Err(PersistError::WriteFailure),
+        let mut logfiles = Vec::new();
+        logfiles.extend(compressed.map(|file| (file, None)));
+        logfiles.extend(to_compress.map(|file| (file, None)));
+        logfiles.extend(logfile.map(|file| (file, Some(self.logfile_size))));
+
+        Ok(logfiles)
+    }
+
+    /// Close the logfile, synchronizing its pending entries first. A synchronization failure is
+    /// reported by the next commit.
+    fn close(&mut self) {
+        if let Some(mut file) = self.file.take() {
+            if self.unsynchronized && file.sync_data().is_err() {
+                self.synchronization_failed = true;
             };
         };
-        let file = match &mut self.file {
-            Some(file) => file,
-            None => return Err(PersistError::WriteFailure),
+        self.unsynchronized = false;
+    }
+
+    /// Feed the given entries to all replicas. Disconnected replicas are forgotten.
+    fn ship(&mut self, entries: Vec<Vec<u8>>) {
+        for entry in entries {
+            self.replicas.retain(|replica| replica.send(entry.clone()).is_ok());
+        };
+    }
+
+    /// Truncate the logfile to remove the torn entry left by a failed write, if there is one. It
+    /// must succeed before writing the logfile again, or moving it.
+    fn repair(&mut self) -> Result<(), ()> {
+        let length = match self.truncation {
+            Some(length) => length,
+            None => return Ok(()),
         };
 
-        let mut writer = logfile::Writer::new(file);
+        let truncated = match self.filesystem.open(&self.paths.logfile, Mode::Update) {
+            Ok(mut file) => logfile::truncate(&mut file, length).is_ok(),
+            Err(error) => error.kind() == ErrorKind::NotFound,
+        };
+        if !truncated {
+            error!("Unable to remove the torn entry at the end of 'logfile'.");
 
-        match self.configuration.fsync_on_persist {
-            true => {
-                if let Err(_) = writer.write_sync(&encoded) {
-                    // We close the logfile since we are not able to write it properly.
-                    self.file = None;
+            return Err(());
+        };
+        self.truncation = None;
 
-                    return Err(PersistError::WriteFailure);
+        Ok(())
+    }
+
+    /// Check whether a failed compression process left "logfile.to_compress" behind. When it
+    /// can't be known, it's considered as left behind, so it's never overwritten.
+    fn is_compression_pending(&self) -> bool {
+        match self.filesystem.size(&self.paths.to_compress) {
+            Ok(_) => true,
+            Err(error) => error.kind() != ErrorKind::NotFound,
+        }
+    }
+
+    /// Flush the logfile and start a compression process, writing a backup file if it's given.
+    fn start_compression_on_demand(&mut self, backup: Option<PathBuf>) -> Result<(), CompactError> {
+        // Flushing the logfile would overwrite the "logfile.to_compress" left by a failed
+        // compression process: it's resumed instead, and the backup has to be requested again.
+        if self.is_compression_pending() {
+            warn!("Resuming the failed compression process instead of flushing the logfile.");
+            self.start_compression(None);
+
+            return match backup {
+                Some(_) => {
+                    error!("Unable to write a backup while resuming the failed compression process.");
+
+                    Err(CompactError::AlreadyRunning)
+                },
+                None => Ok(()),
+            };
+        };
+
+        // An empty "logfile.to_compress" is created when the logfile doesn't exist yet, so the
+        // compressed file is still rewritten.
+        if self.logfile_size == 0 {
+            self.close();
+            if self.repair().is_err() {
+                return Err(CompactError::Failure);
+            };
+            if let Err(error) = self.filesystem.rename(&self.paths.logfile, &self.paths.to_compress) {
+                if error.kind() != ErrorKind::NotFound || self.filesystem.open(&self.paths.to_compress, Mode::Create).is_err() {
+                    error!("Unable to create 'logfile.to_compress'.");
+
+                    return Err(CompactError::Failure);
                 };
+            };
+            self.logfile_bytes = 0;
+        } else if self.flush().is_err() {
+            return Err(CompactError::Failure);
+        };
+
+        self.start_compression(backup);
+
+        Ok(())
+    }
+
+    /// Check whether the logfile reached the configured compaction trigger.
+    fn should_compress(&self) -> bool {
+        match self.configuration.compaction_trigger {
+            CompactionTrigger::Entries(entries) => self.logfile_size >= entries,
+            CompactionTrigger::Size(size) => self.logfile_bytes >= size,
+            CompactionTrigger::Ratio { ratio, minimum_size } => {
+                self.logfile_bytes >= minimum_size && self.logfile_bytes as f64 >= self.compressed_bytes as f64 * ratio
             },
-            false => {
-                if let Err(_) = writer.write(&encoded) {
-                    // We close the logfile since we are not able to write it properly.
-                    self.file = None;
+        }
+    }
 
-                    return Err(PersistError::WriteFailure);
-                };
+    /// Check the status of the last compression process, to finalize it for this storage if
+    /// needed.
+    fn check_compression(&mut self) {
+        let compression = match &self.compression {
+            Some(compression) => compression,
+            None => return,
+        };
+
+        let success = match compression.process.status() {
+            Status::Success => {
+                debug!("The compression process has terminated with a success.");
+                self.compressed_bytes = size(&*self.filesystem, &self.paths.compressed);
+
+                true
+            },
+            Status::Failure(_) => {
+                debug!("The compression process has terminated with a failure.");
+
+                false
+            },
+            Status::Running => return,
+            Status::Lost => {
+                error!("Resuming the compression process after its loss.");
+                self.start_compression(compression.backup.clone());
+
+                return;
             },
         };
 
-        self.logfile_size += 1;
+        self.last_compaction = Some(CompactionResult {
+            success,
+            finished: Utc::now(),
+            entries: match success {
+                true => compression.progress.written.load(Ordering::Relaxed),
+                false => 0,
+            },
+        });
+        self.compression = None;
+    }
 
-        // Check the status of the last compression process, to finalize it for this storage if
-        // needed.
-        if let Some(process) = &self.process {
-            match process.status() {
-                Status::Success => {
-                    debug!("The compression process has terminated with a success.");
-                    self.process = None;
-                },
-                Status::Failure(_) => {
-                    debug!("The compression process has terminated with a failure.");
-                    self.process = None;
-                },
-                Status::Running => {},
-                Status::Lost => {
-                    error!("Resuming the compression process after its loss.");
-                    self.process = Some(Process::execute(Self::compress));
-                }
-            };
+    /// Flush the logfile to "logfile.to_compress", closing it so the next persisted entry starts a
+    /// new logfile.
+    fn flush(&mut self) -> Result<(), ()> {
+        // The entries of a "logfile.to_compress" left by a failed compression process would be
+        // lost: it must be compressed first.
+        if self.is_compression_pending() {
+            error!("Unable to move 'logfile' to 'logfile.to_compress': it already exists.");
+
+            return Err(());
         };
+        self.close();
+        self.repair()?;
+        debug!("Moving 'logfile' to 'logfile.to_compress'.");
+        if self.filesystem.rename(&self.paths.logfile, &self.paths.to_compress).is_err() {
+            error!("Unable to move 'logfile' to 'logfile.to_compress'.");
 
-        // Check if compression is needed and launch a background compression.
-        let already_compressing = match &self.process {
-            Some(_) => true,
-            None => false,
+            return Err(());
         };
-        let should_start_compression = self.logfile_size >= COMPRESSION_THRESHOLD;
-        if !already_compressing && should_start_compression {
-            debug!("Starting the compression process.");
+        self.logfile_size = 0;
+        self.logfile_bytes = 0;
+
+        Ok(())
+    }
 
-            self.file = None;
-            debug!("Moving 'logfile' to 'logfile.to_compress'.");
-            if let Err(_) = rename("logfile", "logfile.to_compress") {
-                error!("Unable to move 'logfile' to 'logfile.to_compress'.");
+    /// Start the background process compressing logfiles, then copying the compressed logfile to
+    /// the given backup path, if any.
+    fn start_compression(&mut self, backup: Option<PathBuf>) {
+        let filesystem = Arc::clone(&self.filesystem);
+        let paths = self.paths.clone();
+        let keyring = self.configuration.keyring.clone();
+        let progress = Arc::new(Progress::default());
+        let shared = Arc::clone(&progress);
+        let destination = backup.clone();
 
-                return Ok(());
-            }
-            self.logfile_size = 0;
+        self.compression = Some(Compression {
+            process: Process::execute(move || {
+                Self::compress(&*filesystem, &paths, &keyring, &shared)?;
+                match destination {
+                    Some(destination) => Self::back_up(&*filesystem, &paths, &destination),
+                    None => Ok(()),
+                }
+            }),
+            progress,
+            started: Utc::now(),
+            backup,
+        });
+    }
+
+    /// Copy "logfile.compressed" to the given backup path. This function is used as a task of a
+    /// background process, right after a compression: no other compression can replace
+    /// "logfile.compressed" in the meantime.
+    fn back_up(filesystem: &dyn Filesystem, paths: &Paths, destination: &Path) -> TaskResult {
+        // Backups never overwrite existing files.
+        if filesystem.size(destination).is_ok() {
+            error!("Unable to write the backup to '{}': the file already exists.", destination.display());
 
-            self.process = Some(Process::execute(Self::compress));
+            return Err(TaskError::Failure);
+        };
+        debug!("Copying 'logfile.compressed' to '{}'.", destination.display());
+        if logfile::copy(filesystem, &paths.compressed, destination).is_err() {
+            error!("Unable to write the backup to '{}'.", destination.display());
+
+            return Err(TaskError::Failure);
         };
+        info!("The backup has been written to '{}'.", destination.display());
 
         Ok(())
     }
 
-    /// Compress "logfile.compressed" and "logfile.to_compress" into "logfile.compressed". This
-    /// function is used as a task of a background process.
-    fn compress() -> TaskResult {
+    /// Compress "logfile.compressed" and "logfile.to_compress" into "logfile.compressed",
+    /// encrypting all entries with the current key of the given keyring. This function is used as
+    /// a task of a background process, reporting its progress through the given shared progress.
+    fn compress(filesystem: &dyn Filesystem, paths: &Paths, keyring: &Keyring, progress: &Progress) -> TaskResult {
         let encoder = Encoder::new();
 
-        let mut to_compress_file = match OpenOptions::new().read(true).open("logfile.to_compress") {
+        let mut to_compress_file = match filesystem.open(&paths.to_compress, Mode::Read) {
             Ok(file) => file,
             Err(_) => {
                 error!("Unable to open 'logfile.to_compress'.");
@@ -279,7 +696,7 @@ impl Storage {
                 return Err(TaskError::Failure);
             },
         };
-        let mut compressed_file = match OpenOptions::new().read(true).write(true).create(true).open("logfile.compressed") {
+        let mut compressed_file = match filesystem.open(&paths.compressed, Mode::UpdateOrCreate) {
             Ok(file) => file,
             Err(_) => {
                 error!("Unable to open 'logfile.compressed'.");
@@ -287,7 +704,7 @@ impl Storage {
                 return Err(TaskError::Failure);
             },
         };
-        let mut compressing_file = match OpenOptions::new().write(true).create(true).truncate(true).open("logfile.compressing") {
+        let mut compressing_file = match filesystem.open(&paths.compressing, Mode::Create) {
             Ok(file) => file,
             Err(_) => {
                 error!("Unable to open 'logfile.compressing'.");
@@ -296,68 +713,77 @@ impl Storage {
             },
         };
 
-        // Read all "logfile.to_compress" entries, deduplicate them and store them in memory.
-        // Entries are stored indexed by their subject.
-        let mut to_compress_reader = logfile::Reader::new(&mut to_compress_file);
-        debug!("Starting to read entries from 'logfile.to_compress'.");
-        let to_compress_entries = match to_compress_reader.all() {
+        let to_compress_size = to_compress_file.size().unwrap_or(0);
+        progress.total.store(to_compress_size + compressed_file.size().unwrap_or(0), Ordering::Relaxed);
+
+        // Stream all "logfile.to_compress" entries, deduplicating them in memory. Entries are
+        // stored indexed by their subject, along with whether they are removals: only the latest
+        // entry of each subject is kept.
+        let mut to_compress = HashMap::new();
+        let mut to_compress_reader = logfile::Reader::new(&mut to_compress_file, keyring);
+        let mut to_compress_entries = match to_compress_reader.entries() {
             Ok(entries) => entries,
             Err(_) => return Err(TaskError::Failure),
         };
-        let mut to_compress_decoded = Vec::with_capacity(to_compress_entries.len());
-        for entry in &to_compress_entries {
-            match encoder.decode(&entry) {
-                Ok(entry) => to_compress_decoded.push(entry),
+        debug!("Starting to read and deduplicate entries from 'logfile.to_compress'.");
+        while let Some(entry) = to_compress_entries.next() {
+            let entry = match entry {
+                Ok(entry) => entry,
                 Err(_) => return Err(TaskError::Failure),
             };
-        };
-        let mut to_compress = HashMap::new();
-        debug!("Starting to deduplicate entries from 'logfile.to_compress'.");
-        for (index, entry) in to_compress_entries.iter().enumerate().rev() {
-            let decoded = &to_compress_decoded[index];
-            let subject = decoded.get_subject();
-            if !to_compress.contains_key(&subject) {
-                to_compress.insert(subject, entry.clone());
+            let decoded = match encoder.decode(&entry) {
+                Ok(decoded) => decoded,
+                Err(_) => return Err(TaskError::Failure),
             };
-        }
+            let removal = matches!(decoded, Decoded::JobRemoval(_));
+            to_compress.insert(decoded.get_subject(), (entry, removal));
+            progress.processed.store(to_compress_entries.position(), Ordering::Relaxed);
+        };
 
-        // @TODO: Read compressed entries with iterations, instead of loading everything in memory.
-        let mut compressed_reader = logfile::Reader::new(&mut compressed_file);
-        debug!("Starting to read entries from 'logfile.compressed'.");
-        let compressed_entries = match compressed_reader.all() {
+        let mut compressed_reader = logfile::Reader::new(&mut compressed_file, keyring);
+        let mut compressed_entries = match compressed_reader.entries() {
             Ok(entries) => entries,
             Err(_) => return Err(TaskError::Failure),
         };
+        let mut compressing_writer = logfile::Writer::new(&mut compressing_file, keyring);
 
-        let mut compressing_writer = logfile::Writer::new(&mut compressing_file);
-
-        // For each entry from "logfile.compressed", check if there is a more recent entry in
-        // "logfile.to_compress". If it's not the case, add the entry to "logfile.compressing".
-        // Since "logfile.compressed" only contains unique entries, there is no need to double
-        // check in the file itself.
+        // Stream all entries from "logfile.compressed", checking for each one if there is a more
+        // recent entry in "logfile.to_compress". If it's not the case, add the entry to
+        // "logfile.compressing". Since "logfile.compressed" only contains unique entries, there is
+        // no need to double check in the file itself.
         debug!("Starting to write entries from 'logfile.compressed' to 'logfile.compressing'.");
-        for compressed_entry in &compressed_entries {
-            let compressed_decoded = match encoder.decode(compressed_entry) {
+        while let Some(compressed_entry) = compressed_entries.next() {
+            let compressed_entry = match compressed_entry {
+                Ok(entry) => entry,
+                Err(_) => return Err(TaskError::Failure),
+            };
+            let compressed_decoded = match encoder.decode(&compressed_entry) {
                 Ok(entry) => entry,
                 Err(_) => return Err(TaskError::Failure),
             };
             if !to_compress.contains_key(&compressed_decoded.get_subject()) {
-                if let Err(_) = compressing_writer.write(compressed_entry) {
+                if compressing_writer.write(&compressed_entry).is_err() {
                     return Err(TaskError::Failure);
                 };
+                progress.written.fetch_add(1, Ordering::Relaxed);
             }
+            progress.processed.store(to_compress_size + compressed_entries.position(), Ordering::Relaxed);
         };
 
         debug!("Starting to write entries from 'logfile.to_compress' to 'logfile.compressing'.");
-        for entry in to_compress.values() {
-            if let Err(_) = compressing_writer.write(entry) {
+        for (entry, removal) in to_compress.values() {
+            if *removal {
+                continue;
+            };
+            if compressing_writer.write(entry).is_err() {
                 return Err(TaskError::Failure);
             };
+            progress.written.fetch_add(1, Ordering::Relaxed);
         }
 
         // Synchronize the written file to the file system, making sure the operation is fully
         // completed.
-        if let Err(_) = compressing_writer.sync() {
+        if compressing_writer.sync().is_err() {
             return Err(TaskError::Failure);
         }
 
@@ -367,13 +793,13 @@ impl Storage {
         // a failure between them will only cause the compression to be re-started, not corrupting
         // any data.
         debug!("Replacing 'logfile.compressed' by 'logfile.compressing'.");
-        if let Err(_) = rename("logfile.compressing", "logfile.compressed") {
+        if filesystem.rename(&paths.compressing, &paths.compressed).is_err() {
             error!("Unable to rename 'logfile.compressing' to 'logfile.compressed'.");
 
             return Err(TaskError::Failure);
         }
         debug!("Removing 'logfile.to_compress'.");
-        if let Err(_) = remove_file("logfile.to_compress") {
+        if filesystem.remove_file(&paths.to_compress).is_err() {
             error!("Unable to remove 'logfile.to_compress'.");
 
             return Err(TaskError::Failure);
@@ -383,11 +809,386 @@ impl Storage {
     }
 }
 
+impl Backend for Storage {
+    /// Initialize the persistent storage by retrieving data from the file system. On success, it
+    /// returns the last log entry for each persisted item (removed items being omitted). This
+    /// method should only be used once, and before any [`persist`] call.
+    fn initialize(&mut self) -> InitializationResult {
+        let mut entries = Vec::new();
+
+        self.lock = Some(prepare(&*self.filesystem, &self.configuration.data_directory)?);
+
+        // Load entries from "logfile.compressed", if it exists.
+        if let Some(loaded) = Self::load(&*self.filesystem, &self.paths.compressed, &self.configuration.keyring)? {
+            entries.extend(loaded);
+        };
+
+        // If "logfile.to_compress" exists, load entries from it and resume compressing.
+        if let Some(loaded) = Self::load(&*self.filesystem, &self.paths.to_compress, &self.configuration.keyring)? {
+            entries.extend(loaded);
+
+            debug!("Resuming the compression process.");
+            self.start_compression(None);
+        };
+
+        // Load entries from "logfile".
+        if let Some(loaded) = Self::load(&*self.filesystem, &self.paths.logfile, &self.configuration.keyring)? {
+            self.logfile_size = loaded.len();
+            entries.extend(loaded);
+        };
+        self.logfile_bytes = size(&*self.filesystem, &self.paths.logfile);
+        self.compressed_bytes = size(&*self.filesystem, &self.paths.compressed);
+
+        debug!("Starting to decode all {:?} entries read.", entries.len());
+        let mut unique_results = HashMap::new();
+        for entry in entries {
+            match self.encoder.decode(&entry) {
+                Ok(decoded) => unique_results.insert(decoded.get_subject(), Entry::from(decoded)),
+                Err(_) => return Err(InitializationError::InvalidEntry),
+            };
+        };
+        let mut results = Vec::with_capacity(unique_results.len());
+        for entry in unique_results.into_values() {
+            if let Entry::JobRemoval(_) = entry {
+                continue;
+            };
+            results.push(entry);
+        };
+
+        Ok(results)
+    }
+
+    /// Persist the given entry to this storage. When needed, it may start the background process
+    /// for compressing the logfile.
+    fn persist(&mut self, entry: Entry) -> PersistResult {
+        let encoded = match self.encoder.encode(Encodable::from(entry)) {
+            Ok(entry) => entry,
+            Err(_) => return Err(PersistError::EncodingFailure),
+        };
+
+        if self.repair().is_err() {
+            return Err(PersistError::WriteFailure);
+        };
+
+        // I'm not sure how to borrow this mutable reference on file properly. It should exist
+        // since we create it, but still there is a second match here. It may be improved.
+        if self.file.is_none() {
+            self.file = match self.filesystem.open(&self.paths.logfile, Mode::Append) {
+                Ok(file) => Some(file),
+                Err(_) => return Err(PersistError::WriteFailure),
+            };
+        };
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Err(PersistError::WriteFailure),
+        };
+
+        let mut writer = logfile::Writer::new(file, &self.configuration.keyring);
+
+        let written = match self.configuration.fsync {
+            Fsync::Always => writer.write_sync(&encoded),
+            Fsync::Frame | Fsync::EverySecond => writer.write(&encoded).map(|_| self.unsynchronized = true),
+            Fsync::Never => writer.write(&encoded),
+        };
+        if written.is_err() {
+            // We close the logfile since we are not able to write it properly, and remove what
+            // may have been written of the entry: the next entries must not follow a torn one.
+            self.close();
+            self.truncation = Some(self.logfile_bytes);
+            let _ = self.repair();
+
+            return Err(PersistError::WriteFailure);
+        };
+
+        self.logfile_size += 1;
+        if let Some(file) = &self.file {
+            self.logfile_bytes = file.size().unwrap_or(self.logfile_bytes);
+        };
+        match self.configuration.fsync {
+            Fsync::Frame | Fsync::EverySecond if !self.replicas.is_empty() => self.unshipped.push(encoded),
+            _ => self.ship(vec![encoded]),
+        };
+
+        self.check_compression();
+
+        // Check if compression is needed and launch a background compression. A failed
+        // compression process is resumed first, since flushing would overwrite its
+        // "logfile.to_compress".
+        if self.compression.is_none() && self.should_compress() {
+            if self.is_compression_pending() {
+                debug!("Resuming the failed compression process.");
+                self.start_compression(None);
+
+                return Ok(());
+            };
+
+            debug!("Starting the compression process.");
+            if self.flush().is_err() {
+                return Ok(());
+            };
+
+            self.start_compression(None);
+        };
+
+        Ok(())
+    }
+
+    /// Synchronize entries persisted since the last commit to the file system, following the
+    /// configured [`Fsync`] policy. It should be called regularly (typically, once per database
+    /// frame). A failure means that some entries persisted since the last commit may be lost.
+    fn commit(&mut self) -> PersistResult {
+        let due = match self.configuration.fsync {
+            Fsync::Frame => true,
+            Fsync::EverySecond => self.last_synchronization.elapsed() >= Duration::from_secs(1),
+            Fsync::Always | Fsync::Never => false,
+        };
+        if !due {
+            return Ok(());
+        };
+        self.last_synchronization = Instant::now();
+
+        if self.unsynchronized {
+            if let Some(file) = &mut self.file {
+                if file.sync_data().is_err() {
+                    self.synchronization_failed = true;
+                };
+            };
+            self.unsynchronized = false;
+        };
+
+        let unshipped = std::mem::take(&mut self.unshipped);
+        match std::mem::replace(&mut self.synchronization_failed, false) {
+            true => {
+                if !self.replicas.is_empty() {
+                    error!("Disconnecting all replicas, since the logfile can't be synchronized.");
+                    self.replicas.clear();
+                };
+
+                Err(PersistError::WriteFailure)
+            },
+            false => {
+                self.ship(unshipped);
+
+                Ok(())
+            },
+        }
+    }
+
+    /// Start replicating this storage to the replica connected through the given stream. A
+    /// snapshot of all persisted entries is sent in background, followed by all entries persisted
+    /// from now on.
+    fn replicate(&mut self, stream: TcpStream) {
+        let logfiles = match self.snapshot() {
+            Ok(logfiles) => logfiles,
+            Err(_) => {
+                error!("Unable to open logfiles for the snapshot of a replica.");
+
+                return;
+            },
+        };
+
+        let (feed, receiver) = channel();
+        replication::serve(stream, replication::Snapshot { logfiles, keyring: self.configuration.keyring.clone() }, receiver);
+        self.replicas.push(feed);
+    }
+
+    /// Read all persisted entries, in their encoded form and in the order they have been
+    /// persisted (the latest entry of an item being the right one).
+    fn entries(&self) -> Result<Vec<logfile::Parsed>, PersistError> {
+        let logfiles = match self.snapshot() {
+            Ok(logfiles) => logfiles,
+            Err(_) => return Err(PersistError::WriteFailure),
+        };
+
+        let mut entries = Vec::new();
+        for (mut file, limit) in logfiles {
+            let mut reader = logfile::Reader::new(&mut file, &self.configuration.keyring);
+            let read = match reader.entries() {
+                Ok(read) => read,
+                Err(_) => return Err(PersistError::WriteFailure),
+            };
+            for entry in read.take(limit.unwrap_or(usize::MAX)) {
+                match entry {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => return Err(PersistError::WriteFailure),
+                };
+            };
+        };
+
+        Ok(entries)
+    }
+
+    /// Remove all persisted entries, once the running compression process (if any) has
+    /// terminated. It's used by replicas before receiving a new snapshot from their primary, and
+    /// by cluster nodes before installing a snapshot of their leader.
+    fn reset(&mut self) -> PersistResult {
+        loop {
+            self.check_compression();
+            if self.compression.is_none() {
+                break;
+            };
+            sleep(Duration::from_millis(10));
+        };
+        self.close();
+        self.truncation = None;
+
+        for path in &[&self.paths.logfile, &self.paths.to_compress, &self.paths.compressed] {
+            match self.filesystem.remove_file(path) {
+                Ok(_) => {},
+                Err(error) if error.kind() == ErrorKind::NotFound => {},
+                Err(_) => {
+                    error!("Unable to remove '{}'.", path.display());
+
+                    return Err(PersistError::WriteFailure);
+                },
+            };
+        };
+        self.logfile_size = 0;
+        self.logfile_bytes = 0;
+        self.compressed_bytes = 0;
+
+        Ok(())
+    }
+
+    /// Start a compression process on demand. The logfile is flushed synchronously, and compressed
+    /// in background. It fails if a compression process is already running.
+    fn compact(&mut self) -> Result<(), CompactError> {
+        self.check_compression();
+        if self.compression.is_some() {
+            return Err(CompactError::AlreadyRunning);
+        };
+
+        debug!("Starting the compression process on demand.");
+        self.start_compression_on_demand(None)
+    }
+
+    /// Start a compression process on demand, writing a backup of the storage to the given path
+    /// once terminated. It fails if a compression process is already running, or if the directory
+    /// of the given path doesn't exist.
+    fn backup(&mut self, path: PathBuf) -> Result<(), CompactError> {
+        self.check_compression();
+        if self.compression.is_some() {
+            return Err(CompactError::AlreadyRunning);
+        };
+        let directory = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        if !directory.is_dir() {
+            error!("Unable to write a backup to '{}': its directory doesn't exist.", path.display());
+
+            return Err(CompactError::Failure);
+        };
+
+        debug!("Starting the compression process for a backup to '{}'.", path.display());
+        self.start_compression_on_demand(Some(path))
+    }
+
+    /// Get the state of the compression process: the progress of the running process, if there is
+    /// one, and the result of the last terminated process, if there is one.
+    fn get_compaction(&mut self) -> Compaction {
+        self.check_compression();
+
+        Compaction {
+            running: self.compression.as_ref().map(|compression| CompactionProgress {
+                started: compression.started,
+                processed_bytes: compression.progress.processed.load(Ordering::Relaxed),
+                total_bytes: compression.progress.total.load(Ordering::Relaxed),
+            }),
+            last: self.last_compaction.clone(),
+        }
+    }
+}
+
+impl Drop for Storage {
+    /// Close the logfile when the storage is dropped (typically, when the server stops),
+    /// synchronizing the entries persisted since the last commit whatever the fsync policy but
+    /// `Never`.
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Get the size of the file at the given path of the given file system, in bytes. A missing file
+/// is considered empty.
+fn size(filesystem: &dyn Filesystem, path: &Path) -> u64 {
+    filesystem.size(path).unwrap_or(0)
+}
+
+/// Open the file at the given path of the given file system for reading, if it exists.
+fn open(filesystem: &dyn Filesystem, path: &Path) -> Result<Option<Box<dyn Handle>>, ()> {
+    match filesystem.open(path, Mode::Read) {
+        Ok(file) => Ok(Some(file)),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+        Err(_) => Err(()),
+    }
+}
+
+/// Create the given data directory of the given file system if needed, and take its exclusive
+/// lock (see [`lock`]).
+fn prepare(filesystem: &dyn Filesystem, directory: &Path) -> Result<Lock, InitializationError> {
+    if filesystem.create_dir_all(directory).is_err() {
+        error!("Unable to create the data directory '{}'.", directory.display());
+
+        return Err(InitializationError::UnreadableFile);
+    };
+
+    match filesystem.lock(directory) {
+        Ok(lock) => Ok(lock),
+        Err(LockError::Locked) => {
+            error!("The data directory '{}' is already used by another process.", directory.display());
+
+            Err(InitializationError::LockedDirectory)
+        },
+        Err(LockError::Failure) => {
+            error!("Unable to lock the data directory '{}'.", directory.display());
+
+            Err(InitializationError::UnreadableFile)
+        },
+    }
+}
+
+/// Take the exclusive lock on the given data directory, preventing other processes from using it.
+/// The lock is held until the returned file is closed. The identifier of the current process is
+/// written in the lock file, for information purposes.
+fn lock(directory: &Path) -> Result<File, LockError> {
+    // The file is only truncated once locked, to keep the identifier of the process holding it.
+    let mut file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(directory.join("lock")) {
+        Ok(file) => file,
+        Err(_) => return Err(LockError::Failure),
+    };
+
+    match file.try_lock_exclusive() {
+        Ok(_) => {},
+        Err(error) if error.kind() == fs2::lock_contended_error().kind() => return Err(LockError::Locked),
+        Err(_) => return Err(LockError::Failure),
+    };
+    // Failing to write the process identifier doesn't prevent the lock from being held.
+    let _ = file.set_len(0).and_then(|_| write!(file, "{}", std::process::id()));
+
+    Ok(file)
+}
+
+/// Mark the given data directory as written by a primary, creating the `primary` file (see
+/// [`is_marked_primary`]).
+pub fn mark_primary(directory: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(directory)?;
+
+    File::create(directory.join(PRIMARY_MARKER))?.sync_all()
+}
+
+/// Check whether the given data directory has been written as a primary, containing the `primary`
+/// file: it can't be used by a replica anymore.
+pub fn is_marked_primary(directory: &Path) -> bool {
+    directory.join(PRIMARY_MARKER).exists()
+}
+
 /// Convert Entry into Encodable.
 impl From<Entry> for Encodable {
     fn from(entry: Entry) -> Self {
         match entry {
             Entry::Job(job) => Encodable::Job(job),
+            Entry::JobRemoval(removal) => Encodable::JobRemoval(removal),
             Entry::Rule(rule) => Encodable::Rule(rule),
         }
     }
@@ -398,6 +1199,7 @@ impl From<Decoded> for Entry {
     fn from(decoded: Decoded) -> Self {
         match decoded {
             Decoded::Job(job) => Entry::Job(job),
+            Decoded::JobRemoval(removal) => Entry::JobRemoval(removal),
             Decoded::Rule(rule) => Entry::Rule(rule),
         }
     }
@@ -408,18 +1210,462 @@ impl Decoded {
     /// concerned by a log entry. For example, two entries about the same job "job.1" will have the
     /// same subject, but an entry about a job "job.1" will not have the same subject than an entry
     /// about the job "job.2". An entry about a job "app.1" will also not have the same subject
-    /// than an entry about the rule "app.1".
+    /// than an entry about the rule "app.1". Removal entries have the same subject than entries
+    /// about the removed item.
+    fn get_subject(&self) -> String {
+        match self {
+            Decoded::Job(job) => subject('j', &job.identifier),
+            Decoded::JobRemoval(removal) => subject('j', &removal.identifier),
+            Decoded::Rule(rule) => subject('r', &rule.identifier),
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "backend-sled"), allow(dead_code))]
+impl Entry {
+    /// Get the subject of this entry, like [`Decoded::get_subject`].
     fn get_subject(&self) -> String {
-        let (prefix, identifier) = match self {
-            Decoded::Job(job) => ('j', &job.identifier),
-            Decoded::Rule(rule) => ('r', &rule.identifier),
+        match self {
+            Entry::Job(job) => subject('j', &job.identifier),
+            Entry::JobRemoval(removal) => subject('j', &removal.identifier),
+            Entry::Rule(rule) => subject('r', &rule.identifier),
+        }
+    }
+}
+
+/// Build the subject of the item with the given identifier, the given prefix being the kind of
+/// the item.
+fn subject(prefix: char, identifier: &str) -> String {
+    let mut subject = String::with_capacity(identifier.len() + 1);
+
+    subject.push(prefix);
+    subject.push_str(identifier);
+
+    subject
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::offset::TimeZone;
+    use super::filesystem::memory::{Fault, Memory};
+    use std::sync::mpsc::TryRecvError;
+
+    /// The latest execution of each job, by identifier.
+    type Jobs = HashMap<String, i64>;
+
+    fn configuration(fsync: Fsync, compaction_trigger: CompactionTrigger) -> Configuration {
+        Configuration {
+            backend: BackendKind::Logfile,
+            data_directory: PathBuf::from("data"),
+            fsync,
+            compaction_trigger,
+            keyring: Keyring::default(),
+            cluster: None,
+        }
+    }
+
+    fn start(filesystem: &Memory, fsync: Fsync, compaction_trigger: CompactionTrigger) -> Storage {
+        let mut storage = Storage::with_filesystem(configuration(fsync, compaction_trigger), Arc::new(filesystem.clone()));
+        assert!(storage.initialize().is_ok());
+
+        storage
+    }
+
+    /// Get the entry of the given index in the persisted sequence: updates of 4 jobs, one of them
+    /// being regularly removed.
+    fn entry(index: usize) -> Entry {
+        let identifier = format!("job.{}", index % 4);
+        match index % 5 {
+            4 => Entry::JobRemoval(JobRemoval { identifier }),
+            _ => Entry::Job(Job { identifier, execution: Utc.timestamp(1592430436 + index as i64, 0), status: JobStatus::Planned }),
+        }
+    }
+
+    fn apply(jobs: &mut Jobs, index: usize) {
+        match entry(index) {
+            Entry::Job(job) => jobs.insert(job.identifier, job.execution.timestamp()),
+            Entry::JobRemoval(removal) => jobs.remove(&removal.identifier),
+            Entry::Rule(_) => None,
+        };
+    }
+
+    fn wait(storage: &mut Storage) {
+        while storage.get_compaction().running.is_some() {
+            sleep(Duration::from_millis(1));
+        };
+    }
+
+    /// Restart the given file system, then check that the storage recovers all acknowledged
+    /// entries: the jobs it contains must be the acknowledged ones, possibly followed by some of
+    /// the given pending entries (in order), whose persistence wasn't acknowledged.
+    fn check(filesystem: &Memory, fsync: Fsync, acknowledged: &Jobs, pending: &[usize]) {
+        filesystem.restart();
+        let mut storage = Storage::with_filesystem(configuration(fsync, CompactionTrigger::Entries(1000)), Arc::new(filesystem.clone()));
+        let entries = match storage.initialize() {
+            Ok(entries) => entries,
+            Err(_) => panic!("The storage can't be initialized."),
+        };
+        let mut recovered = Jobs::new();
+        for entry in entries {
+            if let Entry::Job(job) = entry {
+                recovered.insert(job.identifier, job.execution.timestamp());
+            };
+        };
+
+        let mut expected = acknowledged.clone();
+        let mut matched = recovered == expected;
+        for index in pending {
+            apply(&mut expected, *index);
+            matched = matched || recovered == expected;
+        };
+        assert!(matched, "Recovered {:?}, while {:?} has been acknowledged.", recovered, acknowledged);
+        wait(&mut storage);
+    }
+
+    /// Write the logfile of the storage with the given content.
+    fn write_logfile(filesystem: &Memory, content: &[u8]) {
+        filesystem.create_dir_all(Path::new("data")).unwrap();
+        let mut file = filesystem.open(Path::new("data/logfile"), Mode::Create).unwrap();
+        file.write_all(content).unwrap();
+    }
+
+    #[test]
+    fn test_unchecked_tail() {
+        // A logfile with the format version 1 (without checksums) ending with an incomplete entry,
+        // which could be a corrupted size hiding the following entries as well.
+        let filesystem = Memory::new();
+        write_logfile(&filesystem, &[0, 0, 0, 1, 1, 0, 0, 0, 9, 2, 2]);
+
+        let mut storage = Storage::with_filesystem(configuration(Fsync::Always, CompactionTrigger::Entries(1000)), Arc::new(filesystem.clone()));
+        assert!(matches!(storage.initialize(), Err(InitializationError::CorruptedFile)));
+        assert_eq!(filesystem.read(Path::new("data/logfile")).unwrap().len(), 11);
+    }
+
+    #[test]
+    fn test_migrate_on_startup() {
+        // The same job, with a logfile using the format version 2 (job execution timestamps as
+        // nanoseconds), then 3 (as seconds and nanoseconds, allowing datetimes after 2262).
+        let migrations: [(u8, &[u8], DateTime<Utc>); 2] = [
+            (2, &[0, 0, 0, 16, 0, 0, 4, 116, 111, 116, 111, 22, 71, 187, 92, 238, 225, 80, 0, 0, 23, 168, 179, 134], Utc.ymd(2020, 11, 15).and_hms(16, 30, 0)),
+            (3, &[0, 0, 0, 20, 0, 0, 4, 116, 111, 116, 111, 0, 0, 0, 2, 108, 181, 219, 0, 0, 0, 0, 5, 0, 105, 152, 185, 112], Utc.ymd(2300, 1, 1).and_hms_nano(0, 0, 0, 5)),
+        ];
+        for (version, entry, execution) in migrations {
+            let filesystem = Memory::new();
+            let mut content = b"KRLF".to_vec();
+            content.push(version);
+            content.extend(entry);
+            write_logfile(&filesystem, &content);
+
+            for _ in 0..2 {
+                let mut storage = Storage::with_filesystem(configuration(Fsync::Always, CompactionTrigger::Entries(1000)), Arc::new(filesystem.clone()));
+                let entries = storage.initialize().ok().unwrap();
+                assert!(matches!(&entries[..], [Entry::Job(job)] if job.identifier == "toto" && job.execution == execution));
+                assert_eq!(filesystem.read(Path::new("data/logfile")).unwrap()[4], logfile::VERSION);
+            };
+        };
+    }
+
+    #[test]
+    fn test_power_loss_while_persisting() {
+        for step in 0.. {
+            let filesystem = Memory::new();
+            let mut storage = start(&filesystem, Fsync::Always, CompactionTrigger::Entries(3));
+            filesystem.inject(filesystem.get_steps() + step, Fault::PowerLoss);
+
+            let mut acknowledged = Jobs::new();
+            let mut pending = Vec::new();
+            for index in 0..12 {
+                match storage.persist(entry(index)) {
+                    Ok(_) => apply(&mut acknowledged, index),
+                    Err(_) => {
+                        pending.push(index);
+                        break;
+                    },
+                };
+                wait(&mut storage);
+            };
+
+            let crashed = filesystem.is_down();
+            check(&filesystem, Fsync::Always, &acknowledged, &pending);
+            if !crashed {
+                assert!(step > 0);
+                break;
+            };
+        };
+    }
+
+    #[test]
+    fn test_power_loss_while_compacting() {
+        for step in 0.. {
+            let filesystem = Memory::new();
+            let mut storage = start(&filesystem, Fsync::Frame, CompactionTrigger::Entries(1000));
+            filesystem.inject(filesystem.get_steps() + step, Fault::PowerLoss);
+
+            // Entries are persisted by batches of 3, each one being acknowledged by its commit,
+            // and compacted.
+            let mut acknowledged = Jobs::new();
+            let mut pending = Vec::new();
+            for batch in 0..4 {
+                let indexes: Vec<usize> = (batch * 3..batch * 3 + 3).collect();
+                let persisted = indexes.iter().all(|index| storage.persist(entry(*index)).is_ok());
+                if !persisted || storage.commit().is_err() {
+                    pending = indexes;
+                    break;
+                };
+                indexes.iter().for_each(|index| apply(&mut acknowledged, *index));
+
+                if storage.compact().is_err() {
+                    break;
+                };
+                wait(&mut storage);
+            };
+
+            let crashed = filesystem.is_down();
+            check(&filesystem, Fsync::Frame, &acknowledged, &pending);
+            if !crashed {
+                assert!(step > 0);
+                break;
+            };
+        };
+    }
+
+    #[test]
+    fn test_failures_while_compacting() {
+        // Count the steps of a compaction without failure first, then make each one fail.
+        let steps = {
+            let filesystem = Memory::new();
+            let mut storage = start(&filesystem, Fsync::Always, CompactionTrigger::Entries(1000));
+            (0..6).for_each(|index| assert!(storage.persist(entry(index)).is_ok()));
+            let before = filesystem.get_steps();
+            assert!(storage.compact().is_ok());
+            wait(&mut storage);
+
+            filesystem.get_steps() - before
+        };
+
+        for step in 0..steps {
+            let filesystem = Memory::new();
+            let mut storage = start(&filesystem, Fsync::Always, CompactionTrigger::Entries(1000));
+            let mut acknowledged = Jobs::new();
+            for index in 0..6 {
+                assert!(storage.persist(entry(index)).is_ok());
+                apply(&mut acknowledged, index);
+            };
+
+            filesystem.inject(filesystem.get_steps() + step, Fault::Failure);
+            let _ = storage.compact();
+            wait(&mut storage);
+
+            // The failed compaction is resumed by the next one, without losing the entries
+            // persisted in between.
+            for index in 6..9 {
+                assert!(storage.persist(entry(index)).is_ok());
+                apply(&mut acknowledged, index);
+            };
+            assert!(storage.compact().is_ok());
+            wait(&mut storage);
+            assert!(storage.get_compaction().last.unwrap().success);
+
+            check(&filesystem, Fsync::Always, &acknowledged, &[]);
+        };
+    }
+
+    /// Persist entries with the given compaction trigger until two compactions have been started,
+    /// checking that the logfile never reaches the given limit (computed from the size of the
+    /// compressed logfile) without starting one.
+    fn check_trigger(trigger: CompactionTrigger, limit: impl Fn(u64) -> u64) {
+        let filesystem = Memory::new();
+        let mut storage = start(&filesystem, Fsync::Always, trigger);
+        let mut acknowledged = Jobs::new();
+        let mut compactions = 0;
+        for index in 0..100 {
+            let compressed = size(&filesystem, Path::new("data/logfile.compressed"));
+            assert!(storage.persist(entry(index)).is_ok());
+            apply(&mut acknowledged, index);
+            wait(&mut storage);
+
+            // Starting a compaction moves the logfile to "logfile.to_compress".
+            match filesystem.read(Path::new("data/logfile")) {
+                Some(content) => assert!((content.len() as u64) < limit(compressed)),
+                None => compactions += 1,
+            };
+            if compactions == 2 {
+                break;
+            };
+        };
+        assert_eq!(compactions, 2);
+        assert!(storage.get_compaction().last.unwrap().success);
+
+        check(&filesystem, Fsync::Always, &acknowledged, &[]);
+    }
+
+    #[test]
+    fn test_compaction_triggers() {
+        check_trigger(CompactionTrigger::Entries(3), |_| u64::MAX);
+        check_trigger(CompactionTrigger::Size(100), |_| 100);
+        check_trigger(CompactionTrigger::Ratio { ratio: 2.0, minimum_size: 50 }, |compressed| 50.max(compressed * 2));
+    }
+
+    #[test]
+    fn test_compact() {
+        let filesystem = Memory::new();
+        let mut storage = start(&filesystem, Fsync::Always, CompactionTrigger::Entries(1000));
+        let mut acknowledged = Jobs::new();
+        for index in 0..10 {
+            assert!(storage.persist(entry(index)).is_ok());
+            apply(&mut acknowledged, index);
+        };
+
+        assert!(storage.compact().is_ok());
+        wait(&mut storage);
+        let last = storage.get_compaction().last.unwrap();
+        assert!(last.success);
+        assert_eq!(last.entries, acknowledged.len());
+        assert_eq!(filesystem.list(), vec![PathBuf::from("data/logfile.compressed")].into_iter().collect());
+
+        // Compacting an empty logfile still succeeds.
+        assert!(storage.compact().is_ok());
+        wait(&mut storage);
+        assert!(storage.get_compaction().last.unwrap().success);
+
+        check(&filesystem, Fsync::Always, &acknowledged, &[]);
+    }
+
+    #[test]
+    fn test_failures_while_triggering() {
+        // Count the steps of a triggered compaction without failure first, then make each one
+        // fail: the failed compaction must be resumed by the next triggered one, instead of being
+        // overwritten by the logfile.
+        let steps = {
+            let filesystem = Memory::new();
+            let mut storage = start(&filesystem, Fsync::Always, CompactionTrigger::Entries(3));
+            (0..2).for_each(|index| assert!(storage.persist(entry(index)).is_ok()));
+            let before = filesystem.get_steps();
+            assert!(storage.persist(entry(2)).is_ok());
+            wait(&mut storage);
+
+            filesystem.get_steps() - before
+        };
+
+        for step in 0..steps {
+            let filesystem = Memory::new();
+            let mut storage = start(&filesystem, Fsync::Always, CompactionTrigger::Entries(3));
+            let mut acknowledged = Jobs::new();
+            // Few entries follow the failure, so lost entries aren't hidden by more recent ones.
+            for index in 0..6 {
+                if index == 2 {
+                    filesystem.inject(filesystem.get_steps() + step, Fault::Failure);
+                };
+                if storage.persist(entry(index)).is_ok() {
+                    apply(&mut acknowledged, index);
+                };
+                wait(&mut storage);
+            };
+            assert!(storage.get_compaction().last.unwrap().success);
+
+            check(&filesystem, Fsync::Always, &acknowledged, &[]);
+        };
+    }
+
+    #[test]
+    fn test_fsync() {
+        // Each policy is checked by persisting 2 entries and committing them twice (the second
+        // commit having nothing to synchronize), then persisting a last entry and closing the
+        // storage. The number of synchronizations is expected after each of these steps.
+        let policies = [
+            (Fsync::Always, [2, 2, 2, 3]),
+            (Fsync::Frame, [0, 1, 1, 2]),
+            (Fsync::EverySecond, [0, 1, 1, 2]),
+            (Fsync::Never, [0, 0, 0, 0]),
+        ];
+        for (fsync, expected) in policies {
+            let filesystem = Memory::new();
+            let mut storage = start(&filesystem, fsync, CompactionTrigger::Entries(1000));
+            let mut synchronizations = Vec::new();
+            (0..2).for_each(|index| assert!(storage.persist(entry(index)).is_ok()));
+            synchronizations.push(filesystem.get_synchronizations());
+
+            // With the `EverySecond` policy, the last synchronization is a second old.
+            for _ in 0..2 {
+                storage.last_synchronization -= Duration::from_secs(1);
+                assert!(storage.commit().is_ok());
+                synchronizations.push(filesystem.get_synchronizations());
+            };
+
+            assert!(storage.persist(entry(2)).is_ok());
+            drop(storage);
+            synchronizations.push(filesystem.get_synchronizations());
+            assert_eq!(synchronizations, expected, "Unexpected synchronizations with {:?}.", fsync);
+        };
+
+        // The `EverySecond` policy synchronizes at most once per second.
+        let filesystem = Memory::new();
+        let mut storage = start(&filesystem, Fsync::EverySecond, CompactionTrigger::Entries(1000));
+        assert!(storage.persist(entry(0)).is_ok());
+        assert!(storage.commit().is_ok());
+        assert_eq!(filesystem.get_synchronizations(), 0);
+        storage.last_synchronization -= Duration::from_secs(1);
+        assert!(storage.commit().is_ok());
+        assert_eq!(filesystem.get_synchronizations(), 1);
+    }
+
+    #[test]
+    fn test_failed_synchronization() {
+        let filesystem = Memory::new();
+        let mut storage = start(&filesystem, Fsync::Frame, CompactionTrigger::Entries(1000));
+        let mut acknowledged = Jobs::new();
+        for index in 0..2 {
+            assert!(storage.persist(entry(index)).is_ok());
+            apply(&mut acknowledged, index);
         };
+        assert!(storage.commit().is_ok());
+
+        assert!(storage.persist(entry(2)).is_ok());
+        filesystem.inject(filesystem.get_steps(), Fault::Failure);
+        assert!(storage.commit().is_err());
+
+        check(&filesystem, Fsync::Frame, &acknowledged, &[2]);
+    }
+
+    #[test]
+    fn test_replicate_synchronized() {
+        let filesystem = Memory::new();
+        let mut storage = start(&filesystem, Fsync::Frame, CompactionTrigger::Entries(1000));
+        let (feed, replica) = channel();
+        storage.replicas.push(feed);
+
+        // Entries are only fed to replicas once synchronized.
+        assert!(storage.persist(entry(0)).is_ok());
+        assert!(replica.try_recv().is_err());
+        assert!(storage.commit().is_ok());
+        assert_eq!(filesystem.get_synchronizations(), 1);
+        assert!(replica.try_recv().is_ok());
+
+        // Replicas are disconnected when the synchronization fails, without the entry.
+        assert!(storage.persist(entry(1)).is_ok());
+        filesystem.inject(filesystem.get_steps(), Fault::Failure);
+        assert!(storage.commit().is_err());
+        assert!(matches!(replica.try_recv(), Err(TryRecvError::Disconnected)));
+    }
 
-        let mut subject = String::with_capacity(identifier.capacity() + 1);
+    #[test]
+    fn test_short_write() {
+        let filesystem = Memory::new();
+        let mut storage = start(&filesystem, Fsync::Frame, CompactionTrigger::Entries(1000));
+        let mut acknowledged = Jobs::new();
+        assert!(storage.persist(entry(0)).is_ok());
+        apply(&mut acknowledged, 0);
+        assert!(storage.commit().is_ok());
 
-        subject.push(prefix);
-        subject.push_str(&identifier);
+        // The write is torn, and removing it fails once: it's removed before the next write.
+        filesystem.inject(filesystem.get_steps(), Fault::Failure);
+        filesystem.inject(filesystem.get_steps() + 1, Fault::Failure);
+        assert!(storage.persist(entry(1)).is_err());
+        assert!(storage.persist(entry(2)).is_ok());
+        apply(&mut acknowledged, 2);
+        assert!(storage.commit().is_ok());
 
-        subject
+        check(&filesystem, Fsync::Frame, &acknowledged, &[]);
     }
 }
@@ -0,0 +1,273 @@
+//! Replication of the persistent storage, from a primary to its replicas, through TCP streams.
+//!
+//! Replicas first authenticate to their primary, proving they know the secret configured on the
+//! primary (if any, see the [`handshake`](super::handshake) module): the snapshot contains all
+//! rules, including the credentials of their runners. Then, the replication stream uses the
+//! logfile format: it starts with a header, followed by messages
+//! sent as logfile entries (so each one is protected by a checksum). The first byte of a message
+//! is its kind:
+//!
+//! * `ENTRY`, followed by a persisted entry,
+//! * `SYNCHRONIZED`, sent once the snapshot is complete,
+//! * and `HEARTBEAT`, sent when there is nothing else to send for a while.
+//!
+//! When a replica connects, the primary first sends a snapshot of the storage: all entries of
+//! `logfile.compressed`, `logfile.to_compress` and `logfile` at the time of the connection, then
+//! the `SYNCHRONIZED` message. Afterwards, every entry persisted by the primary is sent as soon as
+//! it's written to the logfile. The snapshot may contain several entries for the same item, the
+//! latest being the right one, like when initializing from logfiles.
+//!
+//! A replica having lost its connection to the primary reconnects, receiving a new snapshot.
+
+use crossbeam_channel::{Receiver as CrossbeamReceiver, Select, Sender as CrossbeamSender, unbounded};
+use log::{debug, error, info, warn};
+use std::io::{BufWriter, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+use super::encoder::Encoder;
+use super::filesystem::Handle;
+use super::handshake::{self, Error as HandshakeError, Secret};
+use super::{Entry, Keyring, logfile};
+
+const ENTRY: u8 = 0;
+const SYNCHRONIZED: u8 = 1;
+const HEARTBEAT: u8 = 2;
+/// The maximum duration without any message sent to a replica.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// The maximum duration without any message received from the primary, before considering the
+/// connection lost.
+const PRIMARY_TIMEOUT: Duration = Duration::from_secs(5);
+/// The duration between two connection attempts to the primary.
+const RECONNECTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Open logfiles, along with the number of entries to read from each one (all entries when there
+/// is none).
+pub type Logfiles = Vec<(Box<dyn Handle>, Option<usize>)>;
+
+/// The logfiles sent as the snapshot of a replication stream, opened when the replica connected.
+/// Since they are open, they can be read even once replaced or removed by a compression process.
+pub struct Snapshot {
+    /// All logfiles, in the order their entries must be sent, along with the number of entries to
+    /// send (all entries of the logfile when there is none).
+    pub logfiles: Logfiles,
+    /// The keyring decrypting entries of the logfiles, sent in plain bytes.
+    pub keyring: Keyring,
+}
+
+/// Send the replication stream to the replica connected through the given stream, in a dedicated
+/// thread: the given snapshot, then all entries received from the given feed. The thread stops
+/// when the replica disconnects, dropping the feed.
+pub fn serve(stream: TcpStream, snapshot: Snapshot, feed: Receiver<Vec<u8>>) {
+    let spawned = thread::Builder::new().name("kairoi/replica".to_string()).spawn(move || {
+        let peer = match stream.peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => String::from("unknown"),
+        };
+        info!("Sending a snapshot to the replica {}.", peer);
+
+        let mut writer = BufWriter::new(stream);
+        match send(&mut writer, snapshot, feed) {
+            Ok(_) => {},
+            Err(_) => warn!("The replica {} has been disconnected.", peer),
+        };
+    });
+    if spawned.is_err() {
+        error!("Unable to start sending the replication stream.");
+    };
+}
+
+/// Write the whole replication stream to the given writer, until it fails.
+fn send(writer: &mut BufWriter<TcpStream>, snapshot: Snapshot, feed: Receiver<Vec<u8>>) -> Result<(), ()> {
+    write_all(writer, &logfile::encode_header())?;
+    for (mut file, limit) in snapshot.logfiles {
+        let mut reader = logfile::Reader::new(&mut file, &snapshot.keyring);
+        let entries = match reader.entries() {
+            Ok(entries) => entries,
+            Err(_) => return Err(()),
+        };
+        for entry in entries.take(limit.unwrap_or(usize::MAX)) {
+            match entry {
+                Ok(entry) => write_message(writer, ENTRY, &entry)?,
+                Err(_) => {
+                    error!("Unable to read a logfile for the snapshot of a replica.");
+
+                    return Err(());
+                },
+            };
+        };
+    };
+    write_message(writer, SYNCHRONIZED, &[])?;
+    flush(writer)?;
+    debug!("The snapshot has been sent, sending persisted entries.");
+
+    loop {
+        match feed.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(entry) => {
+                write_message(writer, ENTRY, &entry)?;
+                // Send all entries already waiting at once.
+                loop {
+                    match feed.try_recv() {
+                        Ok(entry) => write_message(writer, ENTRY, &entry)?,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return Ok(()),
+                    };
+                };
+            },
+            Err(RecvTimeoutError::Timeout) => write_message(writer, HEARTBEAT, &[])?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        flush(writer)?;
+    }
+}
+
+/// Write a message of the given kind, with the given content, to the given writer.
+fn write_message(writer: &mut BufWriter<TcpStream>, kind: u8, content: &[u8]) -> Result<(), ()> {
+    let mut message = Vec::with_capacity(1 + content.len());
+    message.push(kind);
+    message.extend(content);
+
+    match logfile::encode(&message) {
+        Ok(encoded) => write_all(writer, &encoded),
+        Err(_) => Err(()),
+    }
+}
+
+fn write_all(writer: &mut BufWriter<TcpStream>, bytes: &[u8]) -> Result<(), ()> {
+    writer.write_all(bytes).map_err(|_| ())
+}
+
+fn flush(writer: &mut BufWriter<TcpStream>) -> Result<(), ()> {
+    writer.flush().map_err(|_| ())
+}
+
+/// An event of the replication stream received from the primary.
+pub enum Event {
+    /// A new snapshot starts: all previous entries must be forgotten.
+    Snapshot,
+    /// An entry has been persisted by the primary.
+    Entry(Entry),
+    /// The snapshot is complete.
+    Synchronized,
+}
+
+/// Follow a primary, receiving its replication stream in a dedicated thread (`kairoi/follower`).
+/// It keeps reconnecting to the primary when the connection is lost, until dropped.
+pub struct Follower {
+    events: CrossbeamReceiver<Event>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Follower {
+    /// Start following the primary listening to replicas at the given address, authenticating
+    /// with the given secret (if any).
+    pub fn start(primary: String, secret: Option<Secret>) -> Follower {
+        let (sender, events) = unbounded();
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let shared = Arc::clone(&stopped);
+        thread::Builder::new().name("kairoi/follower".to_string()).spawn(move || {
+            while !shared.load(Ordering::Relaxed) {
+                match TcpStream::connect(&primary) {
+                    Ok(mut stream) => match handshake::answer(&mut stream, secret.as_ref()) {
+                        Ok(_) => {
+                            info!("Connected to the primary {}.", primary);
+                            Self::receive(stream, &sender, &shared);
+                            warn!("Disconnected from the primary {}.", primary);
+                        },
+                        Err(HandshakeError::SecretRequired) => error!("Unable to authenticate to the primary {} (it requires a secret).", primary),
+                        Err(HandshakeError::Rejected) => error!("Unable to authenticate to the primary {} (the secret is rejected).", primary),
+                        Err(_) => warn!("Unable to authenticate to the primary {}.", primary),
+                    },
+                    Err(_) => debug!("Unable to connect to the primary {}.", primary),
+                };
+
+                thread::sleep(RECONNECTION_INTERVAL);
+            };
+        }).unwrap();
+
+        Follower {
+            events,
+            stopped,
+        }
+    }
+
+    /// Pull all events received since the last call.
+    pub fn pull(&self) -> Vec<Event> {
+        self.events.try_iter().collect()
+    }
+
+    /// Add received events to the given selection, so the database thread can wait for them.
+    pub fn watch<'a>(&'a self, select: &mut Select<'a>) {
+        select.recv(&self.events);
+    }
+
+    /// Receive the replication stream from the given stream, sending its events, until the
+    /// connection is lost or the follower is stopped.
+    fn receive(mut stream: TcpStream, events: &CrossbeamSender<Event>, stopped: &AtomicBool) {
+        if stream.set_read_timeout(Some(PRIMARY_TIMEOUT)).is_err() {
+            return;
+        };
+
+        let encoder = Encoder::new();
+        let mut parser = logfile::StreamParser::new();
+        let mut started = false;
+        let mut buffer = [0; 8192];
+        while !stopped.load(Ordering::Relaxed) {
+            let read = match stream.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(read) => read,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            let messages = match parser.parse(&buffer[0..read]) {
+                Ok(messages) => messages,
+                Err(_) => {
+                    error!("The replication stream received from the primary is corrupted.");
+
+                    return;
+                },
+            };
+
+            for message in messages {
+                // The previous entries are only forgotten once the primary started sending.
+                if !started {
+                    started = true;
+                    if events.send(Event::Snapshot).is_err() {
+                        return;
+                    };
+                };
+
+                let event = match message.split_first() {
+                    Some((&ENTRY, entry)) => match encoder.decode_version(entry, parser.version()) {
+                        Ok(decoded) => Event::Entry(Entry::from(decoded)),
+                        Err(_) => {
+                            error!("Unable to decode an entry received from the primary.");
+
+                            return;
+                        },
+                    },
+                    Some((&SYNCHRONIZED, _)) => Event::Synchronized,
+                    Some((&HEARTBEAT, _)) => continue,
+                    _ => {
+                        error!("Unknown message received from the primary.");
+
+                        return;
+                    },
+                };
+                if events.send(event).is_err() {
+                    return;
+                };
+            };
+        };
+    }
+}
+
+impl Drop for Follower {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,314 @@
+//! The sled persistence backend, storing entries in an embedded key-value store.
+//!
+//! Entries are stored in the `sled` directory of the data directory, indexed by their subject:
+//! persisting an entry replaces the previous entry of the same item, and persisting a removal
+//! removes it. The store never contains stale entries, so there is nothing to compress: compacting
+//! it only flushes it to the file system, sled reclaiming the space of its own files in
+//! background.
+//!
+//! Entries are stored in their encoded form, along with the format version used to encode them (in
+//! the `metadata` tree). Like logfiles, entries using an older format version are migrated when
+//! initializing. Unlike logfiles, entries are never encrypted, and the store can't be sent as the
+//! snapshot of a replica.
+
+use ::sled::{Config, Db, IVec};
+use chrono::offset::Utc;
+use log::{debug, error, info};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use super::encoder::{Encodable, Encoder};
+use super::filesystem::{Disk, Lock};
+use super::{Backend, CompactError, Compaction, CompactionResult, Configuration, Entry, Fsync, InitializationError, InitializationResult, PersistError, PersistResult, logfile, prepare};
+
+/// The key of the format version, in the `metadata` tree.
+const VERSION: &[u8] = b"version";
+
+pub struct Storage {
+    encoder: Encoder,
+    database: Option<Db>,
+    lock: Option<Lock>,
+    last_compaction: Option<CompactionResult>,
+    /// Whether entries have been written to the store since its last flush.
+    unsynchronized: bool,
+    last_synchronization: Instant,
+    configuration: Configuration,
+}
+
+impl Storage {
+    /// Create a new Storage.
+    pub fn new(configuration: Configuration) -> Storage {
+        Storage {
+            encoder: Encoder::new(),
+            database: None,
+            lock: None,
+            last_compaction: None,
+            unsynchronized: false,
+            last_synchronization: Instant::now(),
+            configuration,
+        }
+    }
+
+    /// Get the open store, failing when it isn't initialized.
+    fn get_database(&self) -> Result<&Db, PersistError> {
+        self.database.as_ref().ok_or(PersistError::WriteFailure)
+    }
+
+    /// Migrate all entries of the given store from the given format version to the current one.
+    fn migrate(&self, database: &Db, version: u8) -> Result<(), InitializationError> {
+        info!("Migrating the sled store from the format version {} to {}.", version, logfile::VERSION);
+        for item in database.iter() {
+            let (key, value) = item.map_err(|_| InitializationError::UnreadableFile)?;
+            let migrated = match self.encoder.migrate(&value, version) {
+                Ok(migrated) => migrated,
+                Err(_) => return Err(InitializationError::InvalidEntry),
+            };
+            if database.insert(key, migrated).is_err() {
+                return Err(InitializationError::UnreadableFile);
+            };
+        };
+
+        Ok(())
+    }
+
+    /// Record the result of an on-demand compaction.
+    fn terminate_compaction(&mut self, success: bool, entries: usize) {
+        self.last_compaction = Some(CompactionResult {
+            success,
+            finished: Utc::now(),
+            entries,
+        });
+    }
+}
+
+impl Backend for Storage {
+    /// Initialize the storage by opening the store (and migrating it, if needed), then decoding
+    /// all its entries.
+    fn initialize(&mut self) -> InitializationResult {
+        let directory = self.configuration.data_directory.clone();
+        self.lock = Some(prepare(&Disk, &directory)?);
+
+        let path = directory.join("sled");
+        let database = match Config::new().path(&path).open() {
+            Ok(database) => database,
+            Err(error) => {
+                error!("Unable to open the sled store '{}': {}.", path.display(), error);
+
+                return Err(InitializationError::UnreadableFile);
+            },
+        };
+        let metadata = database.open_tree("metadata").map_err(|_| InitializationError::UnreadableFile)?;
+        match metadata.get(VERSION) {
+            Ok(Some(version)) if version.as_ref() == [logfile::VERSION] => {},
+            Ok(Some(version)) if version.len() == 1 => self.migrate(&database, version[0])?,
+            Ok(None) => {},
+            Ok(Some(_)) => return Err(InitializationError::CorruptedFile),
+            Err(_) => return Err(InitializationError::UnreadableFile),
+        };
+        let version = metadata.insert(VERSION, &[logfile::VERSION][..]).and_then(|_| database.flush());
+        if version.is_err() {
+            error!("Unable to write the format version of the sled store.");
+
+            return Err(InitializationError::UnreadableFile);
+        };
+
+        let mut entries = Vec::with_capacity(database.len());
+        for value in database.iter().values() {
+            let value = value.map_err(|_| InitializationError::UnreadableFile)?;
+            match self.encoder.decode(&value) {
+                Ok(decoded) => entries.push(Entry::from(decoded)),
+                Err(_) => return Err(InitializationError::InvalidEntry),
+            };
+        };
+        debug!("{:?} entries have been read from the sled store.", entries.len());
+        self.database = Some(database);
+
+        Ok(entries)
+    }
+
+    /// Persist the given entry to the store, flushing it right away with the `Always` fsync
+    /// policy.
+    fn persist(&mut self, entry: Entry) -> PersistResult {
+        let database = self.get_database()?;
+        let subject = entry.get_subject();
+        let written = match entry {
+            Entry::JobRemoval(_) => database.remove(subject),
+            entry => match self.encoder.encode(Encodable::from(entry)) {
+                Ok(encoded) => database.insert(subject, encoded),
+                Err(_) => return Err(PersistError::EncodingFailure),
+            },
+        };
+        if written.is_err() {
+            return Err(PersistError::WriteFailure);
+        };
+
+        match self.configuration.fsync {
+            Fsync::Always => if database.flush().is_err() {
+                return Err(PersistError::WriteFailure);
+            },
+            Fsync::Frame | Fsync::EverySecond => self.unsynchronized = true,
+            Fsync::Never => {},
+        };
+
+        Ok(())
+    }
+
+    /// Flush entries persisted since the last commit to the file system, following the configured
+    /// [`Fsync`] policy.
+    fn commit(&mut self) -> PersistResult {
+        let due = match self.configuration.fsync {
+            Fsync::Frame => true,
+            Fsync::EverySecond => self.last_synchronization.elapsed() >= Duration::from_secs(1),
+            Fsync::Always | Fsync::Never => false,
+        };
+        if !due || !self.unsynchronized {
+            return Ok(());
+        };
+        self.last_synchronization = Instant::now();
+        self.unsynchronized = false;
+
+        match self.get_database()?.flush() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(PersistError::WriteFailure),
+        }
+    }
+
+    /// Remove all entries from the store.
+    fn reset(&mut self) -> PersistResult {
+        let database = self.get_database()?;
+        match database.clear().and_then(|_| database.flush()) {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                error!("Unable to clear the sled store.");
+
+                Err(PersistError::WriteFailure)
+            },
+        }
+    }
+
+    /// Read all entries of the store, in their encoded form. Since the store only contains the
+    /// latest entry of each item, they come in no particular order.
+    fn entries(&self) -> Result<Vec<logfile::Parsed>, PersistError> {
+        self.get_database()?
+            .iter()
+            .values()
+            .map(|value| value.map(|value: IVec| value.to_vec()).map_err(|_| PersistError::WriteFailure))
+            .collect()
+    }
+
+    /// Flush the store to the file system. Since it never contains stale entries, it's the whole
+    /// compaction.
+    fn compact(&mut self) -> Result<(), CompactError> {
+        let database = self.get_database().map_err(|_| CompactError::Failure)?;
+        let flushed = database.flush();
+        let entries = database.len();
+        self.terminate_compaction(flushed.is_ok(), entries);
+
+        match flushed {
+            Ok(_) => Ok(()),
+            Err(_) => Err(CompactError::Failure),
+        }
+    }
+
+    /// Write all entries of the store to the given path, as a compressed logfile. The backup is
+    /// written synchronously, aside, then atomically moved.
+    fn backup(&mut self, path: PathBuf) -> Result<(), CompactError> {
+        let directory = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        if !directory.is_dir() {
+            error!("Unable to write a backup to '{}': its directory doesn't exist.", path.display());
+
+            return Err(CompactError::Failure);
+        };
+        if path.symlink_metadata().is_ok() {
+            error!("Unable to write the backup to '{}': the file already exists.", path.display());
+
+            return Err(CompactError::Failure);
+        };
+
+        let entries = self.entries().map_err(|_| CompactError::Failure)?;
+        if logfile::rewrite(&Disk, &path, &entries, &self.configuration.keyring).is_err() {
+            error!("Unable to write the backup to '{}'.", path.display());
+            self.terminate_compaction(false, 0);
+
+            return Err(CompactError::Failure);
+        };
+        info!("The backup has been written to '{}'.", path.display());
+        self.terminate_compaction(true, entries.len());
+
+        Ok(())
+    }
+
+    /// Get the state of the compaction. Since compactions are synchronous, none is ever running.
+    fn get_compaction(&mut self) -> Compaction {
+        Compaction {
+            running: None,
+            last: self.last_compaction.clone(),
+        }
+    }
+}
+
+impl Drop for Storage {
+    /// Flush the entries persisted since the last commit when the store is closed (typically, when
+    /// the server stops), whatever the fsync policy but `Never`.
+    fn drop(&mut self) {
+        if let (true, Some(database)) = (self.unsynchronized, &self.database) {
+            let _ = database.flush();
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::offset::TimeZone;
+    use std::fs::File;
+    use super::super::{BackendKind, CompactionTrigger, Job, JobRemoval, JobStatus, Keyring, Rule, Runner};
+
+    fn configuration(directory: &Path) -> Configuration {
+        Configuration {
+            backend: BackendKind::Sled,
+            data_directory: directory.to_path_buf(),
+            fsync: Fsync::Frame,
+            compaction_trigger: CompactionTrigger::Entries(5000),
+            keyring: Keyring::default(),
+            cluster: None,
+        }
+    }
+
+    #[test]
+    fn test_persist_and_initialize() {
+        let directory = std::env::temp_dir().join(format!("kairoi-test-sled-{}", std::process::id()));
+        let job = |identifier: &str| Entry::Job(Job { identifier: identifier.to_string(), execution: Utc.timestamp(1592430436, 0), status: JobStatus::Planned });
+
+        let mut storage = Storage::new(configuration(&directory));
+        assert_eq!(storage.initialize().ok().unwrap().len(), 0);
+        storage.persist(job("job.1")).ok().unwrap();
+        storage.persist(job("job.2")).ok().unwrap();
+        storage.persist(job("job.1")).ok().unwrap();
+        storage.persist(Entry::JobRemoval(JobRemoval { identifier: String::from("job.2") })).ok().unwrap();
+        storage.persist(Entry::Rule(Rule { identifier: String::from("job.1"), pattern: String::from("job."), runner: Runner::Shell { command: String::from("script.sh") } })).ok().unwrap();
+        storage.commit().ok().unwrap();
+        assert_eq!(storage.entries().ok().unwrap().len(), 2);
+
+        let backup = directory.with_extension("backup");
+        storage.backup(backup.clone()).ok().unwrap();
+        assert_eq!(storage.get_compaction().last.unwrap().entries, 2);
+        drop(storage);
+
+        let mut storage = Storage::new(configuration(&directory));
+        let mut subjects: Vec<String> = storage.initialize().ok().unwrap().iter().map(Entry::get_subject).collect();
+        subjects.sort();
+        assert_eq!(subjects, vec![String::from("jjob.1"), String::from("rjob.1")]);
+
+        let mut file = File::open(&backup).unwrap();
+        assert_eq!(logfile::Reader::new(&mut file, &Keyring::default()).read().ok().unwrap().entries.len(), 2);
+
+        storage.reset().ok().unwrap();
+        assert_eq!(storage.entries().ok().unwrap().len(), 0);
+
+        std::fs::remove_file(&backup).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
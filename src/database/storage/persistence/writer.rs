@@ -0,0 +1,610 @@
+//! The persistence writer thread, moving all file system operations out of the database thread.
+//!
+//! The [`Writer`] owns a persistence [`Backend`] running in its own thread (`kairoi/writer`), and
+//! feeds it through a channel. Persisting entries only queues them, so the database thread never waits for
+//! the disk. At the end of each frame, the database thread commits all entries persisted during
+//! the frame: commits are numbered by a sequence, and confirmed by the writer thread once all
+//! their entries are written (and synchronized, following the fsync policy). The database thread
+//! regularly checks the last confirmed sequence to complete the writes depending on it.
+//!
+//! Since entries are written after the database thread moved on, a write failure can't be
+//! reported to the write itself: the writer thread stops confirming commits, reporting the
+//! failure instead.
+//!
+//! When listening to replicas, connections are accepted in another thread (`kairoi/replication`),
+//! authenticated, and handed to the writer thread: since it's the only one writing to the logfile, it's able to
+//! take a consistent snapshot of the storage for each new replica.
+//!
+//! In clustered mode, the writer thread also drives the [`Cluster`]: messages from other nodes are
+//! received in other threads and handed to the writer thread, which ticks the cluster node at a
+//! regular interval. Commits are confirmed once committed by the cluster, or rejected when this
+//! node isn't the leader, and the database thread pulls cluster events with [`Writer::pull_events`].
+
+use crossbeam_channel::{Receiver as CrossbeamReceiver, Select, Sender as CrossbeamSender, TryRecvError, unbounded};
+use log::{debug, error, info, warn};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use super::cluster::{Cluster, Envelope, Event, Outcome, TICK_INTERVAL};
+use super::backend::{self, Backend};
+use super::handshake::{self, Error as HandshakeError, Secret};
+use super::{ClusterConfiguration, CompactError, Compaction, Configuration, Entry, InitializationError, InitializationResult, Keyring, ListenError, PersistError, PersistResult};
+
+/// The maximum duration the writer thread waits for a message, before synchronizing the logfile
+/// (with the `EverySecond` fsync policy) and publishing the state of the compaction.
+const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+enum Message {
+    Initialize,
+    Persist(Entry),
+    Commit(u64),
+    Compact,
+    Backup(PathBuf),
+    Replicate(TcpStream),
+    Reset,
+    Peer(Envelope),
+}
+
+enum Confirmation {
+    Initialized(InitializationResult),
+    Committed(u64),
+    /// The commit with the given sequence, and all previous ones not yet confirmed, have been
+    /// rejected by the cluster.
+    Rejected(u64),
+    Failed,
+}
+
+/// The state of the compaction, as published by the writer thread.
+struct Published {
+    compaction: Compaction,
+    /// The number of compaction requests (including backups) handled so far.
+    handled: u64,
+}
+
+pub struct Writer {
+    messages: Sender<Message>,
+    confirmations: CrossbeamReceiver<Confirmation>,
+    /// The cluster events, only sent in clustered mode.
+    events: CrossbeamReceiver<Event>,
+    clustered: bool,
+    published: Arc<Mutex<Published>>,
+    /// The number of compaction requests (including backups) sent to the writer thread.
+    requested: u64,
+    /// Whether entries have been persisted since the last commit.
+    persisted: bool,
+    /// The sequence of the last commit.
+    sequence: u64,
+    /// The sequence of the last commit confirmed by the writer thread.
+    committed: u64,
+    /// The sequence of the last commit rejected by the cluster.
+    rejected: u64,
+}
+
+impl Writer {
+    /// Create a new Writer, spawning its thread with a persistent storage using the given
+    /// configuration.
+    pub fn new(mut configuration: Configuration) -> Writer {
+        let cluster = configuration.cluster.take();
+        let context = (configuration.data_directory.clone(), configuration.keyring.clone());
+
+        Self::spawn(backend::create(configuration), context, cluster)
+    }
+
+    /// Spawn the writer thread with the given storage (and the given cluster, if any, storing its
+    /// replicated log in the given data directory with the given keyring).
+    fn spawn(storage: Box<dyn Backend>, context: (PathBuf, Keyring), cluster: Option<ClusterConfiguration>) -> Writer {
+        let (messages, message_receiver) = channel();
+        let (confirmation_sender, confirmations) = unbounded();
+        let (event_sender, events) = unbounded();
+        let published = Arc::new(Mutex::new(Published {
+            compaction: Compaction {
+                running: None,
+                last: None,
+            },
+            handled: 0,
+        }));
+
+        let shared = Arc::clone(&published);
+        let clustered = cluster.is_some();
+        // Messages from other nodes are handed to the writer thread along with the database ones.
+        let cluster = cluster.map(|cluster| (cluster, messages.clone(), event_sender));
+        thread::Builder::new().name("kairoi/writer".to_string()).spawn(move || {
+            Self::run(storage, context, cluster, message_receiver, confirmation_sender, shared);
+        }).unwrap();
+
+        Writer {
+            messages,
+            confirmations,
+            events,
+            clustered,
+            published,
+            requested: 0,
+            persisted: false,
+            sequence: 0,
+            committed: 0,
+            rejected: 0,
+        }
+    }
+
+    /// Initialize the persistent storage, waiting for the writer thread to retrieve all persisted
+    /// entries. See [`Backend::initialize`].
+    pub fn initialize(&mut self) -> InitializationResult {
+        if self.messages.send(Message::Initialize).is_err() {
+            return Err(InitializationError::UnreadableFile);
+        };
+
+        match self.confirmations.recv() {
+            Ok(Confirmation::Initialized(result)) => result,
+            _ => Err(InitializationError::UnreadableFile),
+        }
+    }
+
+    /// Stop the writer thread, once all queued messages are handled, so persisting entries fails
+    /// from now on.
+    #[cfg(test)]
+    pub fn stop(&mut self) {
+        self.messages = channel().0;
+    }
+
+    /// Queue the given entry to be persisted by the writer thread. It only fails when the writer
+    /// thread has stopped.
+    pub fn persist(&mut self, entry: Entry) -> PersistResult {
+        match self.messages.send(Message::Persist(entry)) {
+            Ok(_) => {
+                self.persisted = true;
+
+                Ok(())
+            },
+            Err(_) => Err(PersistError::WriteFailure),
+        }
+    }
+
+    /// Queue the removal of all persisted entries, before receiving a new snapshot from the
+    /// primary. See [`Backend::reset`]. Like persisted entries, it's durable once committed.
+    pub fn reset(&mut self) -> PersistResult {
+        match self.messages.send(Message::Reset) {
+            Ok(_) => {
+                self.persisted = true;
+
+                Ok(())
+            },
+            Err(_) => Err(PersistError::WriteFailure),
+        }
+    }
+
+    /// Commit all entries persisted since the last commit, returning the sequence of this commit.
+    /// Entries are durable once [`committed`] returns this sequence (or a following one). When no
+    /// entry has been persisted since the last commit, the sequence of the last commit is returned.
+    pub fn commit(&mut self) -> u64 {
+        if !self.persisted {
+            return self.sequence;
+        };
+
+        self.sequence += 1;
+        self.persisted = false;
+        // A stopped writer thread is detected when checking confirmations.
+        let _ = self.messages.send(Message::Commit(self.sequence));
+
+        self.sequence
+    }
+
+    /// Get the sequence of the last confirmed commit. It fails when the writer thread failed to
+    /// write entries, or has stopped.
+    pub fn committed(&mut self) -> Result<u64, PersistError> {
+        loop {
+            match self.confirmations.try_recv() {
+                Ok(Confirmation::Committed(sequence)) => self.committed = sequence,
+                Ok(Confirmation::Rejected(sequence)) => self.rejected = sequence,
+                Ok(_) => return Err(PersistError::WriteFailure),
+                Err(TryRecvError::Empty) => return Ok(self.committed),
+                Err(TryRecvError::Disconnected) => return Err(PersistError::WriteFailure),
+            };
+        }
+    }
+
+    /// Get the sequence of the last commit rejected by the cluster, as of the last call to
+    /// [`committed`]. Commits following the last confirmed one, up to this one, will never be
+    /// confirmed.
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Pull all cluster events since the last call.
+    pub fn pull_events(&self) -> Vec<Event> {
+        self.events.try_iter().collect()
+    }
+
+    /// Add confirmations and cluster events (in clustered mode) to the given selection, so the
+    /// database thread can wait for them.
+    pub fn watch<'a>(&'a self, select: &mut Select<'a>) {
+        select.recv(&self.confirmations);
+        if self.clustered {
+            select.recv(&self.events);
+        };
+    }
+
+    /// Start a compression process on demand, in the writer thread. It fails if a compression
+    /// process is already running, or already requested.
+    pub fn compact(&mut self) -> Result<(), CompactError> {
+        self.request(Message::Compact)
+    }
+
+    /// Start a compression process on demand in the writer thread, writing a backup of the storage
+    /// to the given path. It fails if a compression process is already running, or already
+    /// requested. Other failures (like a missing directory) are only reported by the state of the
+    /// compression process.
+    pub fn backup(&mut self, path: PathBuf) -> Result<(), CompactError> {
+        self.request(Message::Backup(path))
+    }
+
+    /// Start listening to replicas at the given address, accepting their connections in a
+    /// dedicated thread. Replicas must first prove they know the given secret, if any (see the
+    /// [`handshake`](super::handshake) module). Each replica is then handled by the writer thread.
+    /// See [`Backend::replicate`].
+    pub fn listen(&mut self, address: &str, secret: Option<Secret>) -> Result<(), ListenError> {
+        let listener = match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(_) => return Err(ListenError::UnavailableAddress),
+        };
+        info!("Listening to replicas on {}.", address);
+
+        let messages = self.messages.clone();
+        thread::Builder::new().name("kairoi/replication".to_string()).spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        warn!("Unable to accept the connection of a replica: {}.", error);
+
+                        continue;
+                    },
+                };
+                // Replicas are authenticated aside, so a slow replica never delays the others.
+                let (messages, secret) = (messages.clone(), secret.clone());
+                let spawned = thread::Builder::new().name("kairoi/handshake".to_string()).spawn(move || {
+                    let peer = stream.peer_addr().map(|address| address.to_string()).unwrap_or_else(|_| String::from("unknown"));
+                    match handshake::challenge(&mut stream, secret.as_ref()) {
+                        Ok(_) => {
+                            let _ = messages.send(Message::Replicate(stream));
+                        },
+                        Err(HandshakeError::Rejected) => warn!("Rejected the replica {} (invalid secret).", peer),
+                        Err(_) => warn!("Unable to authenticate the replica {}.", peer),
+                    };
+                });
+                if spawned.is_err() {
+                    warn!("Unable to handle the connection of a replica.");
+                };
+            };
+        }).unwrap();
+
+        Ok(())
+    }
+
+    /// Get the state of the compression process, as last published by the writer thread.
+    pub fn get_compaction(&self) -> Compaction {
+        self.get_published(|published| published.compaction.clone())
+    }
+
+    /// Send the given compaction request to the writer thread. The published state of the
+    /// compression process lags behind the requests: a request sent but not handled yet is
+    /// considered as running, so two requests in a row can't both succeed.
+    fn request(&mut self, message: Message) -> Result<(), CompactError> {
+        let (running, handled) = self.get_published(|published| (published.compaction.running.is_some(), published.handled));
+        if running || handled < self.requested {
+            return Err(CompactError::AlreadyRunning);
+        };
+
+        match self.messages.send(message) {
+            Ok(_) => {
+                self.requested += 1;
+
+                Ok(())
+            },
+            Err(_) => Err(CompactError::Failure),
+        }
+    }
+
+    /// Read the state last published by the writer thread.
+    fn get_published<T>(&self, read: impl FnOnce(&Published) -> T) -> T {
+        match self.published.lock() {
+            Ok(published) => read(&published),
+            Err(poisoned) => read(&poisoned.into_inner()),
+        }
+    }
+
+    /// Handle messages with the given storage (and the given cluster, if any, storing its
+    /// replicated log in the given data directory with the given keyring), until the database
+    /// thread stops.
+    fn run(mut storage: Box<dyn Backend>, (directory, keyring): (PathBuf, Keyring), cluster: Option<(ClusterConfiguration, Sender<Message>, CrossbeamSender<Event>)>, messages: Receiver<Message>, confirmations: CrossbeamSender<Confirmation>, published: Arc<Mutex<Published>>) {
+        let mut failed = false;
+        let mut handled = 0;
+        let (mut cluster_configuration, mut node) = (cluster, None);
+        let interval = match cluster_configuration {
+            Some(_) => TICK_INTERVAL,
+            None => IDLE_INTERVAL,
+        };
+
+        loop {
+            let confirmation = match messages.recv_timeout(interval) {
+                Ok(Message::Initialize) => {
+                    let mut result = storage.initialize();
+                    if let (Ok(_), Some((configuration, inbox, events))) = (&result, cluster_configuration.take()) {
+                        match Cluster::start(configuration, &directory, &keyring, inbox, Message::Peer, events) {
+                            Ok(started) => node = Some(started),
+                            Err(error) => result = Err(error),
+                        };
+                    };
+
+                    Some(Confirmation::Initialized(result))
+                },
+                Ok(Message::Persist(entry)) => {
+                    match &mut node {
+                        Some(cluster) => cluster.persist(entry),
+                        None => if storage.persist(entry).is_err() {
+                            error!("Unable to persist an entry to the logfile.");
+                            failed = true;
+                        },
+                    };
+
+                    None
+                },
+                Ok(Message::Commit(sequence)) => {
+                    match &mut node {
+                        Some(_) if failed => Some(Confirmation::Failed),
+                        Some(cluster) => match cluster.commit(sequence) {
+                            Ok(_) => None,
+                            Err(_) => Some(Confirmation::Rejected(sequence)),
+                        },
+                        None => {
+                            if storage.commit().is_err() {
+                                error!("Unable to synchronize the logfile to the file system.");
+                                failed = true;
+                            };
+
+                            Some(match failed {
+                                true => Confirmation::Failed,
+                                false => Confirmation::Committed(sequence),
+                            })
+                        },
+                    }
+                },
+                Ok(Message::Compact) => {
+                    handled += 1;
+                    match storage.compact() {
+                        Ok(_) => {},
+                        Err(CompactError::AlreadyRunning) => debug!("A compression process is already running."),
+                        Err(CompactError::Failure) => error!("Unable to start the compression process."),
+                    };
+
+                    None
+                },
+                Ok(Message::Backup(path)) => {
+                    handled += 1;
+                    match storage.backup(path) {
+                        Ok(_) => {},
+                        Err(CompactError::AlreadyRunning) => error!("Unable to start the backup, a compression process is already running."),
+                        Err(CompactError::Failure) => error!("Unable to start the backup."),
+                    };
+
+                    None
+                },
+                Ok(Message::Replicate(stream)) => {
+                    storage.replicate(stream);
+
+                    None
+                },
+                Ok(Message::Reset) => {
+                    if storage.reset().is_err() {
+                        error!("Unable to remove persisted entries.");
+                        failed = true;
+                    };
+
+                    None
+                },
+                Ok(Message::Peer(envelope)) => {
+                    if let Some(cluster) = &mut node {
+                        cluster.step(envelope);
+                    };
+
+                    None
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    if storage.commit().is_err() {
+                        error!("Unable to synchronize the logfile to the file system.");
+                        failed = true;
+                    };
+
+                    None
+                },
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = storage.commit();
+
+                    break;
+                },
+            };
+
+            if let Some(confirmation) = confirmation {
+                if confirmations.send(confirmation).is_err() {
+                    break;
+                };
+            };
+
+            // A failed node stops taking part in the cluster, since it can't write anymore.
+            if let Some(cluster) = node.as_mut().filter(|_| !failed) {
+                let outcomes = match cluster.process(storage.as_mut()) {
+                    Ok(outcomes) => outcomes.into_iter().map(|outcome| match outcome {
+                        Outcome::Committed(sequence) => Confirmation::Committed(sequence),
+                        Outcome::Rejected(sequence) => Confirmation::Rejected(sequence),
+                    }).collect(),
+                    Err(_) => {
+                        failed = true;
+
+                        vec![Confirmation::Failed]
+                    },
+                };
+                if outcomes.into_iter().any(|confirmation| confirmations.send(confirmation).is_err()) {
+                    break;
+                };
+            };
+
+            let compaction = storage.get_compaction();
+            if let Ok(mut published) = published.lock() {
+                *published = Published { compaction, handled };
+            };
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use std::path::Path;
+    use std::thread::sleep;
+    use super::super::filesystem::memory::{Fault, Memory};
+    use super::super::encoder::{Decoded, Encoder};
+    use super::super::filesystem::{Filesystem, Mode};
+    use super::super::{CompactionTrigger, Fsync, Job, JobStatus, Storage, logfile};
+    use super::super::backend::Kind;
+
+    fn start(filesystem: &Memory, fsync: Fsync) -> Writer {
+        let configuration = Configuration {
+            backend: Kind::Logfile,
+            data_directory: PathBuf::from("data"),
+            fsync,
+            compaction_trigger: CompactionTrigger::Entries(1000),
+            keyring: Keyring::default(),
+            cluster: None,
+        };
+        let storage = Storage::with_filesystem(configuration, Arc::new(filesystem.clone()));
+        let mut writer = Writer::spawn(Box::new(storage), (PathBuf::from("data"), Keyring::default()), None);
+        assert!(writer.initialize().is_ok());
+
+        writer
+    }
+
+    fn entry(index: i64) -> Entry {
+        Entry::Job(Job { identifier: format!("job.{}", index), execution: Utc.timestamp(1592430436 + index, 0), status: JobStatus::Planned })
+    }
+
+    /// Read the identifiers of all jobs persisted in the logfile, in order.
+    fn read(filesystem: &Memory) -> Vec<String> {
+        let mut file = filesystem.open(Path::new("data/logfile"), Mode::Read).unwrap();
+        let content = logfile::Reader::new(&mut *file, &Keyring::default()).read().ok().unwrap();
+
+        content.entries.iter().map(|entry| match Encoder::new().decode(entry) {
+            Ok(Decoded::Job(job)) => job.identifier,
+            _ => panic!("The logfile contains an unexpected entry."),
+        }).collect()
+    }
+
+    /// Wait until the writer thread confirms the given commit sequence, or reports a failure.
+    fn confirm(writer: &mut Writer, sequence: u64) -> Result<(), PersistError> {
+        for _ in 0..1000 {
+            match writer.committed() {
+                Ok(committed) if committed >= sequence => return Ok(()),
+                Ok(_) => sleep(Duration::from_millis(1)),
+                Err(error) => return Err(error),
+            };
+        };
+        panic!("The commit {} hasn't been confirmed.", sequence);
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let filesystem = Memory::new();
+        let mut writer = start(&filesystem, Fsync::Frame);
+
+        // All entries of a commit are synchronized at once, before the commit is confirmed.
+        (0..3).for_each(|index| assert!(writer.persist(entry(index)).is_ok()));
+        let sequence = writer.commit();
+        assert_eq!(sequence, 1);
+        assert!(confirm(&mut writer, sequence).is_ok());
+        assert_eq!(filesystem.get_synchronizations(), 1);
+        let content = filesystem.read(Path::new("data/logfile")).unwrap();
+        filesystem.restart();
+        assert_eq!(filesystem.read(Path::new("data/logfile")).unwrap(), content);
+
+        // Nothing to commit keeps the last sequence.
+        assert_eq!(writer.commit(), sequence);
+    }
+
+    #[test]
+    fn test_failed_group_commit() {
+        let filesystem = Memory::new();
+        let mut writer = start(&filesystem, Fsync::Frame);
+        assert!(writer.persist(entry(0)).is_ok());
+        let sequence = writer.commit();
+        assert!(confirm(&mut writer, sequence).is_ok());
+
+        // The entry is written, but its synchronization fails: its commit is never confirmed.
+        filesystem.inject(filesystem.get_steps() + 1, Fault::Failure);
+        assert!(writer.persist(entry(1)).is_ok());
+        let sequence = writer.commit();
+        assert!(confirm(&mut writer, sequence).is_err());
+        assert!(matches!(writer.committed(), Ok(committed) if committed < sequence));
+    }
+
+    #[test]
+    fn test_ordered_commits() {
+        let filesystem = Memory::new();
+        let mut writer = start(&filesystem, Fsync::Frame);
+
+        // Commits sent without waiting are confirmed in order, with their entries written in order.
+        let mut confirmed = vec![0];
+        for sequence in 1..=5 {
+            (sequence * 2..sequence * 2 + 2).for_each(|index| assert!(writer.persist(entry(index)).is_ok()));
+            assert_eq!(writer.commit(), sequence as u64);
+            confirmed.push(writer.committed().ok().unwrap());
+        };
+        assert!(confirm(&mut writer, 5).is_ok());
+        assert!(confirmed.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(read(&filesystem), (2..12).map(|index| format!("job.{}", index)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        // Entries persisted but not committed yet are still synchronized when the writer stops,
+        // even when their synchronization isn't due yet.
+        let filesystem = Memory::new();
+        let mut writer = start(&filesystem, Fsync::EverySecond);
+        (0..3).for_each(|index| assert!(writer.persist(entry(index)).is_ok()));
+        drop(writer);
+        for _ in 0..1000 {
+            if filesystem.get_synchronizations() > 0 {
+                break;
+            };
+            sleep(Duration::from_millis(1));
+        };
+
+        filesystem.restart();
+        assert_eq!(read(&filesystem), vec!["job.0", "job.1", "job.2"]);
+    }
+
+    #[test]
+    fn test_compaction_conflict() {
+        let filesystem = Memory::new();
+        let mut writer = start(&filesystem, Fsync::Frame);
+        assert!(writer.persist(entry(0)).is_ok());
+        let sequence = writer.commit();
+        assert!(confirm(&mut writer, sequence).is_ok());
+
+        // The second request is sent before the writer thread publishes the first one as running.
+        assert!(writer.compact().is_ok());
+        assert!(matches!(writer.compact(), Err(CompactError::AlreadyRunning)));
+        assert!(matches!(writer.backup(PathBuf::from("backup")), Err(CompactError::AlreadyRunning)));
+
+        // Once the compaction has terminated, a new one can be requested.
+        for _ in 0..1000 {
+            if writer.get_compaction().last.is_some() {
+                break;
+            };
+            sleep(Duration::from_millis(1));
+        };
+        assert!(writer.get_compaction().last.unwrap().success);
+        assert!(writer.compact().is_ok());
+    }
+}
@@ -0,0 +1,477 @@
+//! Transfer of jobs and rules between storages, exported to and imported from NDJSON.
+//!
+//! Each line contains a single record, being either a job, like `{"type": "job", "identifier":
+//! "app.job.1", "execution": "2020-06-17T21:47:16+00:00", "status": "planned"}`, or a rule, like
+//! `{"type": "rule", "identifier": "app.rule", "pattern": "app.", "runner": {"type": "shell",
+//! "command": "script.sh"}}`. Exports contain all rules, then all jobs, ordered by identifier.
+//!
+//! Imports are done in two steps: records are first [`read`] from the input, without any access to
+//! the storage (so it can be done away from the database thread), then the read items are
+//! [`apply`]-ed to the storage. Imported records are written through the [`Storage`], like any
+//! other write: they are persisted and committed with the writes of the frame applying them. An imported item conflicts with an
+//! existing item of the same type and identifier, and conflicts are handled following the given
+//! [`Policy`]. Jobs being executed are never replaced, and imported jobs in the `triggered` state
+//! are planned again, since their executions can't be followed by the importing storage.
+//!
+//! A sharded storage only exports and imports the jobs it owns. Rules are imported by all shards,
+//! but only counted by the shard owning their identifier, and only exported by the first shard: the
+//! numbers of transferred items of all shards add up.
+
+use chrono::{DateTime, offset::Utc};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use super::{Job, JobStatus, Rule, Runner, Storage};
+
+/// The handling of imported items conflicting with existing ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    /// Keep the existing item.
+    Skip,
+    /// Replace the existing item (except jobs being executed, which are kept).
+    Overwrite,
+    /// Fail the whole import, before writing anything.
+    Fail,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The input can't be read, or the output can't be written.
+    Io,
+    /// The line with the given number (starting from 1) isn't a valid record.
+    InvalidRecord(usize),
+    /// The imported item with the given identifier conflicts with an existing one.
+    Conflict(String),
+    /// An imported item can't be persisted.
+    PersistenceFailure,
+}
+
+/// The numbers of transferred items.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transfer {
+    pub jobs: usize,
+    pub rules: usize,
+    /// The number of imported items skipped because of a conflict.
+    pub skipped: usize,
+}
+
+/// A record, as serialized on a single line.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum Record {
+    Job {
+        identifier: String,
+        /// The execution datetime, formatted following the RFC 3339.
+        execution: String,
+        status: StatusRecord,
+    },
+    Rule {
+        identifier: String,
+        pattern: String,
+        runner: RunnerRecord,
+    },
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StatusRecord {
+    Planned,
+    Triggered,
+    Executed,
+    Failed,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum RunnerRecord {
+    Shell {
+        command: String,
+    },
+    Amqp {
+        dsn: String,
+        exchange: String,
+        routing_key: String,
+    },
+}
+
+/// An item read from a record.
+#[derive(Debug, PartialEq)]
+enum Item {
+    Job(Job),
+    Rule(Rule),
+}
+
+impl Item {
+    fn get_identifier(&self) -> &String {
+        match self {
+            Item::Job(job) => job.get_identifier(),
+            Item::Rule(rule) => rule.get_identifier(),
+        }
+    }
+
+    /// Check whether this item conflicts with an item of the given storage.
+    fn conflicts(&self, storage: &Storage) -> bool {
+        match self {
+            Item::Job(job) => storage.get_job(job.get_identifier()).is_some(),
+            Item::Rule(rule) => storage.get_rule(rule.get_identifier()).is_some(),
+        }
+    }
+}
+
+/// All items read from an input, ready to be applied to a storage.
+#[derive(Debug, Default)]
+pub struct Items(Vec<Item>);
+
+/// Write all jobs and rules of the given storage to the given output, as NDJSON.
+pub fn export(storage: &Storage, output: &mut dyn Write) -> Result<Transfer, Error> {
+    let mut rules = match storage.get_shard().index {
+        0 => storage.get_rules(),
+        _ => Vec::new(),
+    };
+    rules.sort_by(|a, b| a.get_identifier().cmp(b.get_identifier()));
+    let mut jobs = storage.get_jobs();
+    jobs.sort_by(|a, b| a.get_identifier().cmp(b.get_identifier()));
+
+    let records = rules.iter().map(|rule| Record::from(*rule)).chain(jobs.iter().map(Record::from));
+    for record in records {
+        if writeln!(output, "{}", format(&record)).is_err() {
+            return Err(Error::Io);
+        };
+    };
+    if output.flush().is_err() {
+        return Err(Error::Io);
+    };
+
+    Ok(Transfer {
+        jobs: jobs.len(),
+        rules: rules.len(),
+        skipped: 0,
+    })
+}
+
+/// Import all jobs and rules from the given NDJSON input into the given storage, handling
+/// conflicts with the given policy (see [`read`] and [`apply`]).
+pub fn import(storage: &mut Storage, input: &mut dyn BufRead, policy: Policy) -> Result<Transfer, Error> {
+    let items = read(input)?;
+
+    apply(storage, items, policy)
+}
+
+/// Read all jobs and rules from the given NDJSON input. The whole input is read and validated at
+/// once: an invalid input is never partially imported.
+pub fn read(input: &mut dyn BufRead) -> Result<Items, Error> {
+    let mut items = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return Err(Error::Io),
+        };
+        if line.trim().is_empty() {
+            continue;
+        };
+        match parse(&line) {
+            Some(item) => items.push(item),
+            None => return Err(Error::InvalidRecord(index + 1)),
+        };
+    };
+
+    Ok(Items(items))
+}
+
+/// Write the given items into the given storage, handling conflicts with the given policy. With
+/// the `Fail` policy, conflicts are checked before writing anything, so a conflict never leads to
+/// a partial import of the storage. Jobs owned by other shards are ignored.
+pub fn apply(storage: &mut Storage, items: Items, policy: Policy) -> Result<Transfer, Error> {
+    let mut items = items.0;
+    items.retain(|item| match item {
+        Item::Job(job) => storage.get_shard().owns(job.get_identifier()),
+        Item::Rule(_) => true,
+    });
+
+    if policy == Policy::Fail {
+        if let Some(item) = items.iter().find(|item| item.conflicts(storage)) {
+            return Err(Error::Conflict(item.get_identifier().clone()));
+        };
+    };
+
+    let mut transfer = Transfer::default();
+    for item in items {
+        let conflicting = item.conflicts(storage);
+        let kept = match &item {
+            Item::Job(job) => conflicting && (policy == Policy::Skip || storage.get_job(job.get_identifier()).map_or(false, |current| *current.get_status() == JobStatus::Triggered)),
+            Item::Rule(_) => conflicting && policy == Policy::Skip,
+        };
+        let counted = storage.get_shard().owns(item.get_identifier());
+        if kept {
+            if counted {
+                transfer.skipped += 1;
+            };
+
+            continue;
+        };
+
+        let written = match item {
+            Item::Job(job) => {
+                transfer.jobs += 1;
+
+                storage.set_job(job)
+            },
+            Item::Rule(rule) => {
+                if counted {
+                    transfer.rules += 1;
+                };
+
+                storage.set_rule(rule)
+            },
+        };
+        if written.is_err() {
+            return Err(Error::PersistenceFailure);
+        };
+    };
+
+    Ok(transfer)
+}
+
+/// Format the given record on a single line.
+fn format(record: &Record) -> String {
+    // Records only contain strings, which can always be serialized.
+    serde_json::to_string(record).unwrap_or_default()
+}
+
+/// Parse the item of the given line, if it's a valid record.
+fn parse(line: &str) -> Option<Item> {
+    match serde_json::from_str(line).ok()? {
+        Record::Job { identifier, execution, status } => {
+            let execution = DateTime::parse_from_rfc3339(&execution).ok()?.with_timezone(&Utc);
+            let status = match status {
+                StatusRecord::Planned | StatusRecord::Triggered => JobStatus::Planned,
+                StatusRecord::Executed => JobStatus::Executed,
+                StatusRecord::Failed => JobStatus::Failed,
+            };
+
+            Some(Item::Job(Job::new(identifier, execution, status)))
+        },
+        Record::Rule { identifier, pattern, runner } => {
+            let runner = match runner {
+                RunnerRecord::Shell { command } => Runner::Shell { command },
+                RunnerRecord::Amqp { dsn, exchange, routing_key } => Runner::Amqp { dsn, exchange, routing_key },
+            };
+
+            Some(Item::Rule(Rule::new(identifier, pattern, runner)))
+        },
+    }
+}
+
+/// Convert Job into Record.
+impl From<&Job> for Record {
+    fn from(job: &Job) -> Self {
+        Record::Job {
+            identifier: job.get_identifier().clone(),
+            execution: job.get_execution().to_rfc3339(),
+            status: match job.get_status() {
+                JobStatus::Planned => StatusRecord::Planned,
+                JobStatus::Triggered => StatusRecord::Triggered,
+                JobStatus::Executed => StatusRecord::Executed,
+                JobStatus::Failed => StatusRecord::Failed,
+            },
+        }
+    }
+}
+
+/// Convert Rule into Record.
+impl From<&Rule> for Record {
+    fn from(rule: &Rule) -> Self {
+        Record::Rule {
+            identifier: rule.get_identifier().clone(),
+            pattern: rule.get_pattern().clone(),
+            runner: match rule.get_runner().clone() {
+                Runner::Shell { command } => RunnerRecord::Shell { command },
+                Runner::Amqp { dsn, exchange, routing_key } => RunnerRecord::Amqp { dsn, exchange, routing_key },
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::offset::TimeZone;
+    use crate::shard::Shard;
+    use std::path::PathBuf;
+    use super::super::{Backend, CompactionTrigger, Configuration, Fsync, Keyring};
+
+    fn start(shard: Shard) -> Storage {
+        let mut storage = Storage::new(Configuration {
+            persistence_backend: Backend::Ephemeral,
+            persistence_data_directory: PathBuf::from("data"),
+            persistence_fsync: Fsync::Frame,
+            persistence_compaction_trigger: CompactionTrigger::Entries(1000),
+            persistence_keyring: Keyring::default(),
+            backup_directory: None,
+            replication_listen: None,
+            replication_primary: None,
+            replication_secret: None,
+            cluster: None,
+            shard,
+        });
+        assert!(storage.initialize().is_ok());
+
+        storage
+    }
+
+    fn job(identifier: &str, hour: u32, status: JobStatus) -> Job {
+        Job::new(identifier.to_string(), Utc.ymd(2020, 6, 17).and_hms(hour, 0, 0), status)
+    }
+
+    fn rule(identifier: &str, command: &str) -> Rule {
+        Rule::new(identifier.to_string(), String::from("app."), Runner::Shell { command: command.to_string() })
+    }
+
+    /// Get all jobs of the given storage, ordered by identifier.
+    fn jobs(storage: &Storage) -> Vec<Job> {
+        let mut jobs = storage.get_jobs();
+        jobs.sort_by(|a, b| a.get_identifier().cmp(b.get_identifier()));
+
+        jobs
+    }
+
+    fn export_to_string(storage: &Storage) -> String {
+        let mut output = Vec::new();
+        assert!(export(storage, &mut output).is_ok());
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let mut exported = start(Shard::new(0, 1));
+        assert!(exported.set_rule(rule("app.rule.2", "b.sh")).is_ok());
+        assert!(exported.set_rule(rule("app.rule.1", "a.sh")).is_ok());
+        for (index, status) in [JobStatus::Planned, JobStatus::Triggered, JobStatus::Executed, JobStatus::Failed].iter().enumerate() {
+            assert!(exported.set_job(job(&format!("app.job.{}", index), index as u32, *status)).is_ok());
+        };
+        let mut output = Vec::new();
+        assert_eq!(export(&exported, &mut output), Ok(Transfer { jobs: 4, rules: 2, skipped: 0 }));
+        let content = String::from_utf8(output).unwrap();
+
+        // Rules come first, then jobs, ordered by identifier.
+        let identifiers: Vec<_> = content.lines().map(|line| parse(line).unwrap().get_identifier().clone()).collect();
+        assert_eq!(identifiers, vec!["app.rule.1", "app.rule.2", "app.job.0", "app.job.1", "app.job.2", "app.job.3"]);
+
+        // All items are imported as they were exported, except triggered jobs, planned again.
+        let mut imported = start(Shard::new(0, 1));
+        assert_eq!(import(&mut imported, &mut content.as_bytes(), Policy::Fail), Ok(Transfer { jobs: 4, rules: 2, skipped: 0 }));
+        assert_eq!(imported.get_rule("app.rule.1"), exported.get_rule("app.rule.1"));
+        assert_eq!(imported.get_rule("app.rule.2"), exported.get_rule("app.rule.2"));
+        assert_eq!(jobs(&imported), vec![
+            job("app.job.0", 0, JobStatus::Planned),
+            job("app.job.1", 1, JobStatus::Planned),
+            job("app.job.2", 2, JobStatus::Executed),
+            job("app.job.3", 3, JobStatus::Failed),
+        ]);
+        assert_eq!(export_to_string(&imported), content.replace(r#""status":"triggered""#, r#""status":"planned""#));
+
+        // Shards only import their own jobs, but all rules, and the numbers of transferred items
+        // of all shards add up.
+        let mut shards = vec![start(Shard::new(0, 2)), start(Shard::new(1, 2))];
+        let mut total = Transfer::default();
+        for shard in &mut shards {
+            let transfer = import(shard, &mut content.as_bytes(), Policy::Fail).unwrap();
+            assert_eq!(shard.get_rules().len(), 2);
+            assert!(shard.get_jobs().iter().all(|job| shard.get_shard().owns(job.get_identifier())));
+            total.jobs += transfer.jobs;
+            total.rules += transfer.rules;
+        };
+        assert_eq!((total.jobs, total.rules), (4, 2));
+        let lines = export_to_string(&shards[0]).lines().count() + export_to_string(&shards[1]).lines().count();
+        assert_eq!(lines, 6);
+    }
+
+    #[test]
+    fn test_import_policies() {
+        let content = [
+            format(&Record::from(&rule("app.rule.1", "new.sh"))),
+            format(&Record::from(&rule("app.rule.2", "new.sh"))),
+            format(&Record::from(&job("app.job.1", 10, JobStatus::Planned))),
+            format(&Record::from(&job("app.job.2", 10, JobStatus::Planned))),
+            format(&Record::from(&job("app.job.3", 10, JobStatus::Planned))),
+        ].join("\n");
+        let existing = || {
+            let mut storage = start(Shard::new(0, 1));
+            assert!(storage.set_rule(rule("app.rule.1", "old.sh")).is_ok());
+            assert!(storage.set_job(job("app.job.1", 1, JobStatus::Planned)).is_ok());
+            assert!(storage.set_job(job("app.job.2", 1, JobStatus::Triggered)).is_ok());
+
+            storage
+        };
+
+        // Existing items are kept.
+        let mut storage = existing();
+        assert_eq!(import(&mut storage, &mut content.as_bytes(), Policy::Skip), Ok(Transfer { jobs: 1, rules: 1, skipped: 3 }));
+        assert_eq!(storage.get_rule("app.rule.1"), Some(&rule("app.rule.1", "old.sh")));
+        assert_eq!(storage.get_rule("app.rule.2"), Some(&rule("app.rule.2", "new.sh")));
+        assert_eq!(jobs(&storage), vec![
+            job("app.job.1", 1, JobStatus::Planned),
+            job("app.job.2", 1, JobStatus::Triggered),
+            job("app.job.3", 10, JobStatus::Planned),
+        ]);
+
+        // Existing items are replaced, except jobs being executed.
+        let mut storage = existing();
+        assert_eq!(import(&mut storage, &mut content.as_bytes(), Policy::Overwrite), Ok(Transfer { jobs: 2, rules: 2, skipped: 1 }));
+        assert_eq!(storage.get_rule("app.rule.1"), Some(&rule("app.rule.1", "new.sh")));
+        assert_eq!(storage.get_rule("app.rule.2"), Some(&rule("app.rule.2", "new.sh")));
+        assert_eq!(jobs(&storage), vec![
+            job("app.job.1", 10, JobStatus::Planned),
+            job("app.job.2", 1, JobStatus::Triggered),
+            job("app.job.3", 10, JobStatus::Planned),
+        ]);
+
+        // The import fails on the first conflict, before writing anything.
+        let mut storage = existing();
+        assert_eq!(import(&mut storage, &mut content.as_bytes(), Policy::Fail), Err(Error::Conflict(String::from("app.rule.1"))));
+        assert_eq!(storage.get_rule("app.rule.2"), None);
+        assert_eq!(storage.get_job("app.job.3"), None);
+
+        // Without conflicts, all policies import all items.
+        for policy in &[Policy::Skip, Policy::Overwrite, Policy::Fail] {
+            let mut storage = start(Shard::new(0, 1));
+            assert_eq!(import(&mut storage, &mut content.as_bytes(), *policy), Ok(Transfer { jobs: 3, rules: 2, skipped: 0 }));
+        };
+
+        // An invalid input is never partially imported, whatever the policy.
+        let invalid = format!("{}\n\n{}", content, "{}");
+        let mut storage = start(Shard::new(0, 1));
+        assert_eq!(import(&mut storage, &mut invalid.as_bytes(), Policy::Overwrite), Err(Error::InvalidRecord(7)));
+        assert!(storage.get_rules().is_empty());
+        assert!(storage.get_jobs().is_empty());
+    }
+
+    #[test]
+    fn test_format_and_parse() {
+        let job = Job::new(String::from("app.job.1"), Utc.ymd(2020, 6, 17).and_hms(21, 47, 16), JobStatus::Executed);
+        let line = format(&Record::from(&job));
+        assert_eq!(line, r#"{"type":"job","identifier":"app.job.1","execution":"2020-06-17T21:47:16+00:00","status":"executed"}"#);
+        assert_eq!(parse(&line), Some(Item::Job(job)));
+
+        let rule = Rule::new(String::from("app.rule"), String::from("app."), Runner::Amqp {
+            dsn: String::from("amqp://localhost"),
+            exchange: String::from("e"),
+            routing_key: String::from("k"),
+        });
+        let line = format(&Record::from(&rule));
+        assert_eq!(line, r#"{"type":"rule","identifier":"app.rule","pattern":"app.","runner":{"type":"amqp","dsn":"amqp://localhost","exchange":"e","routing_key":"k"}}"#);
+        assert_eq!(parse(&line), Some(Item::Rule(rule)));
+
+        // Triggered jobs are planned again, and executions may use any timezone.
+        assert_eq!(
+            parse(r#"{"type": "job", "identifier": "j", "execution": "2020-06-17T23:47:16+02:00", "status": "triggered"}"#),
+            Some(Item::Job(Job::new(String::from("j"), Utc.ymd(2020, 6, 17).and_hms(21, 47, 16), JobStatus::Planned))),
+        );
+        // Test invalid records.
+        assert_eq!(parse(r#"{"type": "job", "identifier": "j", "execution": "2020-06-17 21:47:16", "status": "planned"}"#), None);
+        assert_eq!(parse(r#"{"type": "job", "identifier": "j", "execution": "2020-06-17T21:47:16Z", "status": "done"}"#), None);
+        assert_eq!(parse(r#"{"type": "rule", "identifier": "r", "pattern": "", "runner": {"type": "cron"}}"#), None);
+        assert_eq!(parse(r#"{"type": "rule", "identifier": "r", "pattern": "", "runner": {"type": "shell", "command": "a"}, "other": 1}"#), None);
+        assert_eq!(parse("SET app.job.1 2020-06-17"), None);
+    }
+}
@@ -9,6 +9,7 @@ extern crate serde;
 extern crate simple_logger;
 extern crate validator;
 
+mod administration;
 mod cli;
 mod configuration;
 mod controller;
@@ -17,16 +18,41 @@ mod execution;
 mod logger;
 mod processor;
 mod query;
+mod shard;
 mod sync;
 
 use crossbeam_channel::select;
 use crossbeam_channel::unbounded;
 use self::cli::Application;
+use self::configuration::Backend as ConfigurationBackend;
+use self::configuration::Compaction as ConfigurationCompaction;
+use self::configuration::CompactionTrigger as ConfigurationCompactionTrigger;
 use self::configuration::Configuration;
+use self::configuration::Encryption as ConfigurationEncryption;
+use self::configuration::FsyncMode as ConfigurationFsyncMode;
+use self::configuration::FsyncOnPersist as ConfigurationFsyncOnPersist;
+use self::configuration::Database as ConfigurationDatabase;
 use self::configuration::LogLevel as ConfigurationLogLevel;
+use self::configuration::Persistence as ConfigurationPersistence;
+use self::configuration::RateLimitPolicy as ConfigurationRateLimitPolicy;
+use self::configuration::RateLimitScope as ConfigurationRateLimitScope;
+use self::configuration::User as ConfigurationUser;
+use self::controller::Configuration as ControllerConfiguration;
 use self::controller::Controller;
+use self::controller::Http as ControllerHttp;
+use self::controller::Resp as ControllerResp;
+use self::controller::RateLimit as ControllerRateLimit;
+use self::controller::RateLimitPolicy as ControllerRateLimitPolicy;
+use self::controller::RateLimitScope as ControllerRateLimitScope;
+use self::database::Backend as DatabaseBackend;
+use self::database::ClusterConfiguration as DatabaseClusterConfiguration;
+use self::database::CompactionTrigger as DatabaseCompactionTrigger;
 use self::database::Configuration as DatabaseConfiguration;
 use self::database::Database;
+use self::database::Fsync as DatabaseFsync;
+use self::database::Key as DatabaseKey;
+use self::database::Keyring as DatabaseKeyring;
+use self::database::Secret as DatabaseSecret;
 use self::database::execution::protocol::Request as DatabaseExecutionRequest;
 use self::database::execution::protocol::Response as DatabaseExecutionResponse;
 use self::database::execution::protocol::Runner as DatabaseExecutionRunner;
@@ -36,6 +62,12 @@ use self::processor::Processor;
 use self::processor::protocol::Request as ProcessorExecutionRequest;
 use self::processor::protocol::Response as ProcessorExecutionResponse;
 use self::processor::protocol::Runner as ProcessorExecutionRunner;
+use self::shard::Shard;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 
 fn main() {
     let arguments = Application::handle_arguments();
@@ -53,22 +85,132 @@ fn main() {
     Logger::initialize(LoggerLevel::from(configuration.log.level));
     log::debug!("Booting with the following configuration: {:?}.", &configuration);
 
-    let (query_owning_side, query_reverse_side) = sync::link();
+    let keyring = match load_keyring(&configuration.database.encryption) {
+        Ok(keyring) => keyring,
+        Err(message) => {
+            log::error!("Unable to load the encryption keys: {}.", message);
+
+            return;
+        },
+    };
+
+    let secret = match load_secret(&configuration.replication.secret_file, &configuration.replication.secret_variable) {
+        Ok(secret) => secret.map(|secret| DatabaseSecret::new(&secret)),
+        Err(message) => {
+            log::error!("Unable to load the replication secret: {}.", message);
+
+            return;
+        },
+    };
+
+    let cluster_secret = match &configuration.cluster {
+        Some(cluster) => match load_secret(&cluster.secret_file, &cluster.secret_variable) {
+            Ok(secret) => secret.map(|secret| DatabaseSecret::new(&secret)),
+            Err(message) => {
+                log::error!("Unable to load the cluster secret: {}.", message);
+
+                return;
+            },
+        },
+        None => None,
+    };
+
+    let backend = match backend(&configuration.database) {
+        Ok(backend) => backend,
+        Err(message) => {
+            log::error!("Unable to use the configured persistence backend: {}.", message);
+
+            return;
+        },
+    };
+
+    if let Err(message) = check_shards(&configuration.database) {
+        log::error!("Unable to shard the database: {}.", message);
+
+        return;
+    };
+
+    let database_configuration = DatabaseConfiguration {
+        storage_persistence_backend: backend,
+        storage_persistence_data_directory: PathBuf::from(&configuration.database.data_directory),
+        storage_persistence_fsync: DatabaseFsync::from(configuration.database.fsync_on_persist),
+        storage_persistence_compaction_trigger: DatabaseCompactionTrigger::from(&configuration.database.compaction),
+        storage_persistence_keyring: keyring,
+        storage_backup_directory: configuration.backup.directory.as_ref().map(PathBuf::from),
+        replication_listen: configuration.replication.listen.clone(),
+        replication_primary: configuration.replication.primary.clone(),
+        replication_secret: secret,
+        cluster: configuration.cluster.as_ref().map(|cluster| DatabaseClusterConfiguration {
+            node: cluster.node as u64,
+            listen: cluster.listen.clone(),
+            address: cluster.advertise.clone().unwrap_or_else(|| configuration.controller.listen.to_string()),
+            peers: cluster.peers.iter().map(|peer| (peer.node as u64, peer.address.clone())).collect(),
+            secret: cluster_secret,
+        }),
+        framerate: configuration.database.framerate as u16,
+        client_frame_quota: configuration.database.client_frame_quota as usize,
+        shard: Shard::new(0, configuration.database.shards as usize),
+    };
+
+    if let Some(command) = arguments.command {
+        process::exit(administration::run(command, database_configuration));
+    };
+
+    let users = match load_users(&configuration.controller.users) {
+        Ok(users) => users,
+        Err(message) => {
+            log::error!("Unable to load the passwords of users: {}.", message);
+
+            return;
+        },
+    };
+
+    let shards = database_configuration.shard.count;
     let (database_execution_request_sender, execution_request_receiver) = unbounded();
     let (execution_request_sender, processor_execution_request_receiver) = unbounded();
-    let (execution_response_sender, database_execution_response_receiver) = unbounded();
     let (processor_execution_response_sender, execution_response_receiver) = unbounded();
+    let mut query_links = Vec::with_capacity(shards);
+    let mut execution_response_senders = Vec::with_capacity(shards);
+    let mut databases = Vec::with_capacity(shards);
+    for configuration in database_configuration.split() {
+        let (query_owning_side, query_reverse_side) = sync::link();
+        let (execution_response_sender, database_execution_response_receiver) = unbounded();
+        query_links.push(query_owning_side);
+        execution_response_senders.push(execution_response_sender);
+        databases.push((query_reverse_side, database_execution_response_receiver, configuration));
+    };
+    // The shard of each job being executed, to send its execution response to.
+    let mut executions = HashMap::new();
 
-    // Spawn the controller, the database and the processor.
-    Controller::start(configuration.controller.listen.to_string(), query_owning_side);
-    Database::start(
-        query_reverse_side,
-        (database_execution_request_sender, database_execution_response_receiver),
-        DatabaseConfiguration {
-            storage_persistence_fsync_on_persist: configuration.database.fsync_on_persist,
-            framerate: configuration.database.framerate as u16,
+    // Spawn the controller, the database (each of its shards) and the processor.
+    Controller::start(
+        query_links,
+        ControllerConfiguration {
+            listen: configuration.controller.listen.to_string(),
+            unix_socket: configuration.controller.unix_socket.clone(),
+            rate_limit: ControllerRateLimit {
+                rate: configuration.controller.rate_limit.rate,
+                burst: configuration.controller.rate_limit.burst,
+                policy: ControllerRateLimitPolicy::from(configuration.controller.rate_limit.policy),
+                scope: ControllerRateLimitScope::from(configuration.controller.rate_limit.scope),
+            },
+            http: configuration.controller.http.listen.clone().map(|listen| ControllerHttp {
+                listen,
+                workers: configuration.controller.http.workers as u16,
+            }),
+            resp: configuration.controller.resp.listen.clone().map(|listen| ControllerResp {
+                listen,
+            }),
+            users,
         },
     );
+    for (query_reverse_side, database_execution_response_receiver, configuration) in databases {
+        Database::start(
+            query_reverse_side,
+            (database_execution_request_sender.clone(), database_execution_response_receiver),
+            configuration,
+        );
+    };
     Processor::start((processor_execution_response_sender, processor_execution_request_receiver));
 
     loop {
@@ -79,6 +221,7 @@ fn main() {
             recv(execution_request_receiver) -> message => {
                 match message {
                     Ok(message) => {
+                        executions.insert(message.identifier, Shard::locate(&message.job_identifier, shards));
                         execution_request_sender.send(ProcessorExecutionRequest::from(message)).unwrap();
                     },
                     Err(_) => {
@@ -90,7 +233,9 @@ fn main() {
             recv(execution_response_receiver) -> message => {
                 match message {
                     Ok(message) => {
-                        execution_response_sender.send(DatabaseExecutionResponse::from(message)).unwrap();
+                        if let Some(shard) = executions.remove(&message.identifier) {
+                            execution_response_senders[shard].send(DatabaseExecutionResponse::from(message)).unwrap();
+                        };
                     },
                     Err(_) => {
                         // @TODO: Handle the channel disconnection properly.
@@ -102,6 +247,139 @@ fn main() {
     }
 }
 
+/// Get the configured persistence backend, persisting nothing when the persistence is disabled. It
+/// fails when Kairoi has been compiled without the feature providing it.
+fn backend(database: &ConfigurationDatabase) -> Result<DatabaseBackend, String> {
+    if let ConfigurationPersistence::None = database.persistence {
+        log::warn!("The persistence is disabled: all jobs and rules will be lost when the server stops.");
+
+        return Ok(DatabaseBackend::Ephemeral);
+    };
+
+    match database.backend {
+        ConfigurationBackend::Logfile => Ok(DatabaseBackend::Logfile),
+        #[cfg(feature = "backend-sled")]
+        ConfigurationBackend::Sled => Ok(DatabaseBackend::Sled),
+        #[cfg(not(feature = "backend-sled"))]
+        ConfigurationBackend::Sled => Err(String::from("Kairoi has been compiled without the `backend-sled` feature")),
+    }
+}
+
+/// Check that the data directory is split into the configured number of shards, recording this
+/// number in the `shards` file of the data directory when first splitting it. Since jobs are
+/// persisted in the subdirectory of their shard, changing the number of shards of a data directory
+/// would lose them.
+fn check_shards(database: &ConfigurationDatabase) -> Result<(), String> {
+    if let ConfigurationPersistence::None = database.persistence {
+        return Ok(());
+    };
+
+    let count = database.shards as usize;
+    let directory = Path::new(&database.data_directory);
+    let path = directory.join("shards");
+    match fs::read_to_string(&path) {
+        Ok(text) => match text.trim().parse::<usize>() {
+            Ok(recorded) if recorded == count => Ok(()),
+            Ok(recorded) => Err(format!("the data directory is split into {} shards, not {}", recorded, count)),
+            Err(_) => Err(format!("the file '{}' is invalid", path.display())),
+        },
+        Err(_) if count == 1 => Ok(()),
+        Err(_) => {
+            // The lock file is created by any database using the data directory.
+            if directory.join("lock").exists() {
+                return Err(String::from("the data directory contains the data of a database without shards"));
+            };
+            if fs::create_dir_all(directory).and_then(|_| fs::write(&path, count.to_string())).is_err() {
+                return Err(format!("the file '{}' can't be written", path.display()));
+            };
+
+            Ok(())
+        },
+    }
+}
+
+/// Load the keyring encrypting logfiles, reading all keys from the configured files and
+/// environment variables. Each key is written as 64 hexadecimal characters.
+fn load_keyring(encryption: &ConfigurationEncryption) -> Result<DatabaseKeyring, String> {
+    let parse = |text: String, source: String| match DatabaseKey::parse(&text) {
+        Some(key) => Ok(key),
+        None => Err(format!("{} doesn't contain a valid key (64 hexadecimal characters)", source)),
+    };
+    let from_file = |path: &String| match fs::read_to_string(path) {
+        Ok(text) => parse(text, format!("the file '{}'", path)),
+        Err(_) => Err(format!("the file '{}' can't be read", path)),
+    };
+    let from_variable = |name: &String| match env::var(name) {
+        Ok(text) => parse(text, format!("the environment variable '{}'", name)),
+        Err(_) => Err(format!("the environment variable '{}' isn't set", name)),
+    };
+
+    let current = match (&encryption.key_file, &encryption.key_variable) {
+        (Some(path), _) => Some(from_file(path)?),
+        (None, Some(name)) => Some(from_variable(name)?),
+        (None, None) => None,
+    };
+    let mut previous = Vec::new();
+    for path in &encryption.previous_key_files {
+        previous.push(from_file(path)?);
+    };
+    for name in &encryption.previous_key_variables {
+        previous.push(from_variable(name)?);
+    };
+    if let Some(key) = &current {
+        log::info!("Encrypting logfiles with the key {}.", key.get_fingerprint());
+    };
+
+    Ok(DatabaseKeyring::new(current, previous))
+}
+
+/// Load a secret shared by several servers (a primary and its replicas, or the nodes of a cluster),
+/// if any, reading it from the given file or environment variable. Line feeds ending the file are
+/// ignored.
+fn load_secret(file: &Option<String>, variable: &Option<String>) -> Result<Option<String>, String> {
+    let secret = match (file, variable) {
+        (Some(path), _) => match fs::read_to_string(path) {
+            Ok(text) => text.trim_end_matches(|character| character == '\n' || character == '\r').to_string(),
+            Err(_) => return Err(format!("the file '{}' can't be read", path)),
+        },
+        (None, Some(name)) => match env::var(name) {
+            Ok(text) => text,
+            Err(_) => return Err(format!("the environment variable '{}' isn't set", name)),
+        },
+        (None, None) => return Ok(None),
+    };
+    if secret.is_empty() {
+        return Err(String::from("the secret is empty"));
+    };
+
+    Ok(Some(secret))
+}
+
+/// Load the passwords of all users, reading them from the configured files and environment
+/// variables, and index them by user name. Line feeds ending password files are ignored.
+fn load_users(users: &[ConfigurationUser]) -> Result<HashMap<String, String>, String> {
+    let mut passwords = HashMap::new();
+    for user in users {
+        let password = match (&user.password_file, &user.password_variable) {
+            (Some(path), _) => match fs::read_to_string(path) {
+                Ok(text) => text.trim_end_matches(|character| character == '\n' || character == '\r').to_string(),
+                Err(_) => return Err(format!("the file '{}' can't be read", path)),
+            },
+            (None, Some(name)) => match env::var(name) {
+                Ok(text) => text,
+                Err(_) => return Err(format!("the environment variable '{}' isn't set", name)),
+            },
+            (None, None) => return Err(format!("the user '{}' has no password", user.name)),
+        };
+        if password.is_empty() {
+            return Err(format!("the password of the user '{}' is empty", user.name));
+        };
+        passwords.insert(user.name.clone(), password);
+    };
+
+    Ok(passwords)
+}
+
 impl From<DatabaseExecutionRequest> for ProcessorExecutionRequest {
     fn from(request: DatabaseExecutionRequest) -> Self {
         Self {
@@ -136,3 +414,46 @@ impl From<ConfigurationLogLevel> for LoggerLevel {
         }
     }
 }
+
+impl From<ConfigurationRateLimitPolicy> for ControllerRateLimitPolicy {
+    fn from(policy: ConfigurationRateLimitPolicy) -> Self {
+        match policy {
+            ConfigurationRateLimitPolicy::Delay => ControllerRateLimitPolicy::Delay,
+            ConfigurationRateLimitPolicy::Reject => ControllerRateLimitPolicy::Reject,
+        }
+    }
+}
+
+impl From<ConfigurationRateLimitScope> for ControllerRateLimitScope {
+    fn from(scope: ConfigurationRateLimitScope) -> Self {
+        match scope {
+            ConfigurationRateLimitScope::Connection => ControllerRateLimitScope::Connection,
+            ConfigurationRateLimitScope::Address => ControllerRateLimitScope::Address,
+            ConfigurationRateLimitScope::Identity => ControllerRateLimitScope::Identity,
+        }
+    }
+}
+
+impl From<ConfigurationFsyncOnPersist> for DatabaseFsync {
+    fn from(fsync: ConfigurationFsyncOnPersist) -> Self {
+        match fsync {
+            ConfigurationFsyncOnPersist::Enabled(true) => DatabaseFsync::Always,
+            ConfigurationFsyncOnPersist::Enabled(false) => DatabaseFsync::Never,
+            ConfigurationFsyncOnPersist::Mode(ConfigurationFsyncMode::Frame) => DatabaseFsync::Frame,
+            ConfigurationFsyncOnPersist::Mode(ConfigurationFsyncMode::Everysec) => DatabaseFsync::EverySecond,
+        }
+    }
+}
+
+impl From<&ConfigurationCompaction> for DatabaseCompactionTrigger {
+    fn from(compaction: &ConfigurationCompaction) -> Self {
+        match compaction.trigger {
+            ConfigurationCompactionTrigger::Entries => DatabaseCompactionTrigger::Entries(compaction.entries as usize),
+            ConfigurationCompactionTrigger::Size => DatabaseCompactionTrigger::Size(compaction.size as u64),
+            ConfigurationCompactionTrigger::Ratio => DatabaseCompactionTrigger::Ratio {
+                ratio: compaction.ratio,
+                minimum_size: compaction.minimum_size as u64,
+            },
+        }
+    }
+}
@@ -2,15 +2,43 @@ use chrono::DateTime;
 use chrono::offset::Utc;
 use crate::execution::runner::Runner;
 
+/// The handling of imported items conflicting with existing ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportPolicy {
+    Skip,
+    Overwrite,
+    Fail,
+}
+
 #[derive(Debug, Clone)]
 pub enum Instruction {
     Set {
         identifier: String,
         execution: DateTime<Utc>,
     },
+    Get {
+        identifier: String,
+    },
+    Unset {
+        identifier: String,
+    },
     RuleSet {
         identifier: String,
         pattern: String,
         runner: Runner,
     },
+    RuleList,
+    Compact,
+    CompactStatus,
+    Backup {
+        path: String,
+    },
+    Export {
+        path: String,
+    },
+    Import {
+        path: String,
+        policy: ImportPolicy,
+    },
+    Promote,
 }
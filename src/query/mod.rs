@@ -1,9 +1,27 @@
 pub mod instruction;
+pub mod output;
 
 use instruction::Instruction;
+use output::Output;
 
 pub type Client = u128;
 
+/// The reason why an instruction has not been handled successfully.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The item targeted by the instruction doesn't exist.
+    NotFound,
+    /// The instruction is incompatible with the current state of the targeted item.
+    Conflict,
+    /// The instruction writes to a replica, which is read-only.
+    ReadOnly,
+    /// The instruction writes to a node of a cluster which isn't its leader. The client address
+    /// of the leader is given, if known.
+    NotLeader(Option<String>),
+    /// The instruction failed to be executed (for example, because of a persistence failure).
+    Failure,
+}
+
 #[derive(Clone, Debug)]
 pub struct Request {
     client: Client,
@@ -40,18 +58,18 @@ impl Request {
 #[derive(Debug)]
 pub struct Response {
     request: Request,
-    result: Result<(), ()>,
+    result: Result<Output, Error>,
 }
 
 impl Response {
-    pub fn new(request: Request, result: Result<(), ()>) -> Response {
+    pub fn new(request: Request, result: Result<Output, Error>) -> Response {
         Response {
             request: request,
             result: result,
         }
     }
 
-    pub fn get_result(&self) -> &Result<(), ()> {
+    pub fn get_result(&self) -> &Result<Output, Error> {
         &self.result
     }
 
@@ -0,0 +1,69 @@
+use chrono::DateTime;
+use chrono::offset::Utc;
+use crate::execution::runner::Runner;
+
+/// The status of a job, as returned by queries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobStatus {
+    Planned,
+    Triggered,
+    Executed,
+    Failed,
+}
+
+/// A job, as returned by queries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub identifier: String,
+    pub execution: DateTime<Utc>,
+    pub status: JobStatus,
+}
+
+/// A rule, as returned by queries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub identifier: String,
+    pub pattern: String,
+    pub runner: Runner,
+}
+
+/// The progress of a running compaction, in bytes of logfiles, as returned by queries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionProgress {
+    pub started: DateTime<Utc>,
+    pub processed_bytes: u64,
+    pub total_bytes: u64,
+}
+
+/// The result of the last terminated compaction, as returned by queries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionResult {
+    pub success: bool,
+    pub finished: DateTime<Utc>,
+    pub entries: usize,
+}
+
+/// The state of the storage compaction, as returned by queries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compaction {
+    pub running: Option<CompactionProgress>,
+    pub last: Option<CompactionResult>,
+}
+
+/// The numbers of exported or imported items, as returned by queries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transfer {
+    pub jobs: usize,
+    pub rules: usize,
+    pub skipped: usize,
+}
+
+/// The output of a successfully handled instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    None,
+    Job(Job),
+    Rules(Vec<Rule>),
+    Compaction(Compaction),
+    Transfer(Transfer),
+}
@@ -0,0 +1,77 @@
+//! Partition of jobs between several databases, each one running in its own thread.
+//!
+//! Each job belongs to a single shard, located by hashing its identifier. The hash must never
+//! change, since jobs are persisted in the data directory of their shard: it uses the 64 bits
+//! FNV-1a function, instead of the hashers of the standard library, whose algorithms may change.
+//! Rules aren't partitioned: all shards contain all rules.
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// A shard, among a given number of shards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    /// Create the shard with the given index, among the given number of shards.
+    pub fn new(index: usize, count: usize) -> Self {
+        Self {
+            index,
+            count,
+        }
+    }
+
+    /// Get the index of the shard owning the job with the given identifier, among the given
+    /// number of shards.
+    pub fn locate(identifier: &str, count: usize) -> usize {
+        let hash = identifier.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        });
+
+        (hash % count as u64) as usize
+    }
+
+    /// Check whether this shard owns the job with the given identifier.
+    pub fn owns(&self, identifier: &str) -> bool {
+        self.count == 1 || Self::locate(identifier, self.count) == self.index
+    }
+}
+
+/// A single shard, owning all jobs.
+impl Default for Shard {
+    fn default() -> Self {
+        Self::new(0, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        // Locations are persisted: they must never change.
+        assert_eq!(Shard::locate("", 1 << 32), 0x8422_2325);
+        assert_eq!(Shard::locate("a", 1 << 32), 0x8601_ec8c);
+        assert_eq!(Shard::locate("app.job.1", 1), 0);
+
+        let mut counts = [0; 4];
+        for index in 0..4000 {
+            counts[Shard::locate(&format!("app.job.{}", index), 4)] += 1;
+        };
+        assert!(counts.iter().all(|count| *count > 800 && *count < 1200));
+    }
+
+    #[test]
+    fn test_owns() {
+        let shards: Vec<Shard> = (0..3).map(|index| Shard::new(index, 3)).collect();
+        for identifier in &["app.job.1", "app.job.2", "other.job"] {
+            assert_eq!(shards.iter().filter(|shard| shard.owns(identifier)).count(), 1);
+            assert!(shards[Shard::locate(identifier, 3)].owns(identifier));
+        };
+        assert!(Shard::default().owns("app.job.1"));
+    }
+}
@@ -1,10 +1,10 @@
-use std::sync::mpsc::{channel, Receiver, Sender};
+use crossbeam_channel::{Receiver, Sender, unbounded};
 
 /// Create a new bidirectionnal channel, with the owning side being able to produce messages, and
 /// the reverse side being able to asynchronously confirm these messages.
 pub fn link<T, U>() -> ((Sender<T>, Receiver<U>), (Sender<U>, Receiver<T>)) {
-    let (producer1, consumer1) = channel();
-    let (producer2, consumer2) = channel();
+    let (producer1, consumer1) = unbounded();
+    let (producer2, consumer2) = unbounded();
 
     ((producer1, consumer2), (producer2, consumer1))
 }